@@ -160,19 +160,35 @@ pub fn handle_camera(
 pub struct Sun {
     azimuth: f32,
     altitude: f32,
-    initialized: bool,
+    curr_azimuth: f32,
+    curr_altitude: f32,
 }
 
 impl Default for Sun {
     fn default() -> Self {
         Self {
             azimuth: 3.0,
-            altitude: StrolleSun::default().altitude,
-            initialized: false,
+            altitude: 0.35,
+            curr_azimuth: 3.0,
+            curr_altitude: 0.35,
         }
     }
 }
 
+pub fn setup_sun(mut commands: Commands, sun: Res<Sun>) {
+    commands.spawn((
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: 100.0,
+                ..default()
+            },
+            transform: sun_transform(sun.azimuth, sun.altitude),
+            ..default()
+        },
+        StrolleCelestialBody::default(),
+    ));
+}
+
 pub fn handle_sun(keys: Res<Input<KeyCode>>, mut sun: ResMut<Sun>) {
     if keys.just_pressed(KeyCode::H) {
         sun.azimuth -= 0.05;
@@ -193,18 +209,29 @@ pub fn handle_sun(keys: Res<Input<KeyCode>>, mut sun: ResMut<Sun>) {
 
 pub fn animate_sun(
     time: Res<Time>,
-    mut st_sun: ResMut<StrolleSun>,
     mut sun: ResMut<Sun>,
+    mut sun_xform: Query<&mut Transform, With<StrolleCelestialBody>>,
 ) {
-    if sun.initialized {
-        st_sun.azimuth = st_sun.azimuth
-            + (sun.azimuth - st_sun.azimuth) * time.delta_seconds();
+    let dt = time.delta_seconds();
+
+    sun.curr_azimuth = sun.curr_azimuth + (sun.azimuth - sun.curr_azimuth) * dt;
+
+    sun.curr_altitude =
+        sun.curr_altitude + (sun.altitude - sun.curr_altitude) * dt;
 
-        st_sun.altitude = st_sun.altitude
-            + (sun.altitude - st_sun.altitude) * time.delta_seconds();
-    } else {
-        sun.initialized = true;
-        st_sun.azimuth = sun.azimuth;
-        st_sun.altitude = sun.altitude;
+    for mut xform in sun_xform.iter_mut() {
+        *xform = sun_transform(sun.curr_azimuth, sun.curr_altitude);
     }
 }
+
+/// Returns transform of a directional light shining from given point on the
+/// sky.
+pub fn sun_transform(azimuth: f32, altitude: f32) -> Transform {
+    let dir = Vec3::new(
+        altitude.cos() * azimuth.sin(),
+        altitude.sin(),
+        -altitude.cos() * azimuth.cos(),
+    );
+
+    Transform::default().looking_to(-dir, Vec3::Y)
+}
@@ -81,11 +81,8 @@ fn setup(mut commands: Commands, assets: Res<AssetServer>) {
 
 fn animate(
     time: Res<Time>,
-    mut sun: ResMut<StrolleSun>,
     mut light: Query<&mut Transform, With<PointLight>>,
 ) {
-    sun.altitude = -1.0;
-
     light.single_mut().translation = vec3(
         time.elapsed_seconds().sin() / 2.0,
         1.5,
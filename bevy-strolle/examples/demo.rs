@@ -48,6 +48,7 @@ fn main() {
         .add_systems(Startup, setup_window)
         .add_systems(Startup, setup_camera)
         .add_systems(Startup, setup_scene)
+        .add_systems(Startup, common::setup_sun)
         .add_systems(Update, adjust_materials)
         .add_systems(Update, handle_materials)
         .add_systems(Update, common::handle_camera)
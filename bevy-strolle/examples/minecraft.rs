@@ -50,6 +50,7 @@ fn main() {
         .add_systems(Startup, setup_camera)
         .add_systems(Startup, setup_state)
         .add_systems(Startup, setup_scene)
+        .add_systems(Startup, common::setup_sun)
         // ---
         .add_systems(Update, common::handle_camera)
         .add_systems(Update, common::handle_sun)
@@ -38,7 +38,6 @@ fn setup(
     mut window: Query<&mut Window, With<PrimaryWindow>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut sun: ResMut<StrolleSun>,
 ) {
     let mut window = window.single_mut();
 
@@ -47,7 +46,18 @@ fn setup(
 
     // ---
 
-    sun.altitude = 0.33;
+    commands.spawn((
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                illuminance: 100.0,
+                ..default()
+            },
+            transform: Transform::default()
+                .looking_to(vec3(0.0, -0.33f32.sin(), 0.33f32.cos()), Vec3::Y),
+            ..default()
+        },
+        StrolleCelestialBody::default(),
+    ));
 
     commands
         .spawn(Camera3dBundle {
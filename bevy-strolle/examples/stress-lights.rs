@@ -37,7 +37,6 @@ fn setup(
     mut window: Query<&mut Window, With<PrimaryWindow>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut sun: ResMut<StrolleSun>,
 ) {
     let mut window = window.single_mut();
 
@@ -46,8 +45,6 @@ fn setup(
 
     // ---
 
-    sun.altitude = -1.0;
-
     commands
         .spawn(Camera3dBundle {
             camera_render_graph: CameraRenderGraph::new(
@@ -8,4 +8,8 @@ use strolle as st;
 #[derive(Clone, Debug, Default, Component)]
 pub struct StrolleCamera {
     pub mode: st::CameraMode,
+
+    /// Camera's exposure; by default the light doesn't get scaled at all,
+    /// matching Bevy's own renderer
+    pub exposure: st::Exposure,
 }
@@ -0,0 +1,24 @@
+use bevy::prelude::Component;
+
+/// Turns a directional light into a celestial body (e.g. the sun or the moon),
+/// making it light up the sky.
+///
+/// Light cast by such directional light gets attenuated by the atmosphere, so
+/// e.g. the sun close to the horizon casts reddish light, while the sun below
+/// the horizon doesn't cast any light at all.
+///
+/// Up to [`strolle::CelestialBody::MAX`] bodies are supported.
+#[derive(Clone, Copy, Debug, Component)]
+pub struct StrolleCelestialBody {
+    /// Apparent size of the body, in radians
+    pub angular_diameter: f32,
+}
+
+impl Default for StrolleCelestialBody {
+    fn default() -> Self {
+        // The sun, as seen from the Earth
+        Self {
+            angular_diameter: 0.0093,
+        }
+    }
+}
@@ -1,11 +1,11 @@
 mod camera;
+mod celestial_body;
 mod debug;
 mod event;
 pub mod graph;
 mod rendering_node;
 mod stages;
 mod state;
-mod sun;
 mod utils;
 
 pub mod prelude {
@@ -21,18 +21,17 @@ use bevy::render::RenderApp;
 pub use strolle as st;
 
 pub use self::camera::*;
+pub use self::celestial_body::*;
 pub use self::debug::*;
 pub use self::event::*;
 pub(crate) use self::rendering_node::*;
 pub(crate) use self::state::*;
-pub use self::sun::*;
 
 pub struct StrollePlugin;
 
 impl Plugin for StrollePlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<StrolleEvent>();
-        app.insert_resource(StrolleSun::default());
 
         if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
             render_app.insert_resource(SyncedState::default());
@@ -37,7 +37,7 @@ pub(crate) fn setup(render_app: &mut App) {
 
     render_app.add_systems(
         ExtractSchedule,
-        extract::sun.in_set(RenderSet::ExtractCommands),
+        extract::celestial_bodies.in_set(RenderSet::ExtractCommands),
     );
 
     render_app.add_systems(Render, prepare::meshes.in_set(RenderSet::Prepare));
@@ -55,9 +55,13 @@ pub(crate) fn setup(render_app: &mut App) {
 
     render_app.add_systems(Render, prepare::images.in_set(RenderSet::Prepare));
     render_app.add_systems(Render, prepare::lights.in_set(RenderSet::Prepare));
-    render_app.add_systems(Render, prepare::sun.in_set(RenderSet::Prepare));
     render_app.add_systems(Render, prepare::cameras.in_set(RenderSet::Prepare));
 
+    render_app.add_systems(
+        Render,
+        prepare::celestial_bodies.in_set(RenderSet::Prepare),
+    );
+
     render_app
         .add_systems(Render, prepare::flush.in_set(RenderSet::PrepareFlush));
 }
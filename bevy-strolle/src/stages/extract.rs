@@ -1,5 +1,4 @@
-use std::f32::consts::PI;
-
+use bevy::pbr::NotShadowCaster;
 use bevy::prelude::*;
 use bevy::render::camera::{CameraProjection, CameraRenderGraph};
 use bevy::render::texture::{ImageSampler, ImageSamplerDescriptor};
@@ -9,13 +8,13 @@ use bevy::utils::HashSet;
 use strolle as st;
 
 use crate::state::{
-    ExtractedCamera, ExtractedImage, ExtractedImageData, ExtractedImages,
-    ExtractedInstance, ExtractedInstances, ExtractedLight, ExtractedLights,
-    ExtractedMaterial, ExtractedMaterials, ExtractedMesh, ExtractedMeshes,
-    ExtractedSun,
+    ExtractedCamera, ExtractedCelestialBodies, ExtractedImage,
+    ExtractedImageData, ExtractedImages, ExtractedInstance, ExtractedInstances,
+    ExtractedLight, ExtractedLights, ExtractedMaterial, ExtractedMaterials,
+    ExtractedMesh, ExtractedMeshes,
 };
 use crate::utils::color_to_vec3;
-use crate::{StrolleCamera, StrolleEvent, StrolleSun};
+use crate::{StrolleCamera, StrolleCelestialBody, StrolleEvent};
 
 pub(crate) fn meshes(
     mut commands: Commands,
@@ -190,31 +189,51 @@ pub(crate) fn images(
 #[allow(clippy::type_complexity)]
 pub(crate) fn instances(
     mut commands: Commands,
+    instances: Extract<
+        Query<(
+            Entity,
+            &Handle<Mesh>,
+            &Handle<StandardMaterial>,
+            &GlobalTransform,
+            &InheritedVisibility,
+            Option<&RenderLayers>,
+            Has<NotShadowCaster>,
+        )>,
+    >,
     changed: Extract<
         Query<
+            Entity,
             (
-                Entity,
-                &Handle<Mesh>,
-                &Handle<StandardMaterial>,
-                &GlobalTransform,
-                &InheritedVisibility,
-                Option<&RenderLayers>,
+                With<Handle<Mesh>>,
+                Or<(
+                    Changed<Handle<Mesh>>,
+                    Changed<Handle<StandardMaterial>>,
+                    Changed<GlobalTransform>,
+                    Changed<InheritedVisibility>,
+                    Changed<RenderLayers>,
+                    Added<NotShadowCaster>,
+                )>,
             ),
-            Or<(
-                Changed<Handle<Mesh>>,
-                Changed<Handle<StandardMaterial>>,
-                Changed<GlobalTransform>,
-                Changed<InheritedVisibility>,
-                Changed<RenderLayers>,
-            )>,
         >,
     >,
     mut removed: Extract<RemovedComponents<Handle<Mesh>>>,
+    mut removed_layers: Extract<RemovedComponents<RenderLayers>>,
+    mut removed_not_shadow_casters: Extract<RemovedComponents<NotShadowCaster>>,
 ) {
     let mut removed: Vec<_> = removed.read().collect();
 
-    let changed = changed
+    // Removing a component doesn't trigger any change detection, so instances
+    // that lost their render layers (falling back to the default one) or got
+    // their shadows back have to be picked up separately
+    let changed: HashSet<_> = changed
         .iter()
+        .chain(removed_layers.read())
+        .chain(removed_not_shadow_casters.read())
+        .collect();
+
+    let changed = changed
+        .into_iter()
+        .filter_map(|entity| instances.get(entity).ok())
         .filter_map(
             |(
                 handle,
@@ -223,6 +242,7 @@ pub(crate) fn instances(
                 transform,
                 visibility,
                 layers,
+                not_shadow_caster,
             )| {
                 if !visibility.get() {
                     // TODO inefficient; we should push only if the object was
@@ -231,23 +251,13 @@ pub(crate) fn instances(
                     return None;
                 }
 
-                // TODO this is invalid (but good enough for now); instead, we
-                //      should probably propagate the layers up to the BVH
-                //      leaves and adjust the raytracer to read those
-                if let Some(layers) = layers {
-                    if *layers != RenderLayers::all() {
-                        // TODO inefficient; we should push only if the object
-                        //      was visible before
-                        removed.push(handle);
-                        return None;
-                    }
-                }
-
                 Some(ExtractedInstance {
                     handle,
                     mesh_handle: mesh_handle.id(),
                     material_handle: material_handle.id(),
                     xform: transform.affine(),
+                    visibility: layers_to_mask(layers),
+                    casts_shadows: !not_shadow_caster,
                 })
             },
         )
@@ -271,20 +281,37 @@ pub(crate) fn lights(
             Or<(Changed<SpotLight>, Changed<GlobalTransform>)>,
         >,
     >,
+    changed_directional_lights: Extract<
+        Query<
+            (
+                Entity,
+                &DirectionalLight,
+                &GlobalTransform,
+                Option<&StrolleCelestialBody>,
+            ),
+            Or<(
+                Changed<DirectionalLight>,
+                Changed<GlobalTransform>,
+                Changed<StrolleCelestialBody>,
+            )>,
+        >,
+    >,
     mut removed_point_lights: Extract<RemovedComponents<PointLight>>,
     mut removed_spot_lights: Extract<RemovedComponents<SpotLight>>,
+    mut removed_directional_lights: Extract<
+        RemovedComponents<DirectionalLight>,
+    >,
 ) {
     let mut removed: Vec<_> = removed_point_lights
         .read()
         .chain(removed_spot_lights.read())
+        .chain(removed_directional_lights.read())
         .collect();
 
     let changed_point_lights: Vec<_> = changed_point_lights
         .iter()
         .filter_map(|(handle, light, xform)| {
-            let intensity = light.intensity / (4.0 * PI);
-
-            if intensity < 0.0001 {
+            if light.intensity < 0.0001 {
                 removed.push(handle);
                 return None;
             }
@@ -292,8 +319,10 @@ pub(crate) fn lights(
             let light = st::Light::Point {
                 position: xform.translation(),
                 radius: light.radius,
-                color: color_to_vec3(light.color) * intensity,
+                color: color_to_vec3(light.color),
+                luminous_power: light.intensity,
                 range: light.range,
+                profile: None,
             };
 
             Some(ExtractedLight { handle, light })
@@ -303,9 +332,7 @@ pub(crate) fn lights(
     let changed_spot_lights: Vec<_> = changed_spot_lights
         .iter()
         .filter_map(|(handle, light, xform)| {
-            let intensity = light.intensity / (4.0 * PI);
-
-            if intensity < 0.0001 {
+            if light.intensity < 0.0001 {
                 removed.push(handle);
                 return None;
             }
@@ -316,10 +343,43 @@ pub(crate) fn lights(
             let light = st::Light::Spot {
                 position: translation,
                 radius: light.radius,
-                color: color_to_vec3(light.color) * intensity,
+                color: color_to_vec3(light.color),
+                luminous_power: light.intensity,
                 range: light.range,
                 direction: -(rotation * Vec3::Z).normalize(),
-                angle: light.outer_angle,
+                inner_angle: light.inner_angle,
+                outer_angle: light.outer_angle,
+                profile: None,
+            };
+
+            Some(ExtractedLight { handle, light })
+        })
+        .collect();
+
+    let changed_directional_lights: Vec<_> = changed_directional_lights
+        .iter()
+        .filter_map(|(handle, light, xform, body)| {
+            if light.illuminance < 0.0001 {
+                removed.push(handle);
+                return None;
+            }
+
+            let mut color = color_to_vec3(light.color);
+
+            // Bevy doesn't specify the light's apparent size, so let's assume
+            // it's the sun
+            let mut angular_diameter = 0.0093;
+
+            if let Some(body) = body {
+                color *= celestial_body(light, xform, body).transmittance();
+                angular_diameter = body.angular_diameter;
+            }
+
+            let light = st::Light::Directional {
+                direction: xform.forward(),
+                color,
+                illuminance: light.illuminance,
+                angular_diameter,
             };
 
             Some(ExtractedLight { handle, light })
@@ -329,6 +389,7 @@ pub(crate) fn lights(
     let changed = changed_point_lights
         .into_iter()
         .chain(changed_spot_lights)
+        .chain(changed_directional_lights)
         .collect();
 
     commands.insert_resource(ExtractedLights { changed, removed });
@@ -345,6 +406,7 @@ pub(crate) fn cameras(
             &Projection,
             &GlobalTransform,
             Option<&StrolleCamera>,
+            Option<&RenderLayers>,
         )>,
     >,
 ) {
@@ -355,6 +417,7 @@ pub(crate) fn cameras(
         projection,
         transform,
         strolle_camera,
+        layers,
     ) in cameras.iter()
     {
         if !camera.is_active || **camera_render_graph != crate::graph::NAME {
@@ -367,10 +430,61 @@ pub(crate) fn cameras(
             transform: transform.compute_matrix(),
             projection: projection.get_projection_matrix(),
             mode: strolle_camera.map(|camera| camera.mode),
+            exposure: strolle_camera.map(|camera| camera.exposure),
+            visibility: layers_to_mask(layers),
         });
     }
 }
 
-pub(crate) fn sun(mut commands: Commands, sun: Extract<Res<StrolleSun>>) {
-    commands.insert_resource(ExtractedSun { sun: Some(***sun) });
+pub(crate) fn celestial_bodies(
+    mut commands: Commands,
+    bodies: Extract<
+        Query<(&DirectionalLight, &GlobalTransform, &StrolleCelestialBody)>,
+    >,
+    mut has_warned: Local<bool>,
+) {
+    let count = bodies.iter().len();
+
+    if count > st::CelestialBody::MAX && !*has_warned {
+        warn!(
+            "Found {} celestial bodies, but only {} are supported - ignoring \
+             the rest",
+            count,
+            st::CelestialBody::MAX,
+        );
+
+        *has_warned = true;
+    }
+
+    let bodies = bodies
+        .iter()
+        .take(st::CelestialBody::MAX)
+        .map(|(light, xform, body)| celestial_body(light, xform, body))
+        .collect();
+
+    commands.insert_resource(ExtractedCelestialBodies {
+        bodies: Some(bodies),
+    });
+}
+
+fn celestial_body(
+    light: &DirectionalLight,
+    xform: &GlobalTransform,
+    body: &StrolleCelestialBody,
+) -> st::CelestialBody {
+    st::CelestialBody {
+        direction: -xform.forward(),
+        angular_diameter: body.angular_diameter,
+        illuminance: light.illuminance,
+    }
+}
+
+/// Converts render layers into Strolle's visibility mask; entities without
+/// any layers specified belong to the default one, as in Bevy.
+fn layers_to_mask(layers: Option<&RenderLayers>) -> u32 {
+    layers
+        .copied()
+        .unwrap_or_default()
+        .iter()
+        .fold(0, |mask, layer| mask | (1u32 << layer))
 }
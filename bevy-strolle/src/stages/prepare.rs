@@ -12,9 +12,9 @@ use bevy::utils::HashSet;
 use strolle as st;
 
 use crate::state::{
-    ExtractedCamera, ExtractedImageData, ExtractedImages, ExtractedInstances,
-    ExtractedLights, ExtractedMaterials, ExtractedMeshes, ExtractedSun,
-    SyncedCamera, SyncedState,
+    ExtractedCamera, ExtractedCelestialBodies, ExtractedImageData,
+    ExtractedImages, ExtractedInstances, ExtractedLights, ExtractedMaterials,
+    ExtractedMeshes, SyncedCamera, SyncedState,
 };
 use crate::utils::color_to_vec4;
 use crate::EngineResource;
@@ -245,7 +245,9 @@ pub(crate) fn instances(
                 entry.mesh_handle,
                 entry.material_handle,
                 entry.xform,
-            ),
+            )
+            .with_visibility(entry.visibility)
+            .with_casts_shadows(entry.casts_shadows),
         );
     }
 }
@@ -263,12 +265,12 @@ pub(crate) fn lights(
     }
 }
 
-pub(crate) fn sun(
+pub(crate) fn celestial_bodies(
     mut engine: ResMut<EngineResource>,
-    mut sun: ResMut<ExtractedSun>,
+    mut bodies: ResMut<ExtractedCelestialBodies>,
 ) {
-    if let Some(sun) = sun.sun.take() {
-        engine.update_sun(sun);
+    if let Some(bodies) = bodies.bodies.take() {
+        engine.update_celestial_bodies(bodies);
     }
 }
 
@@ -315,6 +317,8 @@ pub(crate) fn cameras(
 
             transform: ext_camera.transform,
             projection: ext_camera.projection,
+            visibility: ext_camera.visibility,
+            exposure: ext_camera.exposure.unwrap_or_default(),
         };
 
         match state.cameras.entry(entity) {
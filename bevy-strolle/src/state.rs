@@ -89,6 +89,8 @@ pub(crate) struct ExtractedInstance {
     pub mesh_handle: AssetId<Mesh>,
     pub material_handle: AssetId<StandardMaterial>,
     pub xform: Affine3A,
+    pub visibility: u32,
+    pub casts_shadows: bool,
 }
 
 #[derive(Debug, Resource)]
@@ -108,9 +110,11 @@ pub(crate) struct ExtractedCamera {
     pub transform: Mat4,
     pub projection: Mat4,
     pub mode: Option<st::CameraMode>,
+    pub exposure: Option<st::Exposure>,
+    pub visibility: u32,
 }
 
 #[derive(Debug, Resource)]
-pub(crate) struct ExtractedSun {
-    pub sun: Option<st::Sun>,
+pub(crate) struct ExtractedCelestialBodies {
+    pub bodies: Option<Vec<st::CelestialBody>>,
 }
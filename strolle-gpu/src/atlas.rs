@@ -0,0 +1,158 @@
+use glam::{vec2, Vec2, Vec4, Vec4Swizzles};
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+use spirv_std::Sampler;
+
+use crate::TexArray;
+
+/// Texture atlas containing images used by materials.
+///
+/// The atlas is a texture array, with each layer being a separate page (see:
+/// [`Self::PAGE_SHIFT`]), exposed through two views - sRGB and linear -
+/// so that e.g. base color textures get decoded from sRGB while normal maps
+/// are read as-is; which view a particular texture gets sampled through is
+/// decided by its flags (see: [`Self::FLAG_LINEAR`]).
+///
+/// Each image in the atlas comes together with its mip chain, stored at the
+/// same place within atlas' subsequent mip levels (see: [`Self::MIP_LEVELS`]).
+#[derive(Clone, Copy)]
+pub struct AtlasView<'a> {
+    srgb_tex: TexArray<'a>,
+    linear_tex: TexArray<'a>,
+    sampler: &'a Sampler,
+}
+
+impl<'a> AtlasView<'a> {
+    /// Width and height of each atlas' page, in texels.
+    pub const SIZE: u32 = 8192;
+
+    /// Texture contains linear data (e.g. a normal map) and so it should be
+    /// sampled through the linear view.
+    pub const FLAG_LINEAR: u32 = 1;
+
+    /// Texture should be filtered linearly when magnified (otherwise it's
+    /// sampled using the nearest texel).
+    pub const FLAG_MAG_LINEAR: u32 = 1 << 5;
+
+    /// Texture should be filtered linearly when minified (otherwise it's
+    /// sampled using the nearest texel).
+    pub const FLAG_MIN_LINEAR: u32 = 1 << 6;
+
+    /// Texture should be filtered linearly between mip levels (otherwise it's
+    /// sampled using the nearest mip level).
+    pub const FLAG_MIPMAP_LINEAR: u32 = 1 << 7;
+
+    /// Number of mip levels stored in the atlas, including the top one.
+    ///
+    /// Images are allocated at positions and sizes that are multiples of
+    /// `1 << (MIP_LEVELS - 1)`, so that each image's mip chain maps onto the
+    /// same (normalized) rectangle at each atlas' level.
+    pub const MIP_LEVELS: u32 = 5;
+
+    /// Where the addressing mode for the U coordinate is stored in flags (two
+    /// bits, see: [`Self::ADDRESS_REPEAT`] etc.).
+    pub const ADDRESS_U_SHIFT: u32 = 1;
+
+    /// Where the addressing mode for the V coordinate is stored in flags (two
+    /// bits, see: [`Self::ADDRESS_REPEAT`] etc.).
+    pub const ADDRESS_V_SHIFT: u32 = 3;
+
+    /// Where the index of the page containing the texture is stored in flags
+    /// (all the remaining bits).
+    pub const PAGE_SHIFT: u32 = 8;
+
+    pub const ADDRESS_REPEAT: u32 = 0;
+    pub const ADDRESS_MIRROR_REPEAT: u32 = 1;
+    pub const ADDRESS_CLAMP: u32 = 2;
+
+    pub fn new(
+        srgb_tex: TexArray<'a>,
+        linear_tex: TexArray<'a>,
+        sampler: &'a Sampler,
+    ) -> Self {
+        Self {
+            srgb_tex,
+            linear_tex,
+            sampler,
+        }
+    }
+
+    /// Samples texture located at given rectangle of the atlas (`xy` being its
+    /// offset and `zw` being its size, both normalized), at the page stored in
+    /// flags.
+    ///
+    /// Flags describe the texture's color space, addressing modes and filters;
+    /// see: [`Self::FLAG_LINEAR`] etc.
+    ///
+    /// Footprint approximates how much of the texture (in texture coordinates)
+    /// gets covered by a single pixel, e.g. as estimated by the ray cone, and
+    /// it's used to select the mip level; zero selects the top level.
+    pub fn sample(
+        self,
+        texture: Vec4,
+        flags: u32,
+        uv: Vec2,
+        footprint: f32,
+    ) -> Vec4 {
+        let uv = vec2(
+            Self::address(uv.x, (flags >> Self::ADDRESS_U_SHIFT) & 0b11),
+            Self::address(uv.y, (flags >> Self::ADDRESS_V_SHIFT) & 0b11),
+        );
+
+        let size = texture.zw() * (Self::SIZE as f32);
+
+        let lod = (footprint * (size.x * size.y).sqrt())
+            .max(1.0)
+            .log2()
+            .min((Self::MIP_LEVELS - 1) as f32);
+
+        let (lod, filter) = if lod > 0.0 {
+            let lod = if flags & Self::FLAG_MIPMAP_LINEAR == 0 {
+                lod.round()
+            } else {
+                lod
+            };
+
+            (lod, Self::FLAG_MIN_LINEAR)
+        } else {
+            (0.0, Self::FLAG_MAG_LINEAR)
+        };
+
+        // The atlas' sampler always filters linearly, so to get the nearest
+        // texel we have to aim right at its center
+        let uv = if flags & filter == 0 {
+            let size = (size / ((1 << (lod as u32)) as f32)).max(Vec2::ONE);
+
+            ((uv * size).floor().min(size - 1.0) + 0.5) / size
+        } else {
+            uv
+        };
+
+        let uv = (texture.xy() + uv * texture.zw())
+            .extend((flags >> Self::PAGE_SHIFT) as f32);
+
+        if flags & Self::FLAG_LINEAR == 0 {
+            self.srgb_tex.sample_by_lod(*self.sampler, uv, lod)
+        } else {
+            self.linear_tex.sample_by_lod(*self.sampler, uv, lod)
+        }
+    }
+
+    /// Brings given texture coordinate into the 0..=1 range, according to the
+    /// addressing mode.
+    fn address(t: f32, mode: u32) -> f32 {
+        if mode == Self::ADDRESS_CLAMP {
+            t.clamp(0.0, 1.0)
+        } else if mode == Self::ADDRESS_MIRROR_REPEAT {
+            let t = t.abs() % 2.0;
+
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        } else {
+            t - t.floor()
+        }
+    }
+}
@@ -5,7 +5,7 @@ use glam::{uvec2, vec2, vec3, UVec2, Vec3, Vec4Swizzles};
 use spirv_std::num_traits::Float;
 use spirv_std::Sampler;
 
-use crate::{F32Ext, Ray, Tex};
+use crate::{F32Ext, Ray, Tex, World};
 
 #[derive(Clone, Copy)]
 pub struct Atmosphere<'a> {
@@ -37,7 +37,7 @@ impl<'a> Atmosphere<'a> {
 
     /// Resolution of the sky lookup texture.
     ///
-    /// This texture is regenerated each time sun's position changes so it's
+    /// This texture is regenerated each time celestial bodies change so it's
     /// important not to go too crazy in here.
     pub const SKY_LUT_RESOLUTION: UVec2 = uvec2(256, 256);
 
@@ -60,8 +60,6 @@ impl<'a> Atmosphere<'a> {
 
     pub const GROUND_ALBEDO: Vec3 = Vec3::splat(0.25);
 
-    pub const EXPOSURE: f32 = 20.0;
-
     /// Position of the observer in world.
     ///
     /// This is a constant because the atmosphere generally doesn't change that
@@ -83,29 +81,64 @@ impl<'a> Atmosphere<'a> {
         }
     }
 
-    pub fn sample(self, sun_dir: Vec3, ray_dir: Vec3) -> Vec3 {
-        let mut lum = self.sample_sky_lut(ray_dir, sun_dir);
-        let mut sun_lum = self.evaluate_bloom(ray_dir, sun_dir);
+    /// Returns sky's radiance in given direction, as lit by all of the world's
+    /// celestial bodies, in nits (cd/m²).
+    ///
+    /// The sky lookup texture stores in-scattered light per unit of body's
+    /// illuminance (scattering coefficients are per mega-meter and phase
+    /// functions are per steradian), so multiplying it by the illuminance (in
+    /// lux) yields luminance directly - this way the sky and the bodies'
+    /// directional lights balance physically under one camera's `Exposure`.
+    ///
+    /// Bodies themselves are not included here, since their light reaches the
+    /// scene through directional lights - see: [`Self::sample_with_bodies()`].
+    pub fn sample(self, world: World, ray_dir: Vec3) -> Vec3 {
+        if !world.has_sky() {
+            return Vec3::ZERO;
+        }
+
+        self.sample_sky_lut(ray_dir)
+    }
+
+    /// Returns sky's radiance in given direction, including disks of the
+    /// celestial bodies; this is what camera sees.
+    ///
+    /// Each body's disk has luminance of its illuminance divided by the solid
+    /// angle it subtends, attenuated by the atmosphere.
+    pub fn sample_with_bodies(self, world: World, ray_dir: Vec3) -> Vec3 {
+        let mut lum = self.sample(world, ray_dir);
+
+        if !world.has_sky() {
+            return lum;
+        }
 
-        sun_lum = self.interpolate_bloom(sun_lum);
+        let is_below_horizon = Ray::new(Self::VIEW_POS, ray_dir)
+            .intersect_sphere(Self::GROUND_RADIUS_MM)
+            >= 0.0;
 
-        if sun_lum.length_squared() > 0.0 {
-            let ray = Ray::new(Self::VIEW_POS, ray_dir);
+        if is_below_horizon {
+            return lum;
+        }
 
-            if ray.intersect_sphere(Self::GROUND_RADIUS_MM) >= 0.0 {
-                sun_lum = Vec3::ZERO;
-            } else {
-                sun_lum *=
-                    self.sample_transmittance_lut(Self::VIEW_POS, sun_dir);
+        let mut idx = 0;
+
+        while idx < world.celestial_body_count {
+            let body = world.celestial_body(idx);
+            let min_cos_theta = (0.5 * body.angular_diameter()).cos();
+            let solid_angle = 2.0 * PI * (1.0 - min_cos_theta);
+
+            if solid_angle > 0.0 && ray_dir.dot(body.dir()) >= min_cos_theta {
+                lum += body.illuminance() / solid_angle
+                    * self.sample_transmittance_lut(Self::VIEW_POS, body.dir());
             }
+
+            idx += 1;
         }
 
-        lum += sun_lum;
-        lum *= Self::EXPOSURE;
         lum
     }
 
-    fn sample_sky_lut(self, ray_dir: Vec3, sun_dir: Vec3) -> Vec3 {
+    fn sample_sky_lut(self, ray_dir: Vec3) -> Vec3 {
         let height = Self::VIEW_POS.length();
         let up = Self::VIEW_POS / height;
 
@@ -118,21 +151,16 @@ impl<'a> Atmosphere<'a> {
 
         let altitude = horizon - ray_dir.dot(up).acos();
 
+        // Sky lookup texture is generated in world-space, so the azimuth is
+        // measured the same way as in `generate_sky_lut`
         let azimuth = if altitude.abs() > (0.5 * PI - 0.0001) {
             0.0
         } else {
-            let right = sun_dir.cross(up);
-            let forward = up.cross(right);
-
-            let projected_dir = (ray_dir - up * ray_dir.dot(up)).normalize();
-            let sin_theta = projected_dir.dot(right);
-            let cos_theta = projected_dir.dot(forward);
-
-            sin_theta.atan2(cos_theta) + PI
+            ray_dir.x.atan2(-ray_dir.z)
         };
 
         let uv = {
-            let u = azimuth / (2.0 * PI);
+            let u = 0.5 + azimuth / (2.0 * PI);
 
             let v = 0.5
                 + 0.5 * (altitude.abs() * 2.0 / PI).sqrt().copysign(altitude);
@@ -145,32 +173,6 @@ impl<'a> Atmosphere<'a> {
             .xyz()
     }
 
-    fn evaluate_bloom(self, ray_dir: Vec3, sun_dir: Vec3) -> Vec3 {
-        const SUN_SOLID_ANGLE: f32 = 0.53 * PI / 180.0;
-
-        let min_sun_cos_theta = SUN_SOLID_ANGLE.cos();
-        let cos_theta = ray_dir.dot(sun_dir);
-
-        if cos_theta >= min_sun_cos_theta {
-            return Vec3::splat(1.0);
-        }
-
-        let offset = min_sun_cos_theta - cos_theta;
-        let gaussian_bloom = (-offset * 50000.0).exp() * 0.5;
-        let inv_bloom = 1.0 / (0.02 + offset * 300.0) * 0.01;
-
-        Vec3::splat(gaussian_bloom + inv_bloom)
-    }
-
-    fn interpolate_bloom(self, bloom: Vec3) -> Vec3 {
-        const MIN: Vec3 = Vec3::splat(0.002);
-        const MAX: Vec3 = Vec3::splat(1.0);
-
-        let t = ((bloom - MIN) / (MAX - MIN)).clamp(Vec3::ZERO, Vec3::ONE);
-
-        t * t * (3.0 - 2.0 * t)
-    }
-
     fn sample_transmittance_lut(self, pos: Vec3, sun_dir: Vec3) -> Vec3 {
         Self::sample_lut(
             self.transmittance_lut_tex,
@@ -100,6 +100,18 @@ impl Camera {
         self.origin.xyz()
     }
 
+    /// Returns the camera's visibility mask - only instances whose visibility
+    /// overlaps it are rendered.
+    pub fn visibility_mask(self) -> u32 {
+        self.screen.z.to_bits()
+    }
+
+    /// Returns the angle between rays cast through the neighbouring pixels;
+    /// used as the spread angle of ray cones (see: [`crate::AtlasView`]).
+    pub fn pixel_spread_angle(self) -> f32 {
+        self.screen.w
+    }
+
     pub fn is_eq(self, rhs: Self) -> bool {
         self.projection_view
             .abs_diff_eq(rhs.projection_view, 0.0025)
@@ -0,0 +1,46 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{vec4, Vec3, Vec4, Vec4Swizzles};
+
+use crate::Normal;
+
+/// Celestial body lighting up the atmosphere, e.g. the sun or the moon.
+///
+/// Note that celestial bodies affect only the sky - the light they cast onto
+/// the scene is handled by ordinary directional lights.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub struct CelestialBody {
+    /// x - direction towards the body (encoded)
+    /// y - direction towards the body (encoded)
+    /// z - angular diameter, in radians
+    /// w - illuminance
+    pub d0: Vec4,
+}
+
+impl CelestialBody {
+    pub fn new(
+        direction: Vec3,
+        angular_diameter: f32,
+        illuminance: f32,
+    ) -> Self {
+        let direction = Normal::encode(direction);
+
+        Self {
+            d0: vec4(direction.x, direction.y, angular_diameter, illuminance),
+        }
+    }
+
+    /// Returns direction towards this body.
+    pub fn dir(self) -> Vec3 {
+        Normal::decode(self.d0.xy())
+    }
+
+    pub fn angular_diameter(self) -> f32 {
+        self.d0.z
+    }
+
+    pub fn illuminance(self) -> f32 {
+        self.d0.w
+    }
+}
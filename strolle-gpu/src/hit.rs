@@ -1,4 +1,4 @@
-use glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
+use glam::{Affine3A, Vec2, Vec3, Vec4, Vec4Swizzles};
 #[cfg(target_arch = "spirv")]
 use spirv_std::num_traits::Float;
 
@@ -77,7 +77,19 @@ pub struct TriangleHit {
     pub distance: f32,
     pub point: Vec3,
     pub normal: Vec3,
+    /// Tangent at the hit point, with `w` being the sign of the bitangent (see:
+    /// [`crate::Material::normal()`]); zero if the mesh doesn't provide any
+    /// tangents.
+    ///
+    /// Not preserved by [`Self::pack()`].
+    pub tangent: Vec4,
     pub uv: Vec2,
+    /// Square root of the ratio between the hit triangle's area in texture
+    /// coordinates and its area in world-space; multiplied by the ray cone's
+    /// width, it yields the texture footprint (see: [`Self::uv_footprint()`]).
+    ///
+    /// Not preserved by [`Self::pack()`].
+    pub uv_density: f32,
     pub material_id: MaterialId,
 }
 
@@ -87,7 +99,9 @@ impl TriangleHit {
             distance: f32::MAX,
             point: Default::default(),
             normal: Default::default(),
+            tangent: Default::default(),
             uv: Default::default(),
+            uv_density: Default::default(),
             material_id: MaterialId::new(0),
         }
     }
@@ -103,12 +117,41 @@ impl TriangleHit {
                 distance: 0.0,
                 point,
                 normal,
+                tangent: Default::default(),
                 uv: d1.zw(),
+                uv_density: Default::default(),
                 material_id: MaterialId::new(d0.w.to_bits()),
             }
         }
     }
 
+    /// Brings this hit's normal and tangent from mesh-space into world-space,
+    /// given the inverse of the instance's transform.
+    pub fn to_world_space(&mut self, xform_inv: Affine3A) {
+        self.normal = (xform_inv.matrix3.transpose() * self.normal).normalize();
+
+        // Mirroring transforms flip the handedness of the tangent frame, so
+        // the bitangent's sign has to be flipped as well
+        let tangent = xform_inv.matrix3.inverse() * self.tangent.xyz();
+        let sign = 1.0f32.copysign(xform_inv.matrix3.determinant());
+
+        self.tangent =
+            tangent.normalize_or_zero().extend(self.tangent.w * sign);
+
+        // Areas get scaled by the transform as well; we approximate this with
+        // the uniform scaling of the same volume
+        self.uv_density *=
+            xform_inv.matrix3.determinant().abs().powf(1.0 / 3.0);
+    }
+
+    /// Returns the texture footprint of a ray cone of given width hitting this
+    /// triangle from given direction (see: [`crate::AtlasView::sample()`]).
+    pub fn uv_footprint(self, cone_width: f32, dir: Vec3) -> f32 {
+        let cos_theta = self.normal.dot(dir).abs().max(0.01);
+
+        cone_width * self.uv_density / cos_theta
+    }
+
     pub fn pack(self) -> [Vec4; 2] {
         let d0 = self.point.extend(f32::from_bits(self.material_id.get()));
 
@@ -127,3 +170,61 @@ impl TriangleHit {
         !self.is_some()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, vec4};
+
+    use super::*;
+
+    #[test]
+    fn to_world_space() {
+        let hit = TriangleHit {
+            normal: Vec3::Z,
+            tangent: vec4(1.0, 0.0, 0.0, 1.0),
+            ..TriangleHit::none()
+        };
+
+        // Non-uniform scaling keeps the frame's handedness
+        let mut scaled = hit;
+
+        scaled.to_world_space(
+            Affine3A::from_scale(vec3(2.0, 3.0, 4.0)).inverse(),
+        );
+
+        assert!(scaled.normal.abs_diff_eq(Vec3::Z, 1e-6));
+        assert!(scaled.tangent.abs_diff_eq(vec4(1.0, 0.0, 0.0, 1.0), 1e-6));
+
+        // Mirroring flips it
+        let mut mirrored = hit;
+
+        mirrored.to_world_space(
+            Affine3A::from_scale(vec3(-1.0, 1.0, 1.0)).inverse(),
+        );
+
+        assert!(mirrored.normal.abs_diff_eq(Vec3::Z, 1e-6));
+        assert!(mirrored
+            .tangent
+            .abs_diff_eq(vec4(-1.0, 0.0, 0.0, -1.0), 1e-6));
+
+        // Meshes without tangents stay without tangents
+        let mut untangented = TriangleHit {
+            tangent: Vec4::ZERO,
+            ..hit
+        };
+
+        untangented.to_world_space(Affine3A::from_rotation_x(1.0));
+
+        assert_eq!(Vec3::ZERO, untangented.tangent.xyz());
+
+        // Scaling spreads the texture over a larger area
+        let mut scaled = TriangleHit {
+            uv_density: 1.0,
+            ..hit
+        };
+
+        scaled.to_world_space(Affine3A::from_scale(Vec3::splat(2.0)).inverse());
+
+        assert!((scaled.uv_density - 0.5).abs() < 1e-6);
+    }
+}
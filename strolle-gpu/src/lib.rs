@@ -5,14 +5,18 @@
 #![allow(clippy::manual_range_contains)]
 #![allow(clippy::too_many_arguments)]
 
+mod atlas;
 mod atmosphere;
 mod brdf;
 mod bvh_view;
 mod camera;
+mod celestial_body;
 mod frame;
 mod gbuffer;
 mod hit;
 mod light;
+mod light_profiles;
+mod light_tree;
 mod lights;
 mod material;
 mod materials;
@@ -28,14 +32,18 @@ mod triangles;
 mod utils;
 mod world;
 
+pub use self::atlas::*;
 pub use self::atmosphere::*;
 pub use self::brdf::*;
 pub use self::bvh_view::*;
 pub use self::camera::*;
+pub use self::celestial_body::*;
 pub use self::frame::*;
 pub use self::gbuffer::*;
 pub use self::hit::*;
 pub use self::light::*;
+pub use self::light_profiles::*;
+pub use self::light_tree::*;
 pub use self::lights::*;
 pub use self::material::*;
 pub use self::materials::*;
@@ -71,9 +79,10 @@ pub type BvhStack<'a> = &'a mut [u32; BVH_STACK_SIZE * 8 * 8];
 
 /// Maximum stack size per each workgroup-thread when traversing the BVH.
 ///
-/// Affects the maximum size of BVH tree (it must not grow larger than
-/// `2 ^ BVH_STACK_SIZE`).
-pub const BVH_STACK_SIZE: usize = 24;
+/// The stack is shared between the top-level BVH and bottom-level BVHs, and
+/// the CPU-side builder makes sure that their combined stack usage never
+/// exceeds this limit.
+pub const BVH_STACK_SIZE: usize = 32;
 
 /// Golden angle, used for spatial filters.
 pub const GOLDEN_ANGLE: f32 = 2.39996;
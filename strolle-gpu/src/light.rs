@@ -2,7 +2,7 @@ use core::f32::consts::PI;
 use core::ops::Mul;
 
 use bytemuck::{Pod, Zeroable};
-use glam::{vec2, vec4, Vec2, Vec3, Vec4, Vec4Swizzles};
+use glam::{vec2, vec3, vec4, Vec2, Vec3, Vec4, Vec4Swizzles};
 #[cfg(target_arch = "spirv")]
 use spirv_std::num_traits::Float;
 
@@ -14,25 +14,38 @@ use crate::{
 #[derive(Clone, Copy, Default, Pod, Zeroable)]
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 pub struct Light {
-    /// x - position x
+    /// x - position x (if it's a triangle light: first vertex)
     /// y - position y
     /// z - position z
-    /// w - radius
+    /// w - radius (if it's a rect light: width; triangle light: edge1.x)
     pub d0: Vec4,
 
     /// x - color r
     /// y - color g
     /// z - color b
-    /// w - range
+    /// w - range (if it's a rect light: height; triangle light: edge1.y)
     pub d1: Vec4,
 
-    /// x - (as u32) light type
-    /// y - if it's a spot light: direction
-    /// z - if it's a spot light: direction
-    /// w - if it's a spot light: angle
+    /// x - (as u32) light type in the lowest byte, flags above, profile id
+    ///     in the highest 16 bits (see: [`Self::has_profile()`])
+    /// y - if it's a spot, directional or area light: direction
+    ///     if it's a point light: profile's nadir
+    ///     if it's a triangle light: edge1.z
+    /// z - if it's a spot, directional or area light: direction
+    ///     if it's a point light: profile's nadir
+    ///     if it's a triangle light: edge2.x
+    /// w - if it's a spot light: outer angle
+    ///     if it's a directional light: angular diameter
+    ///     if it's an area light: rotation around direction
+    ///     if it's a point light: profile's rotation around nadir
+    ///     if it's a triangle light: edge2.y
     pub d2: Vec4,
 
     /// x - (as u32) see the "slot" functions below
+    /// y - (as u32) path within the light tree (see: [`Self::trail()`])
+    /// z - if it's a spot light: inner angle
+    ///     if it's a triangle light: edge2.z
+    /// w - if it's a spot light: profile's rotation around direction
     pub d3: Vec4,
 
     // Light's data from the previous frame
@@ -45,27 +58,60 @@ impl Light {
     pub const TYPE_NONE: u32 = 0;
     pub const TYPE_POINT: u32 = 1;
     pub const TYPE_SPOT: u32 = 2;
+    pub const TYPE_DIRECTIONAL: u32 = 3;
+    pub const TYPE_RECT: u32 = 4;
+    pub const TYPE_DISK: u32 = 5;
+    pub const TYPE_TRIANGLE: u32 = 6;
+
+    /// Flag set on area lights that emit light from both of their sides.
+    pub const FLAG_TWO_SIDED: u32 = 1 << 8;
+
+    /// Position of the profile id within the type word.
+    pub const PROFILE_SHIFT: u32 = 16;
+
+    /// Distance from which shadow rays of directional lights are cast, i.e.
+    /// occluders farther away than that from the shaded point are ignored.
+    pub const DIRECTIONAL_DISTANCE: f32 = 1000.0;
+
+    /// Creates a light out of an emissive triangle, given its world-space
+    /// vertices and emitted radiance.
+    ///
+    /// Triangle lights are always two-sided; their previous data starts as the
+    /// current one and it's up to the caller to fill it in, if the triangle
+    /// replaces some other light.
+    pub fn triangle(positions: [Vec3; 3], color: Vec3) -> Self {
+        let e1 = positions[1] - positions[0];
+        let e2 = positions[2] - positions[0];
+
+        let d0 = positions[0].extend(e1.x);
+        let d1 = color.extend(e1.y);
+
+        let d2 = vec4(
+            f32::from_bits(Self::TYPE_TRIANGLE | Self::FLAG_TWO_SIDED),
+            e1.z,
+            e2.x,
+            e2.y,
+        );
 
-    pub fn sun(position: Vec3, color: Vec3) -> Self {
         Self {
-            // TODO incorrect
-            d0: position.extend(25.0),
-            d1: color.extend(f32::INFINITY),
-            d2: vec4(
-                f32::from_bits(Self::TYPE_POINT),
-                Default::default(),
-                Default::default(),
-                Default::default(),
-            ),
-            d3: Default::default(),
-            prev_d0: Default::default(),
-            prev_d1: Default::default(),
-            prev_d2: Default::default(),
+            d0,
+            d1,
+            d2,
+            d3: vec4(0.0, 0.0, e2.z, 0.0),
+            prev_d0: d0,
+            prev_d1: d1,
+            prev_d2: d2,
         }
     }
 
     pub fn center(self) -> Vec3 {
-        self.d0.xyz()
+        if self.is_triangle() {
+            let (v0, e1, e2) = self.triangle_vertices();
+
+            v0 + (e1 + e2) / 3.0
+        } else {
+            self.d0.xyz()
+        }
     }
 
     pub fn radius(self) -> f32 {
@@ -81,11 +127,37 @@ impl Light {
     }
 
     pub fn contains(self, point: Vec3) -> bool {
-        self.center().distance(point) <= self.radius()
+        if self.is_directional() {
+            // Directional lights are infinitely large, so to speak
+            return true;
+        }
+
+        self.center().distance(point) <= self.bounding_radius()
+    }
+
+    /// Returns radius of a sphere, centered at [`Self::center()`], that
+    /// encloses the entire light.
+    pub fn bounding_radius(self) -> f32 {
+        if self.is_rect() {
+            0.5 * vec2(self.d0.w, self.d1.w).length()
+        } else if self.is_triangle() {
+            let (v0, e1, e2) = self.triangle_vertices();
+            let center = self.center();
+
+            // Triangle lights are backed by actual geometry, so we allow for
+            // some slack to cover points nudged off their surface
+            center
+                .distance(v0)
+                .max(center.distance(v0 + e1))
+                .max(center.distance(v0 + e2))
+                + Hit::NUDGE_OFFSET
+        } else {
+            self.radius()
+        }
     }
 
     fn ty(self) -> u32 {
-        self.d2.x.to_bits()
+        self.d2.x.to_bits() & 0xff
     }
 
     pub fn is_none(self) -> bool {
@@ -96,24 +168,183 @@ impl Light {
         self.ty() == Self::TYPE_POINT
     }
 
-    pub fn spot_dir(self) -> Vec3 {
-        Normal::decode(self.d2.yz())
+    pub fn is_spot(self) -> bool {
+        self.ty() == Self::TYPE_SPOT
     }
 
-    pub fn spot_angle(self) -> f32 {
-        self.d2.w
+    pub fn is_directional(self) -> bool {
+        self.ty() == Self::TYPE_DIRECTIONAL
+    }
+
+    pub fn is_rect(self) -> bool {
+        self.ty() == Self::TYPE_RECT
+    }
+
+    pub fn is_disk(self) -> bool {
+        self.ty() == Self::TYPE_DISK
+    }
+
+    pub fn is_triangle(self) -> bool {
+        self.ty() == Self::TYPE_TRIANGLE
+    }
+
+    pub fn is_area(self) -> bool {
+        self.is_rect() || self.is_disk() || self.is_triangle()
+    }
+
+    pub fn is_two_sided(self) -> bool {
+        self.d2.x.to_bits() & Self::FLAG_TWO_SIDED > 0
+    }
+
+    /// Returns whether this point or spot light has a photometric profile
+    /// attached (see: [`crate::LightProfilesView`]).
+    pub fn has_profile(self) -> bool {
+        (self.d2.x.to_bits() >> Self::PROFILE_SHIFT) > 0
+    }
+
+    pub fn profile_id(self) -> u32 {
+        (self.d2.x.to_bits() >> Self::PROFILE_SHIFT) - 1
+    }
+
+    /// Returns the profile's nadir (i.e. direction of vertical angle 0°) and
+    /// directions of horizontal angles 0° and 90°.
+    pub fn profile_frame(self) -> (Vec3, Vec3, Vec3) {
+        let nadir = self.dir();
+        let angle = if self.is_spot() { self.d3.w } else { self.d2.w };
+        let (t0, b0) = nadir.any_orthonormal_pair();
+        let tangent = t0 * angle.cos() + b0 * angle.sin();
+        let bitangent = nadir.cross(tangent);
+
+        (nadir, tangent, bitangent)
+    }
+
+    /// Returns surface area of an area light.
+    pub fn area(self) -> f32 {
+        if self.is_rect() {
+            self.d0.w * self.d1.w
+        } else if self.is_triangle() {
+            let (_, e1, e2) = self.triangle_vertices();
+
+            0.5 * e1.cross(e2).length()
+        } else {
+            PI * self.radius().sqr()
+        }
+    }
+
+    /// Returns (approximate) total power emitted by the light, used to decide
+    /// how often the light should be sampled.
+    pub fn power(self) -> f32 {
+        let luma = self.color().luma();
+
+        if self.is_point() {
+            4.0 * PI * luma
+        } else if self.is_spot() {
+            2.0 * PI * (1.0 - self.spot_outer_angle().cos()) * luma
+        } else if self.is_area() {
+            let sides = if self.is_two_sided() { 2.0 } else { 1.0 };
+
+            sides * PI * self.area() * luma
+        } else {
+            luma
+        }
+    }
+
+    /// Returns whether the light's contribution doesn't fall off with
+    /// distance - such lights cannot be placed in the light tree and are
+    /// sampled separately.
+    pub fn is_infinite(self) -> bool {
+        self.is_directional()
+            || ((self.is_point() || self.is_spot())
+                && self.range() == f32::INFINITY)
+    }
+
+    /// Returns path from the light tree's root to this light, where n-th bit
+    /// says whether to go into the right child at n-th level.
+    pub fn trail(self) -> u32 {
+        self.d3.y.to_bits()
+    }
+
+    pub fn set_trail(&mut self, trail: u32) {
+        self.d3.y = f32::from_bits(trail);
+    }
+
+    /// Returns first vertex and both edges of a triangle light.
+    pub fn triangle_vertices(self) -> (Vec3, Vec3, Vec3) {
+        (
+            self.d0.xyz(),
+            vec3(self.d0.w, self.d1.w, self.d2.y),
+            vec3(self.d2.z, self.d2.w, self.d3.z),
+        )
+    }
+
+    /// Returns the direction the front side of an area light faces.
+    pub fn area_normal(self) -> Vec3 {
+        if self.is_triangle() {
+            let (_, e1, e2) = self.triangle_vertices();
+
+            e1.cross(e2).normalize()
+        } else {
+            self.dir()
+        }
+    }
+
+    /// Returns tangent and bitangent of an area light, scaled by its
+    /// half-extents (or its radius, for disks).
+    ///
+    /// The tangent is stored as a rotation around the light's direction,
+    /// relative to `dir().any_orthonormal_pair()`.
+    fn area_axes(self) -> (Vec3, Vec3) {
+        let (t0, b0) = self.dir().any_orthonormal_pair();
+        let tangent = t0 * self.d2.w.cos() + b0 * self.d2.w.sin();
+        let bitangent = self.dir().cross(tangent);
+
+        if self.is_rect() {
+            (0.5 * self.d0.w * tangent, 0.5 * self.d1.w * bitangent)
+        } else {
+            (self.radius() * tangent, self.radius() * bitangent)
+        }
+    }
+
+    /// Maps given sample from the unit square onto the surface of an area
+    /// light, uniformly.
+    fn area_point(self, sample: Vec2) -> Vec3 {
+        if self.is_triangle() {
+            let (v0, e1, e2) = self.triangle_vertices();
+            let su = sample.x.sqrt();
+
+            return v0 + su * (1.0 - sample.y) * e1 + su * sample.y * e2;
+        }
+
+        let (tangent, bitangent) = self.area_axes();
+
+        let offset = if self.is_rect() {
+            2.0 * sample - Vec2::ONE
+        } else {
+            let angle = 2.0 * PI * sample.x;
+
+            vec2(angle.cos(), angle.sin()) * sample.y.sqrt()
+        };
+
+        self.center() + offset.x * tangent + offset.y * bitangent
     }
 
-    pub fn is_slot_remapped(self) -> bool {
-        self.d3.x.to_bits() > 0 && self.d3.x.to_bits() != 0xcafebabe
+    /// Returns direction of a spot or directional light, normal of an area
+    /// light (i.e. the direction its front side faces) or nadir of a point
+    /// light's profile.
+    pub fn dir(self) -> Vec3 {
+        Normal::decode(self.d2.yz())
     }
 
-    pub fn slot_remapped_to(self) -> LightId {
-        LightId::new(self.d3.x.to_bits() - 1)
+    pub fn spot_outer_angle(self) -> f32 {
+        self.d2.w
     }
 
-    pub fn remap_slot(&mut self, id: LightId) {
-        self.d3.x = f32::from_bits(id.get() + 1);
+    pub fn spot_inner_angle(self) -> f32 {
+        self.d3.z
+    }
+
+    pub fn angular_diameter(self) -> f32 {
+        self.d2.w
     }
 
     pub fn is_slot_killed(self) -> bool {
@@ -141,18 +372,52 @@ impl Light {
     }
 
     pub fn radiance(self, hit: Hit) -> LightRadiance {
-        let l = self.center() - hit.point;
-
-        let f_angle = if self.is_point() {
-            1.0
+        let (l, radius) = if self.is_directional() {
+            // Directional lights are infinitely far away, so instead of a
+            // sphere we treat them as a disk of matching angular size placed
+            // at unit distance
+            (-self.dir(), (0.5 * self.angular_diameter()).tan())
+        } else if self.is_area() {
+            // Without a particular point on the light (see:
+            // [`Self::radiance_at()`]), area lights are approximated as
+            // spheres of matching area
+            (self.center() - hit.point, (self.area() / PI).sqrt())
         } else {
-            let angle =
-                self.spot_dir().angle_between(hit.point - self.center());
+            (self.center() - hit.point, self.radius())
+        };
 
-            (1.0 - (angle / self.spot_angle()).powf(3.0)).saturate()
+        let f_angle = if self.is_spot() {
+            // Same falloff as in Bevy's forward renderer - full intensity
+            // within the inner cone, fading out smoothly towards the outer one
+            let cos_angle =
+                self.dir().dot((hit.point - self.center()).normalize());
+
+            let cos_inner = self.spot_inner_angle().cos();
+            let cos_outer = self.spot_outer_angle().cos();
+            let scale = 1.0 / (cos_inner - cos_outer).max(0.0001);
+            let offset = -cos_outer * scale;
+
+            (cos_angle * scale + offset).saturate().sqr()
+        } else if self.is_area() {
+            let cos_angle = self.area_normal().dot(-l.normalize());
+
+            if self.is_two_sided() {
+                cos_angle.abs()
+            } else {
+                cos_angle.saturate()
+            }
+        } else {
+            1.0
         };
 
-        let f_dist = if self.range() == f32::INFINITY {
+        let f_dist = if self.is_area() {
+            // Solid angle of a disk of matching area, as seen head-on - note
+            // that area lights' color is their radiance, so there's no need
+            // for further normalization
+            let dist = l.length();
+
+            2.0 * PI * (1.0 - dist / (dist.sqr() + radius.sqr()).sqrt())
+        } else if self.range() == f32::INFINITY {
             1.0
         } else {
             let l2 = l.length_squared();
@@ -177,8 +442,8 @@ impl Light {
             let center_to_ray = l.dot(r) * r - l;
 
             let closest_point = {
-                let t = self.radius()
-                    * center_to_ray.dot(center_to_ray).inverse_sqrt();
+                let t =
+                    radius * center_to_ray.dot(center_to_ray).inverse_sqrt();
 
                 l + center_to_ray * t.saturate()
             };
@@ -188,7 +453,7 @@ impl Light {
 
             let i_roughness = {
                 let t = hit.gbuffer.clamped_roughness()
-                    + self.radius() * 0.5 * l_spec_length_inverse;
+                    + radius * 0.5 * l_spec_length_inverse;
 
                 hit.gbuffer.clamped_roughness() / t.saturate()
             };
@@ -206,7 +471,57 @@ impl Light {
         }
     }
 
+    /// Returns light's radiance at given hit point, as emitted from given
+    /// point of the light (e.g. the origin of a shadow ray returned from
+    /// [`Self::ray_bnoise()`]).
+    ///
+    /// For area lights this evaluates the light's contribution through that
+    /// particular point, divided by the pdf of sampling it uniformly over the
+    /// light's surface (i.e. multiplied by the area) - so averaging this over
+    /// many points converges to the light's actual contribution, including
+    /// parts of the light that are visible only at grazing angles.
+    ///
+    /// Other kinds of lights don't depend on the point.
+    pub fn radiance_at(self, hit: Hit, light_point: Vec3) -> LightRadiance {
+        if !self.is_area() {
+            return self.radiance(hit);
+        }
+
+        let l = light_point - hit.point;
+        let l_dist2 = l.length_squared().max(0.0001);
+        let l = l.normalize();
+
+        let cos_emitter = self.area_normal().dot(-l);
+
+        let cos_emitter = if self.is_two_sided() {
+            cos_emitter.abs()
+        } else {
+            cos_emitter.saturate()
+        };
+
+        let f_cosine = hit.gbuffer.normal.dot(l).saturate();
+        let diff_brdf = DiffuseBrdf::new(hit.gbuffer).eval();
+        let spec_brdf = SpecularBrdf::new(hit.gbuffer).eval(l, -hit.dir);
+
+        LightRadiance {
+            radiance: self.color() * cos_emitter * self.area() / l_dist2
+                * f_cosine,
+            diff_brdf,
+            spec_brdf,
+        }
+    }
+
     pub fn ray_wnoise(self, noise: &mut WhiteNoise, hit_point: Vec3) -> Ray {
+        if self.is_directional() {
+            return self.ray_directional(noise.sample_disk(), hit_point);
+        }
+
+        if self.is_area() {
+            let sample = vec2(noise.sample(), noise.sample());
+
+            return self.ray_area(sample, hit_point);
+        }
+
         let light_pos = self.center() + self.radius() * noise.sample_sphere();
         let light_to_hit = hit_point - light_pos;
 
@@ -215,19 +530,28 @@ impl Light {
     }
 
     pub fn ray_bnoise(self, sample: Vec2, hit_point: Vec3) -> Ray {
-        let to_light = self.center() - hit_point;
-        let light_dir = to_light.normalize();
-        let light_distance = to_light.length();
-        let light_radius = self.radius() / light_distance;
-        let (light_tangent, light_bitangent) = light_dir.any_orthonormal_pair();
-
         let disk_point = {
             let angle = 2.0 * PI * sample.x;
             let radius = sample.y.sqrt();
 
-            vec2(angle.sin(), angle.cos()) * radius * light_radius
+            vec2(angle.sin(), angle.cos()) * radius
         };
 
+        if self.is_directional() {
+            return self.ray_directional(disk_point, hit_point);
+        }
+
+        if self.is_area() {
+            return self.ray_area(sample, hit_point);
+        }
+
+        let to_light = self.center() - hit_point;
+        let light_dir = to_light.normalize();
+        let light_distance = to_light.length();
+        let light_radius = self.radius() / light_distance;
+        let (light_tangent, light_bitangent) = light_dir.any_orthonormal_pair();
+        let disk_point = disk_point * light_radius;
+
         let ray_dir = light_dir
             + disk_point.x * light_tangent
             + disk_point.y * light_bitangent;
@@ -237,6 +561,44 @@ impl Light {
         Ray::new(hit_point + ray_dir * light_distance, -ray_dir)
             .with_len(light_distance)
     }
+
+    /// Returns a shadow ray going from given point of an area light (see:
+    /// [`Self::area_point()`]) towards the hit point.
+    fn ray_area(self, sample: Vec2, hit_point: Vec3) -> Ray {
+        let mut light_pos = self.area_point(sample);
+
+        if self.is_triangle() {
+            // Triangle lights are made of actual geometry, so we have to move
+            // the ray's origin off the surface, lest it hits the light itself
+            let normal = self.area_normal();
+            let side = (hit_point - light_pos).dot(normal).signum();
+
+            light_pos += normal * side * Hit::NUDGE_OFFSET;
+        }
+
+        let light_to_hit = hit_point - light_pos;
+
+        Ray::new(light_pos, light_to_hit.normalize())
+            .with_len(light_to_hit.length())
+    }
+
+    /// Returns a shadow ray going towards a directional light, through given
+    /// point of the unit disk that represents the light's cone.
+    fn ray_directional(self, disk_point: Vec2, hit_point: Vec3) -> Ray {
+        let light_dir = -self.dir();
+        let light_radius = (0.5 * self.angular_diameter()).tan();
+        let (light_tangent, light_bitangent) = light_dir.any_orthonormal_pair();
+        let disk_point = disk_point * light_radius;
+
+        let ray_dir = light_dir
+            + disk_point.x * light_tangent
+            + disk_point.y * light_bitangent;
+
+        let ray_dir = ray_dir.normalize();
+
+        Ray::new(hit_point + ray_dir * Self::DIRECTIONAL_DISTANCE, -ray_dir)
+            .with_len(Self::DIRECTIONAL_DISTANCE)
+    }
 }
 
 #[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
@@ -0,0 +1,65 @@
+use core::f32::consts::PI;
+
+use glam::Vec3;
+use spirv_std::arch::IndexUnchecked;
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use crate::Light;
+
+/// Photometric profiles of lights, stored as tables of normalized intensities
+/// (see: [`Light::has_profile()`]).
+///
+/// Each table contains [`Self::HEIGHT`] rows of vertical angles, from the
+/// nadir (0°) up to the zenith (180°), and each row contains [`Self::WIDTH`]
+/// horizontal angles, from 0° up to (but excluding) 360°.
+#[derive(Clone, Copy)]
+pub struct LightProfilesView<'a> {
+    items: &'a [f32],
+}
+
+impl<'a> LightProfilesView<'a> {
+    pub const WIDTH: u32 = 32;
+    pub const HEIGHT: u32 = 64;
+    pub const SIZE: u32 = Self::WIDTH * Self::HEIGHT;
+
+    pub fn new(items: &'a [f32]) -> Self {
+        Self { items }
+    }
+
+    /// Returns normalized intensity of given light's profile in the direction
+    /// from the light towards given point.
+    pub fn eval(self, light: Light, point: Vec3) -> f32 {
+        let (nadir, tangent, bitangent) = light.profile_frame();
+        let dir = (point - light.center()).normalize();
+
+        let theta = nadir.dot(dir).clamp(-1.0, 1.0).acos();
+        let mut phi = bitangent.dot(dir).atan2(tangent.dot(dir));
+
+        if phi < 0.0 {
+            phi += 2.0 * PI;
+        }
+
+        let x = phi / (2.0 * PI) * (Self::WIDTH as f32);
+        let y = theta / PI * ((Self::HEIGHT - 1) as f32);
+
+        let x0 = (x.floor() as u32) % Self::WIDTH;
+        let x1 = (x0 + 1) % Self::WIDTH;
+        let y0 = (y.floor() as u32).min(Self::HEIGHT - 1);
+        let y1 = (y0 + 1).min(Self::HEIGHT - 1);
+        let tx = x - x.floor();
+        let ty = y - y.floor();
+
+        let offset = light.profile_id() * Self::SIZE;
+        let get = |x: u32, y: u32| self.get(offset + y * Self::WIDTH + x);
+
+        let i0 = get(x0, y0) * (1.0 - tx) + get(x1, y0) * tx;
+        let i1 = get(x0, y1) * (1.0 - tx) + get(x1, y1) * tx;
+
+        i0 * (1.0 - ty) + i1 * ty
+    }
+
+    fn get(self, idx: u32) -> f32 {
+        unsafe { *self.items.index_unchecked(idx as usize) }
+    }
+}
@@ -0,0 +1,164 @@
+use core::f32::consts::PI;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4, Vec4Swizzles};
+use spirv_std::arch::IndexUnchecked;
+#[cfg(target_arch = "spirv")]
+use spirv_std::num_traits::Float;
+
+use crate::{F32Ext, LightId};
+
+/// Node of the light tree - a hierarchy of lights, used to pick lights
+/// proportionally to their estimated contribution at given point.
+///
+/// Each node bounds its lights with an axis-aligned box and a cone of
+/// directions they emit light towards (as in "Importance Sampling of Many
+/// Lights With Adaptive Tree Splitting" by Conty Estevez and Kulla).
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub struct LightTreeNode {
+    /// xyz - bounding box's min
+    /// w - total power of lights within this node
+    pub d0: Vec4,
+
+    /// xyz - bounding box's max
+    /// w - angle of the cone of normals (theta_o)
+    pub d1: Vec4,
+
+    /// xyz - axis of the cone of normals
+    /// w - angle of the emission falloff around the cone (theta_e)
+    pub d2: Vec4,
+
+    /// x - (as u32) if it's a leaf: light id
+    ///     otherwise: index of the right child (left child is the next node)
+    /// y - (as u32) flags
+    pub d3: Vec4,
+}
+
+impl LightTreeNode {
+    pub const FLAG_LEAF: u32 = 1 << 0;
+    pub const FLAG_TWO_SIDED: u32 = 1 << 1;
+
+    pub fn min(self) -> Vec3 {
+        self.d0.xyz()
+    }
+
+    pub fn max(self) -> Vec3 {
+        self.d1.xyz()
+    }
+
+    pub fn power(self) -> f32 {
+        self.d0.w
+    }
+
+    pub fn axis(self) -> Vec3 {
+        self.d2.xyz()
+    }
+
+    pub fn theta_o(self) -> f32 {
+        self.d1.w
+    }
+
+    pub fn theta_e(self) -> f32 {
+        self.d2.w
+    }
+
+    fn flags(self) -> u32 {
+        self.d3.y.to_bits()
+    }
+
+    pub fn is_leaf(self) -> bool {
+        self.flags() & Self::FLAG_LEAF > 0
+    }
+
+    pub fn is_two_sided(self) -> bool {
+        self.flags() & Self::FLAG_TWO_SIDED > 0
+    }
+
+    pub fn light_id(self) -> LightId {
+        LightId::new(self.d3.x.to_bits())
+    }
+
+    pub fn right_child(self) -> u32 {
+        self.d3.x.to_bits()
+    }
+
+    /// Estimates how much light this node contributes towards given point
+    /// lying on a surface with given normal.
+    ///
+    /// The estimate is conservative - it's zero only if no light within this
+    /// node can possibly illuminate the point.
+    pub fn importance(self, point: Vec3, normal: Vec3) -> f32 {
+        if self.power() <= 0.0 {
+            return 0.0;
+        }
+
+        let center = 0.5 * (self.min() + self.max());
+        let radius = 0.5 * self.min().distance(self.max());
+        let dist = point.distance(center);
+
+        // Clamp the distance so that points close to (or within) the node
+        // don't get unbounded importance
+        let dist2 = dist.max(0.5 * radius).sqr().max(0.0001);
+
+        let is_inside =
+            point.cmpge(self.min()).all() && point.cmple(self.max()).all();
+
+        // Angle subtended by the node's bounding sphere
+        let theta_b = if is_inside || dist <= radius {
+            PI
+        } else {
+            (radius / dist).asin()
+        };
+
+        let wi = (point - center) / dist.max(0.0001);
+
+        // Angle between the cone's axis and the point, minus the cone's and
+        // the bounding sphere's spread
+        let theta_p = {
+            let mut cos_w = self.axis().dot(wi);
+
+            if self.is_two_sided() {
+                cos_w = cos_w.abs();
+            }
+
+            let theta_w = cos_w.clamp(-1.0, 1.0).acos();
+
+            (theta_w - self.theta_o() - theta_b).max(0.0)
+        };
+
+        if theta_p > self.theta_e() {
+            return 0.0;
+        }
+
+        // Angle between the surface's normal and the node, minus the bounding
+        // sphere's spread
+        let theta_i = {
+            let theta_i = normal.dot(-wi).clamp(-1.0, 1.0).acos();
+
+            (theta_i - theta_b).max(0.0)
+        };
+
+        if theta_i >= 0.5 * PI {
+            return 0.0;
+        }
+
+        self.power() * theta_p.cos() * theta_i.cos() / dist2
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct LightTreeView<'a> {
+    items: &'a [LightTreeNode],
+}
+
+impl<'a> LightTreeView<'a> {
+    pub fn new(items: &'a [LightTreeNode]) -> Self {
+        Self { items }
+    }
+
+    pub fn get(self, idx: u32) -> LightTreeNode {
+        unsafe { *self.items.index_unchecked(idx as usize) }
+    }
+}
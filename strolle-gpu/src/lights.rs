@@ -1,15 +1,25 @@
+use core::f32::consts::PI;
+
+use glam::Vec3;
 use spirv_std::arch::IndexUnchecked;
 
-use crate::{Light, LightId};
+use crate::{
+    F32Ext, Hit, Light, LightId, LightProfilesView, LightRadiance,
+    LightTreeView, Vec3Ext, WhiteNoise, World,
+};
 
 #[derive(Clone, Copy)]
 pub struct LightsView<'a> {
     items: &'a [Light],
+    profiles: LightProfilesView<'a>,
 }
 
 impl<'a> LightsView<'a> {
-    pub fn new(items: &'a [Light]) -> Self {
-        Self { items }
+    pub fn new(items: &'a [Light], profiles: &'a [f32]) -> Self {
+        Self {
+            items,
+            profiles: LightProfilesView::new(profiles),
+        }
     }
 
     pub fn get(self, id: LightId) -> Light {
@@ -26,4 +36,217 @@ impl<'a> LightsView<'a> {
     pub fn len(self) -> usize {
         self.items.len()
     }
+
+    /// Returns light's radiance at given hit point, taking into account the
+    /// light's photometric profile (if any).
+    pub fn radiance(self, light: Light, hit: Hit) -> LightRadiance {
+        let radiance = light.radiance(hit);
+
+        if light.has_profile() {
+            radiance * self.profiles.eval(light, hit.point)
+        } else {
+            radiance
+        }
+    }
+
+    /// Same as [`Self::radiance()`], but for light emitted from given point of
+    /// the light (see: [`Light::radiance_at()`]).
+    pub fn radiance_at(
+        self,
+        light: Light,
+        hit: Hit,
+        light_point: Vec3,
+    ) -> LightRadiance {
+        let radiance = light.radiance_at(hit, light_point);
+
+        if light.has_profile() {
+            radiance * self.profiles.eval(light, hit.point)
+        } else {
+            radiance
+        }
+    }
+
+    /// Picks a random light, proportionally to its estimated contribution
+    /// towards given hit point; returns the light's id and the probability of
+    /// picking it (or zero, if no light could be picked).
+    ///
+    /// Infinite lights (see: [`Light::is_infinite()`]) are stored at the
+    /// beginning of the tree and get picked proportionally to their irradiance
+    /// at the hit point, while the rest of the lights is picked by traversing
+    /// the tree - which of both happens depends on how much both groups
+    /// contribute (see: [`Self::group_importances()`]).
+    pub fn sample(
+        self,
+        tree: LightTreeView,
+        wnoise: &mut WhiteNoise,
+        world: World,
+        hit: Hit,
+    ) -> (LightId, f32) {
+        let (infinite, bounded) = self.group_importances(tree, world, hit);
+
+        if infinite + bounded <= 0.0 {
+            return (LightId::new(0), 0.0);
+        }
+
+        if wnoise.sample() * (infinite + bounded) < infinite {
+            let target = wnoise.sample() * infinite;
+            let mut sum = 0.0;
+            let mut picked = (LightId::new(0), 0.0);
+            let mut idx = 0;
+
+            while idx < world.infinite_light_count {
+                let light_id = tree.get(idx).light_id();
+                let importance = self.infinite_importance(light_id, hit);
+
+                if importance > 0.0 {
+                    sum += importance;
+                    picked = (light_id, importance / (infinite + bounded));
+
+                    if target < sum {
+                        break;
+                    }
+                }
+
+                idx += 1;
+            }
+
+            return picked;
+        }
+
+        let mut node_idx = world.infinite_light_count;
+        let mut pdf = bounded / (infinite + bounded);
+
+        loop {
+            let node = tree.get(node_idx);
+
+            if node.is_leaf() {
+                return (node.light_id(), pdf);
+            }
+
+            let left_idx = node_idx + 1;
+            let right_idx = node.right_child();
+
+            let left = Self::importance(tree, left_idx, hit);
+            let right = Self::importance(tree, right_idx, hit);
+
+            if left + right <= 0.0 {
+                return (LightId::new(0), 0.0);
+            }
+
+            let left_pdf = left / (left + right);
+
+            if wnoise.sample() < left_pdf {
+                node_idx = left_idx;
+                pdf *= left_pdf;
+            } else {
+                node_idx = right_idx;
+                pdf *= 1.0 - left_pdf;
+            }
+        }
+    }
+
+    /// Returns probability of [`Self::sample()`] picking given light, e.g. for
+    /// the purposes of multiple importance sampling.
+    pub fn pdf(
+        self,
+        tree: LightTreeView,
+        world: World,
+        hit: Hit,
+        light_id: LightId,
+    ) -> f32 {
+        let (infinite, bounded) = self.group_importances(tree, world, hit);
+
+        if infinite + bounded <= 0.0 {
+            return 0.0;
+        }
+
+        let light = self.get(light_id);
+
+        if light.is_infinite() {
+            return self.infinite_importance(light_id, hit)
+                / (infinite + bounded);
+        }
+
+        let trail = light.trail();
+        let mut node_idx = world.infinite_light_count;
+        let mut depth = 0;
+        let mut pdf = bounded / (infinite + bounded);
+
+        loop {
+            let node = tree.get(node_idx);
+
+            if node.is_leaf() {
+                return pdf;
+            }
+
+            let left_idx = node_idx + 1;
+            let right_idx = node.right_child();
+
+            let left = Self::importance(tree, left_idx, hit);
+            let right = Self::importance(tree, right_idx, hit);
+
+            if left + right <= 0.0 {
+                return 0.0;
+            }
+
+            if (trail >> depth) & 1 == 0 {
+                node_idx = left_idx;
+                pdf *= left / (left + right);
+            } else {
+                node_idx = right_idx;
+                pdf *= right / (left + right);
+            }
+
+            depth += 1;
+        }
+    }
+
+    /// Returns estimated contribution of all infinite lights and of all the
+    /// remaining lights (i.e. of the tree's root) towards given hit point.
+    ///
+    /// Both are expressed as irradiance - since tree's importance is based on
+    /// lights' power, it's scaled down by 4π so that e.g. a point light
+    /// compares fairly against a directional light of the same irradiance.
+    fn group_importances(
+        self,
+        tree: LightTreeView,
+        world: World,
+        hit: Hit,
+    ) -> (f32, f32) {
+        let mut infinite = 0.0;
+        let mut idx = 0;
+
+        while idx < world.infinite_light_count {
+            infinite += self.infinite_importance(tree.get(idx).light_id(), hit);
+            idx += 1;
+        }
+
+        let bounded_count = world.light_count + world.emissive_count
+            - world.infinite_light_count;
+
+        let bounded = if bounded_count > 0 {
+            Self::importance(tree, world.infinite_light_count, hit) / (4.0 * PI)
+        } else {
+            0.0
+        };
+
+        (infinite, bounded)
+    }
+
+    /// Returns irradiance of given infinite light at given hit point.
+    fn infinite_importance(self, light_id: LightId, hit: Hit) -> f32 {
+        let light = self.get(light_id);
+
+        let l = if light.is_directional() {
+            -light.dir()
+        } else {
+            (light.center() - hit.point).normalize()
+        };
+
+        light.color().luma() * hit.gbuffer.normal.dot(l).saturate()
+    }
+
+    fn importance(tree: LightTreeView, node_idx: u32, hit: Hit) -> f32 {
+        tree.get(node_idx).importance(hit.point, hit.gbuffer.normal)
+    }
 }
@@ -1,8 +1,7 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
-use spirv_std::Sampler;
+use glam::{UVec4, Vec2, Vec3, Vec4, Vec4Swizzles};
 
-use crate::Tex;
+use crate::AtlasView;
 
 #[repr(C)]
 #[derive(Clone, Copy, PartialEq, Pod, Zeroable)]
@@ -18,6 +17,9 @@ pub struct Material {
     pub ior: f32,
     pub metallic_roughness_texture: Vec4,
     pub normal_map_texture: Vec4,
+    /// Flags of the textures above (see: [`AtlasView::FLAG_LINEAR`]), in order:
+    /// base color, emissive, metallic-roughness and normal map
+    pub texture_flags: UVec4,
 }
 
 impl Material {
@@ -28,114 +30,127 @@ impl Material {
 
     pub fn base_color(
         self,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
         hit_uv: Vec2,
+        uv_footprint: f32,
     ) -> Vec4 {
         Self::sample_atlas(
-            atlas_tex,
-            atlas_sampler,
+            atlas,
             hit_uv,
+            uv_footprint,
             self.base_color,
             self.base_color_texture,
+            self.texture_flags.x,
         )
     }
 
     pub fn metallic_roughness(
         self,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
         hit_uv: Vec2,
+        uv_footprint: f32,
     ) -> Vec2 {
         Self::sample_atlas(
-            atlas_tex,
-            atlas_sampler,
+            atlas,
             hit_uv,
+            uv_footprint,
             Vec4::new(1.0, self.roughness, self.metallic, 1.0),
             self.metallic_roughness_texture,
+            self.texture_flags.z,
         )
         .zy()
     }
 
     pub fn emissive(
         self,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
         hit_uv: Vec2,
+        uv_footprint: f32,
     ) -> Vec3 {
         Self::sample_atlas(
-            atlas_tex,
-            atlas_sampler,
+            atlas,
             hit_uv,
+            uv_footprint,
             self.emissive,
             self.emissive_texture,
+            self.texture_flags.y,
         )
         .xyz()
     }
 
+    /// Returns whether this material's emission is modulated by a texture.
+    ///
+    /// Emissive triangles with textures are not sampled as lights, so their
+    /// emission has to be picked up when they're hit by indirect rays.
+    pub fn has_emissive_texture(self) -> bool {
+        self.emissive_texture != Vec4::ZERO
+    }
+
     fn sample_atlas(
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
-        mut hit_uv: Vec2,
+        atlas: AtlasView,
+        hit_uv: Vec2,
+        uv_footprint: f32,
         multiplier: Vec4,
         texture: Vec4,
+        flags: u32,
     ) -> Vec4 {
-        // TODO this assumes the texture's sampler is configured to U/V-repeat
-        //      which might not be the case; we should propagate sampler info up
-        //      to here and decide
-        let wrap = |t: f32| {
-            if t > 0.0 {
-                t % 1.0
-            } else {
-                1.0 - (-t % 1.0)
-            }
-        };
-
         if texture == Vec4::ZERO {
             multiplier
         } else {
-            hit_uv.x = wrap(hit_uv.x);
-            hit_uv.y = wrap(hit_uv.y);
+            multiplier * atlas.sample(texture, flags, hit_uv, uv_footprint)
+        }
+    }
+
+    /// Returns the shading normal at given hit point, i.e. `hit_normal`
+    /// perturbed by the material's normal map, if any.
+    ///
+    /// `hit_tangent` is expected to be in the same space as `hit_normal`, with
+    /// `w` containing the sign of the bitangent; meshes without tangents (i.e.
+    /// with `hit_tangent` equal to zero) don't get normal-mapped.
+    ///
+    /// See [`AtlasView::sample()`] for the meaning of `uv_footprint`.
+    pub fn normal(
+        self,
+        atlas: AtlasView,
+        hit_uv: Vec2,
+        uv_footprint: f32,
+        hit_normal: Vec3,
+        hit_tangent: Vec4,
+    ) -> Vec3 {
+        if self.normal_map_texture == Vec4::ZERO {
+            return hit_normal;
+        }
 
-            let uv = texture.xy() + hit_uv * texture.zw();
+        // Interpolated tangents don't have to be perpendicular to the normal,
+        // so let's orthogonalize them first
+        let tangent = hit_tangent.xyz();
+        let tangent = (tangent - hit_normal * hit_normal.dot(tangent))
+            .normalize_or_zero();
 
-            multiplier * atlas_tex.sample_by_lod(*atlas_sampler, uv, 0.0)
+        if tangent == Vec3::ZERO {
+            return hit_normal;
         }
-    }
 
-    // TODO bring back
-    //
-    // pub fn normal(
-    //     &self,
-    //     hit_uv: Vec2,
-    //     hit_normal: Vec3,
-    //     hit_tangent: Vec4,
-    // ) -> Vec3 {
-    //     if self.normal_map_texture == u32::MAX {
-    //         hit_normal
-    //     } else {
-    //         let normal_map_tex = unsafe {
-    //             images.index_unchecked(self.normal_map_texture as usize)
-    //         };
-
-    //         let normal_map_sampler = unsafe {
-    //             samplers.index_unchecked(self.normal_map_texture as usize)
-    //         };
-
-    //         let tangent = hit_tangent.xyz();
-    //         let bitangent = hit_tangent.w * hit_normal.cross(tangent);
-
-    //         let mapped_normal =
-    //             normal_map_tex.sample(*normal_map_sampler, hit_uv);
-
-    //         let mapped_normal = 2.0 * mapped_normal - 1.0;
-
-    //         (mapped_normal.x * tangent
-    //             + mapped_normal.y * bitangent
-    //             + mapped_normal.z * hit_normal)
-    //             .normalize()
-    //     }
-    // }
+        let bitangent = if hit_tangent.w < 0.0 { -1.0 } else { 1.0 }
+            * hit_normal.cross(tangent);
+
+        let mapped_normal = Self::sample_atlas(
+            atlas,
+            hit_uv,
+            uv_footprint,
+            Vec4::ONE,
+            self.normal_map_texture,
+            self.texture_flags.w,
+        )
+        .xyz();
+
+        let mapped_normal = 2.0 * mapped_normal - 1.0;
+
+        (mapped_normal.x * tangent
+            + mapped_normal.y * bitangent
+            + mapped_normal.z * hit_normal)
+            .normalize()
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -14,7 +14,7 @@ pub struct WhiteNoise {
 impl WhiteNoise {
     pub fn new(seed: u32, id: UVec2) -> Self {
         Self {
-            state: seed ^ (48619 * id.x) ^ (95461 * id.y),
+            state: seed ^ id.x.wrapping_mul(48619) ^ id.y.wrapping_mul(95461),
         }
     }
 
@@ -33,10 +33,11 @@ impl WhiteNoise {
 
     /// Generates a uniform sample in range `<0, u32::MAX>`.
     pub fn sample_int(&mut self) -> u32 {
-        self.state = self.state * 747796405 + 2891336453;
+        self.state =
+            self.state.wrapping_mul(747796405).wrapping_add(2891336453);
 
-        let word =
-            ((self.state >> ((self.state >> 28) + 4)) ^ self.state) * 277803737;
+        let word = ((self.state >> ((self.state >> 28) + 4)) ^ self.state)
+            .wrapping_mul(277803737);
 
         (word >> 22) ^ word
     }
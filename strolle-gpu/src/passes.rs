@@ -1,7 +1,7 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{vec3a, vec4, Affine3A, Mat3A, Vec4};
+use glam::{Affine3A, UVec4, Vec4};
 
-use crate::Frame;
+use crate::{Affine3AExt, Frame};
 
 #[repr(C)]
 #[derive(Clone, Copy, Default, Pod, Zeroable)]
@@ -16,9 +16,9 @@ pub struct PassParams {
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 pub struct PrimRasterPassParams {
     pub payload: Vec4,
-    pub curr_xform_inv_d0: Vec4,
-    pub curr_xform_inv_d1: Vec4,
-    pub curr_xform_inv_d2: Vec4,
+    pub curr_xform_d0: Vec4,
+    pub curr_xform_d1: Vec4,
+    pub curr_xform_d2: Vec4,
     pub prev_xform_d0: Vec4,
     pub prev_xform_d1: Vec4,
     pub prev_xform_d2: Vec4,
@@ -33,60 +33,21 @@ impl PrimRasterPassParams {
         self.payload.y.to_bits()
     }
 
-    pub fn curr_xform_inv(self) -> Affine3A {
-        Self::decode_affine([
-            self.curr_xform_inv_d0,
-            self.curr_xform_inv_d1,
-            self.curr_xform_inv_d2,
+    pub fn curr_xform(self) -> Affine3A {
+        Affine3A::decode([
+            self.curr_xform_d0,
+            self.curr_xform_d1,
+            self.curr_xform_d2,
         ])
     }
 
     pub fn prev_xform(self) -> Affine3A {
-        Self::decode_affine([
+        Affine3A::decode([
             self.prev_xform_d0,
             self.prev_xform_d1,
             self.prev_xform_d2,
         ])
     }
-
-    /// Encodes a 3D affine transformation as three Vec4s; we use this to
-    /// overcome padding issues when copying data from CPU into GPU.
-    pub fn encode_affine(xform: Affine3A) -> [Vec4; 3] {
-        let d0 = vec4(
-            xform.matrix3.x_axis.x,
-            xform.matrix3.x_axis.y,
-            xform.matrix3.x_axis.z,
-            xform.translation.x,
-        );
-
-        let d1 = vec4(
-            xform.matrix3.y_axis.x,
-            xform.matrix3.y_axis.y,
-            xform.matrix3.y_axis.z,
-            xform.translation.y,
-        );
-
-        let d2 = vec4(
-            xform.matrix3.z_axis.x,
-            xform.matrix3.z_axis.y,
-            xform.matrix3.z_axis.z,
-            xform.translation.z,
-        );
-
-        [d0, d1, d2]
-    }
-
-    /// See: [`Self::encode_affine()`].
-    pub fn decode_affine([d0, d1, d2]: [Vec4; 3]) -> Affine3A {
-        Affine3A {
-            matrix3: Mat3A {
-                x_axis: vec3a(d0.x, d0.y, d0.z),
-                y_axis: vec3a(d1.x, d1.y, d1.z),
-                z_axis: vec3a(d2.x, d2.y, d2.z),
-            },
-            translation: vec3a(d0.w, d1.w, d2.w),
-        }
-    }
 }
 
 #[repr(C)]
@@ -103,6 +64,8 @@ pub struct FrameDenoisingWaveletPassParams {
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 pub struct FrameCompositionPassParams {
     pub camera_mode: u32,
+    /// Factor the final color gets multiplied by (see: camera's exposure)
+    pub exposure: f32,
 }
 
 #[repr(C)]
@@ -131,3 +94,17 @@ pub struct GiResolvingPassParams {
     pub frame: Frame,
     pub source: u32,
 }
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Pod, Zeroable)]
+#[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
+pub struct AtlasPassParams {
+    /// Image's allocation within the atlas (x, y, width, height), including
+    /// gutters, in texels of the mip level being written
+    pub alloc: UVec4,
+    /// Image's position and size within the atlas (x, y, width, height),
+    /// excluding gutters, in texels of the top mip level
+    pub image: UVec4,
+    /// Image's flags (see: [`crate::AtlasView::FLAG_LINEAR`])
+    pub flags: u32,
+}
@@ -1,16 +1,26 @@
 use core::mem;
 
-use glam::{Vec3, Vec4, Vec4Swizzles};
+use glam::{Affine3A, Vec3, Vec4, Vec4Swizzles};
 use spirv_std::arch::IndexUnchecked;
 #[cfg(target_arch = "spirv")]
 use spirv_std::num_traits::Float;
-use spirv_std::Sampler;
 
 use crate::{
-    BvhStack, BvhView, Material, MaterialId, MaterialsView, Tex, Triangle,
-    TriangleHit, TriangleId, TrianglesView, BVH_STACK_SIZE,
+    Affine3AExt, AtlasView, BvhStack, BvhView, Material, MaterialId,
+    MaterialsView, Triangle, TriangleHit, TriangleId, TrianglesView,
+    BVH_STACK_SIZE,
 };
 
+/// Operation code of a leaf entry; see `bvh::serializer` on the CPU side.
+const OP_LEAF: u32 = 1;
+
+/// Bits of `d0.w` that contain the operation code - top-level BVH's leaves
+/// keep their flags in the remaining bits.
+const OP_MASK: u32 = 0b11;
+
+/// Operation code of an internal node of a wide BVH.
+const OP_INTERNAL_WIDE: u32 = 2;
+
 #[derive(Clone, Copy, Default, PartialEq)]
 pub struct Ray {
     origin: Vec3,
@@ -52,6 +62,9 @@ impl Ray {
 
     /// Returns the closest opaque intersection of this ray with the world, if
     /// any.
+    ///
+    /// Only instances that are visible to rays of given kind and whose
+    /// visibility overlaps `visibility_mask` are taken into account.
     pub fn trace(
         self,
         local_idx: u32,
@@ -59,8 +72,9 @@ impl Ray {
         triangles: TrianglesView,
         bvh: BvhView,
         materials: MaterialsView,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
+        kind: RayKind,
+        visibility_mask: u32,
     ) -> (TriangleHit, usize) {
         let mut hit = TriangleHit::none();
 
@@ -70,8 +84,9 @@ impl Ray {
             triangles,
             bvh,
             materials,
-            atlas_tex,
-            atlas_sampler,
+            atlas,
+            kind,
+            visibility_mask,
             Tracing::ReturnClosest,
             &mut hit,
         );
@@ -81,6 +96,8 @@ impl Ray {
 
     /// Returns whether this ray intersects with anything in the world; used for
     /// shadow rays.
+    ///
+    /// See [`Self::trace()`] for the meaning of `kind` and `visibility_mask`.
     pub fn intersect(
         self,
         local_idx: u32,
@@ -88,8 +105,9 @@ impl Ray {
         triangles: TrianglesView,
         bvh: BvhView,
         materials: MaterialsView,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
+        kind: RayKind,
+        visibility_mask: u32,
     ) -> bool {
         let mut hit = TriangleHit {
             distance: self.len,
@@ -102,8 +120,9 @@ impl Ray {
             triangles,
             bvh,
             materials,
-            atlas_tex,
-            atlas_sampler,
+            atlas,
+            kind,
+            visibility_mask,
             Tracing::ReturnFirst,
             &mut hit,
         );
@@ -111,6 +130,24 @@ impl Ray {
         hit.distance < self.len
     }
 
+    /// Transforms this ray, e.g. from world-space into mesh-space.
+    ///
+    /// Note that the direction is deliberately left unnormalized, so that
+    /// distances measured along the transformed ray match the distances
+    /// measured along the original one.
+    pub fn transform(self, xform: Affine3A) -> Self {
+        let dir = xform.transform_vector3(self.dir);
+
+        Self {
+            origin: xform.transform_point3(self.origin),
+            dir,
+            inv_dir: 1.0 / dir,
+            len: self.len,
+        }
+    }
+
+    /// Traverses the top-level BVH, i.e. the tree built over instances, and
+    /// descends into bottom-level BVHs of the instances we hit.
     fn traverse(
         self,
         local_idx: u32,
@@ -118,8 +155,9 @@ impl Ray {
         triangles: TrianglesView,
         bvh: BvhView,
         materials: MaterialsView,
-        atlas_tex: Tex,
-        atlas_sampler: &Sampler,
+        atlas: AtlasView,
+        kind: RayKind,
+        visibility_mask: u32,
         tracing: Tracing,
         hit: &mut TriangleHit,
     ) -> usize {
@@ -141,95 +179,208 @@ impl Ray {
             used_memory += mem::size_of::<Vec4>();
 
             let d0 = bvh.get(bvh_ptr);
-            let is_internal_node = d0.w.to_bits() == 0;
+            let is_internal_node = d0.w.to_bits() & OP_MASK != OP_LEAF;
 
             if is_internal_node {
+                let got_child = self.visit_internal_node(
+                    bvh,
+                    0,
+                    &mut bvh_ptr,
+                    stack,
+                    &mut stack_ptr,
+                    hit.distance,
+                    &mut used_memory,
+                );
+
+                if got_child {
+                    continue;
+                }
+            } else {
                 used_memory += 3 * mem::size_of::<Vec4>();
 
-                let d1 = bvh.get(bvh_ptr + 1);
-                let d2 = bvh.get(bvh_ptr + 2);
-                let d3 = bvh.get(bvh_ptr + 3);
+                // Flags are stored above the operation code; bits above the
+                // fifth flag contain instance id, used only by CPU-side
+                // raycasts
+                let flags = d0.w.to_bits() >> 2;
+
+                // Whether there are any more instances directly following this
+                // instance.
+                //
+                // This corresponds to a single TLAS leaf node containing
+                // multiple instances.
+                let got_more_instances = flags & 1 == 1;
+
+                // Whether the instance's material supports alpha blending.
+                //
+                // If this is turned on, we have to load the triangle's material
+                // and compute albedo to make sure that the part of triangle we
+                // hit is actually opaque at that particular hit-point.
+                let has_alpha_blending = flags & 2 == 2;
 
-                let mut near_ptr = bvh_ptr + 4;
-                let mut far_ptr = d1.w.to_bits();
+                // Whether the instance is visible to this particular ray (e.g.
+                // because it's on one of the camera's render layers and it
+                // casts shadows, provided we're tracing a shadow ray)
+                let is_visible = d0.x.to_bits() & visibility_mask != 0
+                    && flags & kind.flag() != 0;
 
-                let mut near_distance = self.intersect_box(d0.xyz(), d1.xyz());
-                let mut far_distance = self.intersect_box(d2.xyz(), d3.xyz());
+                let blas_ptr = d0.y.to_bits();
+                let material_id = MaterialId::new(d0.z.to_bits());
 
-                if far_distance < near_distance {
-                    mem::swap(&mut near_ptr, &mut far_ptr);
-                    mem::swap(&mut near_distance, &mut far_distance);
-                }
+                let xform_inv = Affine3A::decode([
+                    bvh.get(bvh_ptr + 1),
+                    bvh.get(bvh_ptr + 2),
+                    bvh.get(bvh_ptr + 3),
+                ]);
+
+                let found_hit = is_visible
+                    && self.transform(xform_inv).traverse_blas(
+                        stack,
+                        stack_ptr,
+                        triangles,
+                        bvh,
+                        materials,
+                        atlas,
+                        tracing,
+                        blas_ptr,
+                        material_id,
+                        has_alpha_blending,
+                        hit,
+                        &mut used_memory,
+                    );
 
-                // If the nearest child is closer than our current best shot,
-                // let's check that child first; use stack to save the other
-                // node for later.
-                //
-                // The reasoning here goes that the closer child is more likely
-                // to contain a triangle we can hit; but if we don't hit that
-                // triangle (kind of a "cache miss" kind of thing), we still
-                // have to check the other node.
-                if far_distance < hit.distance {
-                    unsafe {
-                        *stack.index_unchecked_mut(stack_ptr) = far_ptr;
-                        stack_ptr += 1;
+                if found_hit {
+                    // BLASes are traversed in mesh-space, so we have to bring
+                    // the normal and tangent back into world-space
+                    hit.to_world_space(xform_inv);
+
+                    hit.material_id = material_id;
+
+                    if let Tracing::ReturnFirst = tracing {
+                        break;
                     }
                 }
 
-                if near_distance < hit.distance {
-                    bvh_ptr = near_ptr;
+                if got_more_instances {
+                    bvh_ptr += 4;
                     continue;
                 }
+            }
+
+            // If the control flow got here, then it means we either tested a
+            // leaf-node or tested an internal-node and got a miss.
+            //
+            // In any case, now it's the time to pop the next node from the
+            // stack and investigate it; if the stack is empty, then we've
+            // tested all nodes and we can safely bail out.
+            if stack_ptr > stack_begins_at {
+                unsafe {
+                    stack_ptr -= 1;
+                    bvh_ptr = *stack.index_unchecked(stack_ptr);
+                }
             } else {
-                used_memory += mem::size_of::<Triangle>();
+                break;
+            }
+        }
+
+        if hit.is_some() {
+            hit.point = self.at(hit.distance);
+        }
+
+        used_memory
+    }
+
+    /// Traverses a bottom-level BVH, i.e. the tree built over triangles of a
+    /// single mesh; this ray is expected to be already transformed into the
+    /// mesh-space.
+    ///
+    /// Uses the same stack as [`Self::traverse()`], starting right after the
+    /// top-level BVH's nodes that are currently waiting on the stack.
+    ///
+    /// Returns whether any triangle has been hit.
+    fn traverse_blas(
+        self,
+        stack: BvhStack,
+        stack_begins_at: usize,
+        triangles: TrianglesView,
+        bvh: BvhView,
+        materials: MaterialsView,
+        atlas: AtlasView,
+        tracing: Tracing,
+        blas_ptr: u32,
+        material_id: MaterialId,
+        has_alpha_blending: bool,
+        hit: &mut TriangleHit,
+        used_memory: &mut usize,
+    ) -> bool {
+        let mut found_any_hit = false;
+        let mut bvh_ptr = blas_ptr;
+        let mut stack_ptr = stack_begins_at;
+
+        loop {
+            *used_memory += mem::size_of::<Vec4>();
+
+            let d0 = bvh.get(bvh_ptr);
+            let is_internal_node = d0.w.to_bits() != OP_LEAF;
+
+            if is_internal_node {
+                let got_child = self.visit_internal_node(
+                    bvh,
+                    blas_ptr,
+                    &mut bvh_ptr,
+                    stack,
+                    &mut stack_ptr,
+                    hit.distance,
+                    used_memory,
+                );
+
+                if got_child {
+                    continue;
+                }
+            } else {
+                *used_memory += mem::size_of::<Triangle>();
 
                 let flags = d0.x.to_bits();
 
                 // Whether there are any more triangles directly following this
                 // triangle.
                 //
-                // This corresponds to a single BVH leaf node containing
+                // This corresponds to a single BLAS leaf node containing
                 // multiple triangles.
                 let got_more_triangles = flags & 1 == 1;
 
-                // Whether the triangle we're looking at supports alpha
-                // blending.
-                //
-                // If this is turned on, we have to load the triangle's material
-                // and compute albedo to make sure that the part of triangle we
-                // hit is actually opaque at that particular hit-point.
-                let has_alpha_blending = flags & 2 == 2;
-
                 let triangle_id = TriangleId::new(d0.y.to_bits());
-                let material_id = MaterialId::new(d0.z.to_bits());
 
                 let prev_uv = hit.uv;
                 let prev_normal = hit.normal;
+                let prev_tangent = hit.tangent;
+                let prev_uv_density = hit.uv_density;
                 let prev_distance = hit.distance;
 
                 let mut found_hit = triangles.get(triangle_id).hit(self, hit);
 
                 if found_hit && has_alpha_blending {
-                    used_memory += mem::size_of::<Material>();
-                    used_memory += mem::size_of::<Vec4>();
+                    *used_memory += mem::size_of::<Material>();
+                    *used_memory += mem::size_of::<Vec4>();
 
-                    let base_color = materials.get(material_id).base_color(
-                        atlas_tex,
-                        atlas_sampler,
-                        hit.uv,
-                    );
+                    // We don't know the ray cone here, so alpha is sampled at
+                    // the most detailed level (LOD 0)
+                    let base_color = materials
+                        .get(material_id)
+                        .base_color(atlas, hit.uv, 0.0);
 
                     if base_color.w < 1.0 {
                         found_hit = false;
 
                         hit.uv = prev_uv;
                         hit.normal = prev_normal;
+                        hit.tangent = prev_tangent;
+                        hit.uv_density = prev_uv_density;
                         hit.distance = prev_distance;
                     }
                 }
 
                 if found_hit {
-                    hit.material_id = material_id;
+                    found_any_hit = true;
 
                     if let Tracing::ReturnFirst = tracing {
                         break;
@@ -242,12 +393,6 @@ impl Ray {
                 }
             }
 
-            // If the control flow got here, then it means we either tested a
-            // leaf-node or tested an internal-node and got a miss.
-            //
-            // In any case, now it's the time to pop the next node from the
-            // stack and investigate it; if the stack is empty, then we've
-            // tested all nodes and we can safely bail out.
             if stack_ptr > stack_begins_at {
                 unsafe {
                     stack_ptr -= 1;
@@ -258,11 +403,145 @@ impl Ray {
             }
         }
 
-        if hit.is_some() {
-            hit.point = self.at(hit.distance);
+        found_any_hit
+    }
+
+    /// Checks which children of the internal node at `bvh_ptr` are worth
+    /// visiting; the farther children get pushed onto the stack and `bvh_ptr`
+    /// gets moved onto the nearest child.
+    ///
+    /// `base_ptr` says where the currently traversed tree begins at, since
+    /// pointers to children are stored relatively to it.
+    ///
+    /// Returns `false` if none of the children is worth visiting, in which
+    /// case the caller should pop the next node from the stack.
+    fn visit_internal_node(
+        self,
+        bvh: BvhView,
+        base_ptr: u32,
+        bvh_ptr: &mut u32,
+        stack: BvhStack,
+        stack_ptr: &mut usize,
+        hit_distance: f32,
+        used_memory: &mut usize,
+    ) -> bool {
+        let d0 = bvh.get(*bvh_ptr);
+        let d1 = bvh.get(*bvh_ptr + 1);
+
+        if d0.w.to_bits() == OP_INTERNAL_WIDE {
+            return self.visit_wide_internal_node(
+                bvh,
+                base_ptr,
+                bvh_ptr,
+                stack,
+                stack_ptr,
+                hit_distance,
+                used_memory,
+            );
         }
 
-        used_memory
+        *used_memory += 3 * mem::size_of::<Vec4>();
+
+        let d2 = bvh.get(*bvh_ptr + 2);
+        let d3 = bvh.get(*bvh_ptr + 3);
+
+        let mut near_ptr = *bvh_ptr + 4;
+        let mut far_ptr = base_ptr + d1.w.to_bits();
+
+        let mut near_distance = self.intersect_box(d0.xyz(), d1.xyz());
+        let mut far_distance = self.intersect_box(d2.xyz(), d3.xyz());
+
+        if far_distance < near_distance {
+            mem::swap(&mut near_ptr, &mut far_ptr);
+            mem::swap(&mut near_distance, &mut far_distance);
+        }
+
+        // If the nearest child is closer than our current best shot, let's
+        // check that child first; use stack to save the other node for later.
+        //
+        // The reasoning here goes that the closer child is more likely to
+        // contain a triangle we can hit; but if we don't hit that triangle
+        // (kind of a "cache miss" kind of thing), we still have to check the
+        // other node.
+        if far_distance < hit_distance {
+            unsafe {
+                *stack.index_unchecked_mut(*stack_ptr) = far_ptr;
+                *stack_ptr += 1;
+            }
+        }
+
+        if near_distance < hit_distance {
+            *bvh_ptr = near_ptr;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Wide counterpart of [`Self::visit_internal_node()`], used for nodes
+    /// with more than two children (see: `BvhLayout` on the CPU side).
+    ///
+    /// Wide node consists of `2 * children` items - bounding box of each child
+    /// followed by its pointer, except for the first child, which is stored
+    /// directly after the node.
+    fn visit_wide_internal_node(
+        self,
+        bvh: BvhView,
+        base_ptr: u32,
+        bvh_ptr: &mut u32,
+        stack: BvhStack,
+        stack_ptr: &mut usize,
+        hit_distance: f32,
+        used_memory: &mut usize,
+    ) -> bool {
+        let children = bvh.get(*bvh_ptr + 1).w.to_bits();
+
+        *used_memory += (2 * children as usize - 1) * mem::size_of::<Vec4>();
+
+        let mut near_ptr = 0;
+        let mut near_distance = hit_distance;
+        let mut child_idx = 0;
+
+        while child_idx < children {
+            let min = bvh.get(*bvh_ptr + 2 * child_idx);
+            let max = bvh.get(*bvh_ptr + 2 * child_idx + 1);
+
+            let child_ptr = if child_idx == 0 {
+                *bvh_ptr + 2 * children
+            } else {
+                base_ptr + min.w.to_bits()
+            };
+
+            let child_distance = self.intersect_box(min.xyz(), max.xyz());
+
+            if child_distance < near_distance {
+                // We've found a new nearest child - the previous one (if any)
+                // goes onto the stack, so that we can get back to it later
+                if near_distance < hit_distance {
+                    unsafe {
+                        *stack.index_unchecked_mut(*stack_ptr) = near_ptr;
+                        *stack_ptr += 1;
+                    }
+                }
+
+                near_ptr = child_ptr;
+                near_distance = child_distance;
+            } else if child_distance < hit_distance {
+                unsafe {
+                    *stack.index_unchecked_mut(*stack_ptr) = child_ptr;
+                    *stack_ptr += 1;
+                }
+            }
+
+            child_idx += 1;
+        }
+
+        if near_distance < hit_distance {
+            *bvh_ptr = near_ptr;
+            true
+        } else {
+            false
+        }
     }
 
     /// Checks whether this ray hits given bounding-box and returns their
@@ -321,6 +600,32 @@ impl Ray {
     }
 }
 
+/// Purpose of a ray; instances can opt out of being hit by each kind
+/// separately (e.g. a first-person weapon model might not cast shadows).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum RayKind {
+    /// Ray going from the camera
+    Primary,
+
+    /// Ray checking whether a light is visible from some point
+    Shadow,
+
+    /// Ray gathering indirect lighting
+    Indirect,
+}
+
+impl RayKind {
+    /// Returns the leaf flag that must be set for an instance to be visible to
+    /// this kind of ray; see `bvh::serializer` on the CPU side.
+    pub fn flag(self) -> u32 {
+        match self {
+            RayKind::Primary => 1 << 3,
+            RayKind::Shadow => 1 << 2,
+            RayKind::Indirect => 1 << 4,
+        }
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Tracing {
     ReturnClosest,
@@ -96,21 +96,24 @@ impl DiSample {
     pub fn pdf(self, lights: LightsView, hit: Hit) -> f32 {
         let light = lights.get(self.light_id);
 
-        self.pdf_ex(light, hit)
+        self.pdf_ex(lights, light, hit)
     }
 
     pub fn pdf_prev(self, lights: LightsView, hit: Hit) -> f32 {
         let light = lights.get_prev(self.light_id);
 
-        self.pdf_ex(light, hit)
+        self.pdf_ex(lights, light, hit)
     }
 
-    fn pdf_ex(self, light: Light, mut hit: Hit) -> f32 {
+    fn pdf_ex(self, lights: LightsView, light: Light, mut hit: Hit) -> f32 {
         hit.gbuffer.base_color = Vec4::ONE;
 
         if !light.is_none() && light.contains(self.light_point) {
             // TODO use a cheaper proxy
-            light.radiance(hit).sum().luma()
+            lights
+                .radiance_at(light, hit, self.light_point)
+                .sum()
+                .luma()
         } else {
             0.0
         }
@@ -1,8 +1,8 @@
 use core::ops::{Deref, DerefMut};
 
 use crate::{
-    Hit, LightId, LightRadiance, LightsView, Reservoir, Vec3Ext, WhiteNoise,
-    World,
+    Hit, LightId, LightRadiance, LightTreeView, LightsView, Reservoir, Vec3Ext,
+    WhiteNoise, World,
 };
 
 #[derive(Clone, Copy, Default)]
@@ -14,27 +14,24 @@ impl EphemeralReservoir {
     pub fn build(
         wnoise: &mut WhiteNoise,
         lights: LightsView,
+        light_tree: LightTreeView,
         world: World,
         hit: Hit,
     ) -> Self {
         let mut res = EphemeralReservoir::default();
         let mut res_pdf = 0.0;
 
-        // TODO rust-gpu seems to miscompile `.min()`
-        let max_samples = if world.light_count < 16 {
-            world.light_count
-        } else {
-            16
-        };
+        let light_count = world.light_count + world.emissive_count;
 
-        let sample_ipdf = world.light_count as f32;
+        // TODO rust-gpu seems to miscompile `.min()`
+        let max_samples = if light_count < 16 { light_count } else { 16 };
         let mut sample_nth = 0;
 
         while sample_nth < max_samples {
-            let light_id =
-                LightId::new(wnoise.sample_int() % world.light_count);
+            let (light_id, light_pdf) =
+                lights.sample(light_tree, wnoise, world, hit);
 
-            let light_rad = lights.get(light_id).radiance(hit);
+            let light_rad = lights.radiance(lights.get(light_id), hit);
 
             let sample = EphemeralSample {
                 light_id,
@@ -43,7 +40,13 @@ impl EphemeralReservoir {
 
             let sample_pdf = sample.pdf();
 
-            if res.update(wnoise, sample, sample_pdf * sample_ipdf) {
+            let sample_weight = if light_pdf > 0.0 {
+                sample_pdf / light_pdf
+            } else {
+                0.0
+            };
+
+            if res.update(wnoise, sample, sample_weight) {
                 res_pdf = sample_pdf;
             }
 
@@ -33,6 +33,10 @@ impl Triangle {
         vec2(self.d0.w, self.d1.w)
     }
 
+    pub fn tangent0(self) -> Vec4 {
+        self.d2
+    }
+
     pub fn position1(self) -> Vec3 {
         self.d3.xyz()
     }
@@ -45,6 +49,10 @@ impl Triangle {
         vec2(self.d3.w, self.d4.w)
     }
 
+    pub fn tangent1(self) -> Vec4 {
+        self.d5
+    }
+
     pub fn position2(self) -> Vec3 {
         self.d6.xyz()
     }
@@ -57,6 +65,10 @@ impl Triangle {
         vec2(self.d6.w, self.d7.w)
     }
 
+    pub fn tangent2(self) -> Vec4 {
+        self.d8
+    }
+
     pub fn positions(self) -> [Vec3; 3] {
         [self.position0(), self.position1(), self.position2()]
     }
@@ -93,20 +105,38 @@ impl Triangle {
             return false;
         }
 
+        // When we hit the triangle from behind, both the normal and the tangent
+        // get flipped so that the tangent frame stays consistent
+        let side = 1.0f32.copysign(inv_det);
+
         let normal = {
             let normal = u * self.normal1()
                 + v * self.normal2()
                 + (1.0 - u - v) * self.normal0();
 
-            normal.normalize() * 1.0f32.copysign(inv_det)
+            normal.normalize() * side
         };
 
+        let tangent = u * self.tangent1()
+            + v * self.tangent2()
+            + (1.0 - u - v) * self.tangent0();
+
         let uv = self.uv0()
             + (self.uv1() - self.uv0()) * u
             + (self.uv2() - self.uv0()) * v;
 
+        let uv_density = {
+            let uv_area = (self.uv1() - self.uv0())
+                .perp_dot(self.uv2() - self.uv0())
+                .abs();
+
+            (uv_area / v0v1.cross(v0v2).length()).sqrt()
+        };
+
         hit.uv = uv;
+        hit.uv_density = uv_density;
         hit.normal = normal;
+        hit.tangent = tangent * side;
         hit.distance = distance;
 
         true
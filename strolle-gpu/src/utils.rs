@@ -1,3 +1,4 @@
+mod affine3a_ext;
 mod bilinear_filter;
 mod f32_ext;
 mod u32_ext;
@@ -9,6 +10,7 @@ use core::ops;
 use glam::{uvec2, UVec2};
 use spirv_std::Image;
 
+pub use self::affine3a_ext::*;
 pub use self::bilinear_filter::*;
 pub use self::f32_ext::*;
 pub use self::u32_ext::*;
@@ -16,6 +18,7 @@ pub use self::vec2_ext::*;
 pub use self::vec3_ext::*;
 
 pub type Tex<'a> = &'a Image!(2D, type = f32, sampled);
+pub type TexArray<'a> = &'a Image!(2D, type = f32, sampled, arrayed);
 pub type TexRgba8<'a> = &'a Image!(2D, format = rgba8, sampled = false);
 pub type TexRgba16<'a> = &'a Image!(2D, format = rgba16f, sampled = false);
 pub type TexRgba32<'a> = &'a Image!(2D, format = rgba32f, sampled = false);
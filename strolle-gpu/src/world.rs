@@ -1,30 +1,37 @@
 use bytemuck::{Pod, Zeroable};
-use glam::{vec3, Vec3};
-#[cfg(target_arch = "spirv")]
-use spirv_std::num_traits::Float;
+use spirv_std::arch::IndexUnchecked;
+
+use crate::CelestialBody;
 
 #[repr(C)]
 #[derive(Clone, Copy, Default, Pod, Zeroable)]
 #[cfg_attr(not(target_arch = "spirv"), derive(Debug))]
 pub struct World {
     pub light_count: u32,
-    pub sun_azimuth: f32,
-    pub sun_altitude: f32,
+    /// Number of emissive triangles, stored in the lights buffer right after
+    /// the ordinary lights
+    pub emissive_count: u32,
+    /// Number of lights whose contribution doesn't fall off with distance,
+    /// stored at the beginning of the light tree
+    pub infinite_light_count: u32,
+    /// Number of celestial bodies lighting up the atmosphere; zero means that
+    /// there's no sky at all
+    pub celestial_body_count: u32,
+    pub celestial_bodies: [CelestialBody; World::MAX_CELESTIAL_BODIES],
 }
 
 impl World {
-    // TODO recalculate the distance factor using sun's solid angle
-    pub const SUN_DISTANCE: f32 = 1000.0;
+    pub const MAX_CELESTIAL_BODIES: usize = 4;
+
+    /// Distance at which the sky is assumed to be, e.g. when it's used as a
+    /// secondary light source.
+    pub const SKY_DISTANCE: f32 = 1000.0;
 
-    pub fn sun_dir(self) -> Vec3 {
-        vec3(
-            self.sun_altitude.cos() * self.sun_azimuth.sin(),
-            self.sun_altitude.sin(),
-            -self.sun_altitude.cos() * self.sun_azimuth.cos(),
-        )
+    pub fn celestial_body(self, idx: u32) -> CelestialBody {
+        unsafe { *self.celestial_bodies.index_unchecked(idx as usize) }
     }
 
-    pub fn sun_pos(self) -> Vec3 {
-        self.sun_dir() * Self::SUN_DISTANCE
+    pub fn has_sky(self) -> bool {
+        self.celestial_body_count > 0
     }
 }
@@ -0,0 +1,151 @@
+//! These passes prepare images copied into the atlas straight from GPU
+//! textures - images provided as raw data get the same treatment on the CPU
+//! (see: `Images::flush()`).
+
+use strolle_gpu::prelude::*;
+
+/// Fills image's gutters at the top level of the atlas, according to image's
+/// addressing modes.
+#[spirv(compute(threads(8, 8)))]
+pub fn pad(
+    #[spirv(global_invocation_id)] global_id: UVec3,
+    #[spirv(push_constant)] params: &AtlasPassParams,
+    #[spirv(descriptor_set = 0, binding = 0)] atlas: TexRgba8,
+) {
+    if global_id.x >= params.alloc.z || global_id.y >= params.alloc.w {
+        return;
+    }
+
+    let dst = params.alloc.xy() + global_id.xy();
+    let src = dst.as_ivec2() - params.image.xy().as_ivec2();
+
+    let is_gutter = src.x < 0
+        || src.y < 0
+        || src.x >= params.image.z as i32
+        || src.y >= params.image.w as i32;
+
+    if !is_gutter {
+        return;
+    }
+
+    let src = uvec2(
+        address(
+            src.x,
+            params.image.z,
+            (params.flags >> AtlasView::ADDRESS_U_SHIFT) & 0b11,
+        ),
+        address(
+            src.y,
+            params.image.w,
+            (params.flags >> AtlasView::ADDRESS_V_SHIFT) & 0b11,
+        ),
+    );
+
+    unsafe {
+        atlas.write(dst, atlas.read(params.image.xy() + src));
+    }
+}
+
+/// Generates the next mip level of an image by averaging 2x2 blocks of texels
+/// from the previous level.
+#[spirv(compute(threads(8, 8)))]
+pub fn downsample(
+    #[spirv(global_invocation_id)] global_id: UVec3,
+    #[spirv(push_constant)] params: &AtlasPassParams,
+    #[spirv(descriptor_set = 0, binding = 0)] input: TexRgba8,
+    #[spirv(descriptor_set = 0, binding = 1)] output: TexRgba8,
+) {
+    if global_id.x >= params.alloc.z || global_id.y >= params.alloc.w {
+        return;
+    }
+
+    let dst = params.alloc.xy() + global_id.xy();
+    let src = 2 * dst;
+    let is_srgb = params.flags & AtlasView::FLAG_LINEAR == 0;
+
+    let mut color = Vec4::ZERO;
+    let mut i = 0;
+
+    while i < 4 {
+        let texel = input.read(src + uvec2(i % 2, i / 2));
+
+        // Colors have to be averaged in linear space, otherwise the mip
+        // levels get darker
+        color += if is_srgb {
+            srgb_to_linear(texel.xyz()).extend(texel.w)
+        } else {
+            texel
+        };
+
+        i += 1;
+    }
+
+    let color = color / 4.0;
+
+    let color = if is_srgb {
+        linear_to_srgb(color.xyz()).extend(color.w)
+    } else {
+        color
+    };
+
+    unsafe {
+        output.write(dst, color);
+    }
+}
+
+/// Maps given texel coordinate (possibly lying outside of the image) into the
+/// image, according to the addressing mode (see: [`AtlasView::ADDRESS_REPEAT`]
+/// etc.).
+fn address(coord: i32, len: u32, mode: u32) -> u32 {
+    let len = len as i32;
+
+    let coord = if mode == AtlasView::ADDRESS_CLAMP {
+        coord.clamp(0, len - 1)
+    } else if mode == AtlasView::ADDRESS_MIRROR_REPEAT {
+        let coord = euclid_rem(coord, 2 * len);
+
+        if coord >= len {
+            2 * len - 1 - coord
+        } else {
+            coord
+        }
+    } else {
+        euclid_rem(coord, len)
+    };
+
+    coord as u32
+}
+
+fn euclid_rem(lhs: i32, rhs: i32) -> i32 {
+    let rem = lhs % rhs;
+
+    if rem < 0 {
+        rem + rhs
+    } else {
+        rem
+    }
+}
+
+fn srgb_to_linear(color: Vec3) -> Vec3 {
+    fn eval(c: f32) -> f32 {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    vec3(eval(color.x), eval(color.y), eval(color.z))
+}
+
+fn linear_to_srgb(color: Vec3) -> Vec3 {
+    fn eval(c: f32) -> f32 {
+        if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    vec3(eval(color.x), eval(color.y), eval(color.z))
+}
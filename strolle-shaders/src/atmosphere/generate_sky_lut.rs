@@ -46,22 +46,6 @@ pub fn main(
         )
     };
 
-    let sun_dir = {
-        let altitude = world.sun_altitude % (2.0 * PI);
-
-        if altitude < 0.5 * PI {
-            vec3(0.0, altitude.sin(), -altitude.cos())
-        } else {
-            // There's (probably) something wrong with the way we compute
-            // azimuth during sky-lut sampling which shows up as sun being on
-            // the opposite side of the sky when it's setting down.
-            //
-            // I'm not sure what's wrong with our sampling there, so let's
-            // hotfix it here.
-            vec3(0.0, altitude.sin(), altitude.cos())
-        }
-    };
-
     let atmosphere_distance = Ray::new(Atmosphere::VIEW_POS, ray_dir)
         .intersect_sphere(Atmosphere::ATMOSPHERE_RADIUS_MM);
 
@@ -74,17 +58,27 @@ pub fn main(
         ground_distance
     };
 
-    let out_val = eval(
-        transmittance_lut_tex,
-        transmittance_lut_sampler,
-        scattering_lut_tex,
-        scattering_lut_sampler,
-        Atmosphere::VIEW_POS,
-        ray_dir,
-        sun_dir,
-        t_max,
-        Atmosphere::SKY_LUT_STEPS,
-    );
+    let mut out_val = Vec3::ZERO;
+    let mut idx = 0;
+
+    while idx < world.celestial_body_count {
+        let body = world.celestial_body(idx);
+
+        out_val += body.illuminance()
+            * eval(
+                transmittance_lut_tex,
+                transmittance_lut_sampler,
+                scattering_lut_tex,
+                scattering_lut_sampler,
+                Atmosphere::VIEW_POS,
+                ray_dir,
+                body.dir(),
+                t_max,
+                Atmosphere::SKY_LUT_STEPS,
+            );
+
+        idx += 1;
+    }
 
     unsafe {
         out.write(global_id, out_val.extend(1.0));
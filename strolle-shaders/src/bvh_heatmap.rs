@@ -10,8 +10,9 @@ pub fn main(
     #[spirv(descriptor_set = 0, binding = 1, storage_buffer)] bvh: &[Vec4],
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 3)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 4)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] output: TexRgba32,
 ) {
@@ -19,6 +20,7 @@ pub fn main(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
     if !camera.contains(screen_pos) {
         return;
@@ -32,8 +34,9 @@ pub fn main(
         triangles,
         bvh,
         materials,
-        atlas_tex,
-        atlas_sampler,
+        atlas,
+        RayKind::Primary,
+        camera.visibility_mask(),
     );
 
     let color = gradient(
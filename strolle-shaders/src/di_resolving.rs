@@ -12,9 +12,12 @@ pub fn main(
     materials: &[Material],
     #[spirv(descriptor_set = 0, binding = 3, storage_buffer)]
     lights: &[Light],
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
-    #[spirv(descriptor_set = 0, binding = 6, uniform)] world: &World,
+    #[spirv(descriptor_set = 0, binding = 4, storage_buffer)]
+    light_profiles: &[f32],
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 6)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 7)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 8, uniform)] world: &World,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)]
     atmosphere_transmittance_lut_tex: Tex,
@@ -37,7 +40,8 @@ pub fn main(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
-    let lights = LightsView::new(lights);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
+    let lights = LightsView::new(lights, light_profiles);
     let atmosphere = Atmosphere::new(
         atmosphere_transmittance_lut_tex,
         atmosphere_transmittance_lut_sampler,
@@ -72,8 +76,9 @@ pub fn main(
             triangles,
             bvh,
             materials,
-            atlas_tex,
-            atlas_sampler,
+            atlas,
+            RayKind::Shadow,
+            camera.visibility_mask(),
         );
 
         confidence = if res.sample.is_occluded == is_occluded {
@@ -88,13 +93,17 @@ pub fn main(
         radiance = if res.sample.is_occluded {
             LightRadiance::default()
         } else {
-            lights.get(res.sample.light_id).radiance(hit) * res.w
+            lights.radiance_at(
+                lights.get(res.sample.light_id),
+                hit,
+                res.sample.light_point,
+            ) * res.w
         };
     } else {
         confidence = 1.0;
 
         radiance = LightRadiance {
-            radiance: atmosphere.sample(world.sun_dir(), hit.dir),
+            radiance: atmosphere.sample_with_bodies(*world, hit.dir),
             diff_brdf: Vec3::ONE,
             spec_brdf: Vec3::ZERO,
         };
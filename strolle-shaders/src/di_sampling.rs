@@ -14,9 +14,14 @@ pub fn main(
     materials: &[Material],
     #[spirv(descriptor_set = 0, binding = 4, storage_buffer)]
     lights: &[Light],
-    #[spirv(descriptor_set = 0, binding = 5)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 6)] atlas_sampler: &Sampler,
-    #[spirv(descriptor_set = 0, binding = 7, uniform)] world: &World,
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)]
+    light_profiles: &[f32],
+    #[spirv(descriptor_set = 0, binding = 6, storage_buffer)]
+    light_tree: &[LightTreeNode],
+    #[spirv(descriptor_set = 0, binding = 7)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 9)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 10, uniform)] world: &World,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] prim_gbuffer_d0: TexRgba32,
     #[spirv(descriptor_set = 1, binding = 2)] prim_gbuffer_d1: TexRgba32,
@@ -30,7 +35,9 @@ pub fn main(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
-    let lights = LightsView::new(lights);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
+    let lights = LightsView::new(lights, light_profiles);
+    let light_tree = LightTreeView::new(light_tree);
 
     if !camera.contains(screen_pos) {
         return;
@@ -52,7 +59,8 @@ pub fn main(
 
     // ---
 
-    let mut res = EphemeralReservoir::build(&mut wnoise, lights, *world, hit);
+    let mut res =
+        EphemeralReservoir::build(&mut wnoise, lights, light_tree, *world, hit);
 
     let res = if res.m > 0.0 {
         let ray = lights
@@ -65,8 +73,9 @@ pub fn main(
             triangles,
             bvh,
             materials,
-            atlas_tex,
-            atlas_sampler,
+            atlas,
+            RayKind::Shadow,
+            camera.visibility_mask(),
         );
 
         if is_occluded {
@@ -6,6 +6,8 @@ pub fn pick(
     #[spirv(push_constant)] params: &PassParams,
     #[spirv(descriptor_set = 0, binding = 0, storage_buffer)]
     lights: &[Light],
+    #[spirv(descriptor_set = 0, binding = 1, storage_buffer)]
+    light_profiles: &[f32],
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] prim_gbuffer_d0: TexRgba32,
     #[spirv(descriptor_set = 1, binding = 2)] prim_gbuffer_d1: TexRgba32,
@@ -18,7 +20,7 @@ pub fn pick(
     let lhs_pos = resolve_checkerboard_alt(global_id, params.frame.get() / 2);
     let lhs_idx = camera.screen_to_idx(lhs_pos);
     let mut wnoise = WhiteNoise::new(params.seed, lhs_pos);
-    let lights = LightsView::new(lights);
+    let lights = LightsView::new(lights, light_profiles);
 
     let buf_pos_a = global_id * uvec2(2, 1);
     let buf_pos_b = buf_pos_a + uvec2(1, 0);
@@ -156,8 +158,9 @@ pub fn trace(
     #[spirv(descriptor_set = 0, binding = 1, storage_buffer)] bvh: &[Vec4],
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 3)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 4)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] buf_d0: TexRgba32,
     #[spirv(descriptor_set = 1, binding = 2)] buf_d1: TexRgba32,
@@ -167,6 +170,7 @@ pub fn trace(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
     if !camera.contains(screen_pos) {
         return;
@@ -194,8 +198,9 @@ pub fn trace(
         triangles,
         bvh,
         materials,
-        atlas_tex,
-        atlas_sampler,
+        atlas,
+        RayKind::Shadow,
+        camera.visibility_mask(),
     );
 
     let visibility = if is_occluded { 0.0 } else { 1.0 };
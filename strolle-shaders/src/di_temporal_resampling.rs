@@ -6,6 +6,8 @@ pub fn main(
     #[spirv(push_constant)] params: &PassParams,
     #[spirv(descriptor_set = 0, binding = 0, storage_buffer)]
     lights: &[Light],
+    #[spirv(descriptor_set = 0, binding = 1, storage_buffer)]
+    light_profiles: &[f32],
     #[spirv(descriptor_set = 1, binding = 0, uniform)] curr_camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1, uniform)] prev_camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 2)] reprojection_map: TexRgba32,
@@ -21,7 +23,7 @@ pub fn main(
     let lhs_pos = global_id.xy();
     let lhs_idx = curr_camera.screen_to_idx(lhs_pos);
     let mut wnoise = WhiteNoise::new(params.seed, lhs_pos);
-    let lights = LightsView::new(lights);
+    let lights = LightsView::new(lights, light_profiles);
     let reprojection_map = ReprojectionMap::new(reprojection_map);
 
     if !curr_camera.contains(lhs_pos) {
@@ -74,8 +76,6 @@ pub fn main(
             if rhs_light.is_slot_killed() {
                 rhs.w = 0.0;
                 rhs_killed = true;
-            } else if rhs_light.is_slot_remapped() {
-                rhs.sample.light_id = rhs_light.slot_remapped_to();
             }
 
             rhs_hit = Hit::new(
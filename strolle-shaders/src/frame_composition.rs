@@ -78,5 +78,12 @@ pub fn fs(
         _ => Default::default(),
     };
 
-    *frag_color = color.extend(1.0);
+    // Heatmap's colors don't represent any light, so they don't get exposed
+    let exposure = if params.camera_mode == 5 {
+        1.0
+    } else {
+        params.exposure
+    };
+
+    *frag_color = (color * exposure).extend(1.0);
 }
@@ -11,8 +11,9 @@ pub fn main(
     #[spirv(descriptor_set = 0, binding = 1, storage_buffer)] bvh: &[Vec4],
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 3)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 4)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] prim_gbuffer_d0: TexRgba32,
     #[spirv(descriptor_set = 1, binding = 2)] prim_gbuffer_d1: TexRgba32,
@@ -34,6 +35,7 @@ pub fn main(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
     if !camera.contains(screen_pos) {
         return;
@@ -83,8 +85,9 @@ pub fn main(
         triangles,
         bvh,
         materials,
-        atlas_tex,
-        atlas_sampler,
+        atlas,
+        RayKind::Indirect,
+        camera.visibility_mask(),
     );
 
     // ---
@@ -94,18 +97,40 @@ pub fn main(
 
         gi_material.regularize();
 
+        let gi_depth = gi_ray.origin().distance(gi_hit.point);
+
+        // Ray cone starting at the camera, going through the primary hit and
+        // ending at the indirect one
+        let gi_uv_footprint = gi_hit.uv_footprint(
+            camera.pixel_spread_angle()
+                * (camera.approx_origin().distance(gi_ray.origin()) + gi_depth),
+            gi_ray.dir(),
+        );
+
         GBufferEntry {
             base_color: gi_material.base_color(
-                atlas_tex,
-                atlas_sampler,
+                atlas,
+                gi_hit.uv,
+                gi_uv_footprint,
+            ),
+            normal: gi_material.normal(
+                atlas,
                 gi_hit.uv,
+                gi_uv_footprint,
+                gi_hit.normal,
+                gi_hit.tangent,
             ),
-            normal: gi_hit.normal,
             metallic: gi_material.metallic,
-            emissive: gi_material.emissive(atlas_tex, atlas_sampler, gi_hit.uv),
+            // Emissive triangles without textures are sampled as lights
+            // during direct lighting, so there's no need to carry them here
+            emissive: if gi_material.has_emissive_texture() {
+                gi_material.emissive(atlas, gi_hit.uv, gi_uv_footprint)
+            } else {
+                Vec3::ZERO
+            },
             roughness: gi_material.roughness,
             reflectance: gi_material.reflectance,
-            depth: gi_ray.origin().distance(gi_hit.point),
+            depth: gi_depth,
         }
     } else {
         Default::default()
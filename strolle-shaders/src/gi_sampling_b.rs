@@ -12,10 +12,15 @@ pub fn main(
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     lights: &[Light],
     #[spirv(descriptor_set = 0, binding = 3, storage_buffer)]
+    light_profiles: &[f32],
+    #[spirv(descriptor_set = 0, binding = 4, storage_buffer)]
+    light_tree: &[LightTreeNode],
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
-    #[spirv(descriptor_set = 0, binding = 6, uniform)] world: &World,
+    #[spirv(descriptor_set = 0, binding = 6)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 7)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 9, uniform)] world: &World,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)]
     atmosphere_transmittance_lut_tex: Tex,
@@ -45,8 +50,10 @@ pub fn main(
     let screen_idx = camera.screen_to_idx(screen_pos);
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
-    let lights = LightsView::new(lights);
+    let lights = LightsView::new(lights, light_profiles);
+    let light_tree = LightTreeView::new(light_tree);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
     let atmosphere = Atmosphere::new(
         atmosphere_transmittance_lut_tex,
         atmosphere_transmittance_lut_sampler,
@@ -118,24 +125,27 @@ pub fn main(
     if gi_hit.is_none() {
         light_id = LightId::sky();
         light_pdf = 1.0;
-        light_rad = atmosphere.sample(world.sun_dir(), gi_hit.dir);
+        light_rad = atmosphere.sample(*world, gi_hit.dir);
     } else {
-        let atmosphere_pdf = if world.sun_altitude <= -1.0 {
-            0.0
-        } else {
-            0.25
-        };
+        let atmosphere_pdf = if world.has_sky() { 0.25 } else { 0.0 };
 
-        if world.light_count == 0 || wnoise.sample() < atmosphere_pdf {
+        if world.light_count + world.emissive_count == 0
+            || wnoise.sample() < atmosphere_pdf
+        {
             light_id = LightId::sky();
             light_pdf = atmosphere_pdf;
             light_dir = wnoise.sample_hemisphere(gi_hit.gbuffer.normal);
 
-            light_rad = atmosphere.sample(world.sun_dir(), light_dir)
+            light_rad = atmosphere.sample(*world, light_dir)
                 * gi_hit.gbuffer.normal.dot(light_dir);
         } else {
-            let res =
-                EphemeralReservoir::build(&mut wnoise, lights, *world, gi_hit);
+            let res = EphemeralReservoir::build(
+                &mut wnoise,
+                lights,
+                light_tree,
+                *world,
+                gi_hit,
+            );
 
             if res.w > 0.0 {
                 // For simplicity, we assume an unmodulated diffuse BRDF here
@@ -172,8 +182,9 @@ pub fn main(
                 triangles,
                 bvh,
                 materials,
-                atlas_tex,
-                atlas_sampler,
+                atlas,
+                RayKind::Shadow,
+                camera.visibility_mask(),
             );
 
             if is_occluded {
@@ -196,6 +207,9 @@ pub fn main(
     };
 
     if gi_hit.is_some() {
+        // N.B. `gi_hit.gbuffer.emissive` contains only the textured emissive
+        //      triangles, since the rest is sampled as lights during direct
+        //      lighting (see: `gi_sampling_a`)
         radiance *= gi_hit.gbuffer.base_color.xyz() / PI;
         radiance += gi_hit.gbuffer.emissive;
     }
@@ -213,7 +227,7 @@ pub fn main(
             v2_point = gi_hit.point;
             v2_normal = gi_hit.gbuffer.normal;
         } else {
-            v2_point = v1_point + gi_hit.dir * World::SUN_DISTANCE;
+            v2_point = v1_point + gi_hit.dir * World::SKY_DISTANCE;
             v2_normal = -gi_hit.dir;
         }
 
@@ -169,8 +169,9 @@ pub fn trace(
     #[spirv(descriptor_set = 0, binding = 1, storage_buffer)] bvh: &[Vec4],
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 3)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 4)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1)] buf_d0: TexRgba32,
     #[spirv(descriptor_set = 1, binding = 2)] buf_d1: TexRgba32,
@@ -180,6 +181,7 @@ pub fn trace(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
     if !camera.contains(screen_pos) {
         return;
@@ -207,8 +209,9 @@ pub fn trace(
         triangles,
         bvh,
         materials,
-        atlas_tex,
-        atlas_sampler,
+        atlas,
+        RayKind::Indirect,
+        camera.visibility_mask(),
     );
 
     let visibility = if is_occluded { 0.0 } else { 1.0 };
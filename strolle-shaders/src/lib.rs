@@ -1,6 +1,7 @@
 #![cfg_attr(target_arch = "spirv", no_std)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod atlas;
 pub mod atmosphere;
 pub mod bvh_heatmap;
 pub mod di_resolving;
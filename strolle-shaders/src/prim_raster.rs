@@ -1,4 +1,4 @@
-use spirv_std::arch;
+use spirv_std::arch::{self, Derivative};
 use strolle_gpu::prelude::*;
 
 #[spirv(vertex)]
@@ -11,6 +11,7 @@ pub fn vs(
     // Inputs
     vertex_d0: Vec4,
     vertex_d1: Vec4,
+    vertex_d2: Vec4,
 
     // Outputs
     #[spirv(position)] out_vertex: &mut Vec4,
@@ -19,14 +20,34 @@ pub fn vs(
     out_point: &mut Vec3,
     out_normal: &mut Vec3,
     out_uv: &mut Vec2,
+    out_tangent: &mut Vec4,
 ) {
-    let point = vertex_d0.xyz();
-
-    let prev_point = params
-        .prev_xform()
-        .transform_point3(params.curr_xform_inv().transform_point3(point));
+    let curr_xform = params.curr_xform();
+    let prev_xform = params.prev_xform();
+
+    // Vertices are stored in mesh-space, so we have to bring them into
+    // world-space first
+    let point = curr_xform.transform_point3(vertex_d0.xyz());
+    let prev_point = prev_xform.transform_point3(vertex_d0.xyz());
+
+    // Transforming normals requires inversing and transposing the matrix in
+    // order to get correct results under scaling, see:
+    //
+    // https://paroj.github.io/gltut/Illumination/Tut09%20Normal%20Transformation.html
+    let normal = (curr_xform.matrix3.inverse().transpose() * vertex_d1.xyz())
+        .normalize();
+
+    // Tangents, on the other hand, are transformed just like positions - but
+    // since mirroring flips the handedness of the tangent frame, bitangent's
+    // sign has to be flipped as well
+    let tangent = {
+        let sign = 1.0f32.copysign(curr_xform.matrix3.determinant());
+
+        (curr_xform.matrix3 * vertex_d2.xyz())
+            .normalize_or_zero()
+            .extend(vertex_d2.w * sign)
+    };
 
-    let normal = vertex_d1.xyz();
     let uv = vec2(vertex_d0.w, vertex_d1.w);
 
     *out_vertex = camera.world_to_clip(point);
@@ -35,6 +56,7 @@ pub fn vs(
     *out_point = point;
     *out_normal = normal;
     *out_uv = uv;
+    *out_tangent = tangent;
 }
 
 #[spirv(fragment)]
@@ -43,8 +65,9 @@ pub fn fs(
     #[spirv(push_constant)] params: &PrimRasterPassParams,
     #[spirv(descriptor_set = 0, binding = 0, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 1)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 2)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 1)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 2)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1, uniform)] prev_camera: &Camera,
     #[spirv(front_facing)] front_facing: bool,
@@ -55,6 +78,7 @@ pub fn fs(
     point: Vec3,
     normal: Vec3,
     uv: Vec2,
+    tangent: Vec4,
 
     // Outputs
     out_prim_gbuffer_d0: &mut Vec4,
@@ -64,10 +88,16 @@ pub fn fs(
 ) {
     let material = MaterialsView::new(materials)
         .get(MaterialId::new(params.material_id()));
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
-    let base_color = material.base_color(atlas_tex, atlas_sampler, uv);
+    // For primary hits we know the exact footprint of the pixel, no need for
+    // ray cones
+    let uv_footprint = uv.ddx().length().max(uv.ddy().length());
+
+    let base_color = material.base_color(atlas, uv, uv_footprint);
     let metallic_roughness =
-        material.metallic_roughness(atlas_tex, atlas_sampler, uv);
+        material.metallic_roughness(atlas, uv, uv_footprint);
+
     // If our material is transparent and doesn't rely on refraction, kill the
     // current fragment to re-use GPU in finding the next triangle
     if base_color.w < 0.01 && material.ior == 1.0 {
@@ -75,14 +105,15 @@ pub fn fs(
     }
 
     let normal = {
-        // TODO bring back normal mapping
-        let normal = normal.normalize();
-
-        if front_facing {
-            normal
-        } else {
-            -normal
-        }
+        let side = if front_facing { 1.0 } else { -1.0 };
+
+        material.normal(
+            atlas,
+            uv,
+            uv_footprint,
+            normal.normalize() * side,
+            tangent * side,
+        )
     };
 
     let ray = camera.ray(camera.clip_to_screen(curr_vertex).round().as_uvec2());
@@ -92,7 +123,7 @@ pub fn fs(
         base_color,
         normal,
         metallic: metallic_roughness.x,
-        emissive: material.emissive(atlas_tex, atlas_sampler, uv),
+        emissive: material.emissive(atlas, uv, uv_footprint),
         roughness: metallic_roughness.y,
         reflectance: material.reflectance,
         depth,
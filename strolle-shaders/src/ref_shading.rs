@@ -12,10 +12,15 @@ pub fn main(
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     lights: &[Light],
     #[spirv(descriptor_set = 0, binding = 3, storage_buffer)]
+    light_profiles: &[f32],
+    #[spirv(descriptor_set = 0, binding = 4, storage_buffer)]
+    light_tree: &[LightTreeNode],
+    #[spirv(descriptor_set = 0, binding = 5, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
-    #[spirv(descriptor_set = 0, binding = 6, uniform)] world: &World,
+    #[spirv(descriptor_set = 0, binding = 6)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 7)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 8)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 9, uniform)] world: &World,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1, uniform)] prev_camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 2)]
@@ -35,8 +40,10 @@ pub fn main(
     let mut wnoise = WhiteNoise::new(params.seed, screen_pos);
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
-    let lights = LightsView::new(lights);
+    let lights = LightsView::new(lights, light_profiles);
+    let light_tree = LightTreeView::new(light_tree);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
     let atmosphere = Atmosphere::new(
         atmosphere_transmittance_lut_tex,
         atmosphere_transmittance_lut_sampler,
@@ -57,7 +64,7 @@ pub fn main(
             Default::default()
         };
 
-        let curr_color = rays[3 * screen_idx + 2].xyz();
+        let curr_color = rays[4 * screen_idx + 2].xyz();
 
         unsafe {
             colors.write(screen_pos, prev_color + curr_color.extend(1.0));
@@ -77,27 +84,38 @@ pub fn main(
         color = Vec3::ZERO;
         throughput = Vec3::ONE;
     } else {
-        let d0 = rays[3 * screen_idx];
-        let d1 = rays[3 * screen_idx + 1];
-        let d2 = rays[3 * screen_idx + 2];
+        let d0 = rays[4 * screen_idx];
+        let d1 = rays[4 * screen_idx + 1];
+        let d2 = rays[4 * screen_idx + 2];
 
         ray = Ray::new(d0.xyz(), d1.xyz());
         color = d2.xyz();
         throughput = vec3(d0.w, d1.w, d2.w);
     }
 
+    // x - texture footprint of the hit, y - ray cone's width at the hit
+    let hit_d2 = hits[3 * screen_idx + 2];
+
     let hit = {
         let t_hit = TriangleHit::unpack([
-            hits[2 * screen_idx],
-            hits[2 * screen_idx + 1],
+            hits[3 * screen_idx],
+            hits[3 * screen_idx + 1],
         ]);
 
         if t_hit.is_none() {
-            color += throughput * atmosphere.sample(world.sun_dir(), ray.dir());
+            // Celestial bodies are sampled as directional lights, so after the
+            // first bounce their contribution has been already accounted for
+            let sky = if params.depth == 0 {
+                atmosphere.sample_with_bodies(*world, ray.dir())
+            } else {
+                atmosphere.sample(*world, ray.dir())
+            };
+
+            color += throughput * sky;
 
-            rays[3 * screen_idx] = Default::default();
-            rays[3 * screen_idx + 1] = Default::default();
-            rays[3 * screen_idx + 2] = color.extend(Default::default());
+            rays[4 * screen_idx] = Default::default();
+            rays[4 * screen_idx + 1] = Default::default();
+            rays[4 * screen_idx + 2] = color.extend(Default::default());
 
             return;
         }
@@ -113,14 +131,10 @@ pub fn main(
             origin: ray.origin(),
             dir: ray.dir(),
             gbuffer: GBufferEntry {
-                base_color: material.base_color(
-                    atlas_tex,
-                    atlas_sampler,
-                    t_hit.uv,
-                ),
+                base_color: material.base_color(atlas, t_hit.uv, hit_d2.x),
                 normal: t_hit.normal,
                 metallic: material.metallic,
-                emissive: material.emissive(atlas_tex, atlas_sampler, t_hit.uv),
+                emissive: material.emissive(atlas, t_hit.uv, hit_d2.x),
                 roughness: material.roughness,
                 reflectance: material.reflectance,
                 depth: 0.0,
@@ -130,26 +144,42 @@ pub fn main(
 
     // -------------------------------------------------------------------------
 
+    // Emissive triangles are accounted for here, when they're hit, rather
+    // than when they're sampled as lights (see below) - this way they light
+    // up near-mirror reflections and their textures are taken into account
     color += throughput * hit.gbuffer.emissive;
 
-    if world.light_count > 0 {
-        let light_id = wnoise.sample_int() % world.light_count;
-        let light_pdf = 1.0 / (world.light_count as f32);
-        let light = lights.get(LightId::new(light_id));
-
-        let is_light_occluded =
-            light.ray_wnoise(&mut wnoise, hit.point).intersect(
-                local_idx,
-                stack,
-                triangles,
-                bvh,
-                materials,
-                atlas_tex,
-                atlas_sampler,
-            );
+    let light_count = world.light_count + world.emissive_count;
+
+    let (light_id, light_pdf) = if light_count > 0 {
+        lights.sample(light_tree, &mut wnoise, *world, hit)
+    } else {
+        (LightId::new(0), 0.0)
+    };
+
+    // Emissive triangles have been already accounted for (see above), so
+    // when one gets picked, we just skip it - this doesn't bias other lights,
+    // since their probabilities don't change
+    if light_pdf > 0.0 && !lights.get(light_id).is_triangle() {
+        let light = lights.get(light_id);
+
+        let light_ray = light.ray_wnoise(&mut wnoise, hit.point);
+
+        let is_light_occluded = light_ray.intersect(
+            local_idx,
+            stack,
+            triangles,
+            bvh,
+            materials,
+            atlas,
+            RayKind::Shadow,
+            camera.visibility_mask(),
+        );
 
         if !is_light_occluded {
-            color += throughput * light.radiance(hit).sum() / light_pdf;
+            let light_rad = lights.radiance_at(light, hit, light_ray.origin());
+
+            color += throughput * light_rad.sum() / light_pdf;
         }
     }
 
@@ -159,8 +189,8 @@ pub fn main(
         LayeredBrdf::new(hit.gbuffer).sample(&mut wnoise, -hit.dir);
 
     if reflected_sample.is_invalid() {
-        rays[3 * screen_idx] = Default::default();
-        rays[3 * screen_idx + 1] = Default::default();
+        rays[4 * screen_idx] = Default::default();
+        rays[4 * screen_idx + 1] = Default::default();
         return;
     }
 
@@ -171,7 +201,8 @@ pub fn main(
 
     // -------------------------------------------------------------------------
 
-    rays[3 * screen_idx] = reflected_ray.origin().extend(throughput.x);
-    rays[3 * screen_idx + 1] = reflected_ray.dir().extend(throughput.y);
-    rays[3 * screen_idx + 2] = color.extend(throughput.z);
+    rays[4 * screen_idx] = reflected_ray.origin().extend(throughput.x);
+    rays[4 * screen_idx + 1] = reflected_ray.dir().extend(throughput.y);
+    rays[4 * screen_idx + 2] = color.extend(throughput.z);
+    rays[4 * screen_idx + 3] = vec4(hit_d2.y, 0.0, 0.0, 0.0);
 }
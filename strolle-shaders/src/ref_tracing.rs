@@ -11,8 +11,9 @@ pub fn main(
     #[spirv(descriptor_set = 0, binding = 1, storage_buffer)] bvh: &[Vec4],
     #[spirv(descriptor_set = 0, binding = 2, storage_buffer)]
     materials: &[Material],
-    #[spirv(descriptor_set = 0, binding = 3)] atlas_tex: Tex,
-    #[spirv(descriptor_set = 0, binding = 4)] atlas_sampler: &Sampler,
+    #[spirv(descriptor_set = 0, binding = 3)] atlas_srgb_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 4)] atlas_linear_tex: TexArray,
+    #[spirv(descriptor_set = 0, binding = 5)] atlas_sampler: &Sampler,
     #[spirv(descriptor_set = 1, binding = 0, uniform)] camera: &Camera,
     #[spirv(descriptor_set = 1, binding = 1, storage_buffer)] rays: &[Vec4],
     #[spirv(descriptor_set = 1, binding = 2, storage_buffer)]
@@ -23,6 +24,7 @@ pub fn main(
     let triangles = TrianglesView::new(triangles);
     let bvh = BvhView::new(bvh);
     let materials = MaterialsView::new(materials);
+    let atlas = AtlasView::new(atlas_srgb_tex, atlas_linear_tex, atlas_sampler);
 
     if !camera.contains(screen_pos) {
         return;
@@ -30,31 +32,54 @@ pub fn main(
 
     // -------------------------------------------------------------------------
 
-    let ray = if params.depth == 0 {
-        camera.ray(screen_pos)
+    let (ray, kind, cone_width) = if params.depth == 0 {
+        (camera.ray(screen_pos), RayKind::Primary, 0.0)
     } else {
-        let d0 = rays[3 * screen_idx];
-        let d1 = rays[3 * screen_idx + 1];
+        let d0 = rays[4 * screen_idx];
+        let d1 = rays[4 * screen_idx + 1];
+        let d3 = rays[4 * screen_idx + 3];
 
         if d1 == Default::default() {
             return;
         }
 
-        Ray::new(d0.xyz(), d1.xyz())
+        (Ray::new(d0.xyz(), d1.xyz()), RayKind::Indirect, d3.x)
     };
 
-    let (hit, _) = ray.trace(
+    let (mut hit, _) = ray.trace(
         local_idx,
         stack,
         triangles,
         bvh,
         materials,
-        atlas_tex,
-        atlas_sampler,
+        atlas,
+        kind,
+        camera.visibility_mask(),
     );
 
+    // x - texture footprint of the hit, y - ray cone's width at the hit
+    let mut hit_d2 = Vec4::ZERO;
+
+    if hit.is_some() {
+        let cone_width =
+            cone_width + camera.pixel_spread_angle() * hit.distance;
+
+        let uv_footprint = hit.uv_footprint(cone_width, ray.dir());
+
+        hit.normal = materials.get(hit.material_id).normal(
+            atlas,
+            hit.uv,
+            uv_footprint,
+            hit.normal,
+            hit.tangent,
+        );
+
+        hit_d2 = vec4(uv_footprint, cone_width, 0.0, 0.0);
+    }
+
     let [hit_d0, hit_d1] = hit.pack();
 
-    hits[2 * screen_idx] = hit_d0;
-    hits[2 * screen_idx + 1] = hit_d1;
+    hits[3 * screen_idx] = hit_d0;
+    hits[3 * screen_idx + 1] = hit_d1;
+    hits[3 * screen_idx + 2] = hit_d2;
 }
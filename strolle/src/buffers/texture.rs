@@ -8,6 +8,10 @@ pub struct Texture {
     tex: wgpu::Texture,
     format: wgpu::TextureFormat,
     view: wgpu::TextureView,
+    extra_views: Vec<(wgpu::TextureFormat, wgpu::TextureView)>,
+    view_dimension: wgpu::TextureViewDimension,
+    mip_levels: u32,
+    subresource_views: Vec<wgpu::TextureView>,
     sampler: wgpu::Sampler,
     filterable: bool,
 }
@@ -41,7 +45,53 @@ impl Texture {
     /// Sampler's binding follows the texture so e.g. if the texture has
     /// `binding = 3`, sampler will be `binding = 4`.
     pub fn bind_sampled(&self) -> impl Bindable + '_ {
-        SampledTextureBinder { parent: self }
+        SampledTextureBinder {
+            parent: self,
+            views: vec![&self.view],
+        }
+    }
+
+    /// Creates image + sampler bindings, just like [`Self::bind_sampled()`],
+    /// but with the image bound once per each of given formats:
+    ///
+    /// ```
+    /// #[spirv(descriptor_set = ..., binding = ...)]
+    /// tex_a: &Image!(2D, type=f32, sampled),
+    ///
+    /// #[spirv(descriptor_set = ..., binding = ...)]
+    /// tex_b: &Image!(2D, type=f32, sampled),
+    ///
+    /// #[spirv(descriptor_set = ..., binding = ...)]
+    /// sampler: &Sampler,
+    /// ```
+    ///
+    /// Each format must be either the texture's format or one of its view
+    /// formats (see: [`TextureBuilder::with_view_format()`]).
+    pub fn bind_sampled_as<const N: usize>(
+        &self,
+        formats: [wgpu::TextureFormat; N],
+    ) -> impl Bindable + '_ {
+        let views = formats
+            .into_iter()
+            .map(|format| {
+                if format == self.format {
+                    &self.view
+                } else {
+                    self.extra_views
+                        .iter()
+                        .find(|(view_format, _)| *view_format == format)
+                        .map(|(_, view)| view)
+                        .unwrap_or_else(|| {
+                            panic!("Texture has no view for {format:?}")
+                        })
+                }
+            })
+            .collect();
+
+        SampledTextureBinder {
+            parent: self,
+            views,
+        }
     }
 
     /// Creates an immutable storage texture binding:
@@ -54,7 +104,7 @@ impl Texture {
     /// TODO naga and/or rust-gpu don't support read-only storage textures yet,
     ///      so currently this is equivalent to a writable binding
     pub fn bind_readable(&self) -> impl Bindable + '_ {
-        StorageTextureBinder { parent: self }
+        self.bind_writable()
     }
 
     /// Creates a mutable storage texture binding:
@@ -64,7 +114,23 @@ impl Texture {
     /// tex: &Image!(2D, format = ..., sampled = false),
     /// ```
     pub fn bind_writable(&self) -> impl Bindable + '_ {
-        StorageTextureBinder { parent: self }
+        self.bind_writable_at(0, 0)
+    }
+
+    /// Creates a mutable storage texture binding, just like
+    /// [`Self::bind_writable()`], but for given array layer and mip level
+    /// (seen as an ordinary 2D texture).
+    pub fn bind_writable_at(
+        &self,
+        layer: u32,
+        mip_level: u32,
+    ) -> impl Bindable + '_ {
+        let view = self
+            .subresource_views
+            .get((layer * self.mip_levels + mip_level) as usize)
+            .unwrap_or(&self.view);
+
+        StorageTextureBinder { parent: self, view }
     }
 }
 
@@ -74,6 +140,9 @@ pub struct TextureBuilder {
     size: Option<UVec2>,
     format: Option<wgpu::TextureFormat>,
     usage: Option<wgpu::TextureUsages>,
+    view_formats: Vec<wgpu::TextureFormat>,
+    mip_levels: Option<u32>,
+    layers: Option<u32>,
     sampler: wgpu::SamplerDescriptor<'static>,
 }
 
@@ -102,9 +171,30 @@ impl TextureBuilder {
         self
     }
 
+    /// Allows for the texture to be additionally viewed as given format, e.g.
+    /// as `Rgba8UnormSrgb` for an `Rgba8Unorm` texture.
+    pub fn with_view_format(mut self, format: wgpu::TextureFormat) -> Self {
+        self.view_formats.push(format);
+        self
+    }
+
+    /// Allocates given number of mip levels, including the top one (by
+    /// default textures have just the top level).
+    pub fn with_mip_levels(mut self, mip_levels: u32) -> Self {
+        self.mip_levels = Some(mip_levels);
+        self
+    }
+
+    /// Turns this texture into a texture array with given number of layers.
+    pub fn with_layers(mut self, layers: u32) -> Self {
+        self.layers = Some(layers);
+        self
+    }
+
     pub fn with_linear_filtering_sampler(mut self) -> Self {
         self.sampler.mag_filter = wgpu::FilterMode::Linear;
         self.sampler.min_filter = wgpu::FilterMode::Linear;
+        self.sampler.mipmap_filter = wgpu::FilterMode::Linear;
         self
     }
 
@@ -114,6 +204,9 @@ impl TextureBuilder {
             size,
             format,
             usage,
+            view_formats,
+            mip_levels,
+            layers,
             sampler,
         } = self;
 
@@ -121,6 +214,15 @@ impl TextureBuilder {
         let size = size.expect("Missing property: size");
         let format = format.expect("Missing property: format");
         let usage = usage.expect("Missing property: usage");
+        let mip_levels = mip_levels.unwrap_or(1);
+
+        let view_dimension = if layers.is_some() {
+            wgpu::TextureViewDimension::D2Array
+        } else {
+            wgpu::TextureViewDimension::D2
+        };
+
+        let layers = layers.unwrap_or(1);
 
         debug!(
             "Allocating texture `{label}`; size={size:?}, format={format:?}"
@@ -134,20 +236,62 @@ impl TextureBuilder {
             size: wgpu::Extent3d {
                 width: size.x,
                 height: size.y,
-                depth_or_array_layers: 1,
+                depth_or_array_layers: layers,
             },
-            mip_level_count: 1,
+            mip_level_count: mip_levels,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format,
             usage,
-            view_formats: &[],
+            view_formats: &view_formats,
         });
 
         let filterable = sampler.mag_filter != wgpu::FilterMode::Nearest
             || sampler.min_filter != wgpu::FilterMode::Nearest;
 
-        let view = tex.create_view(&Default::default());
+        let view = tex.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(view_dimension),
+            ..Default::default()
+        });
+
+        let extra_views = view_formats
+            .into_iter()
+            .map(|format| {
+                let view = tex.create_view(&wgpu::TextureViewDescriptor {
+                    format: Some(format),
+                    dimension: Some(view_dimension),
+                    ..Default::default()
+                });
+
+                (format, view)
+            })
+            .collect();
+
+        // Storage bindings can see just one mip level at a time, so for
+        // textures with many levels (or layers) we prepare a separate view for
+        // each one
+        let subresource_views = if mip_levels > 1
+            || view_dimension == wgpu::TextureViewDimension::D2Array
+        {
+            (0..layers)
+                .flat_map(|layer| {
+                    (0..mip_levels).map(move |level| (layer, level))
+                })
+                .map(|(layer, level)| {
+                    tex.create_view(&wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_mip_level: level,
+                        mip_level_count: Some(1),
+                        base_array_layer: layer,
+                        array_layer_count: Some(1),
+                        ..Default::default()
+                    })
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
         let sampler_label = format!("{label}_sampler");
 
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
@@ -159,6 +303,10 @@ impl TextureBuilder {
             tex,
             format,
             view,
+            extra_views,
+            view_dimension,
+            mip_levels,
+            subresource_views,
             sampler,
             filterable,
         }
@@ -167,6 +315,7 @@ impl TextureBuilder {
 
 pub struct SampledTextureBinder<'a> {
     parent: &'a Texture,
+    views: Vec<&'a wgpu::TextureView>,
 }
 
 impl Bindable for SampledTextureBinder<'_> {
@@ -174,21 +323,32 @@ impl Bindable for SampledTextureBinder<'_> {
         &self,
         binding: u32,
     ) -> Vec<(wgpu::BindGroupLayoutEntry, wgpu::BindingResource)> {
-        let image_layout = wgpu::BindGroupLayoutEntry {
-            binding,
-            visibility: wgpu::ShaderStages::all(),
-            ty: wgpu::BindingType::Texture {
-                multisampled: false,
-                view_dimension: wgpu::TextureViewDimension::D2,
-                sample_type: wgpu::TextureSampleType::Float {
-                    filterable: self.parent.filterable,
-                },
-            },
-            count: None,
-        };
+        let mut entries: Vec<_> = self
+            .views
+            .iter()
+            .zip(binding..)
+            .map(|(view, binding)| {
+                let image_layout = wgpu::BindGroupLayoutEntry {
+                    binding,
+                    visibility: wgpu::ShaderStages::all(),
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: self.parent.view_dimension,
+                        sample_type: wgpu::TextureSampleType::Float {
+                            filterable: self.parent.filterable,
+                        },
+                    },
+                    count: None,
+                };
+
+                let image_resource = wgpu::BindingResource::TextureView(view);
+
+                (image_layout, image_resource)
+            })
+            .collect();
 
         let sampler_layout = wgpu::BindGroupLayoutEntry {
-            binding: binding + 1,
+            binding: binding + self.views.len() as u32,
             visibility: wgpu::ShaderStages::all(),
             ty: wgpu::BindingType::Sampler(if self.parent.filterable {
                 wgpu::SamplerBindingType::Filtering
@@ -198,21 +358,17 @@ impl Bindable for SampledTextureBinder<'_> {
             count: None,
         };
 
-        let image_resource =
-            wgpu::BindingResource::TextureView(&self.parent.view);
-
         let sampler_resource =
             wgpu::BindingResource::Sampler(&self.parent.sampler);
 
-        vec![
-            (image_layout, image_resource),
-            (sampler_layout, sampler_resource),
-        ]
+        entries.push((sampler_layout, sampler_resource));
+        entries
     }
 }
 
 pub struct StorageTextureBinder<'a> {
     parent: &'a Texture,
+    view: &'a wgpu::TextureView,
 }
 
 impl Bindable for StorageTextureBinder<'_> {
@@ -231,8 +387,7 @@ impl Bindable for StorageTextureBinder<'_> {
             count: None,
         };
 
-        let image_resource =
-            wgpu::BindingResource::TextureView(&self.parent.view);
+        let image_resource = wgpu::BindingResource::TextureView(self.view);
 
         vec![(image_layout, image_resource)]
     }
@@ -1,72 +1,540 @@
+mod blas;
 mod builder;
+mod cache;
+mod instance;
+mod layout;
 mod node;
 mod nodes;
 mod primitive;
 mod primitives;
+mod raycaster;
+mod refitter;
 mod serializer;
+mod spatial_builder;
+mod stats;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::ops::Range;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::{io, mem};
 
+use fxhash::FxHasher64;
 use spirv_std::glam::Vec4;
 
+pub use self::blas::*;
+use self::cache::BvhCache;
+pub use self::instance::*;
+pub use self::layout::*;
 pub use self::node::*;
 pub use self::nodes::*;
 pub use self::primitive::*;
 pub use self::primitives::*;
 use crate::{
-    utils, Bindable, BufferFlushOutcome, MappedStorageBuffer, Materials, Params,
+    gpu, utils, AlphaMode, Bindable, BufferFlushOutcome, EngineConfig,
+    MappedStorageBuffer, Materials, Params, Triangle,
 };
 
+/// How much the top-level BVH's SAH cost is allowed to degrade (compared to
+/// the cost right after the last full rebuild) before we stop refitting it and
+/// rebuild it from scratch.
+const MAX_REFIT_DEGRADATION: f32 = 1.5;
+
+/// Two-level bounding volume hierarchy.
+///
+/// Each mesh gets its own bottom-level BVH (built over the mesh's triangles,
+/// in mesh-space) and the top-level BVH is built over instances; this way
+/// moving an instance doesn't require rebuilding the tree of its triangles.
 #[derive(Debug)]
-pub struct Bvh {
+pub struct Bvh<P>
+where
+    P: Params,
+{
+    config: EngineConfig,
     buffer: MappedStorageBuffer<Vec<Vec4>>,
     nodes: BvhNodes,
     primitives: BvhPrimitives,
+    instances: Vec<Option<BvhInstance<P>>>,
+    free_instances: Vec<usize>,
+    index: HashMap<P::InstanceHandle, usize>,
+    blases: HashMap<P::MeshHandle, Arc<Blas>>,
+    has_dirty_topology: bool,
+    built_cost: f32,
+    stack_budget: u32,
+    cache: BvhCache,
+
+    /// Handles of instances present in the currently serialized tree, indexed
+    /// by instance ids; used by raycasts.
+    handles: Vec<Option<P::InstanceHandle>>,
+
+    pending_build: Option<PendingBuild<P>>,
+    has_pending_changes: bool,
+    generation: u64,
+    latest_generation: u64,
 }
 
-impl Bvh {
-    pub fn new(device: &wgpu::Device) -> Self {
+impl<P> Bvh<P>
+where
+    P: Params,
+{
+    pub fn new(device: &wgpu::Device, config: &EngineConfig) -> Self {
         Self {
+            config: config.clone(),
             buffer: MappedStorageBuffer::new_default(device, "bvh"),
             nodes: Default::default(),
             primitives: Default::default(),
+            instances: Default::default(),
+            free_instances: Default::default(),
+            index: Default::default(),
+            blases: Default::default(),
+            has_dirty_topology: true,
+            built_cost: Default::default(),
+            stack_budget: Default::default(),
+            cache: Default::default(),
+            handles: Default::default(),
+            pending_build: Default::default(),
+            has_pending_changes: Default::default(),
+            generation: Default::default(),
+            latest_generation: Default::default(),
         }
     }
 
-    pub fn add(&mut self, prim: BvhPrimitive) {
-        self.primitives.add(prim);
+    /// Builds (or restores from cache) bottom-level BVH of given mesh, whose
+    /// triangles start at `first_triangle_id` in the triangle buffer.
+    pub fn insert_mesh(
+        &mut self,
+        mesh_handle: P::MeshHandle,
+        first_triangle_id: u32,
+        triangles: impl IntoIterator<Item = Triangle>,
+    ) {
+        let triangles: Vec<_> = triangles.into_iter().collect();
+        let hash = Blas::content_hash(&self.config, &triangles);
+
+        let cached_blas =
+            self.cache.blases.remove(&hash).filter(|blas| {
+                blas.triangle_count() as usize == triangles.len()
+            });
+
+        let mut blas = if let Some(blas) = cached_blas {
+            blas
+        } else {
+            utils::measure("tick.bvh.blas", || {
+                Blas::new(&self.config, hash, triangles)
+            })
+        };
+
+        blas.serialize(&self.config, first_triangle_id);
+
+        self.blases.insert(mesh_handle, Arc::new(blas));
+    }
+
+    pub fn has_mesh(&self, mesh_handle: P::MeshHandle) -> bool {
+        self.blases.contains_key(&mesh_handle)
     }
 
-    pub fn update(
-        &mut self,
-        ids: Range<usize>,
-    ) -> impl Iterator<Item = &mut BvhPrimitive> {
-        self.primitives.update(ids)
+    pub fn remove_mesh(&mut self, mesh_handle: P::MeshHandle) {
+        self.blases.remove(&mesh_handle);
     }
 
-    pub fn refresh<P>(&mut self, materials: &Materials<P>)
-    where
-        P: Params,
-    {
-        utils::measure("tick.bvh.begin", || {
-            self.primitives.begin_refresh();
-        });
+    pub fn insert_instance(&mut self, instance: BvhInstance<P>) {
+        let instance_handle = instance.handle;
 
-        utils::measure("tick.bvh.build", || {
-            builder::run(&mut self.nodes, &mut self.primitives);
-        });
+        let bounds = self.blases[&instance.mesh_handle]
+            .bounds()
+            .transform(instance.transform);
+
+        let prim = BvhPrimitive {
+            id: Default::default(),
+            center: bounds.center(),
+            bounds,
+        };
+
+        let instance_id =
+            if let Some(&instance_id) = self.index.get(&instance_handle) {
+                instance_id
+            } else {
+                self.has_dirty_topology = true;
+
+                if let Some(instance_id) = self.free_instances.pop() {
+                    instance_id
+                } else {
+                    self.instances.push(None);
+                    self.primitives.add(prim)
+                }
+            };
+
+        *self.primitives.get_mut(instance_id) = BvhPrimitive {
+            id: instance_id as u32,
+            ..prim
+        };
+
+        self.instances[instance_id] = Some(instance);
+        self.index.insert(instance_handle, instance_id);
+    }
+
+    pub fn remove_instance(&mut self, instance_handle: P::InstanceHandle) {
+        let Some(instance_id) = self.index.remove(&instance_handle) else {
+            return;
+        };
+
+        self.primitives.get_mut(instance_id).kill();
+        self.instances[instance_id] = None;
+        self.free_instances.push(instance_id);
+        self.has_dirty_topology = true;
+    }
+
+    /// Rebuilds (or refits) the top-level BVH, so that it reflects the current
+    /// instances.
+    ///
+    /// In the asynchronous mode (see: [`EngineConfig::bvh_async_rebuild`]) the
+    /// tree might get rebuilt on a worker thread instead, in which case it
+    /// becomes visible later, during one of the next [`Self::poll()`]s.
+    pub fn refresh(&mut self, materials: &Materials<P>) {
+        if !self.has_pending_changes {
+            self.latest_generation += 1;
+        }
+
+        if self.pending_build.is_some() {
+            // There's already a tree being built and since it has been built
+            // from a snapshot of instances, it won't contain these changes -
+            // so let's postpone them until that tree is ready
+            self.has_pending_changes = true;
+            return;
+        }
+
+        self.rebuild(materials);
+    }
+
+    /// Swaps in the top-level BVH built on a worker thread, if it's ready.
+    pub fn poll(&mut self, materials: &Materials<P>) {
+        let is_ready = self
+            .pending_build
+            .as_ref()
+            .is_some_and(|build| build.thread.is_finished());
+
+        if !is_ready {
+            return;
+        }
+
+        let build = self.pending_build.take().unwrap();
+        let tlas = build.thread.join().expect("BVH builder has crashed");
+
+        self.nodes = tlas.nodes;
+        self.primitives.attach(tlas.primitives);
+        self.built_cost = tlas.cost;
+
+        debug_assert!(
+            stats::validate(&self.nodes, &self.primitives).depth
+                <= self.stack_budget
+        );
+
+        self.primitives.end_refresh();
+        *self.buffer = tlas.buffer;
+        self.handles = build.handles;
+        self.generation = build.generation;
+
+        if mem::take(&mut self.has_pending_changes) {
+            self.rebuild(materials);
+        }
+    }
+
+    fn rebuild(&mut self, materials: &Materials<P>) {
+        let generation = self.latest_generation;
+
+        // Top-level BVH and bottom-level BVHs share the same stack during the
+        // traversal, so the deeper the bottom-level BVHs, the shallower the
+        // top-level one must be
+        let stack_budget = (gpu::BVH_STACK_SIZE as u32).saturating_sub(
+            self.blases
+                .values()
+                .map(|blas| blas.stack_usage())
+                .max()
+                .unwrap_or_default(),
+        );
+
+        if stack_budget != self.stack_budget {
+            // Nodes built for a different budget can't be refitted nor reused
+            self.stack_budget = stack_budget;
+            self.nodes = Default::default();
+            self.has_dirty_topology = true;
+        }
+
+        let is_refitted = !mem::take(&mut self.has_dirty_topology)
+            && utils::measure("tick.bvh.refit", || self.refit());
+
+        if !is_refitted {
+            utils::measure("tick.bvh.begin", || {
+                self.primitives.begin_refresh();
+            });
+
+            let is_restored = self.restore_tlas();
+
+            if !is_restored && self.config.bvh_async_rebuild {
+                self.spawn_build(materials, generation);
+                return;
+            }
+
+            if !is_restored {
+                utils::measure("tick.bvh.build", || {
+                    builder::run(
+                        &mut self.nodes,
+                        &mut self.primitives,
+                        self.stack_budget,
+                    );
+                });
+            }
+
+            self.built_cost = refitter::cost(&self.nodes);
+
+            debug_assert!(
+                stats::validate(&self.nodes, &self.primitives).depth
+                    <= self.stack_budget
+            );
+        }
+
+        let (instances, handles) = self.snapshot(materials);
 
         utils::measure("tick.bvh.serialize", || {
-            serializer::run(
-                materials,
+            serializer::run_tlas(
+                self.config.bvh_layout,
                 &self.nodes,
                 &self.primitives,
+                &instances,
                 &mut self.buffer,
+                self.stack_budget,
             );
         });
 
         self.primitives.end_refresh();
+        self.handles = handles;
+        self.generation = generation;
+    }
+
+    /// Builds and serializes the top-level BVH on a worker thread.
+    fn spawn_build(&mut self, materials: &Materials<P>, generation: u64) {
+        let mut nodes = mem::take(&mut self.nodes);
+        let mut primitives = self.primitives.detach();
+        let (instances, handles) = self.snapshot(materials);
+        let layout = self.config.bvh_layout;
+        let stack_budget = self.stack_budget;
+
+        let thread = thread::spawn(move || {
+            builder::run(&mut nodes, &mut primitives, stack_budget);
+
+            let cost = refitter::cost(&nodes);
+            let mut buffer = Vec::new();
+
+            serializer::run_tlas(
+                layout,
+                &nodes,
+                &primitives,
+                &instances,
+                &mut buffer,
+                stack_budget,
+            );
+
+            BuiltTlas {
+                nodes,
+                primitives,
+                buffer,
+                cost,
+            }
+        });
+
+        self.pending_build = Some(PendingBuild {
+            generation,
+            handles,
+            thread,
+        });
+    }
+
+    /// Returns instances as seen by the serializer, together with their
+    /// handles.
+    #[allow(clippy::type_complexity)]
+    fn snapshot(
+        &self,
+        materials: &Materials<P>,
+    ) -> (Vec<Option<TlasInstance>>, Vec<Option<P::InstanceHandle>>) {
+        self.instances
+            .iter()
+            .map(|instance| {
+                let Some(instance) = instance else {
+                    return (None, None);
+                };
+
+                let has_alpha_blending = matches!(
+                    materials[instance.material_id].alpha_mode,
+                    AlphaMode::Blend
+                );
+
+                let tlas_instance = TlasInstance {
+                    blas: self.blases[&instance.mesh_handle].clone(),
+                    material_id: instance.material_id,
+                    has_alpha_blending,
+                    transform_inverse: instance.transform_inverse,
+                    visibility: instance.visibility,
+                    casts_shadows: instance.casts_shadows,
+                    visible_to_camera: instance.visible_to_camera,
+                    visible_in_indirect: instance.visible_in_indirect,
+                };
+
+                (Some(tlas_instance), Some(instance.handle))
+            })
+            .unzip()
+    }
+
+    /// Returns generation of the currently serialized tree.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns generation of the tree that will contain all changes made so
+    /// far; once [`Self::generation()`] catches up to it, all those changes
+    /// are visible.
+    pub fn latest_generation(&self) -> u64 {
+        self.latest_generation
+    }
+
+    /// Updates bounds of the top-level BVH without changing its topology, which
+    /// is considerably faster than rebuilding the tree when instances only
+    /// move around.
+    ///
+    /// Returns `false` if the refitted tree got too slow to traverse, in which
+    /// case it should be rebuilt.
+    fn refit(&mut self) -> bool {
+        if self.nodes.nodes.is_empty() {
+            return false;
+        }
+
+        self.primitives.begin_refit();
+
+        let cost = refitter::run(&mut self.nodes, &self.primitives);
+
+        if cost <= self.built_cost * MAX_REFIT_DEGRADATION {
+            true
+        } else {
+            // Nodes have been already refitted, so to keep builder's node
+            // reuse working, primitives have to follow
+            self.primitives.end_refresh();
+
+            false
+        }
+    }
+
+    /// Returns content hash of the instances, identifying the top-level BVH
+    /// built over them.
+    fn tlas_hash(&self) -> u64 {
+        let mut hasher = FxHasher64::default();
+
+        (self.config.bvh_layout as u8).hash(&mut hasher);
+        self.stack_budget.hash(&mut hasher);
+
+        for (instance_id, instance) in self.instances.iter().enumerate() {
+            let Some(instance) = instance else {
+                continue;
+            };
+
+            (instance_id as u32).hash(&mut hasher);
+            self.blases[&instance.mesh_handle].hash().hash(&mut hasher);
+
+            for value in instance.transform.to_cols_array() {
+                value.to_bits().hash(&mut hasher);
+            }
+        }
+
+        hasher.finish()
+    }
+
+    /// Restores the top-level BVH from cache, provided it's been built for the
+    /// current instances.
+    fn restore_tlas(&mut self) -> bool {
+        let Some(tlas) = &self.cache.tlas else {
+            return false;
+        };
+
+        if tlas.hash != self.tlas_hash() || tlas.depth > self.stack_budget {
+            return false;
+        }
+
+        // Cache might've been damaged without affecting its hash, so let's
+        // make sure the tree refers to exactly the current instances
+        let mut is_seen = vec![false; self.instances.len()];
+
+        let is_valid = tlas.primitives.len() == self.index.len()
+            && tlas.primitives.iter().all(|prim| {
+                let id = prim.id as usize;
+
+                self.instances.get(id).is_some_and(Option::is_some)
+                    && !mem::replace(&mut is_seen[id], true)
+            });
+
+        if !is_valid {
+            return false;
+        }
+
+        let tlas = self.cache.tlas.take().unwrap();
+
+        self.nodes = tlas.nodes;
+        self.primitives.set_current(tlas.primitives);
+
+        true
+    }
+
+    /// Writes the built trees into given writer, so that they can be restored
+    /// on the next launch (see: [`Self::read()`]).
+    ///
+    /// This should be called right after [`Self::refresh()`], since otherwise
+    /// the top-level BVH might not correspond to the current instances (in
+    /// which case it simply won't get restored).
+    pub fn write(&self, w: impl io::Write) -> io::Result<()> {
+        let mut blases: Vec<_> =
+            self.blases.values().map(|blas| &**blas).collect();
+
+        // Sort trees so that the same scene always produces the same cache
+        blases.sort_by_key(|blas| blas.hash());
+
+        let tlas = (!self.nodes.nodes.is_empty()).then(|| {
+            (self.tlas_hash(), &self.nodes, self.primitives.previous())
+        });
+
+        cache::write(w, &blases, tlas)
+    }
+
+    /// Reads trees written by [`Self::write()`].
+    ///
+    /// Trees are not used immediately - rather, meshes and instances inserted
+    /// later whose contents match the cache skip building their trees; for this
+    /// reason this should be called before inserting anything into the BVH.
+    pub fn read(&mut self, r: impl io::Read) -> io::Result<()> {
+        self.cache = cache::read(r)?;
+
+        Ok(())
+    }
+
+    /// Returns the closest intersection (or, if `any_hit` is set, whichever
+    /// intersection is found first) of given ray with the world, as of the
+    /// most recent [`Self::refresh()`].
+    pub fn raycast(
+        &self,
+        triangles: &[gpu::Triangle],
+        ray: gpu::Ray,
+        kind: gpu::RayKind,
+        visibility_mask: u32,
+        any_hit: bool,
+    ) -> Option<(P::InstanceHandle, gpu::TriangleHit)> {
+        let (instance_id, hit) = raycaster::run(
+            &self.buffer,
+            triangles,
+            ray,
+            kind,
+            visibility_mask,
+            any_hit,
+        )?;
+
+        let instance_handle = self.handles[instance_id as usize]?;
+
+        Some((instance_handle, hit))
     }
 
     pub fn flush(
@@ -79,9 +547,29 @@ impl Bvh {
 
     pub fn len(&self) -> usize {
         self.nodes.nodes.len()
+            + self.blases.values().map(|blas| blas.len()).sum::<usize>()
     }
 
     pub fn bind_readable(&self) -> impl Bindable + '_ {
         self.buffer.bind_readable()
     }
 }
+
+/// Top-level BVH being built on a worker thread.
+#[derive(Debug)]
+struct PendingBuild<P>
+where
+    P: Params,
+{
+    generation: u64,
+    handles: Vec<Option<P::InstanceHandle>>,
+    thread: JoinHandle<BuiltTlas>,
+}
+
+#[derive(Debug)]
+struct BuiltTlas {
+    nodes: BvhNodes,
+    primitives: BvhPrimitives,
+    buffer: Vec<Vec4>,
+    cost: f32,
+}
@@ -0,0 +1,355 @@
+use std::hash::{Hash, Hasher};
+use std::io;
+
+use fxhash::FxHasher64;
+use glam::Vec4;
+
+use super::cache::{self, Decoder, Encoder};
+use super::{
+    builder, serializer, spatial_builder, stats, BvhNodes, BvhPrimitive,
+    BvhPrimitives,
+};
+use crate::{gpu, BoundingBox, EngineConfig, Triangle};
+
+/// Maximum number of stack entries a bottom-level BVH can use during the
+/// traversal; whatever's left of [`gpu::BVH_STACK_SIZE`] is available to the
+/// top-level BVH (see: [`Blas::stack_usage()`]).
+///
+/// Bottom-level BVHs get at most half of the stack, so that the top-level BVH
+/// can always be at least as deep - that's enough for tens of thousands of
+/// instances to end up in leaves of their own.
+pub const BLAS_STACK_BUDGET: u32 = gpu::BVH_STACK_SIZE as u32 / 2;
+
+/// Bottom-level BVH, i.e. a tree built over triangles of a single mesh.
+///
+/// Triangles are kept in mesh-space, so that a single tree can be shared by
+/// all of the instances that refer to the same mesh.
+///
+/// The tree itself refers to triangles by their indices within the mesh, so
+/// that it doesn't depend on where the mesh's triangles happen to land in the
+/// triangle buffer - that offset gets applied only when the tree is serialized
+/// (see: [`Self::serialize()`]).
+#[derive(Debug)]
+pub struct Blas {
+    hash: u64,
+    nodes: BvhNodes,
+    primitives: BvhPrimitives,
+    triangle_count: u32,
+    bounds: BoundingBox,
+    buffer: Vec<Vec4>,
+    stack_usage: u32,
+}
+
+impl Blas {
+    /// Returns content hash of given triangles, identifying the tree built
+    /// over them (see: [`Self::new()`]).
+    ///
+    /// The hash is stable across launches and platforms, so that it can be
+    /// used to look up trees persisted in cache.
+    pub fn content_hash(config: &EngineConfig, triangles: &[Triangle]) -> u64 {
+        let mut hasher = FxHasher64::default();
+
+        (config.bvh_layout as u8).hash(&mut hasher);
+        config.bvh_spatial_splits.hash(&mut hasher);
+        BLAS_STACK_BUDGET.hash(&mut hasher);
+        (triangles.len() as u32).hash(&mut hasher);
+
+        for triangle in triangles {
+            for position in triangle.positions {
+                position.x.to_bits().hash(&mut hasher);
+                position.y.to_bits().hash(&mut hasher);
+                position.z.to_bits().hash(&mut hasher);
+            }
+        }
+
+        hasher.finish()
+    }
+
+    /// Builds tree over given triangles; before the tree can be used, it has
+    /// to be serialized.
+    pub fn new(
+        config: &EngineConfig,
+        hash: u64,
+        triangles: impl IntoIterator<Item = Triangle>,
+    ) -> Self {
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+        let mut triangle_count = 0;
+
+        let triangles = triangles.into_iter().map(|triangle| {
+            let prim = BvhPrimitive {
+                id: triangle_count,
+                center: triangle.center(),
+                bounds: triangle.bounds(),
+            };
+
+            triangle_count += 1;
+
+            (prim, triangle.positions)
+        });
+
+        if config.bvh_spatial_splits {
+            spatial_builder::run(
+                &mut nodes,
+                &mut primitives,
+                triangles,
+                BLAS_STACK_BUDGET,
+            );
+        } else {
+            for (prim, _) in triangles {
+                primitives.add(prim);
+            }
+
+            primitives.begin_refresh();
+            builder::run(&mut nodes, &mut primitives, BLAS_STACK_BUDGET);
+        }
+
+        debug_assert!(
+            stats::validate(&nodes, &primitives).depth <= BLAS_STACK_BUDGET
+        );
+
+        Self::from_parts(hash, nodes, primitives, triangle_count)
+    }
+
+    fn from_parts(
+        hash: u64,
+        nodes: BvhNodes,
+        primitives: BvhPrimitives,
+        triangle_count: u32,
+    ) -> Self {
+        let bounds = primitives
+            .current(primitives.current_ref())
+            .iter()
+            .map(|prim| prim.bounds)
+            .collect();
+
+        Self {
+            hash,
+            nodes,
+            primitives,
+            triangle_count,
+            bounds,
+            buffer: Default::default(),
+            stack_usage: Default::default(),
+        }
+    }
+
+    /// Serializes the tree for the GPU, for a mesh whose triangles start at
+    /// given id in the triangle buffer.
+    pub fn serialize(&mut self, config: &EngineConfig, first_triangle_id: u32) {
+        self.stack_usage = serializer::run_blas(
+            config.bvh_layout,
+            &self.nodes,
+            &self.primitives,
+            first_triangle_id,
+            &mut self.buffer,
+            BLAS_STACK_BUDGET,
+        );
+    }
+
+    pub fn read(dec: &mut Decoder<impl io::Read>) -> io::Result<Self> {
+        let hash = dec.u64()?;
+        let nodes = dec.nodes()?;
+        let primitives = dec.primitives()?;
+        let triangle_count = dec.u32()?;
+
+        if cache::validate(&nodes, primitives.len())? > BLAS_STACK_BUDGET {
+            return Err(cache::invalid_data("tree too deep"));
+        }
+
+        if primitives.iter().any(|prim| prim.id >= triangle_count) {
+            return Err(cache::invalid_data("triangle id out of bounds"));
+        }
+
+        let primitives = {
+            let mut this = BvhPrimitives::default();
+
+            this.set_current(primitives);
+            this
+        };
+
+        Ok(Self::from_parts(hash, nodes, primitives, triangle_count))
+    }
+
+    pub fn write(&self, enc: &mut Encoder<impl io::Write>) -> io::Result<()> {
+        enc.u64(self.hash)?;
+        enc.nodes(&self.nodes)?;
+        enc.primitives(self.primitives.current(self.primitives.current_ref()))?;
+        enc.u32(self.triangle_count)
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// Returns the number of triangles this tree has been built over.
+    pub fn triangle_count(&self) -> u32 {
+        self.triangle_count
+    }
+
+    pub fn bounds(&self) -> BoundingBox {
+        self.bounds
+    }
+
+    pub fn buffer(&self) -> &[Vec4] {
+        &self.buffer
+    }
+
+    /// Returns the number of stack entries needed to traverse this tree.
+    pub fn stack_usage(&self) -> u32 {
+        self.stack_usage
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.nodes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+
+    use super::super::{
+        BvhNode, BvhNodeHash, BvhNodeId, BvhPrimitiveId, BvhPrimitivesRef,
+    };
+    use super::*;
+
+    #[test]
+    fn triangle_ids_are_offset_on_serialization() {
+        let config = EngineConfig::default();
+
+        let triangles: Vec<_> = (0..16)
+            .map(|i| {
+                let p = vec3(i as f32, 0.0, 0.0);
+
+                Triangle {
+                    positions: [p, p + Vec3::X, p + Vec3::Y],
+                    normals: Default::default(),
+                    uvs: Default::default(),
+                    tangents: Default::default(),
+                }
+            })
+            .collect();
+
+        let hash = Blas::content_hash(&config, &triangles);
+        let mut target = Blas::new(&config, hash, triangles);
+
+        let triangle_ids = |target: &Blas| {
+            let mut ids: Vec<_> = target
+                .buffer()
+                .iter()
+                .filter(|d| d.w.to_bits() == serializer::OP_LEAF)
+                .map(|d| d.y.to_bits())
+                .collect();
+
+            ids.sort();
+            ids
+        };
+
+        // The same mesh can land anywhere in the triangle buffer (e.g. when
+        // meshes get loaded in a different order), which mustn't affect the
+        // tree itself, only its serialized form
+        target.serialize(&config, 0);
+
+        assert_eq!((0..16).collect::<Vec<_>>(), triangle_ids(&target));
+
+        target.serialize(&config, 1000);
+
+        assert_eq!((1000..1016).collect::<Vec<_>>(), triangle_ids(&target));
+    }
+
+    #[test]
+    fn rejects_damaged_cache() {
+        let leaf = |start, end| BvhNode::Leaf {
+            bounds: Default::default(),
+            primitives_ref: BvhPrimitivesRef::new(
+                BvhPrimitiveId::new(start),
+                BvhPrimitiveId::new(end),
+            ),
+        };
+
+        let internal = |left_id, right_id, end| BvhNode::Internal {
+            bounds: Default::default(),
+            primitives_ref: BvhPrimitivesRef::new(
+                BvhPrimitiveId::new(0),
+                BvhPrimitiveId::new(end),
+            ),
+            left_id: BvhNodeId::new(left_id),
+            left_hash: BvhNodeHash::new(0),
+            right_id: BvhNodeId::new(right_id),
+            right_hash: BvhNodeHash::new(0),
+        };
+
+        let read = |nodes: Vec<BvhNode>, triangle_ids: &[u32]| {
+            let mut primitives = BvhPrimitives::default();
+
+            primitives.set_current(
+                triangle_ids
+                    .iter()
+                    .map(|&id| BvhPrimitive {
+                        id,
+                        center: Default::default(),
+                        bounds: Default::default(),
+                    })
+                    .collect(),
+            );
+
+            let nodes = BvhNodes {
+                nodes,
+                free_nodes: Default::default(),
+            };
+
+            let blas = Blas::from_parts(0, nodes, primitives, 2);
+            let mut data = Vec::new();
+
+            cache::write(&mut data, &[&blas], None).unwrap();
+            cache::read(data.as_slice()).map(|_| ())
+        };
+
+        let tree = || vec![internal(1, 2, 2), leaf(0, 1), leaf(1, 2)];
+
+        assert!(read(tree(), &[0, 1]).is_ok());
+
+        // Triangle out of bounds
+        let err = read(tree(), &[0, 2]).unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Cycle
+        let err = read(vec![internal(1, 0, 2), leaf(0, 0)], &[0, 1]);
+
+        assert_eq!(io::ErrorKind::InvalidData, err.unwrap_err().kind());
+
+        // Tree deeper than the stack budget, i.e. one whose serialized form
+        // could overflow the stack on the GPU
+        let chain = |depth: u32| {
+            let mut nodes = Vec::new();
+
+            for level in 0..depth {
+                nodes.push(BvhNode::Internal {
+                    bounds: Default::default(),
+                    primitives_ref: BvhPrimitivesRef::new(
+                        BvhPrimitiveId::new(level),
+                        BvhPrimitiveId::new(depth + 1),
+                    ),
+                    left_id: BvhNodeId::new(2 * level + 1),
+                    left_hash: BvhNodeHash::new(0),
+                    right_id: BvhNodeId::new(2 * level + 2),
+                    right_hash: BvhNodeHash::new(0),
+                });
+
+                nodes.push(leaf(level, level + 1));
+            }
+
+            nodes.push(leaf(depth, depth + 1));
+
+            read(nodes, &vec![0; depth as usize + 1])
+        };
+
+        assert!(chain(BLAS_STACK_BUDGET).is_ok());
+
+        let err = chain(BLAS_STACK_BUDGET + 1).unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -1,20 +1,54 @@
 use core::f32;
 use std::collections::VecDeque;
-use std::hash::{Hash, Hasher};
-use std::thread;
+use std::sync::Mutex;
+use std::{mem, thread};
 
-use fxhash::FxHasher;
 use glam::UVec3;
 
 use super::{
-    BvhNode, BvhNodeHash, BvhNodeId, BvhNodes, BvhPrimitiveId, BvhPrimitives,
-    BvhPrimitivesRef,
+    BvhNode, BvhNodeHash, BvhNodeId, BvhNodes, BvhPrimitive, BvhPrimitiveId,
+    BvhPrimitives, BvhPrimitivesRef,
 };
 use crate::{Axis, BoundingBox};
 
 const BINS: usize = 12;
 
-pub fn run(nodes: &mut BvhNodes, primitives: &mut BvhPrimitives) {
+/// Nodes with at least this many primitives get binned by many threads at
+/// once.
+const MIN_PRIMITIVES_FOR_PARALLEL_BINNING: usize = 16 * 1024;
+
+/// Nodes with at least this many primitives can be built as separate subtrees,
+/// each on its own thread.
+const MIN_PRIMITIVES_FOR_PARALLEL_SUBTREE: usize = 1024;
+
+/// Builds the tree, making sure it doesn't grow deeper than `max_depth` (where
+/// depth is the number of internal nodes on the longest path from the root to
+/// a leaf).
+///
+/// Nodes that get close to the limit are split by median instead of SAH (so
+/// that the remaining subtree stays balanced) and nodes that reach the limit
+/// are turned into leaves, no matter how many primitives they contain.
+///
+/// Large trees are built using many threads - the top of the tree is built by
+/// binning primitives in parallel, and then the remaining subtrees are built
+/// independently; the resulting tree is the same as if it was built serially.
+pub fn run(
+    nodes: &mut BvhNodes,
+    primitives: &mut BvhPrimitives,
+    max_depth: u32,
+) {
+    let threads =
+        thread::available_parallelism().map_or(1, |threads| threads.get());
+
+    run_ex(nodes, primitives, max_depth, threads);
+}
+
+fn run_ex(
+    nodes: &mut BvhNodes,
+    primitives: &mut BvhPrimitives,
+    max_depth: u32,
+    threads: usize,
+) {
     thread::scope(|s| {
         s.spawn(|| {
             let root = nodes.set_root(BvhNode::Leaf {
@@ -22,36 +56,283 @@ pub fn run(nodes: &mut BvhNodes, primitives: &mut BvhPrimitives) {
                 primitives_ref: primitives.current_ref(),
             });
 
-            let root = BvhNodeRef::root(root);
-            let mut stack = VecDeque::from_iter([root]);
-
-            while let Some(node) = stack.pop_front() {
-                match balance(nodes, primitives, node) {
-                    (Some(left), Some(right)) => {
-                        stack.push_back(left);
-                        stack.push_back(right);
-                    }
-                    (Some(node), None) | (None, Some(node)) => {
-                        stack.push_back(node);
-                    }
-                    (None, None) => {
-                        //
+            // Subtrees with at most this many primitives get built on separate
+            // threads; the limit is there so that we end up with enough
+            // subtrees to keep all threads busy
+            let max_subtree_primitives = (primitives.current_ref().len()
+                / (4 * threads))
+                .max(MIN_PRIMITIVES_FOR_PARALLEL_SUBTREE);
+
+            let mut subtrees = Vec::new();
+
+            build(
+                nodes,
+                primitives,
+                BvhNodeRef::root(root),
+                max_depth,
+                threads,
+                |nodes, node| {
+                    let primitives_count =
+                        nodes[node.id].primitives_ref().len();
+
+                    // Subtrees with ghosts are not worth the hassle, since
+                    // they are usually cheap to build - we reuse most of
+                    // their nodes anyway
+                    let is_subtree = threads > 1
+                        && node.ghost.is_none()
+                        && primitives_count
+                            >= MIN_PRIMITIVES_FOR_PARALLEL_SUBTREE
+                        && primitives_count <= max_subtree_primitives;
+
+                    if is_subtree {
+                        subtrees.push(node);
+                        None
+                    } else {
+                        Some(node)
                     }
-                }
-            }
+                },
+            );
+
+            build_subtrees(nodes, primitives, subtrees, max_depth, threads);
         });
     });
 }
 
+/// Builds the tree starting from given node.
+///
+/// `defer` gets called for each node before it's processed and it can take the
+/// node away (by returning `None`), so that it can be processed later.
+fn build(
+    nodes: &mut BvhNodes,
+    primitives: &mut BvhPrimitives,
+    root: BvhNodeRef,
+    max_depth: u32,
+    threads: usize,
+    mut defer: impl FnMut(&BvhNodes, BvhNodeRef) -> Option<BvhNodeRef>,
+) {
+    let mut stack = VecDeque::from_iter([root]);
+
+    while let Some(node) = stack.pop_front() {
+        let Some(node) = defer(nodes, node) else {
+            continue;
+        };
+
+        match balance(nodes, primitives, node, max_depth, threads) {
+            (Some(left), Some(right)) => {
+                stack.push_back(left);
+                stack.push_back(right);
+            }
+            (Some(node), None) | (None, Some(node)) => {
+                stack.push_back(node);
+            }
+            (None, None) => {
+                //
+            }
+        }
+    }
+}
+
+/// Builds given subtrees in parallel and then attaches them into the tree.
+fn build_subtrees(
+    nodes: &mut BvhNodes,
+    primitives: &mut BvhPrimitives,
+    subtrees: Vec<BvhNodeRef>,
+    max_depth: u32,
+    threads: usize,
+) {
+    if subtrees.is_empty() {
+        return;
+    }
+
+    let mut subtrees: Vec<_> = subtrees
+        .into_iter()
+        .map(|node| {
+            let primitives_ref = nodes[node.id].primitives_ref();
+
+            (node, primitives_ref)
+        })
+        .collect();
+
+    // Subtrees cover disjoint ranges of primitives, so we can split the
+    // primitives into separate slices, one per subtree
+    subtrees.sort_by_key(|(_, primitives_ref)| primitives_ref.start().get());
+
+    let mut tasks = Vec::new();
+    let mut tail = primitives.current_mut(primitives.current_ref());
+    let mut tail_offset = 0;
+
+    for (node, primitives_ref) in subtrees {
+        let (_, rest) = mem::take(&mut tail)
+            .split_at_mut(primitives_ref.start().get() as usize - tail_offset);
+
+        let (subtree_primitives, rest) =
+            rest.split_at_mut(primitives_ref.len());
+
+        tail = rest;
+        tail_offset = primitives_ref.end().get() as usize;
+
+        let bounds = nodes[node.id].bounds();
+
+        tasks.push((node, bounds, primitives_ref, subtree_primitives));
+    }
+
+    // Start with the largest subtrees, so that threads finish at roughly the
+    // same time
+    tasks.sort_by_key(|(_, _, primitives_ref, _)| primitives_ref.len());
+
+    let tasks = Mutex::new(tasks);
+    let subtrees = Mutex::new(Vec::new());
+
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let Some((node, bounds, primitives_ref, subtree_primitives)) =
+                    tasks.lock().unwrap().pop()
+                else {
+                    break;
+                };
+
+                let subtree_nodes = build_subtree(
+                    node.depth,
+                    bounds,
+                    subtree_primitives,
+                    max_depth,
+                );
+
+                subtrees.lock().unwrap().push((
+                    node.id,
+                    primitives_ref,
+                    subtree_nodes,
+                ));
+            });
+        }
+    });
+
+    let mut subtrees = subtrees.into_inner().unwrap();
+
+    // Attach subtrees in a deterministic order, so that the same input always
+    // produces the same node ids
+    subtrees.sort_by_key(|(_, primitives_ref, _)| primitives_ref.start().get());
+
+    for (id, primitives_ref, subtree_nodes) in subtrees {
+        attach_subtree(nodes, id, primitives_ref, subtree_nodes);
+    }
+}
+
+/// Builds a subtree over given primitives, using its own nodes, so that it
+/// can be built independently from the rest of the tree.
+fn build_subtree(
+    depth: u32,
+    bounds: BoundingBox,
+    primitives: &mut [BvhPrimitive],
+    max_depth: u32,
+) -> BvhNodes {
+    let mut subtree_nodes = BvhNodes::default();
+    let mut subtree_primitives = BvhPrimitives::default();
+
+    subtree_primitives.set_current(primitives.to_vec());
+
+    subtree_nodes.set_root(BvhNode::Leaf {
+        bounds,
+        primitives_ref: subtree_primitives.current_ref(),
+    });
+
+    let root = BvhNodeRef {
+        depth,
+        ..BvhNodeRef::root(None)
+    };
+
+    build(
+        &mut subtree_nodes,
+        &mut subtree_primitives,
+        root,
+        max_depth,
+        1,
+        |_, node| Some(node),
+    );
+
+    primitives.copy_from_slice(
+        subtree_primitives.current(subtree_primitives.current_ref()),
+    );
+
+    subtree_nodes
+}
+
+/// Moves nodes of given subtree into the tree, replacing node `id`.
+fn attach_subtree(
+    nodes: &mut BvhNodes,
+    id: BvhNodeId,
+    primitives_ref: BvhPrimitivesRef,
+    subtree_nodes: BvhNodes,
+) {
+    let ids: Vec<_> = (0..subtree_nodes.nodes.len())
+        .map(|subtree_node_idx| {
+            if subtree_node_idx == 0 {
+                id
+            } else {
+                nodes.add(Default::default())
+            }
+        })
+        .collect();
+
+    let offset = primitives_ref.start().get() as i32;
+
+    for (subtree_node_idx, mut node) in
+        subtree_nodes.nodes.into_iter().enumerate()
+    {
+        match &mut node {
+            BvhNode::Internal {
+                primitives_ref,
+                left_id,
+                right_id,
+                ..
+            } => {
+                primitives_ref.offset(offset);
+                *left_id = ids[left_id.get() as usize];
+                *right_id = ids[right_id.get() as usize];
+            }
+
+            BvhNode::Leaf { primitives_ref, .. } => {
+                primitives_ref.offset(offset);
+            }
+        }
+
+        nodes[ids[subtree_node_idx]] = node;
+    }
+}
+
 #[inline(always)]
 fn balance(
     nodes: &mut BvhNodes,
     primitives: &mut BvhPrimitives,
     node_ref: BvhNodeRef,
+    max_depth: u32,
+    threads: usize,
 ) -> (Option<BvhNodeRef>, Option<BvhNodeRef>) {
-    if let Some(plane) = find_splitting_plane(nodes, primitives, node_ref.id) {
-        if plane.split_cost < nodes[node_ref.id].sah_cost() {
-            return split(nodes, primitives, node_ref, plane);
+    let remaining_depth = max_depth.saturating_sub(node_ref.depth);
+
+    if remaining_depth > 0 {
+        if let Some(plane) =
+            find_splitting_plane(nodes, primitives, node_ref.id, threads)
+        {
+            if plane.split_cost < nodes[node_ref.id].sah_cost() {
+                let primitives_count =
+                    nodes[node_ref.id].primitives_ref().len() as u32;
+
+                // If there's not much depth left, SAH-based splits could
+                // produce a subtree that's too deep - median splits, on the
+                // other hand, keep the subtree balanced
+                let partition =
+                    if remaining_depth <= primitives_count.ilog2() + 1 {
+                        Partition::Median {
+                            split_by: plane.split_by,
+                        }
+                    } else {
+                        Partition::Plane(plane)
+                    };
+
+                return split(nodes, primitives, node_ref, partition);
+            }
         }
     }
 
@@ -71,6 +352,7 @@ fn find_splitting_plane(
     nodes: &BvhNodes,
     primitives: &BvhPrimitives,
     node_id: BvhNodeId,
+    threads: usize,
 ) -> Option<SplittingPlane> {
     let BvhNode::Leaf { primitives_ref, .. } = nodes[node_id] else {
         unreachable!();
@@ -84,30 +366,53 @@ fn find_splitting_plane(
 
     // ---
 
-    let centroid_bb: BoundingBox = primitives
-        .iter()
-        .map(|primitive| primitive.center)
-        .collect();
-
-    let mut bins = [[Bin::default(); BINS]; 3];
-    let scale = (BINS as f32) / centroid_bb.extent();
+    let (centroid_bb, bins) = if threads > 1
+        && primitives.len() >= MIN_PRIMITIVES_FOR_PARALLEL_BINNING
+    {
+        // Bounding boxes and counts are merged exactly, no matter the order,
+        // so binning in parallel yields the same result as binning serially
+        let chunk_size = primitives.len().div_ceil(threads);
+
+        let centroid_bb = thread::scope(|s| {
+            let handles: Vec<_> = primitives
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(|| centroid_bounds(chunk)))
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .fold(BoundingBox::default(), |a, b| a + b)
+        });
 
-    for primitive in primitives {
-        let bin_id = scale * (primitive.center - centroid_bb.min());
-        let bin_id = bin_id.as_uvec3().min(UVec3::splat((BINS as u32) - 1));
-        let bin_idx = bin_id.x as usize;
-        let bin_idy = bin_id.y as usize;
-        let bin_idz = bin_id.z as usize;
+        let bins = thread::scope(|s| {
+            let handles: Vec<_> = primitives
+                .chunks(chunk_size)
+                .map(|chunk| s.spawn(move || bin(chunk, centroid_bb)))
+                .collect();
+
+            let mut bins = [[Bin::default(); BINS]; 3];
+
+            for handle in handles {
+                for (bins, chunk_bins) in
+                    bins.iter_mut().zip(handle.join().unwrap())
+                {
+                    for (bin, chunk_bin) in bins.iter_mut().zip(chunk_bins) {
+                        bin.count += chunk_bin.count;
+                        bin.bounds += chunk_bin.bounds;
+                    }
+                }
+            }
 
-        bins[0][bin_idx].count += 1;
-        bins[0][bin_idx].bounds += primitive.bounds;
+            bins
+        });
 
-        bins[1][bin_idy].count += 1;
-        bins[1][bin_idy].bounds += primitive.bounds;
+        (centroid_bb, bins)
+    } else {
+        let centroid_bb = centroid_bounds(primitives);
 
-        bins[2][bin_idz].count += 1;
-        bins[2][bin_idz].bounds += primitive.bounds;
-    }
+        (centroid_bb, bin(primitives, centroid_bb))
+    };
 
     // ---
 
@@ -180,11 +485,45 @@ fn find_splitting_plane(
     best
 }
 
+fn centroid_bounds(primitives: &[BvhPrimitive]) -> BoundingBox {
+    primitives
+        .iter()
+        .map(|primitive| primitive.center)
+        .collect()
+}
+
+fn bin(
+    primitives: &[BvhPrimitive],
+    centroid_bb: BoundingBox,
+) -> [[Bin; BINS]; 3] {
+    let mut bins = [[Bin::default(); BINS]; 3];
+    let scale = (BINS as f32) / centroid_bb.extent();
+
+    for primitive in primitives {
+        let bin_id = scale * (primitive.center - centroid_bb.min());
+        let bin_id = bin_id.as_uvec3().min(UVec3::splat((BINS as u32) - 1));
+        let bin_idx = bin_id.x as usize;
+        let bin_idy = bin_id.y as usize;
+        let bin_idz = bin_id.z as usize;
+
+        bins[0][bin_idx].count += 1;
+        bins[0][bin_idx].bounds += primitive.bounds;
+
+        bins[1][bin_idy].count += 1;
+        bins[1][bin_idy].bounds += primitive.bounds;
+
+        bins[2][bin_idz].count += 1;
+        bins[2][bin_idz].bounds += primitive.bounds;
+    }
+
+    bins
+}
+
 fn split(
     nodes: &mut BvhNodes,
     primitives: &mut BvhPrimitives,
     node_ref: BvhNodeRef,
-    plane: SplittingPlane,
+    partition: Partition,
 ) -> (Option<BvhNodeRef>, Option<BvhNodeRef>) {
     let BvhNode::Leaf {
         bounds,
@@ -198,38 +537,57 @@ fn split(
 
     let primitives_data = &mut primitives.current_mut(primitives_ref);
 
-    let mut left_prim_idx = 0;
-    let mut right_prim_idx = (primitives_data.len() - 1) as i32;
+    let pivot = match partition {
+        Partition::Plane(plane) => {
+            let mut left_prim_idx = 0;
+            let mut right_prim_idx = (primitives_data.len() - 1) as i32;
 
-    // TODO optimization idea: don't compute hashes when close to leaves
-    let mut left_hash = FxHasher::default();
-    let mut right_hash = FxHasher::default();
+            while left_prim_idx <= right_prim_idx {
+                let primitive = primitives_data[left_prim_idx as usize];
 
-    let mut left_bounds = BoundingBox::default();
-    let mut right_bounds = BoundingBox::default();
+                if primitive.center[plane.split_by] < plane.split_at {
+                    left_prim_idx += 1;
+                } else {
+                    primitives_data
+                        .swap(left_prim_idx as usize, right_prim_idx as usize);
 
-    while left_prim_idx <= right_prim_idx {
-        let primitive = primitives_data[left_prim_idx as usize];
+                    right_prim_idx -= 1;
+                }
+            }
 
-        if primitive.center[plane.split_by] < plane.split_at {
-            left_prim_idx += 1;
-            left_bounds += primitive.bounds;
+            left_prim_idx as usize
+        }
 
-            primitive.hash(&mut left_hash);
-        } else {
-            primitives_data
-                .swap(left_prim_idx as usize, right_prim_idx as usize);
+        Partition::Median { split_by } => {
+            let pivot = primitives_data.len() / 2;
 
-            right_prim_idx -= 1;
-            right_bounds += primitive.bounds;
+            primitives_data.select_nth_unstable_by(pivot, |a, b| {
+                a.center[split_by].total_cmp(&b.center[split_by])
+            });
 
-            primitive.hash(&mut right_hash);
+            pivot
         }
+    };
+
+    // TODO optimization idea: don't compute hashes when close to leaves
+    let mut left_hash = BvhNodeHash::default();
+    let mut right_hash = BvhNodeHash::default();
+
+    let mut left_bounds = BoundingBox::default();
+    let mut right_bounds = BoundingBox::default();
+
+    for primitive in &primitives_data[..pivot] {
+        left_bounds += primitive.bounds;
+        left_hash = left_hash.combine(BvhNodeHash::of(primitive));
     }
 
-    let pivot = BvhPrimitiveId::new(
-        primitives_ref.start().get() + (left_prim_idx as u32),
-    );
+    for primitive in &primitives_data[pivot..] {
+        right_bounds += primitive.bounds;
+        right_hash = right_hash.combine(BvhNodeHash::of(primitive));
+    }
+
+    let pivot =
+        BvhPrimitiveId::new(primitives_ref.start().get() + (pivot as u32));
 
     let left_primitives_ref =
         BvhPrimitivesRef::new(primitives_ref.start(), pivot);
@@ -237,9 +595,6 @@ fn split(
     let right_primitives_ref =
         BvhPrimitivesRef::new(pivot, primitives_ref.end());
 
-    let left_hash = BvhNodeHash::new(left_hash.finish());
-    let right_hash = BvhNodeHash::new(right_hash.finish());
-
     // ---
 
     let mut left_id = None;
@@ -308,11 +663,13 @@ fn split(
     let left = left_continue.then_some(BvhNodeRef {
         id: left_id,
         ghost: left_ghost,
+        depth: node_ref.depth + 1,
     });
 
     let right = right_continue.then_some(BvhNodeRef {
         id: right_id,
         ghost: right_ghost,
+        depth: node_ref.depth + 1,
     });
 
     (left, right)
@@ -360,6 +717,12 @@ struct SplittingPlane {
     split_cost: f32,
 }
 
+#[derive(Clone, Copy, Debug)]
+enum Partition {
+    Plane(SplittingPlane),
+    Median { split_by: Axis },
+}
+
 #[derive(Clone, Copy, Default, Debug)]
 struct Bin {
     bounds: BoundingBox,
@@ -370,6 +733,7 @@ struct Bin {
 struct BvhNodeRef {
     id: BvhNodeId,
     ghost: Option<BvhNode>,
+    depth: u32,
 }
 
 impl BvhNodeRef {
@@ -377,6 +741,118 @@ impl BvhNodeRef {
         Self {
             id: BvhNodeId::root(),
             ghost,
+            depth: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+
+    fn assert_same_tree(
+        (nodes_a, primitives_a): (&BvhNodes, &BvhPrimitives),
+        (nodes_b, primitives_b): (&BvhNodes, &BvhPrimitives),
+        id_a: BvhNodeId,
+        id_b: BvhNodeId,
+    ) {
+        let node_a = nodes_a[id_a];
+        let node_b = nodes_b[id_b];
+
+        assert_eq!(node_a.bounds(), node_b.bounds());
+
+        assert_eq!(
+            node_a.primitives_ref().as_range(),
+            node_b.primitives_ref().as_range()
+        );
+
+        match (node_a, node_b) {
+            (
+                BvhNode::Internal {
+                    left_id: left_a,
+                    left_hash: left_hash_a,
+                    right_id: right_a,
+                    right_hash: right_hash_a,
+                    ..
+                },
+                BvhNode::Internal {
+                    left_id: left_b,
+                    left_hash: left_hash_b,
+                    right_id: right_b,
+                    right_hash: right_hash_b,
+                    ..
+                },
+            ) => {
+                assert_eq!(left_hash_a, left_hash_b);
+                assert_eq!(right_hash_a, right_hash_b);
+
+                let a = (nodes_a, primitives_a);
+                let b = (nodes_b, primitives_b);
+
+                assert_same_tree(a, b, left_a, left_b);
+                assert_same_tree(a, b, right_a, right_b);
+            }
+
+            (BvhNode::Leaf { .. }, BvhNode::Leaf { .. }) => {
+                let ids_a = primitives_a
+                    .current(node_a.primitives_ref())
+                    .iter()
+                    .map(|primitive| primitive.id);
+
+                let ids_b = primitives_b
+                    .current(node_b.primitives_ref())
+                    .iter()
+                    .map(|primitive| primitive.id);
+
+                assert!(ids_a.eq(ids_b));
+            }
+
+            _ => {
+                panic!("node {id_a:?} and node {id_b:?} have different kinds");
+            }
+        }
+    }
+
+    #[test]
+    fn determinism() {
+        let build = |threads| {
+            let mut nodes = BvhNodes::default();
+            let mut primitives = BvhPrimitives::default();
+
+            // Pseudo-random, but fixed, cloud of primitives - large enough to
+            // go through both parallel binning and parallel subtrees
+            for i in 0..(2 * MIN_PRIMITIVES_FOR_PARALLEL_BINNING as u32) {
+                let x = (i.wrapping_mul(2654435761) % 1000) as f32;
+                let y = (i.wrapping_mul(2246822519) % 1000) as f32;
+                let z = (i.wrapping_mul(3266489917) % 1000) as f32;
+                let center = vec3(x, y, z) / 10.0;
+
+                primitives.add(BvhPrimitive {
+                    id: i,
+                    center,
+                    bounds: BoundingBox::new(center - 0.5, center + 0.5),
+                });
+            }
+
+            primitives.begin_refresh();
+            run_ex(&mut nodes, &mut primitives, 24, threads);
+
+            (nodes, primitives)
+        };
+
+        let (serial_nodes, serial_primitives) = build(1);
+
+        for threads in [2, 3, 8] {
+            let (parallel_nodes, parallel_primitives) = build(threads);
+
+            assert_same_tree(
+                (&serial_nodes, &serial_primitives),
+                (&parallel_nodes, &parallel_primitives),
+                BvhNodeId::root(),
+                BvhNodeId::root(),
+            );
         }
     }
 }
@@ -0,0 +1,655 @@
+//! Binary format for persisting built trees across launches.
+//!
+//! Trees are keyed by content hashes of whatever they were built from (see:
+//! [`Blas::content_hash()`] and [`super::Bvh::tlas_hash()`]) instead of
+//! handles, since handles are not stable between launches - so a cache simply
+//! provides trees that get picked up when meshes and instances with matching
+//! contents appear.
+//!
+//! All numbers are stored as little-endian; the format is versioned and cache
+//! written by a different version is rejected as a whole.
+
+use std::collections::HashMap;
+use std::{io, mem};
+
+use glam::Vec3;
+
+use super::{
+    Blas, BvhNode, BvhNodeHash, BvhNodeId, BvhNodes, BvhPrimitive,
+    BvhPrimitiveId, BvhPrimitivesRef,
+};
+use crate::BoundingBox;
+
+const MAGIC: [u8; 8] = *b"STROLBVH";
+
+/// Version of the format; must be bumped whenever the format changes or when
+/// the builders start producing different trees for the same input.
+const VERSION: u32 = 3;
+
+/// Trees loaded from a cache, waiting to be claimed.
+#[derive(Debug, Default)]
+pub struct BvhCache {
+    pub blases: HashMap<u64, Blas>,
+    pub tlas: Option<CachedTlas>,
+}
+
+#[derive(Debug)]
+pub struct CachedTlas {
+    pub hash: u64,
+    pub nodes: BvhNodes,
+    pub primitives: Vec<BvhPrimitive>,
+    pub depth: u32,
+}
+
+pub fn write(
+    w: impl io::Write,
+    blases: &[&Blas],
+    tlas: Option<(u64, &BvhNodes, &[BvhPrimitive])>,
+) -> io::Result<()> {
+    let mut enc = Encoder::new(io::BufWriter::new(w));
+
+    enc.bytes(&MAGIC)?;
+    enc.u32(VERSION)?;
+    enc.len(blases.len())?;
+
+    for blas in blases {
+        blas.write(&mut enc)?;
+    }
+
+    if let Some((hash, nodes, primitives)) = tlas {
+        enc.u8(1)?;
+        enc.u64(hash)?;
+        enc.nodes(nodes)?;
+        enc.primitives(primitives)?;
+    } else {
+        enc.u8(0)?;
+    }
+
+    enc.finish()
+}
+
+pub fn read(r: impl io::Read) -> io::Result<BvhCache> {
+    let mut dec = Decoder::new(io::BufReader::new(r));
+
+    let mut magic = [0; MAGIC.len()];
+
+    dec.bytes(&mut magic)?;
+
+    if magic != MAGIC {
+        return Err(invalid_data("not a BVH cache"));
+    }
+
+    let version = dec.u32()?;
+
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "unsupported BVH cache version: {version} (expected {VERSION})"
+        )));
+    }
+
+    let mut blases = HashMap::new();
+
+    for _ in 0..dec.len()? {
+        let blas = Blas::read(&mut dec)?;
+
+        blases.insert(blas.hash(), blas);
+    }
+
+    let tlas = match dec.u8()? {
+        0 => None,
+
+        1 => {
+            let hash = dec.u64()?;
+            let nodes = dec.nodes()?;
+            let primitives = dec.primitives()?;
+            let depth = validate(&nodes, primitives.len())?;
+
+            Some(CachedTlas {
+                hash,
+                nodes,
+                primitives,
+                depth,
+            })
+        }
+
+        tag => {
+            return Err(invalid_data(format!("invalid TLAS tag: {tag}")));
+        }
+    };
+
+    Ok(BvhCache { blases, tlas })
+}
+
+pub struct Encoder<W> {
+    w: W,
+}
+
+impl<W> Encoder<W>
+where
+    W: io::Write,
+{
+    fn new(w: W) -> Self {
+        Self { w }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+
+    fn bytes(&mut self, value: &[u8]) -> io::Result<()> {
+        self.w.write_all(value)
+    }
+
+    pub fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    pub fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn len(&mut self, value: usize) -> io::Result<()> {
+        self.u64(value as u64)
+    }
+
+    pub fn f32(&mut self, value: f32) -> io::Result<()> {
+        self.u32(value.to_bits())
+    }
+
+    pub fn vec3(&mut self, value: Vec3) -> io::Result<()> {
+        self.f32(value.x)?;
+        self.f32(value.y)?;
+        self.f32(value.z)
+    }
+
+    pub fn bounds(&mut self, value: BoundingBox) -> io::Result<()> {
+        self.vec3(value.min())?;
+        self.vec3(value.max())
+    }
+
+    pub fn primitives_ref(
+        &mut self,
+        value: BvhPrimitivesRef,
+    ) -> io::Result<()> {
+        self.u32(value.start().get())?;
+        self.u32(value.end().get())
+    }
+
+    pub fn nodes(&mut self, value: &BvhNodes) -> io::Result<()> {
+        self.len(value.nodes.len())?;
+
+        for node in &value.nodes {
+            match *node {
+                BvhNode::Internal {
+                    bounds,
+                    primitives_ref,
+                    left_id,
+                    left_hash,
+                    right_id,
+                    right_hash,
+                } => {
+                    self.u8(0)?;
+                    self.bounds(bounds)?;
+                    self.primitives_ref(primitives_ref)?;
+                    self.u32(left_id.get())?;
+                    self.u64(left_hash.get())?;
+                    self.u32(right_id.get())?;
+                    self.u64(right_hash.get())?;
+                }
+
+                BvhNode::Leaf {
+                    bounds,
+                    primitives_ref,
+                } => {
+                    self.u8(1)?;
+                    self.bounds(bounds)?;
+                    self.primitives_ref(primitives_ref)?;
+                }
+            }
+        }
+
+        self.len(value.free_nodes.len())?;
+
+        for id in &value.free_nodes {
+            self.u32(id.get())?;
+        }
+
+        Ok(())
+    }
+
+    pub fn primitives(&mut self, value: &[BvhPrimitive]) -> io::Result<()> {
+        self.len(value.len())?;
+
+        for primitive in value {
+            self.u32(primitive.id)?;
+            self.vec3(primitive.center)?;
+            self.bounds(primitive.bounds)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Decoder<R> {
+    r: R,
+}
+
+impl<R> Decoder<R>
+where
+    R: io::Read,
+{
+    fn new(r: R) -> Self {
+        Self { r }
+    }
+
+    fn bytes(&mut self, value: &mut [u8]) -> io::Result<()> {
+        self.r.read_exact(value)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        let mut value = [0; 1];
+
+        self.bytes(&mut value)?;
+
+        Ok(value[0])
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut value = [0; 4];
+
+        self.bytes(&mut value)?;
+
+        Ok(u32::from_le_bytes(value))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut value = [0; 8];
+
+        self.bytes(&mut value)?;
+
+        Ok(u64::from_le_bytes(value))
+    }
+
+    pub fn len(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?)
+            .map_err(|_| invalid_data("length out of range"))
+    }
+
+    pub fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_bits(self.u32()?))
+    }
+
+    pub fn vec3(&mut self) -> io::Result<Vec3> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    pub fn bounds(&mut self) -> io::Result<BoundingBox> {
+        Ok(BoundingBox::new(self.vec3()?, self.vec3()?))
+    }
+
+    pub fn primitives_ref(&mut self) -> io::Result<BvhPrimitivesRef> {
+        let start = BvhPrimitiveId::new(self.u32()?);
+        let end = BvhPrimitiveId::new(self.u32()?);
+
+        if start.get() > end.get() {
+            return Err(invalid_data("invalid primitives range"));
+        }
+
+        Ok(BvhPrimitivesRef::new(start, end))
+    }
+
+    pub fn nodes(&mut self) -> io::Result<BvhNodes> {
+        let mut nodes = Vec::new();
+
+        for _ in 0..self.len()? {
+            let node = match self.u8()? {
+                0 => BvhNode::Internal {
+                    bounds: self.bounds()?,
+                    primitives_ref: self.primitives_ref()?,
+                    left_id: BvhNodeId::new(self.u32()?),
+                    left_hash: BvhNodeHash::new(self.u64()?),
+                    right_id: BvhNodeId::new(self.u32()?),
+                    right_hash: BvhNodeHash::new(self.u64()?),
+                },
+
+                1 => BvhNode::Leaf {
+                    bounds: self.bounds()?,
+                    primitives_ref: self.primitives_ref()?,
+                },
+
+                tag => {
+                    return Err(invalid_data(format!(
+                        "invalid node tag: {tag}"
+                    )));
+                }
+            };
+
+            nodes.push(node);
+        }
+
+        let mut free_nodes = Vec::new();
+
+        for _ in 0..self.len()? {
+            free_nodes.push(BvhNodeId::new(self.u32()?));
+        }
+
+        Ok(BvhNodes { nodes, free_nodes })
+    }
+
+    pub fn primitives(&mut self) -> io::Result<Vec<BvhPrimitive>> {
+        let mut primitives = Vec::new();
+
+        for _ in 0..self.len()? {
+            primitives.push(BvhPrimitive {
+                id: self.u32()?,
+                center: self.vec3()?,
+                bounds: self.bounds()?,
+            });
+        }
+
+        Ok(primitives)
+    }
+}
+
+/// Checks that nodes refer only to nodes and primitives that exist and that
+/// the nodes reachable from the root form a proper tree; returns the tree's
+/// depth.
+///
+/// Everything read from a cache later ends up as an index (either on the CPU
+/// or on the GPU), so a damaged cache must get rejected here - otherwise it
+/// could cause a panic or make the GPU read out of bounds.
+pub fn validate(nodes: &BvhNodes, primitive_count: usize) -> io::Result<u32> {
+    let is_node_valid = |id: BvhNodeId| (id.get() as usize) < nodes.nodes.len();
+
+    for node in &nodes.nodes {
+        if node.primitives_ref().end().get() as usize > primitive_count {
+            return Err(invalid_data("primitives range out of bounds"));
+        }
+
+        if let BvhNode::Internal {
+            left_id, right_id, ..
+        } = *node
+        {
+            if !is_node_valid(left_id) || !is_node_valid(right_id) {
+                return Err(invalid_data("child node out of bounds"));
+            }
+        }
+    }
+
+    if !nodes.free_nodes.iter().all(|&id| is_node_valid(id)) {
+        return Err(invalid_data("free node out of bounds"));
+    }
+
+    if nodes.nodes.is_empty() {
+        return Ok(0);
+    }
+
+    let mut depth = 0;
+    let mut visited = vec![false; nodes.nodes.len()];
+    let mut stack = vec![(BvhNodeId::root(), 0)];
+
+    while let Some((id, node_depth)) = stack.pop() {
+        if mem::replace(&mut visited[id.get() as usize], true) {
+            return Err(invalid_data("node referenced more than once"));
+        }
+
+        match nodes[id] {
+            BvhNode::Internal {
+                primitives_ref,
+                left_id,
+                right_id,
+                ..
+            } => {
+                let left_ref = nodes[left_id].primitives_ref();
+                let right_ref = nodes[right_id].primitives_ref();
+
+                let is_covered = left_ref.start() == primitives_ref.start()
+                    && left_ref.end() == right_ref.start()
+                    && right_ref.end() == primitives_ref.end();
+
+                if !is_covered {
+                    return Err(invalid_data(
+                        "children don't match their parent's primitives",
+                    ));
+                }
+
+                stack.push((left_id, node_depth + 1));
+                stack.push((right_id, node_depth + 1));
+            }
+
+            BvhNode::Leaf { primitives_ref, .. } => {
+                // Empty leaves don't get serialized at all, so any pointer
+                // to them would point at whatever happens to follow
+                if primitives_ref.len() == 0 && id != BvhNodeId::root() {
+                    return Err(invalid_data("empty leaf"));
+                }
+
+                depth = depth.max(node_depth);
+            }
+        }
+    }
+
+    Ok(depth)
+}
+
+pub fn invalid_data(
+    error: impl Into<Box<dyn std::error::Error + Send + Sync>>,
+) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec2};
+
+    use super::super::{builder, BvhPrimitives};
+    use super::*;
+    use crate::{EngineConfig, Triangle};
+
+    fn triangles() -> Vec<Triangle> {
+        (0..32)
+            .map(|i| {
+                let offset = vec3(i as f32, (i % 4) as f32, (i % 3) as f32);
+
+                Triangle {
+                    positions: [
+                        offset,
+                        offset + vec3(1.0, 0.0, 0.0),
+                        offset + vec3(0.0, 1.0, 0.0),
+                    ],
+                    normals: Default::default(),
+                    uvs: [Vec2::ZERO; 3],
+                    tangents: Default::default(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = EngineConfig::default();
+        let triangles = triangles();
+        let mut blas = Blas::new(
+            &config,
+            Blas::content_hash(&config, &triangles),
+            triangles,
+        );
+
+        blas.serialize(&config, 100);
+
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        for i in 0..8 {
+            let center = vec3(i as f32 * 3.0, 0.0, 0.0);
+
+            primitives.add(BvhPrimitive {
+                id: i,
+                center,
+                bounds: BoundingBox::new(center - 1.0, center + 1.0),
+            });
+        }
+
+        primitives.begin_refresh();
+        builder::run(&mut nodes, &mut primitives, 16);
+
+        let tlas_primitives =
+            primitives.current(primitives.current_ref()).to_vec();
+
+        let mut data = Vec::new();
+
+        write(&mut data, &[&blas], Some((1234, &nodes, &tlas_primitives)))
+            .unwrap();
+
+        let mut cache = read(data.as_slice()).unwrap();
+
+        // ---
+
+        let mut actual = cache.blases.remove(&blas.hash()).unwrap();
+
+        actual.serialize(&config, 100);
+
+        assert_eq!(blas.buffer(), actual.buffer());
+        assert_eq!(blas.bounds(), actual.bounds());
+        assert_eq!(blas.stack_usage(), actual.stack_usage());
+        assert_eq!(blas.triangle_count(), actual.triangle_count());
+        assert_eq!(blas.len(), actual.len());
+
+        // ---
+
+        let actual = cache.tlas.unwrap();
+
+        assert_eq!(1234, actual.hash);
+        assert_eq!(format!("{nodes:?}"), format!("{:?}", actual.nodes));
+
+        assert_eq!(
+            format!("{tlas_primitives:?}"),
+            format!("{:?}", actual.primitives)
+        );
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let mut data = Vec::new();
+
+        write(&mut data, &[], None).unwrap();
+
+        let cache = read(data.as_slice()).unwrap();
+
+        assert!(cache.blases.is_empty());
+        assert!(cache.tlas.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_data() {
+        let mut data = Vec::new();
+
+        write(&mut data, &[], None).unwrap();
+
+        // Invalid magic
+        let mut invalid = data.clone();
+
+        invalid[0] = b'X';
+
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            read(invalid.as_slice()).unwrap_err().kind()
+        );
+
+        // Unsupported version
+        let mut invalid = data.clone();
+
+        invalid[MAGIC.len()] += 1;
+
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            read(invalid.as_slice()).unwrap_err().kind()
+        );
+
+        // Truncated data
+        let invalid = &data[..data.len() - 1];
+
+        assert_eq!(
+            io::ErrorKind::UnexpectedEof,
+            read(invalid).unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_indices() {
+        let leaf = |start, end| BvhNode::Leaf {
+            bounds: Default::default(),
+            primitives_ref: BvhPrimitivesRef::new(
+                BvhPrimitiveId::new(start),
+                BvhPrimitiveId::new(end),
+            ),
+        };
+
+        let internal = |left_id, right_id| BvhNode::Internal {
+            bounds: Default::default(),
+            primitives_ref: BvhPrimitivesRef::new(
+                BvhPrimitiveId::new(0),
+                BvhPrimitiveId::new(2),
+            ),
+            left_id: BvhNodeId::new(left_id),
+            left_hash: BvhNodeHash::new(0),
+            right_id: BvhNodeId::new(right_id),
+            right_hash: BvhNodeHash::new(0),
+        };
+
+        let primitives = vec![
+            BvhPrimitive {
+                id: 0,
+                center: Vec3::ZERO,
+                bounds: Default::default(),
+            };
+            2
+        ];
+
+        let read_tlas = |nodes: Vec<BvhNode>, free_nodes: Vec<u32>| {
+            let nodes = BvhNodes {
+                nodes,
+                free_nodes: free_nodes
+                    .into_iter()
+                    .map(BvhNodeId::new)
+                    .collect(),
+            };
+
+            let mut data = Vec::new();
+
+            write(&mut data, &[], Some((1234, &nodes, &primitives))).unwrap();
+            read(data.as_slice()).map(|_| ())
+        };
+
+        assert!(read_tlas(
+            vec![internal(1, 2), leaf(0, 1), leaf(1, 2)],
+            vec![]
+        )
+        .is_ok());
+
+        // Child out of bounds
+        let err =
+            read_tlas(vec![internal(1, 3), leaf(0, 1), leaf(1, 2)], vec![])
+                .unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Primitives out of bounds
+        let err =
+            read_tlas(vec![internal(1, 2), leaf(0, 1), leaf(1, 3)], vec![])
+                .unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        // Free node out of bounds
+        let err =
+            read_tlas(vec![internal(1, 2), leaf(0, 1), leaf(1, 2)], vec![3])
+                .unwrap_err();
+
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use glam::Affine3A;
+
+use super::Blas;
+use crate::{gpu, Params};
+
+/// Instance as seen by the top-level BVH.
+#[derive(Debug)]
+pub struct BvhInstance<P>
+where
+    P: Params,
+{
+    pub handle: P::InstanceHandle,
+    pub mesh_handle: P::MeshHandle,
+    pub material_id: gpu::MaterialId,
+    pub transform: Affine3A,
+    pub transform_inverse: Affine3A,
+    pub visibility: u32,
+    pub casts_shadows: bool,
+    pub visible_to_camera: bool,
+    pub visible_in_indirect: bool,
+}
+
+/// Instance as seen by the top-level BVH's serializer.
+///
+/// Contrary to [`BvhInstance`], it doesn't refer to any handles, so that the
+/// serialization can happen on a worker thread (see: `Bvh::refresh()`).
+#[derive(Clone, Debug)]
+pub struct TlasInstance {
+    pub blas: Arc<Blas>,
+    pub material_id: gpu::MaterialId,
+    pub has_alpha_blending: bool,
+    pub transform_inverse: Affine3A,
+    pub visibility: u32,
+    pub casts_shadows: bool,
+    pub visible_to_camera: bool,
+    pub visible_in_indirect: bool,
+}
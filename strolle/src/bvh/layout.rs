@@ -0,0 +1,28 @@
+/// Layout of the BVH, as seen by the GPU.
+///
+/// The builder always produces a binary tree - wide layouts are created by
+/// collapsing that tree during serialization, which trades some additional
+/// box tests for fewer node fetches and shallower trees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BvhLayout {
+    /// Each internal node has two children.
+    #[default]
+    Binary,
+
+    /// Each internal node has up to four children.
+    Wide4,
+
+    /// Each internal node has up to eight children.
+    Wide8,
+}
+
+impl BvhLayout {
+    /// Returns the maximum number of children an internal node can have.
+    pub fn width(self) -> usize {
+        match self {
+            BvhLayout::Binary => 2,
+            BvhLayout::Wide4 => 4,
+            BvhLayout::Wide8 => 8,
+        }
+    }
+}
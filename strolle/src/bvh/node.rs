@@ -1,4 +1,8 @@
-use super::BvhPrimitivesRef;
+use std::hash::{Hash, Hasher};
+
+use fxhash::FxHasher;
+
+use super::{BvhPrimitive, BvhPrimitivesRef};
 use crate::BoundingBox;
 
 #[derive(Clone, Copy, Debug)]
@@ -72,11 +76,32 @@ impl BvhNodeId {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// Hash of node's primitives, used to reuse nodes across rebuilds.
+///
+/// Hash of a node is the combination of hashes of its primitives, regardless
+/// of their order - this way hash of a parent can be computed out of its
+/// children's hashes (see: [`Self::combine()`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct BvhNodeHash(u64);
 
 impl BvhNodeHash {
     pub fn new(hash: u64) -> Self {
         Self(hash)
     }
+
+    pub fn of(primitive: &BvhPrimitive) -> Self {
+        let mut hasher = FxHasher::default();
+
+        primitive.hash(&mut hasher);
+
+        Self(hasher.finish())
+    }
+
+    pub fn combine(self, other: Self) -> Self {
+        Self(self.0.wrapping_add(other.0))
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0
+    }
 }
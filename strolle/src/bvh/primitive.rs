@@ -3,13 +3,15 @@ use std::ops::Range;
 
 use glam::Vec3;
 
-use crate::gpu;
 use crate::utils::BoundingBox;
 
+/// Primitive stored in BVH's leaves.
+///
+/// Depending on the tree, `id` is either a triangle id (for bottom-level
+/// BVHs) or an instance id (for the top-level BVH).
 #[derive(Clone, Copy, Debug)]
 pub struct BvhPrimitive {
-    pub triangle_id: gpu::TriangleId,
-    pub material_id: gpu::MaterialId,
+    pub id: u32,
     pub center: Vec3,
     pub bounds: BoundingBox,
 }
@@ -32,6 +34,15 @@ impl Hash for BvhPrimitive {
         self.center.x.to_bits().hash(state);
         self.center.y.to_bits().hash(state);
         self.center.z.to_bits().hash(state);
+
+        // Instances can change their bounding boxes without moving their
+        // centers (e.g. when they get rotated), so to make sure we don't reuse
+        // stale nodes, let's take bounds into account as well
+        for value in [self.bounds.min(), self.bounds.max()] {
+            value.x.to_bits().hash(state);
+            value.y.to_bits().hash(state);
+            value.z.to_bits().hash(state);
+        }
     }
 }
 
@@ -1,5 +1,4 @@
 use std::mem;
-use std::ops::Range;
 
 use super::{BvhPrimitive, BvhPrimitiveId, BvhPrimitivesRef};
 
@@ -11,15 +10,13 @@ pub struct BvhPrimitives {
 }
 
 impl BvhPrimitives {
-    pub fn add(&mut self, prim: BvhPrimitive) {
+    pub fn add(&mut self, prim: BvhPrimitive) -> usize {
         self.all.push(prim);
+        self.all.len() - 1
     }
 
-    pub fn update(
-        &mut self,
-        ids: Range<usize>,
-    ) -> impl Iterator<Item = &mut BvhPrimitive> {
-        self.all[ids].iter_mut()
+    pub fn get_mut(&mut self, id: usize) -> &mut BvhPrimitive {
+        &mut self.all[id]
     }
 
     pub fn current_ref(&self) -> BvhPrimitivesRef {
@@ -46,6 +43,16 @@ impl BvhPrimitives {
         &mut self.current[start..end]
     }
 
+    /// Returns primitives in the order used by the most recently built (or
+    /// refitted) tree.
+    pub fn previous(&self) -> &[BvhPrimitive] {
+        &self.previous
+    }
+
+    pub fn set_current(&mut self, current: Vec<BvhPrimitive>) {
+        self.current = current;
+    }
+
     pub fn copy_previous_to_current(
         &mut self,
         previous: BvhPrimitivesRef,
@@ -60,6 +67,36 @@ impl BvhPrimitives {
             self.all.iter().filter(|p| p.is_alive()).copied().collect();
     }
 
+    /// Prepares primitives for refitting, i.e. updates the previous primitives
+    /// (in their previous order) with their current data.
+    ///
+    /// This requires primitives' ids to correspond to their indices within
+    /// `all`, which is the case for the top-level BVH.
+    pub fn begin_refit(&mut self) {
+        self.current = self
+            .previous
+            .iter()
+            .map(|p| self.all[p.id as usize])
+            .collect();
+    }
+
+    /// Takes primitives prepared by [`Self::begin_refresh()`], so that the tree
+    /// can be built on another thread while this object keeps accepting new
+    /// primitives.
+    pub fn detach(&mut self) -> Self {
+        Self {
+            all: Default::default(),
+            current: mem::take(&mut self.current),
+            previous: mem::take(&mut self.previous),
+        }
+    }
+
+    /// Brings back primitives taken by [`Self::detach()`].
+    pub fn attach(&mut self, other: Self) {
+        self.current = other.current;
+        self.previous = other.previous;
+    }
+
     pub fn end_refresh(&mut self) {
         self.previous = mem::take(&mut self.current);
     }
@@ -0,0 +1,413 @@
+//! CPU-side traversal of the serialized BVH.
+//!
+//! This mirrors `Ray::trace()` and `Ray::intersect()` from `strolle-gpu`, and
+//! it works on exactly the same buffer as the GPU does, so that whatever the
+//! CPU hits is what gets rendered.
+//!
+//! The only difference is that alpha-blended materials are treated as opaque,
+//! since their textures live only on the GPU.
+
+use glam::{Affine3A, Vec4, Vec4Swizzles};
+
+use super::serializer::{OP_INTERNAL_WIDE, OP_LEAF, OP_MASK};
+use crate::gpu::{self, Affine3AExt};
+
+/// Returns the closest intersection (or, if `any_hit` is set, whichever
+/// intersection is found first) of given ray with the world, together with id
+/// of the instance that got hit.
+///
+/// Instances that are not visible to rays of given kind or whose visibility
+/// doesn't overlap `visibility_mask` are skipped.
+pub fn run(
+    bvh: &[Vec4],
+    triangles: &[gpu::Triangle],
+    ray: gpu::Ray,
+    kind: gpu::RayKind,
+    visibility_mask: u32,
+    any_hit: bool,
+) -> Option<(u32, gpu::TriangleHit)> {
+    if bvh.is_empty() {
+        return None;
+    }
+
+    let mut hit = gpu::TriangleHit {
+        distance: ray.len(),
+        ..gpu::TriangleHit::none()
+    };
+
+    let mut hit_instance_id = None;
+    let mut bvh_ptr = 0;
+    let mut stack = Vec::new();
+
+    loop {
+        let d0 = bvh[bvh_ptr as usize];
+
+        if d0.w.to_bits() & OP_MASK != OP_LEAF {
+            if visit_internal_node(
+                bvh,
+                ray,
+                0,
+                &mut bvh_ptr,
+                &mut stack,
+                hit.distance,
+            ) {
+                continue;
+            }
+        } else {
+            let flags = d0.w.to_bits() >> 2;
+            let got_more_instances = flags & 1 == 1;
+            let instance_id = flags >> 5;
+            let is_visible = d0.x.to_bits() & visibility_mask != 0
+                && flags & kind.flag() != 0;
+            let blas_ptr = d0.y.to_bits();
+
+            let xform_inv = Affine3A::decode([
+                bvh[bvh_ptr as usize + 1],
+                bvh[bvh_ptr as usize + 2],
+                bvh[bvh_ptr as usize + 3],
+            ]);
+
+            let found_hit = is_visible
+                && traverse_blas(
+                    bvh,
+                    triangles,
+                    ray.transform(xform_inv),
+                    blas_ptr,
+                    any_hit,
+                    &mut hit,
+                );
+
+            if found_hit {
+                hit.to_world_space(xform_inv);
+
+                hit.material_id = gpu::MaterialId::new(d0.z.to_bits());
+                hit_instance_id = Some(instance_id);
+
+                if any_hit {
+                    break;
+                }
+            }
+
+            if got_more_instances {
+                bvh_ptr += 4;
+                continue;
+            }
+        }
+
+        if let Some(ptr) = stack.pop() {
+            bvh_ptr = ptr;
+        } else {
+            break;
+        }
+    }
+
+    let instance_id = hit_instance_id?;
+
+    hit.point = ray.at(hit.distance);
+
+    Some((instance_id, hit))
+}
+
+fn traverse_blas(
+    bvh: &[Vec4],
+    triangles: &[gpu::Triangle],
+    ray: gpu::Ray,
+    blas_ptr: u32,
+    any_hit: bool,
+    hit: &mut gpu::TriangleHit,
+) -> bool {
+    let mut found_any_hit = false;
+    let mut bvh_ptr = blas_ptr;
+    let mut stack = Vec::new();
+
+    loop {
+        let d0 = bvh[bvh_ptr as usize];
+
+        if d0.w.to_bits() != OP_LEAF {
+            if visit_internal_node(
+                bvh,
+                ray,
+                blas_ptr,
+                &mut bvh_ptr,
+                &mut stack,
+                hit.distance,
+            ) {
+                continue;
+            }
+        } else {
+            let got_more_triangles = d0.x.to_bits() & 1 == 1;
+            let triangle_id = d0.y.to_bits();
+
+            if triangles[triangle_id as usize].hit(ray, hit) {
+                found_any_hit = true;
+
+                if any_hit {
+                    break;
+                }
+            }
+
+            if got_more_triangles {
+                bvh_ptr += 1;
+                continue;
+            }
+        }
+
+        if let Some(ptr) = stack.pop() {
+            bvh_ptr = ptr;
+        } else {
+            break;
+        }
+    }
+
+    found_any_hit
+}
+
+/// Moves `bvh_ptr` onto the nearest child of given internal node (binary or
+/// wide) and pushes the rest of the children worth visiting onto the stack.
+///
+/// Returns `false` if none of the children is worth visiting.
+fn visit_internal_node(
+    bvh: &[Vec4],
+    ray: gpu::Ray,
+    base_ptr: u32,
+    bvh_ptr: &mut u32,
+    stack: &mut Vec<u32>,
+    hit_distance: f32,
+) -> bool {
+    let ptr = *bvh_ptr;
+    let d0 = bvh[ptr as usize];
+
+    let mut children = [(0, f32::MAX); 8];
+    let mut children_count = 0;
+
+    let mut add_child = |child_ptr: u32, min: Vec4, max: Vec4| {
+        let distance = ray.intersect_box(min.xyz(), max.xyz());
+
+        if distance < hit_distance {
+            children[children_count] = (child_ptr, distance);
+            children_count += 1;
+        }
+    };
+
+    if d0.w.to_bits() == OP_INTERNAL_WIDE {
+        let count = bvh[ptr as usize + 1].w.to_bits();
+
+        for child_idx in 0..count {
+            let min = bvh[(ptr + 2 * child_idx) as usize];
+            let max = bvh[(ptr + 2 * child_idx + 1) as usize];
+
+            let child_ptr = if child_idx == 0 {
+                ptr + 2 * count
+            } else {
+                base_ptr + min.w.to_bits()
+            };
+
+            add_child(child_ptr, min, max);
+        }
+    } else {
+        let d1 = bvh[ptr as usize + 1];
+        let d2 = bvh[ptr as usize + 2];
+        let d3 = bvh[ptr as usize + 3];
+
+        add_child(ptr + 4, d0, d1);
+        add_child(base_ptr + d1.w.to_bits(), d2, d3);
+    }
+
+    let children = &mut children[..children_count];
+
+    // Farthest children go onto the stack first, so that they get popped last
+    children.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    let Some((&(nearest_ptr, _), rest)) = children.split_last() else {
+        return false;
+    };
+
+    stack.extend(rest.iter().map(|(child_ptr, _)| *child_ptr));
+    *bvh_ptr = nearest_ptr;
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, vec4, Vec2, Vec3};
+
+    use super::super::{Blas, BvhLayout};
+    use super::*;
+    use crate::gpu::RayKind;
+    use crate::{EngineConfig, Triangle};
+
+    /// Builds a world consisting of a single mesh (a grid of 16x16 quads
+    /// spanning from (0,0,0) to (16,16,0)) that's instantiated twice: once
+    /// at z=10 (visibility 0b01) and once at z=20 (visibility 0b10).
+    fn world(layout: BvhLayout) -> (Vec<Vec4>, Vec<gpu::Triangle>) {
+        let mut triangles = Vec::new();
+
+        for y in 0..16 {
+            for x in 0..16 {
+                let p = vec3(x as f32, y as f32, 0.0);
+
+                for positions in [
+                    [p, p + Vec3::X, p + Vec3::Y],
+                    [p + Vec3::X, p + Vec3::X + Vec3::Y, p + Vec3::Y],
+                ] {
+                    triangles.push(Triangle {
+                        positions,
+                        normals: [Vec3::Z; 3],
+                        uvs: [Vec2::ZERO, Vec2::X, Vec2::Y],
+                        tangents: Default::default(),
+                    });
+                }
+            }
+        }
+
+        let config = EngineConfig {
+            bvh_layout: layout,
+            ..Default::default()
+        };
+
+        let mut blas = Blas::new(&config, 0, triangles.iter().cloned());
+
+        blas.serialize(&config, 0);
+
+        let mut bvh = Vec::new();
+
+        // Top-level BVH consisting of a single leaf with two instances
+        for (instance_id, z) in [(0, 10.0), (1, 20.0)] {
+            let got_more = (instance_id == 0) as u32;
+
+            let flags = got_more
+                | RayKind::Primary.flag()
+                | RayKind::Shadow.flag()
+                | RayKind::Indirect.flag()
+                | (instance_id << 5);
+
+            bvh.push(vec4(
+                f32::from_bits(1 << instance_id),
+                f32::from_bits(8),
+                f32::from_bits(instance_id),
+                f32::from_bits(OP_LEAF | (flags << 2)),
+            ));
+
+            bvh.extend(
+                Affine3A::from_translation(vec3(0.0, 0.0, z))
+                    .inverse()
+                    .encode(),
+            );
+        }
+
+        bvh.extend_from_slice(blas.buffer());
+
+        let triangles = triangles.iter().map(|t| t.serialize()).collect();
+
+        (bvh, triangles)
+    }
+
+    #[test]
+    fn smoke() {
+        for layout in [BvhLayout::Binary, BvhLayout::Wide4, BvhLayout::Wide8] {
+            let (bvh, triangles) = world(layout);
+
+            let trace = |ray, any_hit| {
+                run(&bvh, &triangles, ray, RayKind::Primary, u32::MAX, any_hit)
+            };
+
+            // Ray going down hits the closer instance first
+            let ray = gpu::Ray::new(vec3(4.25, 7.25, 30.0), -Vec3::Z);
+            let (instance_id, hit) = trace(ray, false).unwrap();
+
+            assert_eq!(1, instance_id, "{layout:?}");
+            assert_eq!(10.0, hit.distance, "{layout:?}");
+            assert_eq!(vec3(4.25, 7.25, 20.0), hit.point, "{layout:?}");
+            assert_eq!(Vec3::Z, hit.normal, "{layout:?}");
+            assert_eq!(1, hit.material_id.get(), "{layout:?}");
+
+            // Ray going up hits the other instance
+            let ray = gpu::Ray::new(vec3(4.25, 7.25, 0.0), Vec3::Z);
+            let (instance_id, hit) = trace(ray, false).unwrap();
+
+            assert_eq!(0, instance_id, "{layout:?}");
+            assert_eq!(10.0, hit.distance, "{layout:?}");
+
+            // Ray too short to reach anything
+            let ray = ray.with_len(5.0);
+
+            assert!(trace(ray, false).is_none());
+            assert!(trace(ray, true).is_none());
+
+            // Ray long enough to reach something
+            let ray = ray.with_len(15.0);
+
+            assert!(trace(ray, true).is_some());
+
+            // Ray that misses the grid
+            let ray = gpu::Ray::new(vec3(-1.0, -1.0, 0.0), Vec3::Z);
+
+            assert!(trace(ray, false).is_none());
+        }
+    }
+
+    #[test]
+    fn visibility() {
+        let (bvh, triangles) = world(BvhLayout::Binary);
+        let ray = gpu::Ray::new(vec3(4.25, 7.25, 30.0), -Vec3::Z);
+
+        let trace = |visibility_mask, any_hit| {
+            run(
+                &bvh,
+                &triangles,
+                ray,
+                RayKind::Primary,
+                visibility_mask,
+                any_hit,
+            )
+        };
+
+        // Ray sees only the farther instance
+        let (instance_id, hit) = trace(0b01, false).unwrap();
+
+        assert_eq!(0, instance_id);
+        assert_eq!(20.0, hit.distance);
+
+        // Ray sees only the closer instance
+        let (instance_id, hit) = trace(0b10, false).unwrap();
+
+        assert_eq!(1, instance_id);
+        assert_eq!(10.0, hit.distance);
+
+        // Ray sees nothing
+        assert!(trace(0b100, false).is_none());
+        assert!(trace(0b100, true).is_none());
+    }
+
+    #[test]
+    fn ray_kinds() {
+        let (mut bvh, triangles) = world(BvhLayout::Binary);
+        let ray = gpu::Ray::new(vec3(4.25, 7.25, 30.0), -Vec3::Z);
+
+        // Turn the closer instance into a shadow-only proxy
+        let d0 = &mut bvh[4];
+
+        d0.w = f32::from_bits(
+            d0.w.to_bits()
+                & !((RayKind::Primary.flag() | RayKind::Indirect.flag()) << 2),
+        );
+
+        for (kind, expected_instance_id) in [
+            (RayKind::Primary, 0),
+            (RayKind::Shadow, 1),
+            (RayKind::Indirect, 0),
+        ] {
+            let (instance_id, _) =
+                run(&bvh, &triangles, ray, kind, u32::MAX, false).unwrap();
+
+            assert_eq!(expected_instance_id, instance_id);
+        }
+
+        // Proxy stays invisible to the camera even if it's the only instance
+        // on the camera's layer
+        let hit = run(&bvh, &triangles, ray, RayKind::Primary, 0b10, false);
+
+        assert!(hit.is_none());
+    }
+}
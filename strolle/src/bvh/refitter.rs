@@ -0,0 +1,209 @@
+use super::{BvhNode, BvhNodeHash, BvhNodeId, BvhNodes, BvhPrimitives};
+use crate::BoundingBox;
+
+/// Keeps the tree's topology intact and recomputes bounds of all nodes,
+/// bottom-up, using the current primitives; returns the tree's SAH cost after
+/// refitting (see: [`cost()`]).
+pub fn run(nodes: &mut BvhNodes, primitives: &BvhPrimitives) -> f32 {
+    if nodes.nodes.is_empty() {
+        return 0.0;
+    }
+
+    let (bounds, cost, _) = refit(nodes, primitives, BvhNodeId::root());
+
+    normalize(bounds, cost)
+}
+
+/// Returns the tree's SAH cost, i.e. the expected number of nodes and
+/// primitives that a random ray has to test when traversing the tree.
+///
+/// This doesn't take into account any specific traversal and intersection
+/// costs - it's meant to compare different trees built over the same
+/// primitives, not to estimate the actual performance.
+pub fn cost(nodes: &BvhNodes) -> f32 {
+    if nodes.nodes.is_empty() {
+        return 0.0;
+    }
+
+    let (bounds, cost) = measure(nodes, BvhNodeId::root());
+
+    normalize(bounds, cost)
+}
+
+fn refit(
+    nodes: &mut BvhNodes,
+    primitives: &BvhPrimitives,
+    id: BvhNodeId,
+) -> (BoundingBox, f32, BvhNodeHash) {
+    match nodes[id] {
+        BvhNode::Internal {
+            left_id, right_id, ..
+        } => {
+            let (left_bounds, left_cost, left_hash) =
+                refit(nodes, primitives, left_id);
+
+            let (right_bounds, right_cost, right_hash) =
+                refit(nodes, primitives, right_id);
+
+            let bounds = left_bounds + right_bounds;
+
+            let BvhNode::Internal {
+                bounds: node_bounds,
+                left_hash: node_left_hash,
+                right_hash: node_right_hash,
+                ..
+            } = &mut nodes[id]
+            else {
+                unreachable!();
+            };
+
+            *node_bounds = bounds;
+
+            // Hashes are used by the builder to reuse nodes across rebuilds, so
+            // we have to keep them in sync with the primitives - otherwise the
+            // builder could pick a node with stale bounds
+            *node_left_hash = left_hash;
+            *node_right_hash = right_hash;
+
+            (
+                bounds,
+                bounds.half_area() + left_cost + right_cost,
+                left_hash.combine(right_hash),
+            )
+        }
+
+        BvhNode::Leaf { primitives_ref, .. } => {
+            let bounds = primitives
+                .current(primitives_ref)
+                .iter()
+                .map(|primitive| primitive.bounds)
+                .collect();
+
+            nodes[id] = BvhNode::Leaf {
+                bounds,
+                primitives_ref,
+            };
+
+            let hash = primitives
+                .current(primitives_ref)
+                .iter()
+                .map(BvhNodeHash::of)
+                .fold(BvhNodeHash::default(), BvhNodeHash::combine);
+
+            (bounds, nodes[id].sah_cost(), hash)
+        }
+    }
+}
+
+fn measure(nodes: &BvhNodes, id: BvhNodeId) -> (BoundingBox, f32) {
+    match nodes[id] {
+        BvhNode::Internal {
+            left_id, right_id, ..
+        } => {
+            let (left_bounds, left_cost) = measure(nodes, left_id);
+            let (right_bounds, right_cost) = measure(nodes, right_id);
+            let bounds = left_bounds + right_bounds;
+
+            (bounds, bounds.half_area() + left_cost + right_cost)
+        }
+
+        BvhNode::Leaf { bounds, .. } => (bounds, nodes[id].sah_cost()),
+    }
+}
+
+fn normalize(bounds: BoundingBox, cost: f32) -> f32 {
+    if bounds.is_set() && bounds.half_area() > 0.0 {
+        cost / bounds.half_area()
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Vec3};
+
+    use super::super::{builder, BvhPrimitive};
+    use super::*;
+
+    fn primitive(id: u32, center: Vec3) -> BvhPrimitive {
+        BvhPrimitive {
+            id,
+            center,
+            bounds: BoundingBox::new(center - 0.5, center + 0.5),
+        }
+    }
+
+    #[test]
+    fn test() {
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        for i in 0..16 {
+            primitives.add(primitive(i, vec3(i as f32 * 2.0, 0.0, 0.0)));
+        }
+
+        primitives.begin_refresh();
+        builder::run(&mut nodes, &mut primitives, 16);
+        primitives.end_refresh();
+
+        let built_cost = cost(&nodes);
+
+        // ---
+        // Case 1: Nothing moved, so refitting shouldn't change anything
+
+        let hashes = |nodes: &BvhNodes| -> Vec<_> {
+            nodes
+                .nodes
+                .iter()
+                .filter_map(|node| match *node {
+                    BvhNode::Internal {
+                        left_hash,
+                        right_hash,
+                        ..
+                    } => Some((left_hash, right_hash)),
+                    BvhNode::Leaf { .. } => None,
+                })
+                .collect()
+        };
+
+        let built_hashes = hashes(&nodes);
+
+        primitives.begin_refit();
+
+        let refitted_cost = run(&mut nodes, &primitives);
+
+        primitives.end_refresh();
+
+        assert!((built_cost - refitted_cost).abs() < 0.001);
+
+        // Hashes must stay the same as well - otherwise the builder wouldn't
+        // be able to reuse the refitted nodes
+        assert_eq!(built_hashes, hashes(&nodes));
+
+        // ---
+        // Case 2: Primitives got shuffled around, so the refitted tree should
+        //         still enclose all of them, but its cost should get worse
+
+        for i in 0..16 {
+            let x = ((i * 7) % 16) as f32 * 2.0;
+
+            *primitives.get_mut(i) = primitive(i as u32, vec3(x, 0.0, 5.0));
+        }
+
+        primitives.begin_refit();
+
+        let refitted_cost = run(&mut nodes, &primitives);
+
+        primitives.end_refresh();
+
+        let root_bounds = match nodes[BvhNodeId::root()] {
+            BvhNode::Internal { bounds, .. } => bounds,
+            BvhNode::Leaf { .. } => unreachable!(),
+        };
+
+        assert_eq!(vec3(-0.5, -0.5, 4.5), root_bounds.min());
+        assert_eq!(vec3(30.5, 0.5, 5.5), root_bounds.max());
+        assert!(refitted_cost > built_cost);
+    }
+}
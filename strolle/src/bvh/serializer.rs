@@ -1,110 +1,463 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use glam::Vec4;
 use spirv_std::glam::vec4;
 
-use super::{BvhNodeId, BvhNodes, BvhPrimitives};
-use crate::{AlphaMode, BvhNode, Materials, Params};
+use super::{
+    BvhLayout, BvhNodeId, BvhNodes, BvhPrimitive, BvhPrimitives, TlasInstance,
+};
+use crate::gpu::Affine3AExt;
+use crate::BvhNode;
+
+pub const OP_INTERNAL: u32 = 0;
+pub const OP_LEAF: u32 = 1;
+pub const OP_INTERNAL_WIDE: u32 = 2;
 
-pub fn run<P>(
-    materials: &Materials<P>,
+/// Bits of `d0.w` that contain the operation code; see: [`run_tlas()`].
+pub const OP_MASK: u32 = 0b11;
+
+/// Serializes a bottom-level BVH, i.e. a tree of triangles.
+///
+/// Pointers to children are stored relatively to the beginning of the buffer,
+/// so that the buffer can be later copied anywhere into the main BVH buffer
+/// (see: [`run_tlas()`]); triangle ids are offset by `first_triangle_id`, since
+/// primitives refer to triangles by their indices within the mesh.
+///
+/// Returns the number of stack entries needed to traverse this tree; it's
+/// guaranteed not to exceed `stack_budget`, provided the tree's depth doesn't
+/// exceed it either.
+pub fn run_blas(
+    layout: BvhLayout,
     nodes: &BvhNodes,
     primitives: &BvhPrimitives,
+    first_triangle_id: u32,
     buffer: &mut Vec<Vec4>,
-) where
-    P: Params,
-{
+    stack_budget: u32,
+) -> u32 {
     buffer.clear();
 
-    serialize(materials, nodes, primitives, buffer, BvhNodeId::root());
+    Serializer::new(layout, nodes, primitives, stack_budget).run(
+        buffer,
+        &mut |buffer, primitive, got_more_entries| {
+            buffer.push(vec4(
+                f32::from_bits(got_more_entries as u32),
+                f32::from_bits(first_triangle_id + primitive.id),
+                Default::default(),
+                f32::from_bits(OP_LEAF),
+            ));
+        },
+    )
 }
 
-fn serialize<P>(
-    materials: &Materials<P>,
+/// Serializes the top-level BVH, i.e. a tree of instances, followed by
+/// bottom-level BVHs of all the meshes these instances refer to.
+///
+/// Each instance occupies four entries: the first one contains visibility
+/// mask, pointer to the bottom-level BVH, material id and - next to the
+/// operation code - flags, while the remaining ones contain the inverted
+/// transform.
+///
+/// Flags are, starting from the least significant bit: whether there are more
+/// instances following this one, whether the material uses alpha blending,
+/// whether the instance casts shadows, whether it's visible to the camera and
+/// whether it's visible in indirect lighting - followed by the instance id.
+///
+/// Returns the number of stack entries needed to traverse the top-level BVH;
+/// see: [`run_blas()`].
+pub fn run_tlas(
+    layout: BvhLayout,
     nodes: &BvhNodes,
     primitives: &BvhPrimitives,
+    instances: &[Option<TlasInstance>],
     buffer: &mut Vec<Vec4>,
-    id: BvhNodeId,
-) -> u32
-where
-    P: Params,
-{
-    const OP_INTERNAL: u32 = 0;
-    const OP_LEAF: u32 = 1;
-
-    let ptr = buffer.len();
-
-    match nodes[id] {
-        BvhNode::Internal {
-            left_id, right_id, ..
-        } => {
-            buffer.push(Default::default());
-            buffer.push(Default::default());
-            buffer.push(Default::default());
-            buffer.push(Default::default());
-
-            let left_bb = nodes[left_id].bounds();
-            let right_bb = nodes[right_id].bounds();
-
-            let _left_ptr =
-                serialize(materials, nodes, primitives, buffer, left_id);
-
-            let right_ptr =
-                serialize(materials, nodes, primitives, buffer, right_id);
-
-            buffer[ptr] = vec4(
-                left_bb.min().x,
-                left_bb.min().y,
-                left_bb.min().z,
-                f32::from_bits(OP_INTERNAL),
-            );
-
-            buffer[ptr + 1] = vec4(
-                left_bb.max().x,
-                left_bb.max().y,
-                left_bb.max().z,
-                f32::from_bits(right_ptr),
-            );
-
-            buffer[ptr + 2] = vec4(
-                right_bb.min().x,
-                right_bb.min().y,
-                right_bb.min().z,
-                Default::default(),
-            );
+    stack_budget: u32,
+) -> u32 {
+    buffer.clear();
 
-            buffer[ptr + 3] = vec4(
-                right_bb.max().x,
-                right_bb.max().y,
-                right_bb.max().z,
+    // Pointers to bottom-level BVHs are not known until the entire top-level
+    // BVH gets serialized, so we leave placeholders that are patched later
+    let mut blas_refs = Vec::new();
+
+    let stack_usage = Serializer::new(layout, nodes, primitives, stack_budget)
+        .run(buffer, &mut |buffer, primitive, got_more_entries| {
+            let instance = instances[primitive.id as usize]
+                .as_ref()
+                .expect("top-level BVH refers to a removed instance");
+
+            // Apart from the flags, we also store instance id here - it's not
+            // needed by the GPU, but it allows for the CPU-side raycasts to
+            // tell which instance got hit (see: `raycaster`)
+            let flags = (got_more_entries as u32)
+                | ((instance.has_alpha_blending as u32) << 1)
+                | ((instance.casts_shadows as u32) << 2)
+                | ((instance.visible_to_camera as u32) << 3)
+                | ((instance.visible_in_indirect as u32) << 4)
+                | (primitive.id << 5);
+
+            blas_refs.push((buffer.len(), &instance.blas));
+
+            buffer.push(vec4(
+                f32::from_bits(instance.visibility),
                 Default::default(),
-            );
+                f32::from_bits(instance.material_id.get()),
+                f32::from_bits(OP_LEAF | (flags << 2)),
+            ));
+
+            buffer.extend(instance.transform_inverse.encode());
+        });
+
+    let mut blas_ptrs = HashMap::new();
+
+    for (ptr, blas) in blas_refs {
+        let blas_ptr =
+            *blas_ptrs.entry(Arc::as_ptr(blas)).or_insert_with(|| {
+                let blas_ptr = buffer.len();
+
+                buffer.extend_from_slice(blas.buffer());
+
+                blas_ptr as u32
+            });
+
+        buffer[ptr].y = f32::from_bits(blas_ptr);
+    }
+
+    stack_usage
+}
+
+struct Serializer<'a> {
+    layout: BvhLayout,
+    nodes: &'a BvhNodes,
+    primitives: &'a BvhPrimitives,
+    stack_budget: u32,
+
+    /// Height of each node, i.e. the depth of the subtree rooted at it;
+    /// indexed by node ids.
+    heights: Vec<u32>,
+
+    stack_usage: u32,
+}
+
+impl<'a> Serializer<'a> {
+    fn new(
+        layout: BvhLayout,
+        nodes: &'a BvhNodes,
+        primitives: &'a BvhPrimitives,
+        stack_budget: u32,
+    ) -> Self {
+        let mut heights = vec![0; nodes.nodes.len()];
+
+        if layout != BvhLayout::Binary && !nodes.nodes.is_empty() {
+            Self::measure(nodes, &mut heights, BvhNodeId::root());
+        }
+
+        Self {
+            layout,
+            nodes,
+            primitives,
+            stack_budget,
+            heights,
+            stack_usage: 0,
+        }
+    }
+
+    fn measure(nodes: &BvhNodes, heights: &mut [u32], id: BvhNodeId) -> u32 {
+        let height = match nodes[id] {
+            BvhNode::Internal {
+                left_id, right_id, ..
+            } => {
+                let left = Self::measure(nodes, heights, left_id);
+                let right = Self::measure(nodes, heights, right_id);
+
+                left.max(right) + 1
+            }
+
+            BvhNode::Leaf { .. } => 0,
+        };
+
+        heights[id.get() as usize] = height;
+        height
+    }
+
+    fn run(
+        mut self,
+        buffer: &mut Vec<Vec4>,
+        serialize_primitive: &mut impl FnMut(&mut Vec<Vec4>, &BvhPrimitive, bool),
+    ) -> u32 {
+        if !self.nodes.nodes.is_empty() {
+            self.serialize(buffer, BvhNodeId::root(), 0, serialize_primitive);
         }
 
-        BvhNode::Leaf { primitives_ref, .. } => {
-            for (primitive_idx, primitive) in
-                primitives.current(primitives_ref).iter().enumerate()
-            {
-                let material = &materials[primitive.material_id];
+        self.stack_usage
+    }
+
+    /// Serializes given node; `stack` is the number of entries that will be
+    /// already present on the stack when the GPU reaches this node.
+    fn serialize(
+        &mut self,
+        buffer: &mut Vec<Vec4>,
+        id: BvhNodeId,
+        stack: u32,
+        serialize_primitive: &mut impl FnMut(&mut Vec<Vec4>, &BvhPrimitive, bool),
+    ) -> u32 {
+        let ptr = buffer.len();
+        let nodes = self.nodes;
+
+        match nodes[id] {
+            BvhNode::Internal { .. } if self.layout != BvhLayout::Binary => {
+                let children = self.collapse(id, stack);
+                let stack = stack + (children.len() as u32) - 1;
+
+                for _ in 0..(2 * children.len()) {
+                    buffer.push(Default::default());
+                }
+
+                for (child_idx, &child_id) in children.iter().enumerate() {
+                    let child_ptr = self.serialize(
+                        buffer,
+                        child_id,
+                        stack,
+                        serialize_primitive,
+                    );
+
+                    let child_bb = nodes[child_id].bounds();
+
+                    // First child is always located right after the node, so
+                    // instead of its pointer we store the operation code and
+                    // the number of children
+                    let (min_w, max_w) = if child_idx == 0 {
+                        (OP_INTERNAL_WIDE, children.len() as u32)
+                    } else {
+                        (child_ptr, 0)
+                    };
+
+                    buffer[ptr + 2 * child_idx] = vec4(
+                        child_bb.min().x,
+                        child_bb.min().y,
+                        child_bb.min().z,
+                        f32::from_bits(min_w),
+                    );
+
+                    buffer[ptr + 2 * child_idx + 1] = vec4(
+                        child_bb.max().x,
+                        child_bb.max().y,
+                        child_bb.max().z,
+                        f32::from_bits(max_w),
+                    );
+                }
+            }
+
+            BvhNode::Internal {
+                left_id, right_id, ..
+            } => {
+                buffer.push(Default::default());
+                buffer.push(Default::default());
+                buffer.push(Default::default());
+                buffer.push(Default::default());
+
+                let left_bb = nodes[left_id].bounds();
+                let right_bb = nodes[right_id].bounds();
 
-                let flags = {
+                let _left_ptr = self.serialize(
+                    buffer,
+                    left_id,
+                    stack + 1,
+                    serialize_primitive,
+                );
+
+                let right_ptr = self.serialize(
+                    buffer,
+                    right_id,
+                    stack + 1,
+                    serialize_primitive,
+                );
+
+                buffer[ptr] = vec4(
+                    left_bb.min().x,
+                    left_bb.min().y,
+                    left_bb.min().z,
+                    f32::from_bits(OP_INTERNAL),
+                );
+
+                buffer[ptr + 1] = vec4(
+                    left_bb.max().x,
+                    left_bb.max().y,
+                    left_bb.max().z,
+                    f32::from_bits(right_ptr),
+                );
+
+                buffer[ptr + 2] = vec4(
+                    right_bb.min().x,
+                    right_bb.min().y,
+                    right_bb.min().z,
+                    Default::default(),
+                );
+
+                buffer[ptr + 3] = vec4(
+                    right_bb.max().x,
+                    right_bb.max().y,
+                    right_bb.max().z,
+                    Default::default(),
+                );
+            }
+
+            BvhNode::Leaf { primitives_ref, .. } => {
+                self.stack_usage = self.stack_usage.max(stack);
+
+                for (primitive_idx, primitive) in
+                    self.primitives.current(primitives_ref).iter().enumerate()
+                {
                     let got_more_entries =
                         primitive_idx + 1 < primitives_ref.len();
 
-                    let has_alpha_blending =
-                        matches!(material.alpha_mode, AlphaMode::Blend);
+                    serialize_primitive(buffer, primitive, got_more_entries);
+                }
+            }
+        }
+
+        ptr as u32
+    }
 
-                    (got_more_entries as u32)
-                        | ((has_alpha_blending as u32) << 1)
-                };
+    /// Collapses given internal node (of a binary tree) into a wide node, i.e.
+    /// returns up to `layout.width()` of its descendants that together cover
+    /// the same primitives.
+    ///
+    /// Descendants with the largest surface area get opened first, since those
+    /// are the ones most likely to get hit by rays.
+    ///
+    /// Since wide nodes push more entries onto the stack than binary ones, a
+    /// descendant gets opened only if the resulting subtree still fits within
+    /// the stack budget - in the worst case we end up with a regular binary
+    /// node, which always fits as long as the tree's depth does.
+    fn collapse(&self, id: BvhNodeId, stack: u32) -> Vec<BvhNodeId> {
+        let nodes = self.nodes;
 
-                buffer.push(vec4(
-                    f32::from_bits(flags),
-                    f32::from_bits(primitive.triangle_id.get()),
-                    f32::from_bits(primitive.material_id.get()),
-                    f32::from_bits(OP_LEAF),
-                ));
-            }
+        let BvhNode::Internal {
+            left_id, right_id, ..
+        } = nodes[id]
+        else {
+            unreachable!();
+        };
+
+        let mut children = vec![left_id, right_id];
+
+        let fits = |children: &[BvhNodeId]| {
+            let height = children
+                .iter()
+                .map(|child_id| self.heights[child_id.get() as usize])
+                .max()
+                .unwrap_or_default();
+
+            stack + (children.len() as u32) - 1 + height <= self.stack_budget
+        };
+
+        while children.len() < self.layout.width() {
+            let mut candidates: Vec<_> = children
+                .iter()
+                .enumerate()
+                .filter(|(_, &child_id)| {
+                    matches!(nodes[child_id], BvhNode::Internal { .. })
+                })
+                .map(|(child_idx, &child_id)| (child_idx, child_id))
+                .collect();
+
+            candidates.sort_by(|(_, a), (_, b)| {
+                let a = nodes[*a].bounds().half_area();
+                let b = nodes[*b].bounds().half_area();
+
+                b.total_cmp(&a)
+            });
+
+            let opened =
+                candidates.into_iter().find_map(|(child_idx, child_id)| {
+                    let BvhNode::Internal {
+                        left_id, right_id, ..
+                    } = nodes[child_id]
+                    else {
+                        unreachable!();
+                    };
+
+                    let mut opened = children.clone();
+
+                    opened[child_idx] = left_id;
+                    opened.insert(child_idx + 1, right_id);
+
+                    fits(&opened).then_some(opened)
+                });
+
+            let Some(opened) = opened else {
+                break;
+            };
+
+            children = opened;
         }
+
+        children
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::super::{builder, stats};
+    use super::*;
+    use crate::BoundingBox;
+
+    #[test]
+    fn wide() {
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        for i in 0..64 {
+            let center =
+                vec3((i % 4) as f32, (i / 4 % 4) as f32, (i / 16) as f32);
+
+            primitives.add(BvhPrimitive {
+                id: i,
+                center,
+                bounds: BoundingBox::new(center - 0.25, center + 0.25),
+            });
+        }
+
+        primitives.begin_refresh();
+        builder::run(&mut nodes, &mut primitives, 16);
+
+        for layout in [BvhLayout::Binary, BvhLayout::Wide4, BvhLayout::Wide8] {
+            let mut buffer = Vec::new();
+
+            let stack_usage =
+                run_blas(layout, &nodes, &primitives, 0, &mut buffer, 24);
 
-    ptr as u32
+            assert!(stack_usage <= 24);
+
+            let mut ids: Vec<_> = buffer
+                .iter()
+                .filter(|d| d.w.to_bits() == OP_LEAF)
+                .map(|d| d.y.to_bits())
+                .collect();
+
+            ids.sort();
+
+            assert_eq!((0..64).collect::<Vec<_>>(), ids, "{layout:?}");
+
+            if layout != BvhLayout::Binary {
+                assert_eq!(OP_INTERNAL_WIDE, buffer[0].w.to_bits());
+                assert_eq!(layout.width() as u32, buffer[1].w.to_bits());
+            }
+        }
+
+        // When the stack budget is as tight as it gets, wide layouts must fall
+        // back to binary nodes wherever widening would overflow the stack
+        let depth = stats::validate(&nodes, &primitives).depth;
+
+        for layout in [BvhLayout::Wide4, BvhLayout::Wide8] {
+            let mut buffer = Vec::new();
+
+            let stack_usage =
+                run_blas(layout, &nodes, &primitives, 0, &mut buffer, depth);
+
+            assert!(stack_usage <= depth, "{layout:?}: {stack_usage}");
+        }
+    }
 }
@@ -0,0 +1,644 @@
+//! Builder that, apart from regular object splits, considers spatial splits as
+//! well, i.e. it's allowed to split a triangle into two references that go
+//! into both children.
+//!
+//! This is especially helpful for scenes with large, long or thin triangles
+//! (walls, floors etc.) that would otherwise end up enlarging nodes they
+//! belong to, causing lots of overlap between siblings.
+//!
+//! See: "Spatial Splits in Bounding Volume Hierarchies" (Stich et al.).
+
+use glam::{UVec3, Vec3};
+
+use super::{
+    BvhNode, BvhNodeHash, BvhNodeId, BvhNodes, BvhPrimitive, BvhPrimitiveId,
+    BvhPrimitives, BvhPrimitivesRef,
+};
+use crate::{Axis, BoundingBox};
+
+const BINS: usize = 12;
+
+/// Minimum overlap between children produced by an object split, relative to
+/// the root's surface area, that makes us consider spatial splits as well.
+///
+/// Without this limit we'd duplicate references even in places where object
+/// splits already do a fine job, needlessly bloating the tree.
+const MIN_OVERLAP: f32 = 1e-5;
+
+/// Builds the tree, making sure it doesn't grow deeper than `max_depth`; see:
+/// [`super::builder::run()`].
+pub fn run(
+    nodes: &mut BvhNodes,
+    primitives: &mut BvhPrimitives,
+    triangles: impl IntoIterator<Item = (BvhPrimitive, [Vec3; 3])>,
+    max_depth: u32,
+) {
+    let refs: Vec<_> = triangles
+        .into_iter()
+        .map(|(prim, positions)| Reference { prim, positions })
+        .collect();
+
+    let root_area = refs
+        .iter()
+        .map(|r| r.prim.bounds)
+        .collect::<BoundingBox>()
+        .half_area();
+
+    if let Some(BvhNode::Internal {
+        left_id, right_id, ..
+    }) = nodes.set_root(Default::default())
+    {
+        nodes.remove_tree(left_id);
+        nodes.remove_tree(right_id);
+    }
+
+    let mut out = Vec::with_capacity(refs.len());
+
+    build(
+        nodes,
+        &mut out,
+        BvhNodeId::root(),
+        refs,
+        root_area,
+        max_depth,
+        0,
+    );
+
+    primitives.set_current(out);
+}
+
+fn build(
+    nodes: &mut BvhNodes,
+    out: &mut Vec<BvhPrimitive>,
+    id: BvhNodeId,
+    refs: Vec<Reference>,
+    root_area: f32,
+    max_depth: u32,
+    depth: u32,
+) {
+    let bounds: BoundingBox = refs.iter().map(|r| r.prim.bounds).collect();
+    let start = BvhPrimitiveId::new(out.len() as u32);
+
+    let remaining_depth = max_depth.saturating_sub(depth);
+
+    let split = if refs.len() > 1 && remaining_depth > 0 {
+        find_split(&refs, bounds, root_area)
+    } else {
+        None
+    };
+
+    let split = split
+        .filter(|split| split.cost < (refs.len() as f32) * bounds.half_area())
+        .map(|split| {
+            // If there's not much depth left, let's switch to median splits
+            // that keep the subtree balanced (and, contrary to spatial splits,
+            // don't duplicate references)
+            if remaining_depth <= (refs.len() as u32).ilog2() + 1 {
+                Split {
+                    kind: SplitKind::Median {
+                        axis: split.kind.axis(),
+                    },
+                    ..split
+                }
+            } else {
+                split
+            }
+        });
+
+    if let Some(split) = split {
+        let (left_refs, right_refs) = split.partition(refs);
+
+        if !left_refs.is_empty() && !right_refs.is_empty() {
+            let left_id = nodes.add(Default::default());
+            let right_id = nodes.add(Default::default());
+
+            build(
+                nodes,
+                out,
+                left_id,
+                left_refs,
+                root_area,
+                max_depth,
+                depth + 1,
+            );
+
+            build(
+                nodes,
+                out,
+                right_id,
+                right_refs,
+                root_area,
+                max_depth,
+                depth + 1,
+            );
+
+            let end = BvhPrimitiveId::new(out.len() as u32);
+
+            nodes[id] = BvhNode::Internal {
+                bounds,
+                primitives_ref: BvhPrimitivesRef::new(start, end),
+                left_id,
+                left_hash: BvhNodeHash::new(0),
+                right_id,
+                right_hash: BvhNodeHash::new(0),
+            };
+
+            return;
+        }
+
+        // Partitioning can (rarely) put all references on one side due to
+        // floating-point inaccuracies - in that case let's just bail out and
+        // create a leaf
+        out.extend(left_refs.into_iter().chain(right_refs).map(|r| r.prim));
+    } else {
+        out.extend(refs.into_iter().map(|r| r.prim));
+    }
+
+    let end = BvhPrimitiveId::new(out.len() as u32);
+
+    nodes[id] = BvhNode::Leaf {
+        bounds,
+        primitives_ref: BvhPrimitivesRef::new(start, end),
+    };
+}
+
+fn find_split(
+    refs: &[Reference],
+    bounds: BoundingBox,
+    root_area: f32,
+) -> Option<Split> {
+    let object_split = find_object_split(refs);
+
+    let should_try_spatial_split = object_split
+        .map_or(true, |split| split.overlap > MIN_OVERLAP * root_area);
+
+    let spatial_split = if should_try_spatial_split {
+        find_spatial_split(refs, bounds)
+    } else {
+        None
+    };
+
+    match (object_split, spatial_split) {
+        (Some(object), Some(spatial)) => {
+            if spatial.cost < object.cost {
+                Some(spatial)
+            } else {
+                Some(object)
+            }
+        }
+        (object, spatial) => object.or(spatial),
+    }
+}
+
+/// Finds the best object split, i.e. a split that puts each reference into
+/// exactly one child, depending on where its center lies.
+fn find_object_split(refs: &[Reference]) -> Option<Split> {
+    let centroid_bb: BoundingBox = refs.iter().map(|r| r.prim.center).collect();
+    let scale = (BINS as f32) / centroid_bb.extent();
+
+    let mut bins = [[Bin::default(); BINS]; 3];
+
+    for r in refs {
+        let bin_id = scale * (r.prim.center - centroid_bb.min());
+        let bin_id = bin_id.as_uvec3().min(UVec3::splat((BINS as u32) - 1));
+
+        for axis in 0..3 {
+            let bin = &mut bins[axis][bin_id[axis] as usize];
+
+            bin.bounds += r.prim.bounds;
+            bin.entries += 1;
+            bin.exits += 1;
+        }
+    }
+
+    let scale = centroid_bb.extent() / (BINS as f32);
+
+    find_best_split(&bins, |axis, bin_idx| {
+        let axis = Axis::from(axis);
+
+        SplitKind::Object {
+            axis,
+            position: centroid_bb.min()[axis]
+                + scale[axis] * ((bin_idx + 1) as f32),
+        }
+    })
+}
+
+/// Finds the best spatial split, i.e. a split that clips references crossing
+/// the splitting plane and puts the clipped parts into both children.
+fn find_spatial_split(
+    refs: &[Reference],
+    bounds: BoundingBox,
+) -> Option<Split> {
+    let mut bins = [[Bin::default(); BINS]; 3];
+    let bin_size = bounds.extent() / (BINS as f32);
+
+    for axis in 0..3 {
+        let axis_min = bounds.min()[Axis::from(axis)];
+        let bin_size = bin_size[Axis::from(axis)];
+
+        if bin_size <= 0.0 {
+            continue;
+        }
+
+        let bin_of = |value: f32| {
+            (((value - axis_min) / bin_size) as usize).min(BINS - 1)
+        };
+
+        for r in refs {
+            let first_bin = bin_of(r.prim.bounds.min()[Axis::from(axis)]);
+            let last_bin = bin_of(r.prim.bounds.max()[Axis::from(axis)]);
+
+            for bin_idx in first_bin..=last_bin {
+                let bin_min = axis_min + bin_size * (bin_idx as f32);
+                let bin_max = bin_min + bin_size;
+
+                let clipped = r.clip(Axis::from(axis), bin_min, bin_max);
+
+                if clipped.is_set() {
+                    bins[axis][bin_idx].bounds += clipped;
+                }
+            }
+
+            bins[axis][first_bin].entries += 1;
+            bins[axis][last_bin].exits += 1;
+        }
+    }
+
+    find_best_split(&bins, |axis, bin_idx| {
+        let axis = Axis::from(axis);
+
+        SplitKind::Spatial {
+            axis,
+            position: bounds.min()[axis]
+                + bin_size[axis] * ((bin_idx + 1) as f32),
+        }
+    })
+}
+
+/// Sweeps over bins and finds the split with the lowest SAH cost.
+fn find_best_split(
+    bins: &[[Bin; BINS]; 3],
+    kind: impl Fn(usize, usize) -> SplitKind,
+) -> Option<Split> {
+    let mut best: Option<Split> = None;
+
+    for (axis, bins) in bins.iter().enumerate() {
+        let mut right_bb = [BoundingBox::default(); BINS];
+        let mut right_count = [0; BINS];
+        let mut acc_bb = BoundingBox::default();
+        let mut acc_count = 0;
+
+        for bin_idx in (1..BINS).rev() {
+            if bins[bin_idx].bounds.is_set() {
+                acc_bb += bins[bin_idx].bounds;
+            }
+
+            acc_count += bins[bin_idx].exits;
+
+            right_bb[bin_idx] = acc_bb;
+            right_count[bin_idx] = acc_count;
+        }
+
+        let mut left_bb = BoundingBox::default();
+        let mut left_count = 0;
+
+        for bin_idx in 0..(BINS - 1) {
+            if bins[bin_idx].bounds.is_set() {
+                left_bb += bins[bin_idx].bounds;
+            }
+
+            left_count += bins[bin_idx].entries;
+
+            let right_bb = right_bb[bin_idx + 1];
+            let right_count = right_count[bin_idx + 1];
+
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = (left_count as f32) * left_bb.half_area()
+                + (right_count as f32) * right_bb.half_area();
+
+            if best.map_or(true, |best| cost < best.cost) {
+                let overlap = left_bb.intersection(right_bb);
+
+                best = Some(Split {
+                    kind: kind(axis, bin_idx),
+                    cost,
+                    overlap: if overlap.is_set() {
+                        overlap.half_area()
+                    } else {
+                        0.0
+                    },
+                });
+            }
+        }
+    }
+
+    best
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Reference {
+    prim: BvhPrimitive,
+    positions: [Vec3; 3],
+}
+
+impl Reference {
+    /// Returns bounds of the part of this reference's triangle that lies
+    /// within given slab.
+    fn clip(&self, axis: Axis, min: f32, max: f32) -> BoundingBox {
+        let mut bounds = BoundingBox::default();
+
+        for i in 0..3 {
+            let a = self.positions[i];
+            let b = self.positions[(i + 1) % 3];
+
+            if a[axis] >= min && a[axis] <= max {
+                bounds += a;
+            }
+
+            for plane in [min, max] {
+                if (a[axis] < plane && b[axis] > plane)
+                    || (a[axis] > plane && b[axis] < plane)
+                {
+                    let t = (plane - a[axis]) / (b[axis] - a[axis]);
+                    let mut point = a.lerp(b, t);
+
+                    // Make sure the point lies exactly on the plane, so that
+                    // numerical errors don't make the bounds leak outside
+                    point[axis] = plane;
+                    bounds += point;
+                }
+            }
+        }
+
+        if bounds.is_set() {
+            bounds.intersection(self.prim.bounds)
+        } else {
+            bounds
+        }
+    }
+
+    fn with_bounds(self, bounds: BoundingBox) -> Option<Self> {
+        bounds.is_set().then_some(Self {
+            prim: BvhPrimitive {
+                center: bounds.center(),
+                bounds,
+                ..self.prim
+            },
+            ..self
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Split {
+    kind: SplitKind,
+    cost: f32,
+    overlap: f32,
+}
+
+impl Split {
+    fn partition(
+        self,
+        refs: Vec<Reference>,
+    ) -> (Vec<Reference>, Vec<Reference>) {
+        let mut left = Vec::new();
+        let mut right = Vec::new();
+
+        match self.kind {
+            SplitKind::Object { axis, position } => {
+                for r in refs {
+                    if r.prim.center[axis] < position {
+                        left.push(r);
+                    } else {
+                        right.push(r);
+                    }
+                }
+            }
+
+            SplitKind::Spatial { axis, position } => {
+                for r in refs {
+                    if r.prim.bounds.max()[axis] <= position {
+                        left.push(r);
+                    } else if r.prim.bounds.min()[axis] >= position {
+                        right.push(r);
+                    } else {
+                        // Reference straddles the splitting plane, so it gets
+                        // clipped and goes into both children
+                        let left_bb = r.clip(axis, f32::MIN, position);
+                        let right_bb = r.clip(axis, position, f32::MAX);
+
+                        left.extend(r.with_bounds(left_bb));
+                        right.extend(r.with_bounds(right_bb));
+                    }
+                }
+            }
+
+            SplitKind::Median { axis } => {
+                left = refs;
+                left.sort_by(|a, b| {
+                    a.prim.center[axis].total_cmp(&b.prim.center[axis])
+                });
+
+                right = left.split_off(left.len() / 2);
+            }
+        }
+
+        (left, right)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SplitKind {
+    Object { axis: Axis, position: f32 },
+    Spatial { axis: Axis, position: f32 },
+    Median { axis: Axis },
+}
+
+impl SplitKind {
+    fn axis(self) -> Axis {
+        match self {
+            SplitKind::Object { axis, .. }
+            | SplitKind::Spatial { axis, .. }
+            | SplitKind::Median { axis } => axis,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Debug)]
+struct Bin {
+    bounds: BoundingBox,
+    entries: u32,
+    exits: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::super::{builder, refitter, stats};
+    use super::*;
+
+    /// Creates a scene similar to `stress-bvh` - a large floor with lots of
+    /// small, rotated boxes scattered on top of it, plus a couple of long
+    /// walls.
+    fn scene() -> Vec<(BvhPrimitive, [Vec3; 3])> {
+        let mut triangles = Vec::new();
+
+        let mut add = |positions: [Vec3; 3]| {
+            let bounds: BoundingBox = positions.into_iter().collect();
+
+            triangles.push((
+                BvhPrimitive {
+                    id: triangles.len() as u32,
+                    center: bounds.center(),
+                    bounds,
+                },
+                positions,
+            ));
+        };
+
+        // Floor
+        add([
+            vec3(-50.0, 0.0, -50.0),
+            vec3(50.0, 0.0, -50.0),
+            vec3(50.0, 0.0, 50.0),
+        ]);
+        add([
+            vec3(-50.0, 0.0, -50.0),
+            vec3(50.0, 0.0, 50.0),
+            vec3(-50.0, 0.0, 50.0),
+        ]);
+
+        // Walls
+        for z in [-50.0, 50.0] {
+            add([vec3(-50.0, 0.0, z), vec3(50.0, 0.0, z), vec3(50.0, 10.0, z)]);
+            add([
+                vec3(-50.0, 0.0, z),
+                vec3(50.0, 10.0, z),
+                vec3(-50.0, 10.0, z),
+            ]);
+        }
+
+        // Boxes (just their diagonal slices, which is enough for our purposes)
+        for x in 0..16 {
+            for z in 0..16 {
+                let center =
+                    vec3(x as f32 * 6.0 - 45.0, 1.0, z as f32 * 6.0 - 45.0);
+
+                add([
+                    center + vec3(-0.5, -0.5, -0.5),
+                    center + vec3(0.5, -0.5, 0.5),
+                    center + vec3(0.5, 0.5, 0.5),
+                ]);
+
+                add([
+                    center + vec3(-0.5, -0.5, -0.5),
+                    center + vec3(0.5, 0.5, 0.5),
+                    center + vec3(-0.5, 0.5, -0.5),
+                ]);
+            }
+        }
+
+        triangles
+    }
+
+    fn collect(
+        nodes: &BvhNodes,
+        primitives: &BvhPrimitives,
+        id: BvhNodeId,
+        out: &mut Vec<BvhPrimitive>,
+    ) {
+        match nodes[id] {
+            BvhNode::Internal {
+                left_id, right_id, ..
+            } => {
+                collect(nodes, primitives, left_id, out);
+                collect(nodes, primitives, right_id, out);
+            }
+
+            BvhNode::Leaf {
+                bounds,
+                primitives_ref,
+            } => {
+                for prim in primitives.current(primitives_ref) {
+                    assert!(prim.bounds.min().cmpge(bounds.min()).all());
+                    assert!(prim.bounds.max().cmple(bounds.max()).all());
+
+                    out.push(*prim);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reachability() {
+        let scene = scene();
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        run(&mut nodes, &mut primitives, scene.iter().copied(), 16);
+
+        let mut refs = Vec::new();
+
+        collect(&nodes, &primitives, BvhNodeId::root(), &mut refs);
+
+        // Spatial splits should've kicked in
+        assert!(refs.len() > scene.len());
+        assert!(stats::validate(&nodes, &primitives).depth <= 16);
+
+        // Each triangle has to be reachable and its references have to cover
+        // the entire triangle
+        for (prim, _) in &scene {
+            let bounds: BoundingBox = refs
+                .iter()
+                .filter(|r| r.id == prim.id)
+                .map(|r| r.bounds)
+                .collect();
+
+            assert!(bounds.is_set(), "triangle #{} is not reachable", prim.id);
+
+            assert!(
+                (bounds.min() - prim.bounds.min()).abs().max_element() < 0.001
+                    && (bounds.max() - prim.bounds.max()).abs().max_element()
+                        < 0.001,
+                "triangle #{} is not fully covered",
+                prim.id
+            );
+        }
+    }
+
+    #[test]
+    fn sah_cost() {
+        let scene = scene();
+
+        let object_cost = {
+            let mut nodes = BvhNodes::default();
+            let mut primitives = BvhPrimitives::default();
+
+            for (prim, _) in &scene {
+                primitives.add(*prim);
+            }
+
+            primitives.begin_refresh();
+            builder::run(&mut nodes, &mut primitives, 16);
+            refitter::cost(&nodes)
+        };
+
+        let spatial_cost = {
+            let mut nodes = BvhNodes::default();
+            let mut primitives = BvhPrimitives::default();
+
+            run(&mut nodes, &mut primitives, scene.iter().copied(), 16);
+            refitter::cost(&nodes)
+        };
+
+        assert!(
+            spatial_cost < object_cost,
+            "spatial_cost={spatial_cost}, object_cost={object_cost}"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use super::{BvhNode, BvhNodeId, BvhNodes, BvhPrimitives};
+
+/// Statistics of a tree, as collected by [`validate()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BvhStats {
+    /// Number of internal nodes on the longest path from the root to a leaf;
+    /// this is also the number of stack entries needed to traverse the tree
+    /// when it's serialized as [`super::BvhLayout::Binary`].
+    pub depth: u32,
+
+    /// Number of internal nodes reachable from the root.
+    pub internal_nodes: usize,
+
+    /// Number of leaves reachable from the root.
+    pub leaves: usize,
+
+    /// Number of primitives reachable from the root.
+    ///
+    /// Note that with spatial splits the same primitive can be referenced by
+    /// many leaves, in which case it's counted many times.
+    pub primitives: usize,
+
+    /// Largest number of primitives in a single leaf.
+    pub max_leaf_size: usize,
+}
+
+/// Walks the tree, checking its invariants and collecting its statistics.
+///
+/// Panics if the tree is malformed, e.g. when an internal node's primitives
+/// don't match its children's primitives or when a primitive lies outside
+/// of its leaf.
+pub fn validate(nodes: &BvhNodes, primitives: &BvhPrimitives) -> BvhStats {
+    let mut stats = BvhStats::default();
+
+    if !nodes.nodes.is_empty() {
+        visit(nodes, primitives, BvhNodeId::root(), 0, &mut stats);
+    }
+
+    stats
+}
+
+fn visit(
+    nodes: &BvhNodes,
+    primitives: &BvhPrimitives,
+    id: BvhNodeId,
+    depth: u32,
+    stats: &mut BvhStats,
+) {
+    match nodes[id] {
+        BvhNode::Internal {
+            primitives_ref,
+            left_id,
+            right_id,
+            ..
+        } => {
+            let left_ref = nodes[left_id].primitives_ref();
+            let right_ref = nodes[right_id].primitives_ref();
+
+            assert!(
+                left_ref.start() == primitives_ref.start()
+                    && left_ref.end() == right_ref.start()
+                    && right_ref.end() == primitives_ref.end(),
+                "node {id:?} has primitives {:?}, but its children have {:?} \
+                 and {:?}",
+                primitives_ref.as_range(),
+                left_ref.as_range(),
+                right_ref.as_range(),
+            );
+
+            stats.internal_nodes += 1;
+
+            visit(nodes, primitives, left_id, depth + 1, stats);
+            visit(nodes, primitives, right_id, depth + 1, stats);
+        }
+
+        BvhNode::Leaf {
+            bounds,
+            primitives_ref,
+        } => {
+            // Root's bounds are not tracked, so there's nothing to check there
+            if id != BvhNodeId::root() {
+                for primitive in primitives.current(primitives_ref) {
+                    assert!(
+                        primitive.bounds.min().cmpge(bounds.min()).all()
+                            && primitive.bounds.max().cmple(bounds.max()).all(),
+                        "primitive {} lies outside of its leaf {id:?}",
+                        primitive.id,
+                    );
+                }
+            }
+
+            stats.depth = stats.depth.max(depth);
+            stats.leaves += 1;
+            stats.primitives += primitives_ref.len();
+            stats.max_leaf_size = stats.max_leaf_size.max(primitives_ref.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::super::{builder, BvhPrimitive, BLAS_STACK_BUDGET};
+    use super::*;
+    use crate::{gpu, BoundingBox};
+
+    #[test]
+    fn depth_limit() {
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        // Primitives that get exponentially closer to each other - for SAH
+        // the best split is to peel off one primitive at a time, which
+        // produces a tree as deep as the number of primitives
+        for i in 0..256 {
+            let center = vec3(0.5f32.powi(i), 0.0, 0.0);
+
+            primitives.add(BvhPrimitive {
+                id: i as u32,
+                center,
+                bounds: BoundingBox::new(center, center + 1e-9),
+            });
+        }
+
+        for max_depth in [4, 12, 24] {
+            primitives.begin_refresh();
+            builder::run(&mut nodes, &mut primitives, max_depth);
+
+            let stats = validate(&nodes, &primitives);
+
+            assert!(stats.depth <= max_depth, "{max_depth}: {stats:?}");
+            assert_eq!(256, stats.primitives, "{max_depth}: {stats:?}");
+
+            primitives.end_refresh();
+        }
+    }
+
+    #[test]
+    fn stack_budget_leaves_room_for_instances() {
+        let mut nodes = BvhNodes::default();
+        let mut primitives = BvhPrimitives::default();
+
+        // Grid of ~10k instances, as seen by the top-level BVH
+        let mut id = 0;
+
+        for x in 0..22 {
+            for y in 0..22 {
+                for z in 0..22 {
+                    let center = vec3(x as f32, y as f32, z as f32) * 3.0;
+
+                    primitives.add(BvhPrimitive {
+                        id,
+                        center,
+                        bounds: BoundingBox::new(center - 0.5, center + 0.5),
+                    });
+
+                    id += 1;
+                }
+            }
+        }
+
+        // Bottom-level BVHs might use up their entire budget, so that's what
+        // the top-level BVH is left with in the worst case
+        let tlas_budget = gpu::BVH_STACK_SIZE as u32 - BLAS_STACK_BUDGET;
+
+        primitives.begin_refresh();
+        builder::run(&mut nodes, &mut primitives, tlas_budget);
+
+        let stats = validate(&nodes, &primitives);
+
+        assert_eq!(22 * 22 * 22, stats.primitives);
+        assert!(stats.depth <= tlas_budget);
+        assert!(stats.max_leaf_size <= 4, "{stats:?}");
+    }
+}
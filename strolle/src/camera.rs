@@ -5,12 +5,35 @@ use spirv_std::glam::{uvec2, Mat4, UVec2, Vec3};
 
 use crate::gpu;
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 pub struct Camera {
     pub mode: CameraMode,
     pub viewport: CameraViewport,
     pub transform: Mat4,
     pub projection: Mat4,
+
+    /// Which instances this camera can see - an instance is visible only if
+    /// its visibility (see: [`crate::Instance::with_visibility()`]) overlaps
+    /// this mask.
+    ///
+    /// Applies to all rays traced for this camera, i.e. primary, shadow and
+    /// indirect ones.
+    pub visibility: u32,
+
+    pub exposure: Exposure,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            viewport: Default::default(),
+            transform: Default::default(),
+            projection: Default::default(),
+            visibility: u32::MAX,
+            exposure: Default::default(),
+        }
+    }
 }
 
 impl Camera {
@@ -60,8 +83,21 @@ impl Camera {
                 .viewport
                 .size
                 .as_vec2()
-                .extend(Default::default())
-                .extend(Default::default()),
+                .extend(f32::from_bits(self.visibility))
+                .extend(self.pixel_spread_angle()),
+        }
+    }
+
+    /// Returns the angle between rays cast through the neighbouring pixels
+    /// (zero for orthographic projections, where all rays are parallel).
+    fn pixel_spread_angle(&self) -> f32 {
+        let is_perspective = self.projection.w_axis.w == 0.0;
+
+        if is_perspective {
+            (2.0 / (self.projection.y_axis.y * self.viewport.size.y as f32))
+                .atan()
+        } else {
+            0.0
         }
     }
 }
@@ -167,6 +203,51 @@ impl Default for CameraMode {
     }
 }
 
+/// Camera's exposure, i.e. how much the light reaching the camera gets scaled
+/// before it's displayed.
+///
+/// Exposure is described by its EV100 value (exposure value at ISO 100) - the
+/// higher the value, the darker the image; e.g. a sunny day is about 15, while
+/// an indoor scene is about 7.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exposure {
+    pub ev100: f32,
+}
+
+impl Exposure {
+    /// Exposure that doesn't scale the light at all, matching renderers that
+    /// don't model camera's exposure (such as Bevy).
+    pub const NEUTRAL: Self = Self {
+        ev100: -0.263_034_4,
+    };
+
+    pub fn from_ev100(ev100: f32) -> Self {
+        Self { ev100 }
+    }
+
+    /// Creates exposure out of physical camera's settings: aperture (as an
+    /// f-number), shutter speed (in seconds) and sensitivity (ISO).
+    pub fn from_physical(aperture: f32, shutter_speed: f32, iso: f32) -> Self {
+        Self {
+            ev100: (aperture * aperture / shutter_speed * 100.0 / iso).log2(),
+        }
+    }
+
+    /// Returns the factor the light reaching the camera gets multiplied by.
+    ///
+    /// Follows "Moving Frostbite to Physically Based Rendering" - the maximum
+    /// luminance that doesn't saturate the sensor maps to 1.0.
+    pub fn multiplier(&self) -> f32 {
+        1.0 / (1.2 * self.ev100.exp2())
+    }
+}
+
+impl Default for Exposure {
+    fn default() -> Self {
+        Self::NEUTRAL
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct CameraViewport {
     pub format: wgpu::TextureFormat,
@@ -197,3 +278,56 @@ impl CameraHandle {
         Self(id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposure() {
+        assert!((Exposure::NEUTRAL.multiplier() - 1.0).abs() < 1e-5);
+
+        // "Sunny 16" rule
+        let sunny = Exposure::from_physical(16.0, 1.0 / 100.0, 100.0);
+
+        assert!((sunny.ev100 - 14.64).abs() < 0.01, "{}", sunny.ev100);
+
+        // Doubling the sensitivity makes the image twice as bright
+        let a = Exposure::from_physical(2.8, 1.0 / 60.0, 400.0);
+        let b = Exposure::from_physical(2.8, 1.0 / 60.0, 800.0);
+
+        assert!((b.multiplier() / a.multiplier() - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pixel_spread_angle() {
+        let camera = |projection| Camera {
+            viewport: CameraViewport {
+                size: uvec2(2, 2),
+                ..Default::default()
+            },
+            projection,
+            ..Default::default()
+        };
+
+        // With 90° of vertical field of view and two pixels, each pixel covers
+        // 45°
+        let perspective = camera(Mat4::perspective_rh(
+            std::f32::consts::FRAC_PI_2,
+            1.0,
+            0.1,
+            100.0,
+        ));
+
+        assert!(
+            (perspective.pixel_spread_angle() - std::f32::consts::FRAC_PI_4)
+                .abs()
+                < 1e-5
+        );
+
+        let orthographic =
+            camera(Mat4::orthographic_rh(-1.0, 1.0, -1.0, 1.0, 0.1, 100.0));
+
+        assert_eq!(0.0, orthographic.pixel_spread_angle());
+    }
+}
@@ -152,7 +152,7 @@ impl CameraBuffers {
             StorageBuffer::new(
                 device,
                 format!("di_reservoir_{}", idx),
-                viewport_buffer_size(2 * 4 * 4),
+                viewport_buffer_size(3 * 4 * 4),
             )
         });
 
@@ -274,14 +274,14 @@ impl CameraBuffers {
         let ref_rays = StorageBuffer::new(
             device,
             "ref_rays",
-            viewport_buffer_size(3 * 4 * 4),
+            viewport_buffer_size(4 * 4 * 4),
         );
 
         // TODO initialize lazily
         let ref_hits = StorageBuffer::new(
             device,
             "ref_hits",
-            viewport_buffer_size(2 * 4 * 4),
+            viewport_buffer_size(3 * 4 * 4),
         );
 
         // TODO initialize lazily
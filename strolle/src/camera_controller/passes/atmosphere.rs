@@ -1,8 +1,8 @@
 use std::sync::Mutex;
 
 use crate::{
-    gpu, Camera, CameraBuffers, CameraComputePass, CameraController, Engine,
-    Params,
+    gpu, Camera, CameraBuffers, CameraComputePass, CameraController,
+    CelestialBody, Engine, Params,
 };
 
 #[derive(Debug)]
@@ -12,7 +12,7 @@ pub struct AtmospherePass {
     generate_sky_lut_pass: CameraComputePass<()>,
 
     is_initialized: Mutex<bool>,
-    known_sun_altitude: Mutex<Option<f32>>,
+    known_celestial_bodies: Mutex<Option<Vec<CelestialBody>>>,
 }
 
 impl AtmospherePass {
@@ -60,7 +60,7 @@ impl AtmospherePass {
             generate_sky_lut_pass,
 
             is_initialized: Mutex::new(false),
-            known_sun_altitude: Mutex::new(None),
+            known_celestial_bodies: Mutex::new(None),
         }
     }
 
@@ -73,7 +73,8 @@ impl AtmospherePass {
         P: Params,
     {
         let mut is_initialized = self.is_initialized.lock().unwrap();
-        let mut known_sun_altitude = self.known_sun_altitude.lock().unwrap();
+        let mut known_celestial_bodies =
+            self.known_celestial_bodies.lock().unwrap();
 
         // Transmittance and scattering don't depend on anything so it's enough
         // if we just generate them once, the first time they are needed
@@ -95,10 +96,9 @@ impl AtmospherePass {
             *is_initialized = true;
         }
 
-        // On the other hand, the sky lookup texture depends on sun's altitude
-        if known_sun_altitude
-            .map_or(true, |altitude| altitude != engine.sun.altitude)
-        {
+        // On the other hand, the sky lookup texture depends on the celestial
+        // bodies
+        if known_celestial_bodies.as_ref() != Some(&engine.celestial_bodies) {
             self.generate_sky_lut_pass.run(
                 camera,
                 encoder,
@@ -106,7 +106,7 @@ impl AtmospherePass {
                 (),
             );
 
-            *known_sun_altitude = Some(engine.sun.altitude);
+            *known_celestial_bodies = Some(engine.celestial_bodies.clone());
         }
     }
 }
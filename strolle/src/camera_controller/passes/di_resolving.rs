@@ -23,6 +23,7 @@ impl DiResolvingPass {
                 &engine.bvh.bind_readable(),
                 &engine.materials.bind_readable(),
                 &engine.lights.bind_readable(),
+                &engine.lights.bind_profiles(),
                 &engine.images.bind_atlas(),
                 &engine.world.bind_readable(),
             ])
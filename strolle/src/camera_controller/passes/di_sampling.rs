@@ -24,6 +24,8 @@ impl DiSamplingPass {
                 &engine.bvh.bind_readable(),
                 &engine.materials.bind_readable(),
                 &engine.lights.bind_readable(),
+                &engine.lights.bind_profiles(),
+                &engine.lights.bind_tree(),
                 &engine.images.bind_atlas(),
                 &engine.world.bind_readable(),
             ])
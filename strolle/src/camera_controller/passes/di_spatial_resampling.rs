@@ -29,7 +29,11 @@ impl DiSpatialResamplingPass {
 
         let pick_pass =
             CameraComputePass::builder("di_spatial_resampling_pick")
-                .bind([&engine.lights.bind_readable()])
+                .bind([
+                    &engine.lights.bind_readable(),
+                    &engine.lights.bind_profiles(),
+                    &engine.lights.bind_profiles(),
+                ])
                 .bind([
                     &buffers.curr_camera.bind_readable(),
                     &buffers.prim_gbuffer_d0.curr().bind_readable(),
@@ -18,7 +18,11 @@ impl DiTemporalResamplingPass {
         P: Params,
     {
         let pass = CameraComputePass::builder("di_temporal_resampling")
-            .bind([&engine.lights.bind_readable()])
+            .bind([
+                &engine.lights.bind_readable(),
+                &engine.lights.bind_profiles(),
+                &engine.lights.bind_profiles(),
+            ])
             .bind([
                 &buffers.curr_camera.bind_readable(),
                 &buffers.prev_camera.bind_readable(),
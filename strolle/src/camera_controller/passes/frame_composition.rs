@@ -120,6 +120,7 @@ impl FrameCompositionPass {
 
         let params = gpu::FrameCompositionPassParams {
             camera_mode: camera.camera.mode.serialize(),
+            exposure: camera.camera.exposure.multiplier(),
         };
 
         pass.set_scissor_rect(
@@ -43,6 +43,8 @@ impl GiSamplingPass {
                 &engine.triangles.bind_readable(),
                 &engine.bvh.bind_readable(),
                 &engine.lights.bind_readable(),
+                &engine.lights.bind_profiles(),
+                &engine.lights.bind_tree(),
                 &engine.materials.bind_readable(),
                 &engine.images.bind_atlas(),
                 &engine.world.bind_readable(),
@@ -4,6 +4,7 @@ use std::ops::Range;
 use glam::vec4;
 use log::debug;
 
+use crate::gpu::Affine3AExt;
 use crate::{
     gpu, BindGroup, Camera, CameraBuffers, CameraController, Engine, Params,
 };
@@ -193,23 +194,30 @@ impl PrimRasterPass {
         pass.set_bind_group(0, self.bg0.get(alternate), &[]);
         pass.set_bind_group(1, self.bg1.get(alternate), &[]);
 
-        for (instance_handle, instance_entry) in engine.instances.iter() {
+        for (_, instance_entry) in engine.instances.iter() {
             let instance = &instance_entry.instance;
 
+            if !instance.visible_to_camera
+                || instance.visibility & camera.camera.visibility == 0
+            {
+                continue;
+            }
+
             let Some(material_id) =
                 engine.materials.lookup(instance.material_handle)
             else {
                 continue;
             };
 
-            let params = {
-                let curr_xform_inv = gpu::PrimRasterPassParams::encode_affine(
-                    instance.transform_inverse,
-                );
+            let Some((vertices, vertex_buffer)) =
+                engine.triangles.as_vertex_buffer(instance.mesh_handle)
+            else {
+                continue;
+            };
 
-                let prev_xform = gpu::PrimRasterPassParams::encode_affine(
-                    instance_entry.prev_transform,
-                );
+            let params = {
+                let curr_xform = instance.transform.encode();
+                let prev_xform = instance_entry.prev_transform.encode();
 
                 gpu::PrimRasterPassParams {
                     payload: vec4(
@@ -218,21 +226,15 @@ impl PrimRasterPass {
                         Default::default(),
                         Default::default(),
                     ),
-                    curr_xform_inv_d0: curr_xform_inv[0],
-                    curr_xform_inv_d1: curr_xform_inv[1],
-                    curr_xform_inv_d2: curr_xform_inv[2],
+                    curr_xform_d0: curr_xform[0],
+                    curr_xform_d1: curr_xform[1],
+                    curr_xform_d2: curr_xform[2],
                     prev_xform_d0: prev_xform[0],
                     prev_xform_d1: prev_xform[1],
                     prev_xform_d2: prev_xform[2],
                 }
             };
 
-            let Some((vertices, vertex_buffer)) =
-                engine.triangles.as_vertex_buffer(instance_handle)
-            else {
-                continue;
-            };
-
             pass.set_vertex_buffer(0, vertex_buffer);
 
             pass.set_push_constants(
@@ -25,6 +25,8 @@ impl RefShadingPass {
                 &engine.triangles.bind_readable(),
                 &engine.bvh.bind_readable(),
                 &engine.lights.bind_readable(),
+                &engine.lights.bind_profiles(),
+                &engine.lights.bind_tree(),
                 &engine.materials.bind_readable(),
                 &engine.images.bind_atlas(),
                 &engine.world.bind_readable(),
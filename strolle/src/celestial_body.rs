@@ -0,0 +1,107 @@
+use glam::Vec3;
+
+use crate::{gpu, Light};
+
+/// Celestial body lighting up the sky, e.g. the sun or the moon.
+///
+/// Celestial bodies affect only the atmosphere - to make a body actually cast
+/// light onto the scene, a directional light has to be inserted for it as well
+/// (see: [`CelestialBody::light()`]).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CelestialBody {
+    /// Direction towards the body
+    pub direction: Vec3,
+
+    /// Apparent size of the body, in radians (e.g. the sun as seen from the
+    /// Earth is about 0.0093)
+    pub angular_diameter: f32,
+
+    /// Illuminance provided by the body above the atmosphere, in lux (e.g.
+    /// about 120000 for the sun and about 0.3 for the full moon)
+    pub illuminance: f32,
+}
+
+impl CelestialBody {
+    /// Maximum number of celestial bodies in the world.
+    pub const MAX: usize = gpu::World::MAX_CELESTIAL_BODIES;
+
+    /// Returns a directional light cast by this body, i.e. with the body's
+    /// light attenuated by the atmosphere.
+    ///
+    /// Bodies below the horizon don't cast any light.
+    pub fn light(&self) -> Light {
+        Light::Directional {
+            direction: -self.direction.normalize(),
+            color: self.transmittance(),
+            illuminance: self.illuminance,
+            angular_diameter: self.angular_diameter,
+        }
+    }
+
+    /// Returns how much of the body's light gets through the atmosphere, for
+    /// each color channel.
+    pub fn transmittance(&self) -> Vec3 {
+        strolle_shaders::atmosphere::generate_transmittance_lut::eval(
+            gpu::Atmosphere::VIEW_POS,
+            self.direction.normalize(),
+        )
+    }
+
+    pub(crate) fn serialize(&self) -> gpu::CelestialBody {
+        gpu::CelestialBody::new(
+            self.direction.normalize(),
+            self.angular_diameter,
+            self.illuminance,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+
+    #[test]
+    fn light() {
+        let body = |direction| CelestialBody {
+            direction,
+            angular_diameter: 0.0093,
+            illuminance: 100.0,
+        };
+
+        let Light::Directional {
+            direction,
+            color,
+            illuminance,
+            ..
+        } = body(vec3(0.0, 1.0, -1.0)).light()
+        else {
+            unreachable!();
+        };
+
+        assert!(direction.abs_diff_eq(vec3(0.0, -1.0, 1.0).normalize(), 1e-4));
+        assert!(color.min_element() > 0.0);
+        assert!(color.max_element() < 1.0);
+        assert_eq!(100.0, illuminance);
+
+        // Light travelling through more atmosphere gets dimmer and redder
+        let Light::Directional { color: low, .. } =
+            body(vec3(0.0, 0.05, -1.0)).light()
+        else {
+            unreachable!();
+        };
+
+        assert!(low.z < color.z);
+        assert!(low.x / low.z > color.x / color.z);
+
+        // Bodies below the horizon don't cast any light at all
+        let Light::Directional { color, .. } =
+            body(vec3(0.0, -0.5, -1.0)).light()
+        else {
+            unreachable!();
+        };
+
+        assert_eq!(Vec3::ZERO, color);
+    }
+}
@@ -0,0 +1,32 @@
+use crate::BvhLayout;
+
+/// Engine's configuration; unlike cameras, it cannot be changed once the
+/// engine has been created.
+#[derive(Clone, Debug, Default)]
+pub struct EngineConfig {
+    /// Layout of the BVH used for ray tracing; see [`BvhLayout`].
+    pub bvh_layout: BvhLayout,
+
+    /// Whether bottom-level BVHs (i.e. trees of meshes' triangles) should be
+    /// built with spatial splits.
+    ///
+    /// Spatial splits can considerably improve ray tracing performance for
+    /// meshes that contain large or long, thin triangles (e.g. walls), at the
+    /// expense of slower builds and larger trees.
+    pub bvh_spatial_splits: bool,
+
+    /// Whether the top-level BVH (i.e. the tree of instances) should be rebuilt
+    /// on a worker thread.
+    ///
+    /// When enabled, changes to instances don't block [`crate::Engine::tick()`];
+    /// instead, the engine keeps rendering the previous tree until the new one
+    /// is ready. See [`crate::Engine::bvh_generation()`] for a way to tell when
+    /// changes become visible.
+    ///
+    /// Note that this applies only to the top-level BVH - bottom-level BVHs
+    /// (i.e. trees of meshes' triangles) are still built synchronously, during
+    /// the [`crate::Engine::tick()`] that follows inserting a mesh, so adding
+    /// large meshes (that aren't in [`crate::Engine::read_bvh_cache()`]) can
+    /// still cause a hitch.
+    pub bvh_async_rebuild: bool,
+}
@@ -0,0 +1,425 @@
+//! Parser for IES (IESNA LM-63) photometric files.
+//!
+//! See: [`IesProfile`].
+
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fmt;
+
+/// Photometric profile of a light fixture, as described by an IES file.
+///
+/// Profile describes light's intensity (in candelas) in each direction, as a
+/// function of vertical angle (0° = nadir, i.e. straight down, 180° = zenith)
+/// and horizontal angle (rotation around the vertical axis) - see
+/// [`IesProfile::intensity()`].
+///
+/// Only type C photometry (which is used by the vast majority of fixtures) is
+/// supported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IesProfile {
+    vertical_angles: Vec<f32>,
+    horizontal_angles: Vec<f32>,
+
+    /// Intensities, in candelas, for each horizontal angle and then each
+    /// vertical angle
+    candelas: Vec<f32>,
+}
+
+impl IesProfile {
+    pub fn parse(text: &str) -> Result<Self, IesError> {
+        let mut lines = text.lines();
+
+        // Skip the header (keywords etc.) up to the `TILT=` line
+        let tilt = loop {
+            let line = lines.next().ok_or(IesError::MissingTilt)?;
+
+            if let Some(tilt) = line.trim().strip_prefix("TILT=") {
+                break tilt.trim().to_owned();
+            }
+        };
+
+        let mut values = lines
+            .flat_map(|line| {
+                line.split(|c: char| c.is_whitespace() || c == ',')
+            })
+            .filter(|value| !value.is_empty());
+
+        let mut next = || -> Result<f32, IesError> {
+            let value = values.next().ok_or(IesError::UnexpectedEnd)?;
+
+            value
+                .parse()
+                .map_err(|_| IesError::InvalidNumber(value.to_owned()))
+        };
+
+        if tilt == "INCLUDE" {
+            // Tilt data describes how the fixture's output changes when it's
+            // tilted, which is not applicable here - so let's just skip it
+            let _lamp_to_luminaire_geometry = next()?;
+            let tilt_angles = next()? as usize;
+
+            for _ in 0..(2 * tilt_angles) {
+                next()?;
+            }
+        }
+
+        let _lamps = next()?;
+        let _lumens_per_lamp = next()?;
+        let multiplier = next()?;
+        let vertical_angles = next()? as usize;
+        let horizontal_angles = next()? as usize;
+        let photometric_type = next()? as u32;
+        let _units_type = next()?;
+        let _width = next()?;
+        let _length = next()?;
+        let _height = next()?;
+        let ballast_factor = next()?;
+        let ballast_lamp_factor = next()?;
+        let _input_watts = next()?;
+
+        if photometric_type != 1 {
+            return Err(IesError::UnsupportedPhotometricType(photometric_type));
+        }
+
+        if vertical_angles == 0 || horizontal_angles == 0 {
+            return Err(IesError::NoAngles);
+        }
+
+        let vertical_angles = (0..vertical_angles)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let horizontal_angles = (0..horizontal_angles)
+            .map(|_| next())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let factor = multiplier * ballast_factor * ballast_lamp_factor;
+
+        let candelas = (0..(vertical_angles.len() * horizontal_angles.len()))
+            .map(|_| next().map(|candela| candela * factor))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for angles in [&vertical_angles, &horizontal_angles] {
+            if angles.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err(IesError::UnsortedAngles);
+            }
+        }
+
+        Ok(Self {
+            vertical_angles,
+            horizontal_angles,
+            candelas,
+        })
+    }
+
+    /// Returns intensity, in candelas, in given direction; angles are in
+    /// degrees.
+    ///
+    /// Directions not covered by the profile (e.g. above the horizon, for
+    /// profiles that describe only the bottom hemisphere) have zero intensity.
+    pub fn intensity(&self, vertical: f32, horizontal: f32) -> f32 {
+        let horizontal = self.fold_horizontal(horizontal);
+
+        let Some((h0, h1, ht)) =
+            lerp_params(&self.horizontal_angles, horizontal)
+        else {
+            return 0.0;
+        };
+
+        let Some((v0, v1, vt)) = lerp_params(&self.vertical_angles, vertical)
+        else {
+            return 0.0;
+        };
+
+        let candela = |h: usize, v: usize| {
+            self.candelas[h * self.vertical_angles.len() + v]
+        };
+
+        let c0 = candela(h0, v0) * (1.0 - vt) + candela(h0, v1) * vt;
+        let c1 = candela(h1, v0) * (1.0 - vt) + candela(h1, v1) * vt;
+
+        c0 * (1.0 - ht) + c1 * ht
+    }
+
+    /// Returns the highest intensity, in candelas, within this profile.
+    pub fn max_intensity(&self) -> f32 {
+        self.candelas.iter().copied().fold(0.0, f32::max)
+    }
+
+    /// Returns the total luminous power, in lumens, emitted according to this
+    /// profile (i.e. its intensity integrated over the sphere).
+    ///
+    /// Using this as light's luminous power makes the light reproduce the
+    /// profile's absolute intensities.
+    pub fn luminous_power(&self) -> f32 {
+        const STEPS: usize = 180;
+
+        let dv = PI / (STEPS as f32);
+        let dh = 2.0 * PI / ((2 * STEPS) as f32);
+        let mut power = 0.0;
+
+        for v in 0..STEPS {
+            let vertical = (v as f32 + 0.5) * dv;
+
+            for h in 0..(2 * STEPS) {
+                let horizontal = (h as f32 + 0.5) * dh;
+
+                power += self
+                    .intensity(vertical.to_degrees(), horizontal.to_degrees())
+                    * vertical.sin()
+                    * dv
+                    * dh;
+            }
+        }
+
+        power
+    }
+
+    /// Resamples this profile into a `width` x `height` table of intensities
+    /// relative to profile's average intensity (i.e. `luminous_power / 4π`).
+    ///
+    /// This way a light with luminous power of `P` emits `P` lumens no matter
+    /// its profile, and a light with luminous power equal to
+    /// [`Self::luminous_power()`] emits exactly the profile's candelas.
+    ///
+    /// Columns span horizontal angles from 0° (inclusive) to 360° (exclusive),
+    /// rows span vertical angles from 0° to 180° (both inclusive).
+    pub(crate) fn table(&self, width: usize, height: usize) -> Vec<f32> {
+        let avg = self.luminous_power() / (4.0 * PI);
+        let norm = if avg > 0.0 { 1.0 / avg } else { 0.0 };
+
+        (0..height)
+            .flat_map(|y| {
+                let vertical = 180.0 * (y as f32) / ((height - 1) as f32);
+
+                (0..width).map(move |x| {
+                    let horizontal = 360.0 * (x as f32) / (width as f32);
+
+                    self.intensity(vertical, horizontal) * norm
+                })
+            })
+            .collect()
+    }
+
+    /// Maps given horizontal angle into the range covered by the profile,
+    /// exploiting the profile's symmetry.
+    fn fold_horizontal(&self, horizontal: f32) -> f32 {
+        let mut horizontal = horizontal.rem_euclid(360.0);
+        let first = self.horizontal_angles[0];
+        let last = self.horizontal_angles[self.horizontal_angles.len() - 1];
+
+        if self.horizontal_angles.len() == 1 {
+            // Profile is symmetric around the vertical axis
+            return first;
+        }
+
+        if first == 90.0 && last == 270.0 {
+            // Profile is symmetric around the 90°-270° plane
+            if !(90.0..=270.0).contains(&horizontal) {
+                horizontal = (180.0 - horizontal).rem_euclid(360.0);
+            }
+
+            return horizontal;
+        }
+
+        if last <= 180.0 && horizontal > 180.0 {
+            // Profile is symmetric around the 0°-180° plane
+            horizontal = 360.0 - horizontal;
+        }
+
+        if last <= 90.0 && horizontal > 90.0 {
+            // Profile is symmetric in each quadrant
+            horizontal = 180.0 - horizontal;
+        }
+
+        horizontal
+    }
+}
+
+/// Finds angles surrounding given angle, returning their indices together with
+/// the interpolation factor between them.
+fn lerp_params(angles: &[f32], angle: f32) -> Option<(usize, usize, f32)> {
+    let first = angles[0];
+    let last = angles[angles.len() - 1];
+
+    if angles.len() == 1 {
+        return Some((0, 0, 0.0));
+    }
+
+    if angle < first || angle > last {
+        return None;
+    }
+
+    let idx = angles
+        .windows(2)
+        .position(|pair| angle <= pair[1])
+        .unwrap_or(angles.len() - 2);
+
+    let t = (angle - angles[idx]) / (angles[idx + 1] - angles[idx]);
+
+    Some((idx, idx + 1, t))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IesError {
+    MissingTilt,
+    UnexpectedEnd,
+    InvalidNumber(String),
+    UnsupportedPhotometricType(u32),
+    NoAngles,
+    UnsortedAngles,
+}
+
+impl fmt::Display for IesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IesError::MissingTilt => write!(f, "missing `TILT=` line"),
+            IesError::UnexpectedEnd => write!(f, "unexpected end of file"),
+
+            IesError::InvalidNumber(value) => {
+                write!(f, "invalid number: `{value}`")
+            }
+
+            IesError::UnsupportedPhotometricType(ty) => {
+                write!(f, "unsupported photometric type: {ty}")
+            }
+
+            IesError::NoAngles => write!(f, "profile contains no angles"),
+
+            IesError::UnsortedAngles => {
+                write!(f, "profile's angles are not sorted")
+            }
+        }
+    }
+}
+
+impl Error for IesError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_approx(expected: f32, actual: f32) {
+        assert!(
+            (expected - actual).abs() < 1e-3,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn symmetric() {
+        let profile =
+            IesProfile::parse(include_str!("ies/symmetric.ies")).unwrap();
+
+        assert_approx(1000.0, profile.intensity(0.0, 0.0));
+        assert_approx(800.0, profile.intensity(33.75, 0.0));
+        assert_approx(400.0, profile.intensity(67.5, 123.0));
+
+        // The profile doesn't cover the upper hemisphere
+        assert_approx(0.0, profile.intensity(120.0, 0.0));
+
+        // Rotationally symmetric profiles don't depend on the horizontal angle
+        for horizontal in [0.0, 45.0, 200.0, 359.0] {
+            assert_approx(700.0, profile.intensity(45.0, horizontal));
+        }
+
+        assert_approx(1000.0, profile.max_intensity());
+    }
+
+    #[test]
+    fn quadrant() {
+        let profile =
+            IesProfile::parse(include_str!("ies/quadrant.ies")).unwrap();
+
+        // Candelas are scaled by the multiplier and the ballast factors
+        assert_approx(200.0, profile.intensity(0.0, 0.0));
+        assert_approx(100.0, profile.intensity(90.0, 0.0));
+        assert_approx(60.0, profile.intensity(90.0, 90.0));
+        assert_approx(80.0, profile.intensity(90.0, 45.0));
+        assert_approx(90.0, profile.intensity(90.0, 22.5));
+
+        // Quadrant-symmetric profiles mirror the first quadrant
+        for horizontal in [90.0, 270.0] {
+            assert_approx(60.0, profile.intensity(90.0, horizontal));
+        }
+
+        for horizontal in [45.0, 135.0, 225.0, 315.0] {
+            assert_approx(80.0, profile.intensity(90.0, horizontal));
+        }
+    }
+
+    #[test]
+    fn luminous_power() {
+        // Isotropic light of 100 cd emits 100 * 4π lm
+        let profile = IesProfile::parse(
+            "TILT=NONE\n1 1000 1 2 1 1 1 0 0 0\n1 1 100\n0 180\n0\n100 100",
+        )
+        .unwrap();
+
+        assert!((profile.luminous_power() / (400.0 * PI) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn table() {
+        let profile =
+            IesProfile::parse(include_str!("ies/symmetric.ies")).unwrap();
+
+        let power = profile.luminous_power();
+        let table = profile.table(4, 5);
+
+        assert_eq!(20, table.len());
+
+        // A light with profile's luminous power reproduces profile's absolute
+        // intensities; first row is the nadir, last row is the zenith
+        let intensity = |idx: usize| table[idx] * power / (4.0 * PI);
+
+        assert!((intensity(0) / 1000.0 - 1.0).abs() < 1e-3);
+        assert!((intensity(4) / 700.0 - 1.0).abs() < 1e-3);
+        assert_approx(0.0, intensity(19));
+
+        // Profile only redistributes light's power - averaged over the sphere,
+        // the table is one
+        let avg = {
+            let (width, height) = (64, 65);
+            let table = profile.table(width, height);
+            let mut sum = 0.0;
+            let mut weight = 0.0;
+
+            for y in 0..height {
+                let vertical = PI * (y as f32) / ((height - 1) as f32);
+
+                for x in 0..width {
+                    sum += table[y * width + x] * vertical.sin();
+                    weight += vertical.sin();
+                }
+            }
+
+            sum / weight
+        };
+
+        assert!((avg - 1.0).abs() < 0.02, "{avg}");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Err(IesError::MissingTilt),
+            IesProfile::parse("IESNA:LM-63-2002\n[TEST] foo\n"),
+        );
+
+        assert_eq!(
+            Err(IesError::UnexpectedEnd),
+            IesProfile::parse(include_str!("ies/truncated.ies")),
+        );
+
+        assert_eq!(
+            Err(IesError::InvalidNumber("abc".into())),
+            IesProfile::parse("TILT=NONE\n1 1000 abc"),
+        );
+
+        assert_eq!(
+            Err(IesError::UnsupportedPhotometricType(2)),
+            IesProfile::parse("TILT=NONE\n1 1000 1 1 1 2 1 0 0 0\n1 1 100"),
+        );
+    }
+}
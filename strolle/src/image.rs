@@ -7,10 +7,7 @@ where
 {
     pub(crate) data: ImageData<P>,
     pub(crate) texture_descriptor: wgpu::TextureDescriptor<'static>,
-
-    // TODO propagate sampler's addressing modes to the shader so that we know
-    //      whether the texture should be repeated, etc.
-    pub(crate) _sampler_descriptor: wgpu::SamplerDescriptor<'static>,
+    pub(crate) sampler_descriptor: wgpu::SamplerDescriptor<'static>,
 }
 
 impl<P> Image<P>
@@ -27,7 +24,7 @@ where
         Self {
             data,
             texture_descriptor,
-            _sampler_descriptor: sampler_descriptor,
+            sampler_descriptor,
         }
     }
 }
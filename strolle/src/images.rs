@@ -1,12 +1,28 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 use derivative::Derivative;
-use glam::{uvec2, vec4, Vec4};
-use guillotiere::{size2, Allocation, AtlasAllocator};
-use log::warn;
+use glam::{uvec2, uvec4, vec4, UVec2, Vec4};
+use guillotiere::{size2, Allocation, AllocatorOptions, AtlasAllocator, Size};
+use log::{info, warn};
 
-use crate::{Bindable, Image, ImageData, Params, Texture};
+use crate::{
+    gpu, BindGroup, Bindable, BufferFlushOutcome, Image, ImageData, Params,
+    Shaders, Texture,
+};
+
+/// Number of texels surrounding each image in the atlas.
+///
+/// Gutters get filled according to image's addressing mode (e.g. with the
+/// opposite edge for repeated textures) so that bilinear filtering near image's
+/// edges doesn't pick up texels of the neighbouring images - they are this
+/// wide so that at the lowest mip level there's still one texel left.
+const GUTTER: u32 = 1 << (gpu::AtlasView::MIP_LEVELS - 1);
+
+/// Allocations' positions and sizes are multiples of this, so that each mip
+/// level of the atlas contains whole texels of each image.
+const ALIGNMENT: u32 = 1 << (gpu::AtlasView::MIP_LEVELS - 1);
 
 #[derive(Derivative)]
 #[derivative(Debug)]
@@ -15,61 +31,166 @@ where
     P: Params,
 {
     #[derivative(Debug = "ignore")]
-    atlas: AtlasAllocator,
+    pages: AtlasPages,
     atlas_texture: Texture,
     atlas_changes: Vec<AtlasChange<P>>,
-    images: HashMap<P::ImageHandle, Allocation>,
-    dynamic_textures: Vec<(P::ImageTexture, Allocation)>,
+    images: HashMap<P::ImageHandle, IndexedImage>,
+    dynamic_textures: HashMap<P::ImageHandle, P::ImageTexture>,
+    pad_pass: AtlasPass,
+    downsample_pass: AtlasPass,
+
+    /// Images as they are currently laid out on the GPU, if they've been moved
+    /// since then (see: [`Self::compact()`])
+    prev_images: Option<HashMap<P::ImageHandle, IndexedImage>>,
+
+    /// Whether enough images have been removed for the atlas to fit into
+    /// fewer pages
+    needs_compaction: bool,
 }
 
 impl<P> Images<P>
 where
     P: Params,
 {
-    const ATLAS_WIDTH: u32 = 8192;
-    const ATLAS_HEIGHT: u32 = 8192;
+    const ATLAS_WIDTH: u32 = gpu::AtlasView::SIZE;
+    const ATLAS_HEIGHT: u32 = gpu::AtlasView::SIZE;
 
-    pub fn new(device: &wgpu::Device) -> Self {
-        let atlas = AtlasAllocator::new(size2(
-            Self::ATLAS_WIDTH as i32,
-            Self::ATLAS_HEIGHT as i32,
-        ));
+    pub fn new(device: &wgpu::Device, shaders: &Shaders) -> Self {
+        let pages = AtlasPages::default();
+        let atlas_texture = Self::create_atlas_texture(device, 1);
 
-        let atlas_texture = Texture::builder("atlas")
-            .with_size(uvec2(Self::ATLAS_WIDTH, Self::ATLAS_HEIGHT))
-            .with_format(wgpu::TextureFormat::Rgba8UnormSrgb)
-            .with_usage(wgpu::TextureUsages::TEXTURE_BINDING)
-            .with_usage(wgpu::TextureUsages::COPY_DST)
-            .build(device);
+        let (pad_pass, downsample_pass) =
+            Self::create_passes(device, shaders, &atlas_texture);
 
         Self {
-            atlas,
+            pages,
             atlas_texture,
             atlas_changes: Default::default(),
             images: Default::default(),
             dynamic_textures: Default::default(),
+            pad_pass,
+            downsample_pass,
+            prev_images: None,
+            needs_compaction: false,
         }
     }
 
+    fn create_atlas_texture(device: &wgpu::Device, pages: u32) -> Texture {
+        // Images are stored as-is and then decoded when sampled, depending on
+        // their color space - so while the atlas is a linear texture, it can
+        // be also viewed as an sRGB one
+        Texture::builder("atlas")
+            .with_size(uvec2(Self::ATLAS_WIDTH, Self::ATLAS_HEIGHT))
+            .with_layers(pages)
+            .with_format(wgpu::TextureFormat::Rgba8Unorm)
+            .with_view_format(wgpu::TextureFormat::Rgba8UnormSrgb)
+            .with_mip_levels(gpu::AtlasView::MIP_LEVELS)
+            .with_usage(wgpu::TextureUsages::TEXTURE_BINDING)
+            .with_usage(wgpu::TextureUsages::STORAGE_BINDING)
+            .with_usage(wgpu::TextureUsages::COPY_SRC)
+            .with_usage(wgpu::TextureUsages::COPY_DST)
+            .with_linear_filtering_sampler()
+            .build(device)
+    }
+
+    fn create_passes(
+        device: &wgpu::Device,
+        shaders: &Shaders,
+        atlas_texture: &Texture,
+    ) -> (AtlasPass, AtlasPass) {
+        let pad_pass = AtlasPass::new(
+            device,
+            "atlas_pad",
+            &shaders.atlas_pad,
+            Self::pad_bind_groups(device, atlas_texture),
+        );
+
+        let downsample_pass = AtlasPass::new(
+            device,
+            "atlas_downsample",
+            &shaders.atlas_downsample,
+            Self::downsample_bind_groups(device, atlas_texture),
+        );
+
+        (pad_pass, downsample_pass)
+    }
+
+    /// Returns bind groups for [`Self::pad_pass`], one per page.
+    fn pad_bind_groups(
+        device: &wgpu::Device,
+        atlas_texture: &Texture,
+    ) -> Vec<BindGroup> {
+        (0..atlas_texture.tex().depth_or_array_layers())
+            .map(|page| {
+                BindGroup::builder(format!("atlas_pad_bg{page}"))
+                    .add(&atlas_texture.bind_writable_at(page, 0))
+                    .build(device)
+            })
+            .collect()
+    }
+
+    /// Returns bind groups for [`Self::downsample_pass`], one per each page's
+    /// mip level (except the top one).
+    fn downsample_bind_groups(
+        device: &wgpu::Device,
+        atlas_texture: &Texture,
+    ) -> Vec<BindGroup> {
+        (0..atlas_texture.tex().depth_or_array_layers())
+            .flat_map(|page| {
+                (1..gpu::AtlasView::MIP_LEVELS).map(move |level| (page, level))
+            })
+            .map(|(page, level)| {
+                BindGroup::builder(format!("atlas_downsample_bg{page}_{level}"))
+                    .add(&atlas_texture.bind_writable_at(page, level - 1))
+                    .add(&atlas_texture.bind_writable_at(page, level))
+                    .build(device)
+            })
+            .collect()
+    }
+
     pub fn insert(&mut self, handle: P::ImageHandle, item: Image<P>) {
-        let size = size2(
-            item.texture_descriptor.size.width as i32,
-            item.texture_descriptor.size.height as i32,
+        let size = uvec2(
+            item.texture_descriptor.size.width,
+            item.texture_descriptor.size.height,
         );
 
-        let alloc = if let Some(alloc) = self.images.get(&handle) {
-            if size == alloc.rectangle.size() {
-                Some(*alloc)
-            } else {
-                self.atlas.deallocate(alloc.id);
-                self.atlas.allocate(size)
+        let alloc_size = size2(
+            (size.x + 2 * GUTTER).next_multiple_of(ALIGNMENT) as i32,
+            (size.y + 2 * GUTTER).next_multiple_of(ALIGNMENT) as i32,
+        );
+
+        // If the image is being replaced, whatever we were about to upload for
+        // it is now stale
+        self.atlas_changes.retain(|change| match change {
+            AtlasChange::Set { handle: h, .. } => *h != handle,
+        });
+
+        self.dynamic_textures.remove(&handle);
+
+        let alloc = match self.images.get(&handle) {
+            Some(image) if alloc_size == image.alloc.rectangle.size() => {
+                Some((image.page, image.alloc))
+            }
+
+            image => {
+                if let Some(image) = image {
+                    self.pages.deallocate(image.page, image.alloc);
+                    self.images.remove(&handle);
+                }
+
+                self.pages.allocate(alloc_size).or_else(|| {
+                    // All pages are full, but maybe we can still make some
+                    // space by getting rid of the fragmentation
+                    if self.compact() {
+                        self.pages.allocate(alloc_size)
+                    } else {
+                        None
+                    }
+                })
             }
-        } else {
-            self.atlas.allocate(size)
         };
 
-        let Some(alloc) = alloc else {
-            // TODO allocate new atlas, up to 16 (Metal's limit)
+        let Some((page, alloc)) = alloc else {
             warn!(
                 "Cannot add image `{:?}` - no more space in the atlas",
                 handle
@@ -78,87 +199,212 @@ where
             return;
         };
 
-        self.images.insert(handle, alloc);
+        let image = IndexedImage {
+            page,
+            alloc,
+            size,
+            flags: Self::flags(&item),
+            address_modes: [
+                item.sampler_descriptor.address_mode_u,
+                item.sampler_descriptor.address_mode_v,
+            ],
+        };
+
+        self.images.insert(handle, image);
 
         match item.data {
             data @ (ImageData::Raw { .. }
             | ImageData::Texture {
                 is_dynamic: false, ..
             }) => {
-                self.atlas_changes.push(AtlasChange::Set {
-                    x: alloc.rectangle.min.x as u32,
-                    y: alloc.rectangle.min.y as u32,
-                    w: alloc.rectangle.width() as u32,
-                    h: alloc.rectangle.height() as u32,
-                    data,
-                });
+                self.atlas_changes.push(AtlasChange::Set { handle, data });
             }
 
             ImageData::Texture {
                 texture,
                 is_dynamic: true,
             } => {
-                self.dynamic_textures.push((texture, alloc));
+                self.dynamic_textures.insert(handle, texture);
+            }
+        }
+    }
+
+    fn flags(item: &Image<P>) -> u32 {
+        let mut flags = 0;
+
+        // Non-color textures (e.g. normal maps) are expected to be provided in
+        // a non-sRGB format, which makes us sample them through the linear view
+        if !item.texture_descriptor.format.is_srgb() {
+            flags |= gpu::AtlasView::FLAG_LINEAR;
+        }
+
+        let address_mode = |mode| match mode {
+            wgpu::AddressMode::Repeat => gpu::AtlasView::ADDRESS_REPEAT,
+            wgpu::AddressMode::MirrorRepeat => {
+                gpu::AtlasView::ADDRESS_MIRROR_REPEAT
             }
+
+            // There's no border color in the atlas, so clamping to border is
+            // approximated with clamping to edge
+            wgpu::AddressMode::ClampToEdge
+            | wgpu::AddressMode::ClampToBorder => gpu::AtlasView::ADDRESS_CLAMP,
+        };
+
+        flags |= address_mode(item.sampler_descriptor.address_mode_u)
+            << gpu::AtlasView::ADDRESS_U_SHIFT;
+
+        flags |= address_mode(item.sampler_descriptor.address_mode_v)
+            << gpu::AtlasView::ADDRESS_V_SHIFT;
+
+        if item.sampler_descriptor.mag_filter == wgpu::FilterMode::Linear {
+            flags |= gpu::AtlasView::FLAG_MAG_LINEAR;
+        }
+
+        if item.sampler_descriptor.min_filter == wgpu::FilterMode::Linear {
+            flags |= gpu::AtlasView::FLAG_MIN_LINEAR;
+        }
+
+        if item.sampler_descriptor.mipmap_filter == wgpu::FilterMode::Linear {
+            flags |= gpu::AtlasView::FLAG_MIPMAP_LINEAR;
         }
+
+        flags
     }
 
     pub fn remove(&mut self, handle: P::ImageHandle) {
-        let Some(alloc) = self.images.remove(&handle) else {
+        let Some(image) = self.images.remove(&handle) else {
             return;
         };
 
-        self.atlas.deallocate(alloc.id);
+        self.pages.deallocate(image.page, image.alloc);
+        self.dynamic_textures.remove(&handle);
+
+        if self.pages.can_shrink() {
+            self.needs_compaction = true;
+        }
     }
 
-    pub fn lookup(&self, handle: P::ImageHandle) -> Option<Vec4> {
-        self.images.get(&handle).map(|alloc| {
-            vec4(
-                alloc.rectangle.min.x as f32 / (Self::ATLAS_WIDTH as f32),
-                alloc.rectangle.min.y as f32 / (Self::ATLAS_HEIGHT as f32),
-                alloc.rectangle.width() as f32 / (Self::ATLAS_WIDTH as f32),
-                alloc.rectangle.height() as f32 / (Self::ATLAS_HEIGHT as f32),
-            )
+    /// Repacks all images from scratch, getting rid of the fragmentation left
+    /// by the removed ones; returns whether it succeeded.
+    ///
+    /// Images get actually moved on the GPU during the next flush.
+    fn compact(&mut self) -> bool {
+        let sizes: Vec<_> = self
+            .images
+            .iter()
+            .map(|(handle, image)| (*handle, image.alloc.rectangle.size()))
+            .collect();
+
+        let Some((pages, allocs)) = AtlasPages::pack(&sizes) else {
+            return false;
+        };
+
+        info!(
+            "Compacting atlas: {} -> {} page(s)",
+            self.pages.len(),
+            pages.len()
+        );
+
+        self.prev_images.get_or_insert_with(|| self.images.clone());
+        self.pages = pages;
+
+        for (handle, (page, alloc)) in allocs {
+            let image = self.images.get_mut(&handle).unwrap();
+
+            image.page = page;
+            image.alloc = alloc;
+        }
+
+        true
+    }
+
+    /// Returns image's rectangle within the atlas (excluding gutters),
+    /// together with its flags (see: [`gpu::AtlasView::sample()`]), which
+    /// include the image's page.
+    pub fn lookup(&self, handle: P::ImageHandle) -> Option<(Vec4, u32)> {
+        self.images.get(&handle).map(|image| {
+            let [x, y, w, h] = image.rect();
+
+            let rect = vec4(
+                x as f32 / (Self::ATLAS_WIDTH as f32),
+                y as f32 / (Self::ATLAS_HEIGHT as f32),
+                w as f32 / (Self::ATLAS_WIDTH as f32),
+                h as f32 / (Self::ATLAS_HEIGHT as f32),
+            );
+
+            let flags =
+                image.flags | (image.page << gpu::AtlasView::PAGE_SHIFT);
+
+            (rect, flags)
         })
     }
 
-    pub fn lookup_opt(&self, handle: Option<P::ImageHandle>) -> Option<Vec4> {
+    pub fn lookup_opt(
+        &self,
+        handle: Option<P::ImageHandle>,
+    ) -> Option<(Vec4, u32)> {
         self.lookup(handle?)
     }
 
-    pub fn flush(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+    /// Returns how much of the atlas is being used.
+    pub fn occupancy(&self) -> AtlasOccupancy {
+        let page_area = (Self::ATLAS_WIDTH * Self::ATLAS_HEIGHT) as f32;
+
+        AtlasOccupancy {
+            images: self.images.len(),
+            pages: self
+                .pages
+                .used_areas()
+                .map(|area| area as f32 / page_area)
+                .collect(),
+            max_pages: AtlasPages::MAX_PAGES,
+        }
+    }
+
+    /// Sends pending changes to the GPU; returns whether the atlas' texture
+    /// has been reallocated (in which case bind groups referring to it have to
+    /// be rebuilt, and - since images might have been moved - materials have
+    /// to be refreshed).
+    pub fn flush(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        shaders: &Shaders,
+    ) -> BufferFlushOutcome {
+        if mem::take(&mut self.needs_compaction) {
+            self.compact();
+        }
+
         let mut encoder = None;
 
+        let reallocated = self.prev_images.is_some()
+            || self.layers()
+                != self.atlas_texture.tex().depth_or_array_layers();
+
+        if reallocated {
+            let encoder = encoder.get_or_insert_with(|| {
+                device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("strolle_atlas"),
+                })
+            });
+
+            self.reallocate(device, shaders, encoder);
+        }
+
+        // Images copied from GPU textures, which need their gutters and mip
+        // levels to be generated on the GPU as well
+        let mut copied_images = Vec::new();
+
         for change in mem::take(&mut self.atlas_changes) {
             match change {
-                AtlasChange::Set { x, y, w, h, data } => {
-                    let size = wgpu::Extent3d {
-                        width: w,
-                        height: h,
-                        depth_or_array_layers: 1,
+                AtlasChange::Set { handle, data } => {
+                    let Some(image) = self.images.get(&handle).copied() else {
+                        continue;
                     };
 
                     match data {
                         ImageData::Raw { data } => {
-                            queue.write_texture(
-                                wgpu::ImageCopyTexture {
-                                    texture: self.atlas_texture.tex(),
-                                    mip_level: 0,
-                                    origin: wgpu::Origin3d { x, y, z: 0 },
-                                    aspect: wgpu::TextureAspect::All,
-                                },
-                                &data,
-                                wgpu::ImageDataLayout {
-                                    offset: 0,
-                                    bytes_per_row: Some(w * 4),
-                                    rows_per_image: None,
-                                },
-                                wgpu::Extent3d {
-                                    width: w,
-                                    height: h,
-                                    depth_or_array_layers: 1,
-                                },
-                            );
+                            self.write_data(queue, &data, image);
                         }
 
                         ImageData::Texture { texture, .. } => {
@@ -170,56 +416,332 @@ where
                                 )
                             });
 
-                            encoder.copy_texture_to_texture(
-                                texture.as_image_copy(),
-                                wgpu::ImageCopyTexture {
-                                    texture: self.atlas_texture.tex(),
-                                    mip_level: 0,
-                                    origin: wgpu::Origin3d { x, y, z: 0 },
-                                    aspect: wgpu::TextureAspect::All,
-                                },
-                                size,
-                            );
+                            self.copy_texture(encoder, &texture, image);
+                            copied_images.push(image);
                         }
                     }
                 }
             }
         }
 
-        for (tex, alloc) in &self.dynamic_textures {
+        for (handle, texture) in &self.dynamic_textures {
+            let image = self.images[handle];
+
             let encoder = encoder.get_or_insert_with(|| {
                 device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
                     label: Some("strolle_atlas"),
                 })
             });
 
-            encoder.copy_texture_to_texture(
-                tex.as_image_copy(),
+            self.copy_texture(encoder, texture, image);
+            copied_images.push(image);
+        }
+
+        if let Some(mut encoder) = encoder {
+            self.generate_mip_levels(&mut encoder, &copied_images);
+
+            queue.submit([encoder.finish()]);
+        }
+
+        BufferFlushOutcome { reallocated }
+    }
+
+    /// Recreates the atlas' texture so that it matches the current number of
+    /// pages, moving images that are already there into their current places.
+    fn reallocate(
+        &mut self,
+        device: &wgpu::Device,
+        shaders: &Shaders,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let prev_images = self
+            .prev_images
+            .take()
+            .unwrap_or_else(|| self.images.clone());
+
+        let atlas_texture = Self::create_atlas_texture(device, self.layers());
+        let prev_texture = mem::replace(&mut self.atlas_texture, atlas_texture);
+
+        // Images that are about to be uploaded anyway don't have to be moved;
+        // what's more, since raw images are written before the encoder gets
+        // executed, moving them would overwrite their new contents
+        let pending: HashSet<_> = self
+            .atlas_changes
+            .iter()
+            .map(|change| match change {
+                AtlasChange::Set { handle, .. } => *handle,
+            })
+            .chain(self.dynamic_textures.keys().copied())
+            .collect();
+
+        for (handle, image) in &self.images {
+            if pending.contains(handle) {
+                continue;
+            }
+
+            let Some(prev_image) = prev_images.get(handle) else {
+                continue;
+            };
+
+            let [prev_x, prev_y, w, h] = prev_image.alloc_rect();
+            let [x, y, ..] = image.alloc_rect();
+
+            for level in 0..gpu::AtlasView::MIP_LEVELS {
+                encoder.copy_texture_to_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: prev_texture.tex(),
+                        mip_level: level,
+                        origin: wgpu::Origin3d {
+                            x: prev_x >> level,
+                            y: prev_y >> level,
+                            z: prev_image.page,
+                        },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    wgpu::ImageCopyTexture {
+                        texture: self.atlas_texture.tex(),
+                        mip_level: level,
+                        origin: wgpu::Origin3d {
+                            x: x >> level,
+                            y: y >> level,
+                            z: image.page,
+                        },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    wgpu::Extent3d {
+                        width: w >> level,
+                        height: h >> level,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        (self.pad_pass, self.downsample_pass) =
+            Self::create_passes(device, shaders, &self.atlas_texture);
+    }
+
+    /// Returns the number of layers atlas' texture should have.
+    fn layers(&self) -> u32 {
+        // Texture must have at least one layer, even if the atlas is empty
+        self.pages.len().max(1) as u32
+    }
+
+    /// Writes given raw image into the atlas, together with its gutters and
+    /// mip levels.
+    fn write_data(
+        &self,
+        queue: &wgpu::Queue,
+        data: &[u8],
+        image: IndexedImage,
+    ) {
+        let [x, y, w, h] = image.alloc_rect();
+        let is_srgb = image.flags & gpu::AtlasView::FLAG_LINEAR == 0;
+        let mut data =
+            pad(data, image.size, uvec2(w, h), GUTTER, image.address_modes);
+
+        for level in 0..gpu::AtlasView::MIP_LEVELS {
+            if level > 0 {
+                data = downsample(
+                    &data,
+                    w >> (level - 1),
+                    h >> (level - 1),
+                    is_srgb,
+                );
+            }
+
+            queue.write_texture(
                 wgpu::ImageCopyTexture {
                     texture: self.atlas_texture.tex(),
-                    mip_level: 0,
+                    mip_level: level,
                     origin: wgpu::Origin3d {
-                        x: alloc.rectangle.min.x as u32,
-                        y: alloc.rectangle.min.y as u32,
-                        z: 0,
+                        x: x >> level,
+                        y: y >> level,
+                        z: image.page,
                     },
                     aspect: wgpu::TextureAspect::All,
                 },
+                &data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some((w >> level) * 4),
+                    rows_per_image: None,
+                },
                 wgpu::Extent3d {
-                    width: alloc.rectangle.size().width as u32,
-                    height: alloc.rectangle.size().height as u32,
+                    width: w >> level,
+                    height: h >> level,
                     depth_or_array_layers: 1,
                 },
-            )
+            );
         }
+    }
 
-        if let Some(encoder) = encoder {
-            queue.submit([encoder.finish()]);
+    /// Copies given texture into the top level of the atlas; gutters and mip
+    /// levels are then generated with [`Self::generate_mip_levels()`].
+    fn copy_texture(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        image: IndexedImage,
+    ) {
+        let [x, y, w, h] = image.rect();
+
+        encoder.copy_texture_to_texture(
+            texture.as_image_copy(),
+            wgpu::ImageCopyTexture {
+                texture: self.atlas_texture.tex(),
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x,
+                    y,
+                    z: image.page,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::Extent3d {
+                width: w,
+                height: h,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Fills gutters and generates mip levels of given images, which must've
+    /// been already copied into the top level of the atlas.
+    fn generate_mip_levels(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        images: &[IndexedImage],
+    ) {
+        let mut pass =
+            encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("strolle_atlas_mip_levels_pass"),
+            });
+
+        for image in images {
+            let [x, y, w, h] = image.alloc_rect();
+
+            let params = |level: u32| gpu::AtlasPassParams {
+                alloc: uvec4(x >> level, y >> level, w >> level, h >> level),
+                image: image.rect().into(),
+                flags: image.flags,
+            };
+
+            let page = image.page as usize;
+
+            self.pad_pass.run(&mut pass, page, params(0));
+
+            for level in 1..gpu::AtlasView::MIP_LEVELS {
+                let bind_group = page
+                    * (gpu::AtlasView::MIP_LEVELS as usize - 1)
+                    + level as usize
+                    - 1;
+
+                self.downsample_pass
+                    .run(&mut pass, bind_group, params(level));
+            }
         }
     }
 
+    /// Binds the atlas as seen through its sRGB view, its linear view and the
+    /// sampler (see: [`gpu::AtlasView`]).
     pub fn bind_atlas(&self) -> impl Bindable + '_ {
-        self.atlas_texture.bind_sampled()
+        self.atlas_texture.bind_sampled_as([
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureFormat::Rgba8Unorm,
+        ])
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexedImage {
+    page: u32,
+    alloc: Allocation,
+    size: UVec2,
+    flags: u32,
+    address_modes: [wgpu::AddressMode; 2],
+}
+
+impl IndexedImage {
+    /// Returns image's position and size within the atlas, excluding gutters.
+    fn rect(&self) -> [u32; 4] {
+        [
+            self.alloc.rectangle.min.x as u32 + GUTTER,
+            self.alloc.rectangle.min.y as u32 + GUTTER,
+            self.size.x,
+            self.size.y,
+        ]
+    }
+
+    /// Returns image's position and size within the atlas, including gutters
+    /// (and the alignment, see: [`ALIGNMENT`]).
+    fn alloc_rect(&self) -> [u32; 4] {
+        [
+            self.alloc.rectangle.min.x as u32,
+            self.alloc.rectangle.min.y as u32,
+            self.alloc.rectangle.width() as u32,
+            self.alloc.rectangle.height() as u32,
+        ]
+    }
+}
+
+/// Compute pass operating on the atlas (see: `strolle_shaders::atlas`).
+#[derive(Debug)]
+struct AtlasPass {
+    pipeline: wgpu::ComputePipeline,
+    bind_groups: Vec<BindGroup>,
+}
+
+impl AtlasPass {
+    fn new(
+        device: &wgpu::Device,
+        label: &str,
+        (module, entry_point): &(wgpu::ShaderModule, &'static str),
+        bind_groups: Vec<BindGroup>,
+    ) -> Self {
+        let pipeline_layout_label = format!("strolle_{label}_pipeline_layout");
+
+        let pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(&pipeline_layout_label),
+                bind_group_layouts: &[bind_groups[0].layout()],
+                push_constant_ranges: &[wgpu::PushConstantRange {
+                    stages: wgpu::ShaderStages::COMPUTE,
+                    range: 0..mem::size_of::<gpu::AtlasPassParams>() as u32,
+                }],
+            });
+
+        let pipeline_label = format!("strolle_{label}_pipeline");
+
+        let pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(&pipeline_label),
+                layout: Some(&pipeline_layout),
+                module,
+                entry_point,
+            });
+
+        Self {
+            pipeline,
+            bind_groups,
+        }
+    }
+
+    fn run<'a>(
+        &'a self,
+        pass: &mut wgpu::ComputePass<'a>,
+        bind_group: usize,
+        params: gpu::AtlasPassParams,
+    ) {
+        pass.set_pipeline(&self.pipeline);
+        pass.set_push_constants(0, bytemuck::bytes_of(&params));
+        pass.set_bind_group(0, self.bind_groups[bind_group].get(false), &[]);
+
+        pass.dispatch_workgroups(
+            params.alloc.z.div_ceil(8),
+            params.alloc.w.div_ceil(8),
+            1,
+        );
     }
 }
 
@@ -230,12 +752,433 @@ where
     P: Params,
 {
     Set {
-        x: u32,
-        y: u32,
-        w: u32,
-        h: u32,
+        handle: P::ImageHandle,
 
         #[derivative(Debug = "ignore")]
         data: ImageData<P>,
     },
 }
+
+/// How much of the atlas is being used (see:
+/// [`crate::Engine::atlas_occupancy()`]).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AtlasOccupancy {
+    /// Number of images stored in the atlas
+    pub images: usize,
+
+    /// Fraction of each page's area that's been allocated (including images'
+    /// gutters and alignment)
+    pub pages: Vec<f32>,
+
+    /// Maximum number of pages the atlas can grow to
+    pub max_pages: usize,
+}
+
+/// Allocation within the atlas, together with its page.
+type PageAllocation = (u32, Allocation);
+
+/// Allocator of space within atlas' pages.
+#[derive(Default)]
+struct AtlasPages {
+    pages: Vec<AtlasAllocator>,
+}
+
+impl AtlasPages {
+    /// Maximum number of pages the atlas can grow to.
+    const MAX_PAGES: usize = 16;
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Allocates given area at the first page that's got enough space for it,
+    /// adding a new page if necessary.
+    fn allocate(&mut self, size: Size) -> Option<PageAllocation> {
+        for (page, allocator) in self.pages.iter_mut().enumerate() {
+            if let Some(alloc) = allocator.allocate(size) {
+                return Some((page as u32, alloc));
+            }
+        }
+
+        if self.pages.len() >= Self::MAX_PAGES {
+            return None;
+        }
+
+        let mut allocator = Self::create_page();
+        let alloc = allocator.allocate(size)?;
+
+        self.pages.push(allocator);
+
+        Some((self.pages.len() as u32 - 1, alloc))
+    }
+
+    fn deallocate(&mut self, page: u32, alloc: Allocation) {
+        self.pages[page as usize].deallocate(alloc.id);
+    }
+
+    /// Returns the allocated area of each page, in texels.
+    fn used_areas(&self) -> impl Iterator<Item = i32> + '_ {
+        self.pages.iter().map(|allocator| {
+            let mut area = 0;
+
+            allocator.for_each_allocated_rectangle(|_, rect| {
+                area += rect.area();
+            });
+
+            area
+        })
+    }
+
+    /// Returns whether the allocated area has dropped low enough for all of
+    /// the allocations to (most likely) fit into fewer pages.
+    ///
+    /// We leave some slack here, so that a single image going in and out
+    /// doesn't cause the atlas to be repacked each time.
+    fn can_shrink(&self) -> bool {
+        if self.pages.len() <= 1 {
+            return false;
+        }
+
+        let page_area = (gpu::AtlasView::SIZE * gpu::AtlasView::SIZE) as i64;
+        let used_area: i64 = self.used_areas().map(|area| area as i64).sum();
+
+        used_area <= (self.pages.len() as i64 - 1) * page_area * 3 / 4
+    }
+
+    /// Packs given areas from scratch, largest first, returning the new
+    /// allocator together with each area's new place (in the same order as
+    /// given).
+    ///
+    /// Returns `None` if the areas don't fit into [`Self::MAX_PAGES`].
+    fn pack<K>(sizes: &[(K, Size)]) -> Option<(Self, Vec<(K, PageAllocation)>)>
+    where
+        K: Copy,
+    {
+        let mut order: Vec<_> = (0..sizes.len()).collect();
+
+        order.sort_by_key(|&idx| Reverse(sizes[idx].1.area()));
+
+        let mut pages = Self::default();
+        let mut allocs = vec![None; sizes.len()];
+
+        for idx in order {
+            allocs[idx] = Some(pages.allocate(sizes[idx].1)?);
+        }
+
+        let allocs = sizes
+            .iter()
+            .zip(allocs)
+            .map(|((key, _), alloc)| (*key, alloc.unwrap()))
+            .collect();
+
+        Some((pages, allocs))
+    }
+
+    fn create_page() -> AtlasAllocator {
+        AtlasAllocator::with_options(
+            size2(gpu::AtlasView::SIZE as i32, gpu::AtlasView::SIZE as i32),
+            &AllocatorOptions {
+                alignment: size2(ALIGNMENT as i32, ALIGNMENT as i32),
+                ..Default::default()
+            },
+        )
+    }
+}
+
+/// Maps given texel coordinate (possibly lying outside of the image) into the
+/// image, according to the addressing mode.
+fn address(coord: i32, len: u32, mode: wgpu::AddressMode) -> u32 {
+    let len = len as i32;
+
+    let coord = match mode {
+        wgpu::AddressMode::Repeat => coord.rem_euclid(len),
+
+        wgpu::AddressMode::MirrorRepeat => {
+            let coord = coord.rem_euclid(2 * len);
+
+            if coord >= len {
+                2 * len - 1 - coord
+            } else {
+                coord
+            }
+        }
+
+        wgpu::AddressMode::ClampToEdge | wgpu::AddressMode::ClampToBorder => {
+            coord.clamp(0, len - 1)
+        }
+    };
+
+    coord as u32
+}
+
+/// Surrounds given RGBA image with gutters, extending it into an image of
+/// given size (with the image itself starting at `(gutter, gutter)`).
+fn pad(
+    data: &[u8],
+    size: UVec2,
+    padded_size: UVec2,
+    gutter: u32,
+    [address_mode_u, address_mode_v]: [wgpu::AddressMode; 2],
+) -> Vec<u8> {
+    let mut padded =
+        Vec::with_capacity((4 * padded_size.x * padded_size.y) as usize);
+
+    for y in 0..padded_size.y {
+        let src_y = address(y as i32 - gutter as i32, size.y, address_mode_v);
+
+        for x in 0..padded_size.x {
+            let src_x =
+                address(x as i32 - gutter as i32, size.x, address_mode_u);
+
+            let src = (4 * (src_y * size.x + src_x)) as usize;
+
+            padded.extend_from_slice(&data[src..src + 4]);
+        }
+    }
+
+    padded
+}
+
+/// Generates the next mip level of given RGBA image by averaging its 2x2
+/// blocks of texels.
+///
+/// Color channels of sRGB images are averaged in linear space, so that the mip
+/// levels don't get darker.
+fn downsample(data: &[u8], w: u32, h: u32, is_srgb: bool) -> Vec<u8> {
+    fn srgb_to_linear(c: f32) -> f32 {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    fn linear_to_srgb(c: f32) -> f32 {
+        if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    }
+
+    let srgb_to_linear: [f32; 256] =
+        std::array::from_fn(|c| srgb_to_linear(c as f32 / 255.0));
+
+    let mut out = Vec::with_capacity((w * h) as usize);
+
+    for y in 0..h / 2 {
+        for x in 0..w / 2 {
+            for channel in 0..4 {
+                let is_color = is_srgb && channel < 3;
+
+                let sum: f32 = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .into_iter()
+                    .map(|(dx, dy)| {
+                        let idx = 4 * ((2 * y + dy) * w + 2 * x + dx) + channel;
+                        let value = data[idx as usize];
+
+                        if is_color {
+                            srgb_to_linear[value as usize]
+                        } else {
+                            value as f32 / 255.0
+                        }
+                    })
+                    .sum();
+
+                let value = sum / 4.0;
+
+                let value = if is_color {
+                    linear_to_srgb(value)
+                } else {
+                    value
+                };
+
+                out.push((value * 255.0).round() as u8);
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::uvec2;
+
+    #[test]
+    fn address() {
+        let target = |mode| {
+            (-4..7)
+                .map(|coord| super::address(coord, 3, mode))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0],
+            target(wgpu::AddressMode::Repeat)
+        );
+
+        assert_eq!(
+            vec![2, 2, 1, 0, 0, 1, 2, 2, 1, 0, 0],
+            target(wgpu::AddressMode::MirrorRepeat)
+        );
+
+        assert_eq!(
+            vec![0, 0, 0, 0, 0, 1, 2, 2, 2, 2, 2],
+            target(wgpu::AddressMode::ClampToEdge)
+        );
+    }
+
+    #[test]
+    fn pad() {
+        // 2x2 image with each texel having a different red channel
+        let data = [
+            [1, 0, 0, 255],
+            [2, 0, 0, 255],
+            [3, 0, 0, 255],
+            [4, 0, 0, 255],
+        ]
+        .concat();
+
+        let target = |address_modes| {
+            let padded =
+                super::pad(&data, uvec2(2, 2), uvec2(4, 4), 1, address_modes);
+
+            assert_eq!(4 * 4 * 4, padded.len());
+
+            padded.chunks(4).map(|texel| texel[0]).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                4, 3, 4, 3, //
+                2, 1, 2, 1, //
+                4, 3, 4, 3, //
+                2, 1, 2, 1, //
+            ],
+            target([wgpu::AddressMode::Repeat; 2])
+        );
+
+        assert_eq!(
+            vec![
+                1, 1, 2, 2, //
+                1, 1, 2, 2, //
+                3, 3, 4, 4, //
+                3, 3, 4, 4, //
+            ],
+            target([wgpu::AddressMode::ClampToEdge; 2])
+        );
+
+        // Repeated horizontally, clamped vertically
+        assert_eq!(
+            vec![
+                2, 1, 2, 1, //
+                2, 1, 2, 1, //
+                4, 3, 4, 3, //
+                4, 3, 4, 3, //
+            ],
+            target([wgpu::AddressMode::Repeat, wgpu::AddressMode::ClampToEdge])
+        );
+    }
+
+    #[test]
+    fn pad_aligned() {
+        // 2x1 image padded beyond its gutters, as it happens for allocations
+        // rounded up to the alignment
+        let data = [[1, 0, 0, 255], [2, 0, 0, 255]].concat();
+
+        let padded = super::pad(
+            &data,
+            uvec2(2, 1),
+            uvec2(6, 3),
+            1,
+            [wgpu::AddressMode::Repeat; 2],
+        );
+
+        assert_eq!(
+            vec![
+                2, 1, 2, 1, 2, 1, //
+                2, 1, 2, 1, 2, 1, //
+                2, 1, 2, 1, 2, 1, //
+            ],
+            padded.chunks(4).map(|texel| texel[0]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn downsample() {
+        // 4x2 image: black & white texels on the left, two grays on the right
+        let data = [
+            [0, 0, 0, 0],
+            [255, 255, 255, 255],
+            [100, 100, 100, 255],
+            [100, 100, 100, 255],
+            [0, 0, 0, 0],
+            [255, 255, 255, 255],
+            [100, 100, 100, 255],
+            [100, 100, 100, 255],
+        ]
+        .concat();
+
+        // Linear images get averaged as-is
+        assert_eq!(
+            vec![128, 128, 128, 128, 100, 100, 100, 255],
+            super::downsample(&data, 4, 2, false)
+        );
+
+        // sRGB images get averaged in linear space (except for alpha)
+        assert_eq!(
+            vec![188, 188, 188, 128, 100, 100, 100, 255],
+            super::downsample(&data, 4, 2, true)
+        );
+    }
+
+    #[test]
+    fn pages() {
+        use guillotiere::size2;
+
+        use super::AtlasPages;
+
+        let page = size2(8192, 8192);
+        let mut target = AtlasPages::default();
+
+        // Allocations that don't fit into the existing pages get new ones
+        let (page0, a) = target.allocate(size2(8192, 4096)).unwrap();
+        let (page1, b) = target.allocate(page).unwrap();
+        let (page2, c) = target.allocate(size2(4096, 4096)).unwrap();
+
+        assert_eq!((0, 1, 0), (page0, page1, page2));
+        assert_eq!(2, target.len());
+        assert!(!target.can_shrink());
+
+        // Freeing the second page makes it possible to fit everything into
+        // one page
+        target.deallocate(page1, b);
+
+        assert!(target.can_shrink());
+
+        let (target, allocs) = AtlasPages::pack(&[
+            ("a", a.rectangle.size()),
+            ("c", c.rectangle.size()),
+        ])
+        .unwrap();
+
+        assert_eq!(1, target.len());
+        assert_eq!(
+            vec!["a", "c"],
+            allocs.iter().map(|(k, _)| *k).collect::<Vec<_>>()
+        );
+        assert!(allocs.iter().all(|(_, (page, _))| *page == 0));
+
+        // There's only so many pages
+        let mut target = AtlasPages::default();
+
+        for _ in 0..AtlasPages::MAX_PAGES {
+            assert!(target.allocate(page).is_some());
+        }
+
+        assert!(target.allocate(size2(16, 16)).is_none());
+        assert!(AtlasPages::pack(&[((), page); AtlasPages::MAX_PAGES + 1])
+            .is_none());
+    }
+}
@@ -11,6 +11,10 @@ where
     pub(crate) material_handle: P::MaterialHandle,
     pub(crate) transform: Affine3A,
     pub(crate) transform_inverse: Affine3A,
+    pub(crate) visibility: u32,
+    pub(crate) casts_shadows: bool,
+    pub(crate) visible_to_camera: bool,
+    pub(crate) visible_in_indirect: bool,
 }
 
 impl<P> Instance<P>
@@ -27,6 +31,51 @@ where
             material_handle,
             transform,
             transform_inverse: transform.inverse(),
+            visibility: u32::MAX,
+            casts_shadows: true,
+            visible_to_camera: true,
+            visible_in_indirect: true,
         }
     }
+
+    /// Specifies which cameras can see this instance - the instance is
+    /// rendered by a camera only if `visibility & camera.visibility != 0`.
+    ///
+    /// By default instances are visible to all cameras.
+    pub fn with_visibility(mut self, visibility: u32) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    /// Specifies whether this instance blocks light, i.e. whether it's hit by
+    /// shadow rays.
+    ///
+    /// Turned on by default; turning it off is useful e.g. for first-person
+    /// weapon models, which shouldn't shadow the world.
+    pub fn with_casts_shadows(mut self, casts_shadows: bool) -> Self {
+        self.casts_shadows = casts_shadows;
+        self
+    }
+
+    /// Specifies whether this instance is seen directly by cameras.
+    ///
+    /// Turned on by default; turning it off (together with
+    /// [`Self::with_visible_in_indirect()`]) is useful e.g. for invisible
+    /// shadow-caster proxies.
+    pub fn with_visible_to_camera(mut self, visible_to_camera: bool) -> Self {
+        self.visible_to_camera = visible_to_camera;
+        self
+    }
+
+    /// Specifies whether this instance is seen by indirect rays, i.e. whether
+    /// it shows up in reflections and contributes to global illumination.
+    ///
+    /// Turned on by default.
+    pub fn with_visible_in_indirect(
+        mut self,
+        visible_in_indirect: bool,
+    ) -> Self {
+        self.visible_in_indirect = visible_in_indirect;
+        self
+    }
 }
@@ -1,15 +1,13 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 
 use derivative::Derivative;
 use glam::Affine3A;
 use rand::Rng;
 
-use crate::bvh::Bvh;
+use crate::bvh::{Bvh, BvhInstance};
 use crate::materials::Materials;
-use crate::meshes::Meshes;
-use crate::triangles::Triangles;
 use crate::{Instance, Params};
 
 #[derive(Debug, Derivative)]
@@ -19,6 +17,7 @@ where
     P: Params,
 {
     instances: HashMap<P::InstanceHandle, InstanceEntry<P>>,
+    removed: HashSet<P::InstanceHandle>,
     dirty: bool,
 }
 
@@ -58,38 +57,56 @@ where
             .map(|(handle, entry)| (*handle, entry))
     }
 
+    pub fn get(&self, handle: P::InstanceHandle) -> Option<&Instance<P>> {
+        self.instances.get(&handle).map(|entry| &entry.instance)
+    }
+
     pub fn remove(&mut self, handle: P::InstanceHandle) {
-        self.dirty |= self.instances.remove(&handle).is_some();
+        if self.instances.remove(&handle).is_some() {
+            self.removed.insert(handle);
+            self.dirty = true;
+        }
     }
 
     pub fn is_empty(&self) -> bool {
         self.instances.is_empty()
     }
 
+    /// Pushes changed instances into the BVH, returning their handles
+    /// (including handles of the removed instances).
     pub fn refresh(
         &mut self,
-        meshes: &Meshes<P>,
+        changed_meshes: &HashSet<P::MeshHandle>,
         materials: &Materials<P>,
-        triangles: &mut Triangles<P>,
-        bvh: &mut Bvh,
-    ) -> bool {
-        if !mem::take(&mut self.dirty) {
-            return false;
+        bvh: &mut Bvh<P>,
+    ) -> HashSet<P::InstanceHandle> {
+        if !self.dirty && changed_meshes.is_empty() {
+            return Default::default();
         }
 
+        self.dirty = false;
+
+        let mut changed = mem::take(&mut self.removed);
+
         for (&instance_handle, entry) in &mut self.instances {
-            if !mem::take(&mut entry.dirty) {
+            let is_dirty = mem::take(&mut entry.dirty)
+                || changed_meshes.contains(&entry.instance.mesh_handle);
+
+            if !is_dirty {
                 continue;
             }
 
-            let Some(mesh) = meshes.get(entry.instance.mesh_handle) else {
+            changed.insert(instance_handle);
+
+            if !bvh.has_mesh(entry.instance.mesh_handle) {
                 // If the mesh is not yet available, it might be still being
                 // loaded in the background - in that case let's try again next
                 // frame
+                bvh.remove_instance(instance_handle);
                 entry.dirty = true;
                 self.dirty = true;
                 continue;
-            };
+            }
 
             let Some(material_id) =
                 materials.lookup(entry.instance.material_handle)
@@ -100,42 +117,20 @@ where
                 continue;
             };
 
-            let mesh_triangles = mesh.triangles().iter().map(|triangle| {
-                triangle.build(
-                    entry.instance.transform,
-                    entry.instance.transform_inverse,
-                )
+            bvh.insert_instance(BvhInstance {
+                handle: instance_handle,
+                mesh_handle: entry.instance.mesh_handle,
+                material_id,
+                transform: entry.instance.transform,
+                transform_inverse: entry.instance.transform_inverse,
+                visibility: entry.instance.visibility,
+                casts_shadows: entry.instance.casts_shadows,
+                visible_to_camera: entry.instance.visible_to_camera,
+                visible_in_indirect: entry.instance.visible_in_indirect,
             });
-
-            if let Some(count) = triangles.count(instance_handle) {
-                if mesh.triangles().len() == count {
-                    triangles.update(
-                        bvh,
-                        instance_handle,
-                        mesh_triangles,
-                        material_id,
-                    );
-                } else {
-                    triangles.remove(bvh, instance_handle);
-
-                    triangles.create(
-                        bvh,
-                        instance_handle.to_owned(),
-                        mesh_triangles,
-                        material_id,
-                    );
-                }
-            } else {
-                triangles.create(
-                    bvh,
-                    instance_handle.to_owned(),
-                    mesh_triangles,
-                    material_id,
-                );
-            }
         }
 
-        true
+        changed
     }
 }
 
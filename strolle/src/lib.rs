@@ -50,11 +50,15 @@ mod bvh;
 mod camera;
 mod camera_controller;
 mod camera_controllers;
+mod celestial_body;
+mod config;
+mod ies;
 mod image;
 mod images;
 mod instance;
 mod instances;
 mod light;
+mod light_tree;
 mod lights;
 mod material;
 mod materials;
@@ -62,8 +66,9 @@ mod mesh;
 mod mesh_triangle;
 mod meshes;
 mod noise;
+mod raycast_hit;
+mod raycast_options;
 mod shaders;
-mod sun;
 mod triangle;
 mod triangles;
 mod utils;
@@ -72,22 +77,29 @@ use std::fmt::Debug;
 use std::hash::Hash;
 use std::ops::Deref;
 use std::time::Instant;
-use std::{env, mem};
+use std::{env, io, mem};
 
 pub use glam;
+use glam::Vec3;
 use log::{info, trace};
 use strolle_gpu as gpu;
 
 pub(crate) use self::buffers::*;
+pub use self::bvh::BvhLayout;
 pub(crate) use self::bvh::*;
 pub use self::camera::*;
 pub(crate) use self::camera_controller::*;
 pub(crate) use self::camera_controllers::*;
+pub use self::celestial_body::*;
+pub use self::config::*;
+pub use self::ies::*;
 pub use self::image::*;
+pub use self::images::AtlasOccupancy;
 pub(crate) use self::images::*;
 pub use self::instance::*;
 pub(crate) use self::instances::*;
 pub use self::light::*;
+pub(crate) use self::light_tree::*;
 pub(crate) use self::lights::*;
 pub use self::material::*;
 pub(crate) use self::materials::*;
@@ -95,8 +107,9 @@ pub use self::mesh::*;
 pub use self::mesh_triangle::*;
 pub(crate) use self::meshes::*;
 pub(crate) use self::noise::*;
+pub use self::raycast_hit::*;
+pub use self::raycast_options::*;
 pub(crate) use self::shaders::*;
-pub use self::sun::*;
 pub(crate) use self::triangle::*;
 pub(crate) use self::triangles::*;
 pub(crate) use self::utils::*;
@@ -111,17 +124,16 @@ where
     meshes: Meshes<P>,
     instances: Instances<P>,
     triangles: Triangles<P>,
-    bvh: Bvh,
+    bvh: Bvh<P>,
     lights: Lights<P>,
     images: Images<P>,
     materials: Materials<P>,
     world: MappedUniformBuffer<gpu::World>,
     cameras: CameraControllers,
-    sun: Sun,
+    celestial_bodies: Vec<CelestialBody>,
     frame: gpu::Frame,
     has_dirty_materials: bool,
     has_dirty_images: bool,
-    has_dirty_sun: bool,
     print_stats: bool,
 }
 
@@ -130,17 +142,24 @@ where
     P: Params,
 {
     pub fn new(device: &wgpu::Device) -> Self {
-        info!("Initializing");
+        Self::with_config(device, EngineConfig::default())
+    }
+
+    pub fn with_config(device: &wgpu::Device, config: EngineConfig) -> Self {
+        info!("Initializing; config={config:?}");
+
+        let shaders = Shaders::new(device);
+        let images = Images::new(device, &shaders);
 
         Self {
-            shaders: Shaders::new(device),
+            shaders,
             noise: Noise::new(device),
             meshes: Meshes::default(),
             instances: Instances::default(),
             triangles: Triangles::new(device),
-            bvh: Bvh::new(device),
+            bvh: Bvh::new(device, &config),
             lights: Lights::new(device),
-            images: Images::new(device),
+            images,
             materials: Materials::new(device),
             world: MappedUniformBuffer::new(
                 device,
@@ -148,15 +167,35 @@ where
                 Default::default(),
             ),
             cameras: Default::default(),
-            sun: Default::default(),
+            celestial_bodies: Default::default(),
             frame: gpu::Frame::new(1),
             has_dirty_materials: false,
             has_dirty_images: false,
-            has_dirty_sun: true,
             print_stats: env::var("STROLLE_STATS").as_deref() == Ok("1"),
         }
     }
 
+    /// Writes BVHs built so far into given writer, so that they can be reused
+    /// on the next launch (see: [`Self::read_bvh_cache()`]).
+    ///
+    /// This should be called after [`Self::tick()`], once the scene's loaded.
+    pub fn write_bvh_cache(&self, w: impl io::Write) -> io::Result<()> {
+        self.bvh.write(w)
+    }
+
+    /// Reads BVHs written by [`Self::write_bvh_cache()`].
+    ///
+    /// Meshes and instances whose contents match the cache will skip building
+    /// their BVHs, which can considerably speed up loading large static scenes;
+    /// for this reason this should be called before inserting any meshes.
+    ///
+    /// Returns an error if the cache is malformed or has been written by an
+    /// incompatible version of Strolle, in which case the cache should be
+    /// simply discarded.
+    pub fn read_bvh_cache(&mut self, r: impl io::Read) -> io::Result<()> {
+        self.bvh.read(r)
+    }
+
     /// Creates or updates a mesh.
     pub fn insert_mesh(&mut self, handle: P::MeshHandle, item: Mesh) {
         self.meshes.insert(handle, item);
@@ -213,6 +252,14 @@ where
         self.has_dirty_images = true;
     }
 
+    /// Returns how much of the images' atlas is being used.
+    ///
+    /// Note that removed images are compacted away during [`Self::tick()`],
+    /// so the returned occupancy can decrease after calling it.
+    pub fn atlas_occupancy(&self) -> AtlasOccupancy {
+        self.images.occupancy()
+    }
+
     /// Creates or updates an instance.
     pub fn insert_instance(
         &mut self,
@@ -225,7 +272,7 @@ where
     /// Removes an instance.
     pub fn remove_instance(&mut self, handle: P::InstanceHandle) {
         self.instances.remove(handle);
-        self.triangles.remove(&mut self.bvh, handle);
+        self.bvh.remove_instance(handle);
     }
 
     /// Creates or updates a light.
@@ -238,10 +285,157 @@ where
         self.lights.remove(handle);
     }
 
-    /// Updates sun's parameters.
-    pub fn update_sun(&mut self, sun: Sun) {
-        self.sun = sun;
-        self.has_dirty_sun = true;
+    /// Uploads a photometric profile, so that it can be attached to point and
+    /// spot lights (see: [`LightProfile`]).
+    ///
+    /// Profile only shapes light's distribution - light's brightness is still
+    /// controlled by its luminous power (see:
+    /// [`IesProfile::luminous_power()`]).
+    pub fn add_light_profile(
+        &mut self,
+        profile: &IesProfile,
+    ) -> LightProfileHandle {
+        self.lights.add_profile(profile)
+    }
+
+    /// Removes a photometric profile.
+    ///
+    /// Lights that are still using this profile should be updated, since its
+    /// slot might get reused by another profile later.
+    pub fn remove_light_profile(&mut self, handle: LightProfileHandle) {
+        self.lights.remove_profile(handle);
+    }
+
+    /// Updates celestial bodies lighting up the sky; when there are no bodies,
+    /// there's no sky either.
+    ///
+    /// Note that celestial bodies don't cast any light onto the scene on their
+    /// own - see: [`CelestialBody::light()`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than [`CelestialBody::MAX`] bodies.
+    pub fn update_celestial_bodies(&mut self, bodies: Vec<CelestialBody>) {
+        assert!(
+            bodies.len() <= CelestialBody::MAX,
+            "too many celestial bodies (max. {})",
+            CelestialBody::MAX,
+        );
+
+        self.celestial_bodies = bodies;
+    }
+
+    /// Returns the closest intersection of given ray with the world, if any.
+    ///
+    /// This uses the same BVH as the GPU does and it honours the same
+    /// per-instance flags, so it matches what's rendered - with one exception:
+    /// **alpha is not taken into account**, i.e. materials with
+    /// [`AlphaMode::Blend`] are treated as fully opaque, since their textures
+    /// live only on the GPU.
+    ///
+    /// By default the ray sees what the camera sees (across all visibility
+    /// layers); see [`Self::raycast_with()`] for other kinds of rays.
+    ///
+    /// Returns `None` if `dir` is zero (or not finite).
+    ///
+    /// Note that the world is seen as of the most recent [`Self::tick()`], so
+    /// e.g. instances inserted after the last tick are not yet raycastable.
+    pub fn raycast(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_len: f32,
+    ) -> Option<RaycastHit<P>> {
+        self.raycast_with(origin, dir, max_len, RaycastOptions::default())
+    }
+
+    /// Same as [`Self::raycast()`], but takes into account only instances
+    /// matching given options.
+    pub fn raycast_with(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_len: f32,
+        options: RaycastOptions,
+    ) -> Option<RaycastHit<P>> {
+        let ray = gpu::Ray::new(origin, dir.try_normalize()?).with_len(max_len);
+
+        let (instance_handle, hit) = self.bvh.raycast(
+            self.triangles.as_slice(),
+            ray,
+            options.kind.serialize(),
+            options.visibility_mask,
+            false,
+        )?;
+
+        let instance = self.instances.get(instance_handle)?;
+
+        Some(RaycastHit {
+            instance: instance_handle,
+            distance: hit.distance,
+            point: hit.point,
+            normal: hit.normal,
+            uv: hit.uv,
+            material: instance.material_handle,
+        })
+    }
+
+    /// Returns whether given ray intersects anything in the world within
+    /// `max_len`; see: [`Self::raycast()`] (including its remark about alpha).
+    ///
+    /// This is faster than [`Self::raycast()`], since it stops at the first
+    /// intersection found instead of looking for the closest one.
+    ///
+    /// Returns `false` if `dir` is zero (or not finite).
+    pub fn occluded(&self, origin: Vec3, dir: Vec3, max_len: f32) -> bool {
+        self.occluded_with(origin, dir, max_len, RaycastOptions::default())
+    }
+
+    /// Same as [`Self::occluded()`], but takes into account only instances
+    /// matching given options - e.g. [`RayKind::Shadow`] checks whether light
+    /// can get through.
+    pub fn occluded_with(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_len: f32,
+        options: RaycastOptions,
+    ) -> bool {
+        let Some(dir) = dir.try_normalize() else {
+            return false;
+        };
+
+        let ray = gpu::Ray::new(origin, dir).with_len(max_len);
+
+        self.bvh
+            .raycast(
+                self.triangles.as_slice(),
+                ray,
+                options.kind.serialize(),
+                options.visibility_mask,
+                true,
+            )
+            .is_some()
+    }
+
+    /// Returns generation of the BVH that's currently used for rendering (and
+    /// raycasting).
+    ///
+    /// Each [`Self::tick()`] that changes instances bumps the latest generation
+    /// (see: [`Self::bvh_latest_generation()`]) - once this function returns
+    /// that number, those changes have become visible.
+    ///
+    /// Without [`EngineConfig::bvh_async_rebuild`] both numbers are always the
+    /// same after each tick.
+    pub fn bvh_generation(&self) -> u64 {
+        self.bvh.generation()
+    }
+
+    /// Returns generation of the BVH that will contain all changes made to
+    /// instances up to the most recent [`Self::tick()`]; see:
+    /// [`Self::bvh_generation()`].
+    pub fn bvh_latest_generation(&self) -> u64 {
+        self.bvh.latest_generation()
     }
 
     /// Creates a new camera that can be used to render the world.
@@ -307,11 +501,13 @@ where
             self.noise.flush(queue);
         });
 
-        utils::measure("tick.images", || {
-            self.images.flush(device, queue);
+        // Reallocating the atlas might move images around, in which case
+        // materials have to pick up their new places
+        let atlas_reallocated = utils::measure("tick.images", || {
+            self.images.flush(device, queue, &self.shaders).reallocated
         });
 
-        if any_material_modified || any_image_modified {
+        if any_material_modified || any_image_modified || atlas_reallocated {
             utils::measure("tick.materials", || {
                 self.materials.refresh(&self.images);
             });
@@ -319,45 +515,85 @@ where
 
         // ---
 
-        let any_instance_changed = utils::measure("tick.instances", || {
+        let changed_meshes = utils::measure("tick.meshes", || {
+            self.meshes.refresh(&mut self.triangles, &mut self.bvh)
+        });
+
+        let mut changed_instances = utils::measure("tick.instances", || {
             self.instances.refresh(
-                &self.meshes,
+                &changed_meshes,
                 &self.materials,
-                &mut self.triangles,
                 &mut self.bvh,
             )
         });
 
+        let any_instance_changed = !changed_instances.is_empty();
+
+        // Materials don't keep track of which instances use them, so when any
+        // material changes, all instances have to be revisited
+        if any_material_modified {
+            changed_instances
+                .extend(self.instances.iter().map(|(handle, _)| handle));
+        }
+
+        if !changed_instances.is_empty() {
+            utils::measure("tick.lights.emissive", || {
+                self.lights.refresh_emissive(
+                    &changed_instances,
+                    &self.instances,
+                    &self.materials,
+                    &self.triangles,
+                );
+            });
+        }
+
         if any_instance_changed {
             utils::measure("tick.bvh", || {
                 self.bvh.refresh(&self.materials);
             });
         }
 
+        utils::measure("tick.bvh.poll", || {
+            self.bvh.poll(&self.materials);
+        });
+
+        self.triangles
+            .release(self.bvh.generation(), self.bvh.latest_generation());
+
         // ---
 
         *self.world = gpu::World {
             light_count: self.lights.len(),
-            sun_azimuth: self.sun.azimuth,
-            sun_altitude: self.sun.altitude,
+            emissive_count: self.lights.emissive_len(),
+            infinite_light_count: 0,
+            celestial_body_count: self.celestial_bodies.len() as u32,
+            celestial_bodies: Default::default(),
         };
 
-        utils::measure("tick.world", || {
-            self.world.flush(queue);
-        });
-
-        if mem::take(&mut self.has_dirty_sun) {
-            self.lights.update_sun(*self.world);
+        for (body, item) in self
+            .celestial_bodies
+            .iter()
+            .zip(&mut self.world.celestial_bodies)
+        {
+            *item = body.serialize();
         }
 
         let any_buffer_reallocated = utils::measure("tick.buffers", || {
-            false
+            atlas_reallocated
                 | self.bvh.flush(device, queue).reallocated
                 | self.triangles.flush(device, queue).reallocated
                 | self.lights.flush(device, queue).reallocated
                 | self.materials.flush(device, queue).reallocated
         });
 
+        // Number of infinite lights is known only after the light tree gets
+        // rebuilt, i.e. after flushing the lights
+        self.world.infinite_light_count = self.lights.infinite_len();
+
+        utils::measure("tick.world", || {
+            self.world.flush(queue);
+        });
+
         // ---
 
         if any_buffer_reallocated {
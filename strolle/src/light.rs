@@ -1,23 +1,85 @@
-use glam::{vec4, Vec3};
+use std::f32::consts::PI;
+
+use glam::{vec2, vec4, Affine3A, Quat, Vec2, Vec3, Vec4};
 
 use crate::gpu;
 
+/// Light source.
+///
+/// Lights are described in photometric units - each light has a color (in
+/// linear RGB), which gets scaled by the light's luminous power, illuminance
+/// or luminance; the final image is then scaled by camera's exposure (see:
+/// [`crate::Exposure`]).
 #[derive(Clone, Debug)]
 pub enum Light {
     Point {
         position: Vec3,
         radius: f32,
         color: Vec3,
+        /// Luminous power, in lumens
+        luminous_power: f32,
         range: f32,
+        /// Photometric profile; profile redistributes light's luminous power
+        /// without changing it - to get profile's absolute intensities, use
+        /// [`crate::IesProfile::luminous_power()`] as the light's power
+        profile: Option<LightProfile>,
     },
 
     Spot {
         position: Vec3,
         radius: f32,
         color: Vec3,
+        /// Luminous power, in lumens; as in Bevy and Blender, this is the power
+        /// of a point light the spot light is cut out from (i.e. narrowing the
+        /// cone doesn't make the light brighter)
+        luminous_power: f32,
         range: f32,
         direction: Vec3,
-        angle: f32,
+        /// Angle at which the light starts to fade out, in radians
+        inner_angle: f32,
+        /// Angle at which the light fades out completely, in radians
+        outer_angle: f32,
+        /// Photometric profile; note that spot light's profile always has its
+        /// nadir aligned with the light's direction (i.e. only the profile's
+        /// rotation around that direction is taken into account); see:
+        /// [`Light::Point::profile`]
+        profile: Option<LightProfile>,
+    },
+
+    /// Light infinitely far away, e.g. the sun.
+    Directional {
+        /// Direction the light travels in
+        direction: Vec3,
+        color: Vec3,
+        /// Illuminance, in lux
+        illuminance: f32,
+        /// Apparent size of the light, in radians - controls the softness of
+        /// shadows (e.g. the sun as seen from the Earth is about 0.0093)
+        angular_diameter: f32,
+    },
+
+    /// Rectangular area light, e.g. a window or a panel.
+    ///
+    /// The rectangle lies in the transform's local XY plane, centered at its
+    /// origin, and emits light towards the transform's forward direction (i.e.
+    /// local -Z) - or in both directions, if `two_sided` is set.
+    Rect {
+        transform: Affine3A,
+        size: Vec2,
+        color: Vec3,
+        /// Luminance, in nits (cd/m²)
+        luminance: f32,
+        two_sided: bool,
+    },
+
+    /// Disk-shaped area light; see: [`Light::Rect`].
+    Disk {
+        transform: Affine3A,
+        radius: f32,
+        color: Vec3,
+        /// Luminance, in nits (cd/m²)
+        luminance: f32,
+        two_sided: bool,
     },
 }
 
@@ -26,43 +88,138 @@ impl Light {
         let d0;
         let d1;
         let d2;
+        let mut d3 = Vec4::ZERO;
 
         match self {
             Light::Point {
                 position,
                 radius,
                 color,
+                luminous_power,
                 range,
+                profile,
             } => {
                 d0 = position.extend(*radius);
-                d1 = color.extend(*range);
+                d1 = luminous_intensity(*color, *luminous_power).extend(*range);
 
-                d2 = vec4(
-                    f32::from_bits(gpu::Light::TYPE_POINT),
-                    Default::default(),
-                    Default::default(),
-                    Default::default(),
-                );
+                d2 = if let Some(profile) = profile {
+                    let nadir = profile.rotation * -Vec3::Y;
+                    let angle = profile.angle(nadir);
+                    let nadir = gpu::Normal::encode(nadir);
+
+                    vec4(
+                        f32::from_bits(profile.encode(gpu::Light::TYPE_POINT)),
+                        nadir.x,
+                        nadir.y,
+                        angle,
+                    )
+                } else {
+                    vec4(
+                        f32::from_bits(gpu::Light::TYPE_POINT),
+                        Default::default(),
+                        Default::default(),
+                        Default::default(),
+                    )
+                };
             }
 
             Light::Spot {
                 position,
                 radius,
                 color,
+                luminous_power,
                 range,
                 direction,
-                angle,
+                inner_angle,
+                outer_angle,
+                profile,
             } => {
+                let ty = profile.map_or(gpu::Light::TYPE_SPOT, |profile| {
+                    profile.encode(gpu::Light::TYPE_SPOT)
+                });
+
+                let angle = profile.map_or(0.0, |profile| {
+                    profile.angle(direction.normalize())
+                });
+
                 let direction = gpu::Normal::encode(*direction);
 
                 d0 = position.extend(*radius);
-                d1 = color.extend(*range);
+                d1 = luminous_intensity(*color, *luminous_power).extend(*range);
 
                 d2 = vec4(
-                    f32::from_bits(gpu::Light::TYPE_SPOT),
+                    f32::from_bits(ty),
                     direction.x,
                     direction.y,
-                    *angle,
+                    *outer_angle,
+                );
+
+                d3 = vec4(0.0, 0.0, inner_angle.min(*outer_angle), angle);
+            }
+
+            Light::Directional {
+                direction,
+                color,
+                illuminance,
+                angular_diameter,
+            } => {
+                let direction = gpu::Normal::encode(direction.normalize());
+
+                d0 = Default::default();
+                d1 = (*color * *illuminance).extend(f32::INFINITY);
+
+                d2 = vec4(
+                    f32::from_bits(gpu::Light::TYPE_DIRECTIONAL),
+                    direction.x,
+                    direction.y,
+                    *angular_diameter,
+                );
+            }
+
+            Light::Rect {
+                transform,
+                size,
+                color,
+                luminance,
+                two_sided,
+            } => {
+                let (normal, angle, scale) = area_frame(transform);
+                let size = *size * scale;
+
+                d0 = Vec3::from(transform.translation).extend(size.x);
+                d1 = (*color * *luminance).extend(size.y);
+
+                d2 = vec4(
+                    f32::from_bits(area_type(
+                        gpu::Light::TYPE_RECT,
+                        *two_sided,
+                    )),
+                    normal.x,
+                    normal.y,
+                    angle,
+                );
+            }
+
+            Light::Disk {
+                transform,
+                radius,
+                color,
+                luminance,
+                two_sided,
+            } => {
+                let (normal, angle, scale) = area_frame(transform);
+
+                d0 = Vec3::from(transform.translation).extend(radius * scale.x);
+                d1 = (*color * *luminance).extend(Default::default());
+
+                d2 = vec4(
+                    f32::from_bits(area_type(
+                        gpu::Light::TYPE_DISK,
+                        *two_sided,
+                    )),
+                    normal.x,
+                    normal.y,
+                    angle,
                 );
             }
         }
@@ -71,10 +228,282 @@ impl Light {
             d0,
             d1,
             d2,
-            d3: Default::default(),
+            d3,
             prev_d0: Default::default(),
             prev_d1: Default::default(),
             prev_d2: Default::default(),
         }
     }
 }
+
+/// Photometric profile attached to a point or a spot light.
+#[derive(Clone, Copy, Debug)]
+pub struct LightProfile {
+    pub handle: LightProfileHandle,
+
+    /// Orientation of the profile - by default the profile's nadir (i.e.
+    /// vertical angle 0°) points towards -Y and its horizontal angle 0° points
+    /// towards +X.
+    pub rotation: Quat,
+}
+
+impl LightProfile {
+    fn encode(self, ty: u32) -> u32 {
+        ty | ((self.handle.0 + 1) << gpu::Light::PROFILE_SHIFT)
+    }
+
+    /// Returns rotation of the profile's horizontal angle 0° around given
+    /// nadir (see: `gpu::Light::profile_frame()`).
+    fn angle(self, nadir: Vec3) -> f32 {
+        let tangent = self.rotation * Vec3::X;
+        let (t0, b0) = nadir.any_orthonormal_pair();
+
+        tangent.dot(b0).atan2(tangent.dot(t0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LightProfileHandle(u32);
+
+impl LightProfileHandle {
+    pub(crate) fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    pub(crate) fn get(self) -> u32 {
+        self.0
+    }
+}
+
+/// Converts luminous power (in lumens) of an isotropic light into its luminous
+/// intensity (in candelas).
+fn luminous_intensity(color: Vec3, luminous_power: f32) -> Vec3 {
+    color * luminous_power / (4.0 * PI)
+}
+
+/// Returns orientation of an area light lying in the transform's local XY
+/// plane: its (encoded) normal, its rotation around that normal (see:
+/// `gpu::Light::area_axes()`) and its scale.
+fn area_frame(transform: &Affine3A) -> (Vec2, f32, Vec2) {
+    let x_axis = Vec3::from(transform.matrix3.x_axis);
+    let y_axis = Vec3::from(transform.matrix3.y_axis);
+    let normal = -x_axis.cross(y_axis).normalize();
+    let tangent = x_axis.normalize();
+    let (t0, b0) = normal.any_orthonormal_pair();
+    let angle = tangent.dot(b0).atan2(tangent.dot(t0));
+
+    (
+        gpu::Normal::encode(normal),
+        angle,
+        vec2(x_axis.length(), y_axis.length()),
+    )
+}
+
+fn area_type(ty: u32, two_sided: bool) -> u32 {
+    if two_sided {
+        ty | gpu::Light::FLAG_TWO_SIDED
+    } else {
+        ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Quat};
+
+    use super::*;
+
+    #[test]
+    fn area_light_orientation() {
+        let transform = Affine3A::from_scale_rotation_translation(
+            vec3(2.0, 3.0, 1.0),
+            Quat::from_rotation_x(0.7) * Quat::from_rotation_y(-1.2),
+            vec3(1.0, 2.0, 3.0),
+        );
+
+        let light = Light::Rect {
+            transform,
+            size: vec2(1.0, 2.0),
+            color: Vec3::ONE,
+            luminance: 1.0,
+            two_sided: true,
+        }
+        .serialize();
+
+        assert!(light.is_rect());
+        assert!(light.is_two_sided());
+        assert_eq!(vec3(1.0, 2.0, 3.0), light.center());
+        assert!((light.area() - 12.0).abs() < 1e-4);
+
+        let forward = transform.transform_vector3(-Vec3::Z).normalize();
+
+        assert!(light.dir().abs_diff_eq(forward, 1e-3));
+
+        // Points on the light's edge must land on the transformed rectangle
+        let corner = light.ray_bnoise(vec2(0.25, 1.0), Vec3::ZERO).origin();
+        let corner = transform.inverse().transform_point3(corner);
+
+        assert!(
+            corner.abs().abs_diff_eq(vec3(0.25, 1.0, 0.0), 1e-3),
+            "{corner}"
+        );
+    }
+
+    #[test]
+    fn area_light_radiance_at() {
+        let light = Light::Rect {
+            transform: Affine3A::IDENTITY,
+            size: vec2(10.0, 10.0),
+            color: Vec3::ONE,
+            luminance: 1.0,
+            two_sided: false,
+        }
+        .serialize();
+
+        let hit = |point: Vec3, normal: Vec3| gpu::Hit {
+            origin: Vec3::ZERO,
+            dir: (point - Vec3::ZERO).normalize_or_zero(),
+            point,
+            gbuffer: gpu::GBufferEntry {
+                base_color: Vec4::ONE,
+                normal,
+                roughness: 1.0,
+                ..Default::default()
+            },
+        };
+
+        // Averages the light's contribution over a grid of points on its
+        // surface
+        let integrate = |hit: gpu::Hit| {
+            let n = 64;
+            let mut sum = 0.0;
+
+            for i in 0..n {
+                for j in 0..n {
+                    let sample = (vec2(i as f32, j as f32) + 0.5) / (n as f32);
+                    let point = light.ray_bnoise(sample, hit.point).origin();
+
+                    sum += light.radiance_at(hit, point).radiance.x;
+                }
+            }
+
+            sum / ((n * n) as f32)
+        };
+
+        // Far away, the light is pretty much a sphere
+        let far = hit(vec3(0.0, 0.0, -100.0), Vec3::Z);
+        let expected = light.radiance(far).radiance.x;
+        let actual = integrate(far);
+
+        assert!((actual / expected - 1.0).abs() < 0.02, "{actual}");
+
+        // Right beside the light's plane most of the light is seen at grazing
+        // angles, which the sphere doesn't account for
+        let beside = hit(vec3(5.5, 0.0, -0.1), -Vec3::X);
+        let approx = light.radiance(beside).radiance.x;
+        let actual = integrate(beside);
+
+        assert!(actual > 4.0 * approx, "{actual} vs {approx}");
+
+        // Behind the light there's nothing to see
+        let behind = hit(vec3(0.0, 0.0, 1.0), -Vec3::Z);
+
+        assert_eq!(0.0, integrate(behind));
+    }
+
+    #[test]
+    fn profile_orientation() {
+        let rotation = Quat::from_rotation_z(0.4) * Quat::from_rotation_y(1.3);
+
+        let profile = LightProfile {
+            handle: LightProfileHandle::new(3),
+            rotation,
+        };
+
+        let light = Light::Point {
+            position: Vec3::ZERO,
+            radius: 0.1,
+            color: Vec3::ONE,
+            luminous_power: 4.0 * PI,
+            range: 10.0,
+            profile: Some(profile),
+        }
+        .serialize();
+
+        assert!(light.is_point());
+        assert!(light.has_profile());
+        assert_eq!(3, light.profile_id());
+
+        let (nadir, tangent, _) = light.profile_frame();
+
+        assert!(nadir.abs_diff_eq(rotation * -Vec3::Y, 1e-3), "{nadir}");
+        assert!(tangent.abs_diff_eq(rotation * Vec3::X, 1e-3), "{tangent}");
+
+        // Spot light's profile follows the light's direction
+        let direction = vec3(1.0, -2.0, 0.5).normalize();
+
+        let light = Light::Spot {
+            position: Vec3::ZERO,
+            radius: 0.1,
+            color: Vec3::ONE,
+            luminous_power: 4.0 * PI,
+            range: 10.0,
+            direction,
+            inner_angle: 0.3,
+            outer_angle: 0.5,
+            profile: Some(profile),
+        }
+        .serialize();
+
+        assert!(light.is_spot());
+        assert_eq!(3, light.profile_id());
+
+        let (nadir, tangent, _) = light.profile_frame();
+        let expected = rotation * Vec3::X;
+        let expected =
+            (expected - direction * expected.dot(direction)).normalize();
+
+        assert!(nadir.abs_diff_eq(direction, 1e-3), "{nadir}");
+        assert!(tangent.abs_diff_eq(expected, 1e-3), "{tangent}");
+    }
+
+    #[test]
+    fn photometric_units() {
+        let point = Light::Point {
+            position: Vec3::ZERO,
+            radius: 0.1,
+            color: vec3(1.0, 0.5, 0.25),
+            luminous_power: 800.0,
+            range: 10.0,
+            profile: None,
+        }
+        .serialize();
+
+        let intensity = 800.0 / (4.0 * PI);
+
+        assert!(point
+            .color()
+            .abs_diff_eq(vec3(1.0, 0.5, 0.25) * intensity, 1e-3));
+
+        let directional = Light::Directional {
+            direction: -Vec3::Y,
+            color: Vec3::ONE,
+            illuminance: 100000.0,
+            angular_diameter: 0.0093,
+        }
+        .serialize();
+
+        assert_eq!(Vec3::splat(100000.0), directional.color());
+
+        let disk = Light::Disk {
+            transform: Affine3A::IDENTITY,
+            radius: 1.0,
+            color: vec3(0.5, 1.0, 1.0),
+            luminance: 200.0,
+            two_sided: false,
+        }
+        .serialize();
+
+        assert_eq!(vec3(100.0, 200.0, 200.0), disk.color());
+    }
+}
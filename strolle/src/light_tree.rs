@@ -0,0 +1,432 @@
+use std::f32::consts::PI;
+
+use glam::{Quat, Vec3};
+
+use crate::utils::{Axis, BoundingBox};
+use crate::{gpu, Bindable, BufferFlushOutcome, MappedStorageBuffer};
+
+/// Hierarchy of lights, used to pick lights proportionally to their estimated
+/// contribution at given point (see: [`gpu::LightsView::sample()`]).
+///
+/// Lights whose contribution doesn't fall off with distance (see:
+/// [`gpu::Light::is_infinite()`]) are stored at the beginning of the buffer as
+/// standalone leaves, while the rest of the lights are arranged into a binary
+/// tree rooted right after them.
+#[derive(Debug)]
+pub struct LightTree {
+    buffer: MappedStorageBuffer<Vec<gpu::LightTreeNode>>,
+    infinite_len: u32,
+}
+
+impl LightTree {
+    pub fn new(device: &wgpu::Device) -> Self {
+        Self {
+            buffer: MappedStorageBuffer::new_default(device, "light_tree"),
+            infinite_len: 0,
+        }
+    }
+
+    /// Rebuilds the tree from scratch, storing each light's path within the
+    /// tree back into the light (see: [`gpu::Light::trail()`]).
+    ///
+    /// `lights` are expected to be indexed by their ids.
+    pub fn rebuild(&mut self, lights: &mut [gpu::Light]) {
+        self.buffer.clear();
+        self.infinite_len = Self::build_all(&mut self.buffer, lights);
+    }
+
+    /// Builds the tree into `nodes`, returning number of infinite lights.
+    fn build_all(
+        nodes: &mut Vec<gpu::LightTreeNode>,
+        lights: &mut [gpu::Light],
+    ) -> u32 {
+        let mut items = Vec::new();
+
+        for (light_id, light) in lights.iter().enumerate() {
+            let light_id = light_id as u32;
+
+            if light.is_none() {
+                continue;
+            }
+
+            let bounds = LightBounds::new(*light);
+
+            if light.is_infinite() {
+                nodes.push(
+                    bounds.serialize(light_id, gpu::LightTreeNode::FLAG_LEAF),
+                );
+            } else {
+                items.push((light_id, bounds));
+            }
+        }
+
+        let infinite_len = nodes.len() as u32;
+
+        if !items.is_empty() {
+            Self::build(nodes, lights, &mut items, 0, 0);
+        }
+
+        infinite_len
+    }
+
+    fn build(
+        nodes: &mut Vec<gpu::LightTreeNode>,
+        lights: &mut [gpu::Light],
+        items: &mut [(u32, LightBounds)],
+        depth: u32,
+        trail: u32,
+    ) -> LightBounds {
+        assert!(depth < u32::BITS, "light tree is too deep");
+
+        let node_idx = nodes.len();
+
+        nodes.push(Default::default());
+
+        if let [(light_id, bounds)] = items {
+            lights[*light_id as usize].set_trail(trail);
+
+            nodes[node_idx] =
+                bounds.serialize(*light_id, gpu::LightTreeNode::FLAG_LEAF);
+
+            return *bounds;
+        }
+
+        // Splitting at the median keeps the tree balanced, which guarantees
+        // that each light's trail fits within an u32
+        let axis = {
+            let centroids: BoundingBox =
+                items.iter().map(|(_, bounds)| bounds.centroid()).collect();
+
+            let extent = centroids.extent();
+
+            Axis::all()
+                .max_by(|a, b| extent[*a].total_cmp(&extent[*b]))
+                .unwrap()
+        };
+
+        let mid = items.len() / 2;
+
+        items.select_nth_unstable_by(mid, |(_, a), (_, b)| {
+            a.centroid()[axis].total_cmp(&b.centroid()[axis])
+        });
+
+        let (left, right) = items.split_at_mut(mid);
+        let left = Self::build(nodes, lights, left, depth + 1, trail);
+        let right_idx = nodes.len() as u32;
+
+        let right =
+            Self::build(nodes, lights, right, depth + 1, trail | (1 << depth));
+
+        let bounds = left.union(right);
+
+        nodes[node_idx] = bounds.serialize(right_idx, 0);
+        bounds
+    }
+
+    pub fn infinite_len(&self) -> u32 {
+        self.infinite_len
+    }
+
+    pub fn flush(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> BufferFlushOutcome {
+        self.buffer.flush(device, queue)
+    }
+
+    pub fn bind_readable(&self) -> impl Bindable + '_ {
+        self.buffer.bind_readable()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LightBounds {
+    bounds: BoundingBox,
+    power: f32,
+    axis: Vec3,
+    theta_o: f32,
+    theta_e: f32,
+    two_sided: bool,
+}
+
+impl LightBounds {
+    fn new(light: gpu::Light) -> Self {
+        let bounds = if light.is_directional() {
+            BoundingBox::new(Vec3::ZERO, Vec3::ZERO)
+        } else if light.is_triangle() {
+            let (v0, e1, e2) = light.triangle_vertices();
+
+            [v0, v0 + e1, v0 + e2].into_iter().collect()
+        } else {
+            let radius = Vec3::splat(light.bounding_radius());
+
+            BoundingBox::new(light.center() - radius, light.center() + radius)
+        };
+
+        let (axis, theta_o, theta_e) = if light.is_spot() {
+            let inner = light.spot_inner_angle();
+            let outer = light.spot_outer_angle();
+
+            (light.dir(), inner, (outer - inner).max(0.0))
+        } else if light.is_area() {
+            (light.area_normal(), 0.0, 0.5 * PI)
+        } else {
+            (Vec3::Z, PI, 0.5 * PI)
+        };
+
+        Self {
+            bounds,
+            power: light.power(),
+            axis,
+            theta_o,
+            theta_e,
+            two_sided: light.is_area() && light.is_two_sided(),
+        }
+    }
+
+    fn centroid(&self) -> Vec3 {
+        self.bounds.center()
+    }
+
+    fn union(self, other: Self) -> Self {
+        let (axis, theta_o) = Self::union_cones(
+            (self.axis, self.theta_o),
+            (other.axis, other.theta_o),
+        );
+
+        Self {
+            bounds: self.bounds + other.bounds,
+            power: self.power + other.power,
+            axis,
+            theta_o,
+            theta_e: self.theta_e.max(other.theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    /// Returns the smallest cone that encloses both given cones, each given as
+    /// an axis and a spread angle.
+    fn union_cones(a: (Vec3, f32), b: (Vec3, f32)) -> (Vec3, f32) {
+        let (a_axis, a_theta) = a;
+        let (b_axis, b_theta) = b;
+        let theta_d = a_axis.angle_between(b_axis);
+
+        if (theta_d + b_theta).min(PI) <= a_theta {
+            return a;
+        }
+
+        if (theta_d + a_theta).min(PI) <= b_theta {
+            return b;
+        }
+
+        let theta_o = 0.5 * (a_theta + theta_d + b_theta);
+        let rotation_axis = a_axis.cross(b_axis);
+
+        if theta_o >= PI || rotation_axis.length_squared() == 0.0 {
+            return (a_axis, PI);
+        }
+
+        let rotation =
+            Quat::from_axis_angle(rotation_axis.normalize(), theta_o - a_theta);
+
+        (rotation * a_axis, theta_o)
+    }
+
+    fn serialize(self, d3x: u32, flags: u32) -> gpu::LightTreeNode {
+        let flags = if self.two_sided {
+            flags | gpu::LightTreeNode::FLAG_TWO_SIDED
+        } else {
+            flags
+        };
+
+        gpu::LightTreeNode {
+            d0: self.bounds.min().extend(self.power),
+            d1: self.bounds.max().extend(self.theta_o),
+            d2: self.axis.extend(self.theta_e),
+            d3: glam::vec4(
+                f32::from_bits(d3x),
+                f32::from_bits(flags),
+                0.0,
+                0.0,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{uvec2, vec3};
+
+    use super::*;
+    use crate::Light;
+
+    fn hit(point: Vec3, normal: Vec3) -> gpu::Hit {
+        gpu::Hit {
+            origin: point + normal,
+            dir: -normal,
+            point,
+            gbuffer: gpu::GBufferEntry {
+                normal,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn sampling() {
+        let mut lights: Vec<_> = (0..12)
+            .map(|idx| {
+                Light::Point {
+                    position: vec3(idx as f32, 1.0, (idx % 3) as f32),
+                    radius: 0.1,
+                    color: Vec3::splat(1.0 + idx as f32),
+                    luminous_power: 4.0 * PI,
+                    range: 50.0,
+                    profile: None,
+                }
+                .serialize()
+            })
+            .collect();
+
+        lights.push(
+            Light::Directional {
+                direction: -Vec3::Y,
+                color: Vec3::ONE,
+                illuminance: 1.0,
+                angular_diameter: 0.01,
+            }
+            .serialize(),
+        );
+
+        lights.push(gpu::Light::triangle(
+            [
+                vec3(0.0, 2.0, 0.0),
+                vec3(0.0, 2.0, 1.0),
+                vec3(1.0, 2.0, 0.0),
+            ],
+            Vec3::ONE,
+        ));
+
+        lights.push(
+            Light::Spot {
+                position: vec3(5.0, 3.0, 0.0),
+                radius: 0.1,
+                color: Vec3::ONE,
+                luminous_power: 4.0 * PI,
+                range: 50.0,
+                direction: Vec3::Y,
+                inner_angle: 0.2,
+                outer_angle: 0.3,
+                profile: None,
+            }
+            .serialize(),
+        );
+
+        let mut nodes = Vec::new();
+        let infinite_len = LightTree::build_all(&mut nodes, &mut lights);
+
+        assert_eq!(1, infinite_len);
+
+        let world = gpu::World {
+            light_count: lights.len() as u32,
+            infinite_light_count: infinite_len,
+            ..Default::default()
+        };
+
+        let view = gpu::LightsView::new(&lights, &[]);
+        let tree = gpu::LightTreeView::new(&nodes);
+        let hit = hit(vec3(3.0, 0.0, 1.0), Vec3::Y);
+
+        // Probabilities of all lights must add up to one
+        let total: f32 = (0..lights.len())
+            .map(|id| view.pdf(tree, world, hit, gpu::LightId::new(id as u32)))
+            .sum();
+
+        assert!((total - 1.0).abs() < 1e-4, "total={total}");
+
+        // The spot light points away from the shaded point, so it must never
+        // get picked
+        let spot_id = gpu::LightId::new(14);
+
+        assert_eq!(0.0, view.pdf(tree, world, hit, spot_id));
+
+        // Sampling must agree with the reported probabilities
+        let mut wnoise = gpu::WhiteNoise::new(1234, uvec2(0, 0));
+        let mut hits = vec![0; lights.len()];
+
+        for _ in 0..20000 {
+            let (light_id, light_pdf) =
+                view.sample(tree, &mut wnoise, world, hit);
+
+            assert!(light_pdf > 0.0);
+
+            assert!(
+                (light_pdf - view.pdf(tree, world, hit, light_id)).abs() < 1e-5
+            );
+
+            hits[light_id.get() as usize] += 1;
+        }
+
+        for (id, hits) in hits.into_iter().enumerate() {
+            let expected =
+                view.pdf(tree, world, hit, gpu::LightId::new(id as u32));
+
+            let actual = (hits as f32) / 20000.0;
+
+            assert!(
+                (actual - expected).abs() < 0.02,
+                "light #{id}: actual={actual}, expected={expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn infinite_lights_are_weighted_by_power() {
+        let pdf_of_sun = |illuminance| {
+            let mut lights: Vec<_> = (0..12)
+                .map(|idx| {
+                    Light::Point {
+                        position: vec3(idx as f32, 1.0, 0.0),
+                        radius: 0.1,
+                        color: Vec3::ONE,
+                        luminous_power: 1000.0,
+                        range: 50.0,
+                        profile: None,
+                    }
+                    .serialize()
+                })
+                .collect();
+
+            lights.push(
+                Light::Directional {
+                    direction: -Vec3::Y,
+                    color: Vec3::ONE,
+                    illuminance,
+                    angular_diameter: 0.01,
+                }
+                .serialize(),
+            );
+
+            let mut nodes = Vec::new();
+            let infinite_len = LightTree::build_all(&mut nodes, &mut lights);
+
+            let world = gpu::World {
+                light_count: lights.len() as u32,
+                infinite_light_count: infinite_len,
+                ..Default::default()
+            };
+
+            let view = gpu::LightsView::new(&lights, &[]);
+            let tree = gpu::LightTreeView::new(&nodes);
+            let hit = hit(vec3(6.0, 0.0, 0.0), Vec3::Y);
+
+            view.pdf(tree, world, hit, gpu::LightId::new(12))
+        };
+
+        // Dim sun shouldn't take samples away from the bright local lights,
+        // while a bright one should get most of them
+        assert!(pdf_of_sun(0.1) < 0.01, "{}", pdf_of_sun(0.1));
+        assert!(pdf_of_sun(100000.0) > 0.99, "{}", pdf_of_sun(100000.0));
+    }
+}
@@ -1,11 +1,15 @@
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::mem;
+use std::ops::Range;
 
-use derivative::Derivative;
-
+use crate::gpu::Vec3Ext;
+use crate::utils::Allocator;
 use crate::{
-    gpu, Bindable, BufferFlushOutcome, Light, MappedStorageBuffer, Params,
+    gpu, Bindable, BufferFlushOutcome, IesProfile, Instances, Light,
+    LightProfileHandle, LightTree, MappedStorageBuffer, Materials, Params,
+    Triangles,
 };
 
 #[derive(Debug)]
@@ -14,46 +18,75 @@ where
     P: Params,
 {
     buffer: MappedStorageBuffer<Vec<gpu::Light>>,
-    index: HashMap<LightHandle<P>, gpu::LightId>,
-    created: HashSet<LightHandle<P>>,
-    updated: HashSet<LightHandle<P>>,
-    remapped: HashMap<LightHandle<P>, gpu::LightId>,
+    index: HashMap<P::LightHandle, gpu::LightId>,
+    created: HashSet<P::LightHandle>,
+    updated: HashSet<P::LightHandle>,
     killed: HashSet<gpu::LightId>,
-    next_light_id: gpu::LightId,
-}
 
-impl<P> Lights<P>
-where
-    P: Params,
-{
-    pub fn new(device: &wgpu::Device) -> Self {
-        let mut buffer =
-            MappedStorageBuffer::<Vec<_>>::new_default(device, "stolle_lights");
+    /// Slots freed by removed lights, reused by the lights created later.
+    ///
+    /// Thanks to this, removing a light doesn't affect ids of other lights
+    /// and so it doesn't invalidate their reservoirs.
+    free_slots: Allocator,
+
+    /// Number of slots occupied by lights, including the free ones.
+    slot_count: u32,
+
+    /// Emissive triangles, stored in the buffer right after the light slots
+    /// (see: [`gpu::World::emissive_count`]).
+    emissive: Vec<gpu::Light>,
 
-        buffer.push(gpu::Light::sun(Default::default(), Default::default()));
+    /// Range of `emissive` occupied by each instance's triangles.
+    ///
+    /// Instances keep their ranges for as long as their triangle count doesn't
+    /// change, so that updating one instance doesn't affect ids of triangles
+    /// of other instances (and thus doesn't invalidate their reservoirs).
+    emissive_instances: HashMap<P::InstanceHandle, Range<usize>>,
 
-        // ---
+    /// Ranges of `emissive` freed by removed instances.
+    free_emissive: Allocator,
 
-        let mut index = HashMap::new();
+    /// Whether the emissive triangles have to be re-uploaded, either because
+    /// they have changed or because they have been shifted by a new slot.
+    has_dirty_emissive: bool,
 
-        index.insert(LightHandle::Sun, gpu::LightId::new(0));
+    tree: LightTree,
 
-        // ---
+    /// Tables of photometric profiles (see: [`gpu::LightProfilesView`]).
+    profiles: MappedStorageBuffer<Vec<f32>>,
 
+    /// Slots freed by removed profiles, reused by the profiles added later.
+    free_profiles: Allocator,
+}
+
+impl<P> Lights<P>
+where
+    P: Params,
+{
+    pub fn new(device: &wgpu::Device) -> Self {
         Self {
-            buffer,
-            index,
+            buffer: MappedStorageBuffer::new_default(device, "lights"),
+            index: Default::default(),
             created: Default::default(),
             updated: Default::default(),
-            remapped: Default::default(),
             killed: Default::default(),
-            next_light_id: gpu::LightId::new(1),
+            free_slots: Default::default(),
+            slot_count: 0,
+            emissive: Default::default(),
+            emissive_instances: Default::default(),
+            free_emissive: Default::default(),
+            has_dirty_emissive: Default::default(),
+            tree: LightTree::new(device),
+            profiles: MappedStorageBuffer::new_default(
+                device,
+                "light_profiles",
+            ),
+            free_profiles: Default::default(),
         }
     }
 
     pub fn insert(&mut self, handle: P::LightHandle, item: Light) {
         let item = item.serialize();
-        let handle = LightHandle::Light(handle);
 
         match self.index.entry(handle) {
             Entry::Occupied(entry) => {
@@ -63,71 +96,190 @@ where
             }
 
             Entry::Vacant(entry) => {
-                if let Some(slot) =
-                    self.buffer.get_mut(self.next_light_id.get() as usize)
-                {
-                    *slot = item;
-                    entry.insert(self.next_light_id);
+                let idx = if let Some(slot) = self.free_slots.take(1) {
+                    slot.start
                 } else {
-                    let id = gpu::LightId::new(self.buffer.len() as u32);
+                    // New slot overlaps the first emissive triangle, so all of
+                    // them have to be shifted
+                    self.has_dirty_emissive = true;
+                    self.slot_count += 1;
+
+                    self.slot_count as usize - 1
+                };
 
+                if let Some(slot) = self.buffer.get_mut(idx) {
+                    *slot = item;
+                } else {
                     self.buffer.push(item);
-                    entry.insert(id);
                 }
 
+                entry.insert(gpu::LightId::new(idx as u32));
                 self.created.insert(handle);
-                *self.next_light_id.get_mut() += 1;
             }
         }
     }
 
-    pub fn update_sun(&mut self, world: gpu::World) {
-        let color =
-            strolle_shaders::atmosphere::generate_transmittance_lut::eval(
-                gpu::Atmosphere::VIEW_POS,
-                world.sun_dir(),
-            );
-
-        // TODO probably incorrect
-        let color = color * gpu::Atmosphere::EXPOSURE * 5.0;
-
-        self.update(
-            0,
-            LightHandle::Sun,
-            gpu::Light::sun(world.sun_pos(), color),
-        );
-    }
-
     pub fn remove(&mut self, handle: P::LightHandle) {
-        let handle = LightHandle::Light(handle);
-
         let Some(id) = self.index.remove(&handle) else {
             return;
         };
 
         let idx = id.get() as usize;
 
-        self.buffer.remove(idx);
-        self.buffer.push(Default::default());
+        self.buffer[idx] = Default::default();
+        self.free_slots.give(idx..(idx + 1));
 
         self.created.remove(&handle);
         self.updated.remove(&handle);
-        self.remapped.remove(&handle);
         self.killed.insert(id);
+    }
+
+    pub fn add_profile(&mut self, profile: &IesProfile) -> LightProfileHandle {
+        let size = gpu::LightProfilesView::SIZE as usize;
+
+        let table = profile.table(
+            gpu::LightProfilesView::WIDTH as usize,
+            gpu::LightProfilesView::HEIGHT as usize,
+        );
+
+        let id = if let Some(slot) = self.free_profiles.take(1) {
+            slot.start
+        } else {
+            let id = self.profiles.len() / size;
+
+            self.profiles.resize((id + 1) * size, 0.0);
+            id
+        };
+
+        self.profiles[(id * size)..((id + 1) * size)].copy_from_slice(&table);
+
+        LightProfileHandle::new(id as u32)
+    }
+
+    pub fn remove_profile(&mut self, handle: LightProfileHandle) {
+        let size = gpu::LightProfilesView::SIZE as usize;
+        let id = handle.get() as usize;
+
+        self.profiles[(id * size)..((id + 1) * size)].fill(0.0);
+        self.free_profiles.give(id..(id + 1));
+    }
+
+    /// Collects triangles of given instances with emissive materials, so that
+    /// they can be sampled as lights.
+    ///
+    /// Triangles with emissive textures are skipped - they are accounted for
+    /// when hit by indirect rays instead (see: [`crate::Material::emissive`]).
+    pub fn refresh_emissive(
+        &mut self,
+        changed_instances: &HashSet<P::InstanceHandle>,
+        instances: &Instances<P>,
+        materials: &Materials<P>,
+        triangles: &Triangles<P>,
+    ) {
+        for &handle in changed_instances {
+            let lights = Self::emissive_triangles(
+                handle, instances, materials, triangles,
+            );
+
+            let prev_range = self.emissive_instances.remove(&handle);
+
+            if prev_range.is_none() && lights.is_empty() {
+                continue;
+            }
 
-        *self.next_light_id.get_mut() -= 1;
+            self.has_dirty_emissive = true;
 
-        for (other_handle, other_id) in self.index.iter_mut() {
-            if other_id.get() > id.get() {
-                self.remapped.entry(*other_handle).or_insert(*other_id);
+            let range = match prev_range {
+                Some(range) if range.len() == lights.len() => range,
 
-                *other_id.get_mut() -= 1;
+                prev_range => {
+                    if let Some(range) = prev_range {
+                        self.emissive[range.clone()].fill(Default::default());
+                        self.free_emissive.give(range);
+                    }
+
+                    if lights.is_empty() {
+                        continue;
+                    }
+
+                    self.free_emissive.take(lights.len()).unwrap_or_else(|| {
+                        let start = self.emissive.len();
+
+                        self.emissive
+                            .resize(start + lights.len(), Default::default());
+
+                        start..self.emissive.len()
+                    })
+                }
+            };
+
+            // Triangles remember what was there before, so that reservoirs
+            // pointing at their ids can be reprojected the same way as for
+            // regular lights (modulo `d3`, which isn't tracked)
+            for (slot, mut light) in
+                self.emissive[range.clone()].iter_mut().zip(lights)
+            {
+                light.prev_d0 = slot.d0;
+                light.prev_d1 = slot.d1;
+                light.prev_d2 = slot.d2;
+
+                *slot = light;
             }
+
+            self.emissive_instances.insert(handle, range);
+        }
+    }
+
+    fn emissive_triangles(
+        handle: P::InstanceHandle,
+        instances: &Instances<P>,
+        materials: &Materials<P>,
+        triangles: &Triangles<P>,
+    ) -> Vec<gpu::Light> {
+        let Some(instance) = instances.get(handle) else {
+            return Default::default();
+        };
+
+        let Some(material_id) = materials.lookup(instance.material_handle)
+        else {
+            return Default::default();
+        };
+
+        let material = &materials[material_id];
+        let color = material.emissive.truncate();
+
+        if color.luma() <= 0.0 || material.emissive_texture.is_some() {
+            return Default::default();
         }
+
+        let Some(triangles) = triangles.get(instance.mesh_handle) else {
+            return Default::default();
+        };
+
+        triangles
+            .iter()
+            .map(|triangle| {
+                let positions = triangle.positions().map(|position| {
+                    instance.transform.transform_point3(position)
+                });
+
+                gpu::Light::triangle(positions, color)
+            })
+            // Skip degenerate triangles
+            .filter(|light| light.power() > 0.0)
+            .collect()
     }
 
     pub fn len(&self) -> u32 {
-        self.next_light_id.get()
+        self.index.len() as u32
+    }
+
+    pub fn emissive_len(&self) -> u32 {
+        self.emissive.len() as u32
+    }
+
+    pub fn infinite_len(&self) -> u32 {
+        self.tree.infinite_len()
     }
 
     pub fn flush(
@@ -135,27 +287,58 @@ where
         device: &wgpu::Device,
         queue: &wgpu::Queue,
     ) -> BufferFlushOutcome {
-        for id in &self.killed {
-            self.buffer[id.get() as usize].kill_slot();
+        let is_tree_dirty = self.has_dirty_emissive
+            || !self.created.is_empty()
+            || !self.updated.is_empty()
+            || !self.killed.is_empty();
+
+        let is_emissive_dirty = mem::take(&mut self.has_dirty_emissive);
+
+        if is_emissive_dirty {
+            self.buffer.truncate(self.slot_count as usize);
+            self.buffer.extend_from_slice(&self.emissive);
+        }
+
+        if is_tree_dirty {
+            let len = self.slot_count + self.emissive_len();
+
+            self.tree.rebuild(&mut self.buffer[..len as usize]);
         }
 
-        for (handle, id) in &self.remapped {
-            self.buffer[id.get() as usize].remap_slot(self.index[&handle]);
+        for id in &self.killed {
+            self.buffer[id.get() as usize].kill_slot();
         }
 
-        let outcome = self.buffer.flush(device, queue);
+        let outcome = BufferFlushOutcome {
+            reallocated: self.buffer.flush(device, queue).reallocated
+                | self.tree.flush(device, queue).reallocated
+                | self.profiles.flush(device, queue).reallocated,
+        };
 
         for handle in self.created.iter().chain(&self.updated) {
             self.buffer[self.index[handle].get() as usize].commit();
         }
 
-        for id in self.killed.iter().chain(self.remapped.values()) {
+        if is_emissive_dirty {
+            for (idx, light) in self.emissive.iter_mut().enumerate() {
+                let has_changed = light.prev_d0 != light.d0
+                    || light.prev_d1 != light.d1
+                    || light.prev_d2 != light.d2;
+
+                if has_changed {
+                    light.commit();
+
+                    self.buffer[self.slot_count as usize + idx].commit();
+                }
+            }
+        }
+
+        for id in &self.killed {
             self.buffer[id.get() as usize].clear_slot();
         }
 
         self.created.clear();
         self.updated.clear();
-        self.remapped.clear();
         self.killed.clear();
 
         outcome
@@ -165,10 +348,18 @@ where
         self.buffer.bind_readable()
     }
 
+    pub fn bind_profiles(&self) -> impl Bindable + '_ {
+        self.profiles.bind_readable()
+    }
+
+    pub fn bind_tree(&self) -> impl Bindable + '_ {
+        self.tree.bind_readable()
+    }
+
     fn update(
         &mut self,
         idx: usize,
-        handle: LightHandle<P>,
+        handle: P::LightHandle,
         mut new: gpu::Light,
     ) {
         let old = self.buffer[idx];
@@ -181,15 +372,3 @@ where
         self.buffer[idx] = new;
     }
 }
-
-// TODO sun should be handled outside of Strolle, there's no reason to
-//      special-case it here
-#[derive(Debug, Derivative)]
-#[derivative(Clone, Copy, PartialEq, Eq, Hash)]
-enum LightHandle<P>
-where
-    P: Params,
-{
-    Sun,
-    Light(P::LightHandle),
-}
@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 
-use spirv_std::glam::{vec4, Vec4};
+use spirv_std::glam::{uvec4, vec4, Vec4};
 
 use crate::{gpu, Images, Params};
 
@@ -11,7 +11,18 @@ where
 {
     pub base_color: Vec4,
     pub base_color_texture: Option<P::ImageHandle>,
+    /// Emitted light, as luminance in nits (cd/m²)
+    ///
+    /// Triangles of instances with non-zero emissive (and without
+    /// [`Self::emissive_texture`]) get sampled as lights, using this value as
+    /// their radiance.
     pub emissive: Vec4,
+
+    /// Texture modulating [`Self::emissive`]
+    ///
+    /// Triangles with emissive textures are not sampled as lights - instead,
+    /// they light up the scene when hit by indirect rays, which is noisier but
+    /// takes the texture into account.
     pub emissive_texture: Option<P::ImageHandle>,
     pub perceptual_roughness: f32,
     pub metallic: f32,
@@ -27,25 +38,36 @@ where
     P: Params,
 {
     pub(crate) fn serialize(&self, images: &Images<P>) -> gpu::Material {
+        let texture = |handle| images.lookup_opt(handle).unwrap_or_default();
+
+        let (base_color_texture, base_color_flags) =
+            texture(self.base_color_texture);
+
+        let (emissive_texture, emissive_flags) = texture(self.emissive_texture);
+
+        let (metallic_roughness_texture, metallic_roughness_flags) =
+            texture(self.metallic_roughness_texture);
+
+        let (normal_map_texture, normal_map_flags) =
+            texture(self.normal_map_texture);
+
         gpu::Material {
             base_color: self.base_color,
-            base_color_texture: images
-                .lookup_opt(self.base_color_texture)
-                .unwrap_or_default(),
+            base_color_texture,
             emissive: self.emissive,
-            emissive_texture: images
-                .lookup_opt(self.emissive_texture)
-                .unwrap_or_default(),
+            emissive_texture,
             roughness: self.perceptual_roughness.powf(2.0),
             metallic: self.metallic,
-            metallic_roughness_texture: images
-                .lookup_opt(self.metallic_roughness_texture)
-                .unwrap_or_default(),
+            metallic_roughness_texture,
             reflectance: self.reflectance,
             ior: self.ior,
-            normal_map_texture: images
-                .lookup_opt(self.normal_map_texture)
-                .unwrap_or_default(),
+            normal_map_texture,
+            texture_flags: uvec4(
+                base_color_flags,
+                emissive_flags,
+                metallic_roughness_flags,
+                normal_map_flags,
+            ),
         }
     }
 }
@@ -1,8 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 
 use derivative::Derivative;
 
-use crate::{Mesh, Params};
+use crate::{Bvh, Mesh, MeshTriangle, Params, Triangles};
 
 #[derive(Debug, Derivative)]
 #[derivative(Default)]
@@ -11,6 +12,7 @@ where
     P: Params,
 {
     meshes: HashMap<P::MeshHandle, Mesh>,
+    changed: HashSet<P::MeshHandle>,
 }
 
 impl<P> Meshes<P>
@@ -19,17 +21,59 @@ where
 {
     pub fn insert(&mut self, handle: P::MeshHandle, item: Mesh) {
         self.meshes.insert(handle, item);
-    }
-
-    pub fn get(&self, handle: P::MeshHandle) -> Option<&Mesh> {
-        self.meshes.get(&handle)
+        self.changed.insert(handle);
     }
 
     pub fn remove(&mut self, handle: P::MeshHandle) {
-        self.meshes.remove(&handle);
+        if self.meshes.remove(&handle).is_some() {
+            self.changed.insert(handle);
+        }
     }
 
     pub fn len(&self) -> usize {
         self.meshes.len()
     }
+
+    /// Uploads triangles of all meshes modified since the last refresh and
+    /// rebuilds their bottom-level BVHs; returns handles of those meshes, so
+    /// that the instances referring to them can be updated as well.
+    ///
+    /// Bottom-level BVHs are built right here, on the calling thread, even in
+    /// the asynchronous mode (see: [`crate::EngineConfig::bvh_async_rebuild`]).
+    pub fn refresh(
+        &mut self,
+        triangles: &mut Triangles<P>,
+        bvh: &mut Bvh<P>,
+    ) -> HashSet<P::MeshHandle> {
+        let changed = mem::take(&mut self.changed);
+
+        for &handle in &changed {
+            triangles.remove(handle);
+            bvh.remove_mesh(handle);
+
+            let Some(mesh) = self.meshes.get(&handle) else {
+                continue;
+            };
+
+            // Empty meshes don't have anything to trace against, so instead of
+            // building an empty tree let's pretend the mesh doesn't exist (the
+            // instances will wait until the mesh gets some triangles)
+            if mesh.triangles().is_empty() {
+                continue;
+            }
+
+            let triangle_ids = triangles.create(
+                handle,
+                mesh.triangles().iter().map(MeshTriangle::build),
+            );
+
+            bvh.insert_mesh(
+                handle,
+                triangle_ids.start as u32,
+                mesh.triangles().iter().map(MeshTriangle::build),
+            );
+        }
+
+        changed
+    }
 }
@@ -0,0 +1,28 @@
+use glam::{Vec2, Vec3};
+
+use crate::Params;
+
+/// Intersection of a ray with the world; see: [`crate::Engine::raycast()`].
+#[derive(Clone, Copy, Debug)]
+pub struct RaycastHit<P>
+where
+    P: Params,
+{
+    /// Instance that got hit.
+    pub instance: P::InstanceHandle,
+
+    /// Distance from the ray's origin to the hit point, in world units.
+    pub distance: f32,
+
+    /// Hit point, in world-space.
+    pub point: Vec3,
+
+    /// Interpolated normal at the hit point, in world-space.
+    pub normal: Vec3,
+
+    /// Interpolated texture coordinates at the hit point.
+    pub uv: Vec2,
+
+    /// Material of the instance that got hit.
+    pub material: P::MaterialHandle,
+}
@@ -0,0 +1,56 @@
+use crate::gpu;
+
+/// Options for [`crate::Engine::raycast_with()`] and
+/// [`crate::Engine::occluded_with()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RaycastOptions {
+    /// Kind of ray to cast - instances that opted out of being hit by this
+    /// kind of ray are skipped (see: [`crate::Instance::with_casts_shadows()`]
+    /// etc.), exactly as they are when rendering.
+    ///
+    /// By default rays are cast as if they came from the camera, i.e. they
+    /// hit only what's visible on the screen.
+    pub kind: RayKind,
+
+    /// Only instances whose visibility overlaps this mask are taken into
+    /// account (see: [`crate::Instance::with_visibility()`]).
+    ///
+    /// By default all instances are taken into account.
+    pub visibility_mask: u32,
+}
+
+impl Default for RaycastOptions {
+    fn default() -> Self {
+        Self {
+            kind: Default::default(),
+            visibility_mask: u32::MAX,
+        }
+    }
+}
+
+/// Kind of a raycast; see: [`RaycastOptions::kind`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RayKind {
+    /// Ray hitting instances visible to the camera (see:
+    /// [`crate::Instance::with_visible_to_camera()`]).
+    #[default]
+    Primary,
+
+    /// Ray hitting instances that cast shadows (see:
+    /// [`crate::Instance::with_casts_shadows()`]).
+    Shadow,
+
+    /// Ray hitting instances visible in indirect lighting (see:
+    /// [`crate::Instance::with_visible_in_indirect()`]).
+    Indirect,
+}
+
+impl RayKind {
+    pub(crate) fn serialize(self) -> gpu::RayKind {
+        match self {
+            RayKind::Primary => gpu::RayKind::Primary,
+            RayKind::Shadow => gpu::RayKind::Shadow,
+            RayKind::Indirect => gpu::RayKind::Indirect,
+        }
+    }
+}
@@ -39,6 +39,8 @@ macro_rules! shaders {
 }
 
 shaders!([
+    atlas_downsample,
+    atlas_pad,
     atmosphere_generate_scattering_lut,
     atmosphere_generate_sky_lut,
     atmosphere_generate_transmittance_lut,
@@ -3,11 +3,9 @@ use std::fmt::Debug;
 use std::mem;
 use std::ops::Range;
 
-use crate::bvh::Bvh;
 use crate::utils::Allocator;
 use crate::{
-    gpu, Bindable, BufferFlushOutcome, BvhPrimitive, MappedStorageBuffer,
-    Params, Triangle,
+    gpu, Bindable, BufferFlushOutcome, MappedStorageBuffer, Params, Triangle,
 };
 
 #[derive(Debug)]
@@ -15,9 +13,9 @@ pub struct Triangles<P>
 where
     P: Params,
 {
-    allocator: Allocator,
+    ids: TriangleIds,
     buffer: MappedStorageBuffer<Vec<gpu::Triangle>>,
-    index: HashMap<P::InstanceHandle, IndexedInstance>,
+    index: HashMap<P::MeshHandle, IndexedMesh>,
     dirty: bool,
 }
 
@@ -27,7 +25,7 @@ where
 {
     pub fn new(device: &wgpu::Device) -> Self {
         Self {
-            allocator: Default::default(),
+            ids: Default::default(),
             buffer: MappedStorageBuffer::new_default(device, "triangles"),
             index: Default::default(),
             dirty: Default::default(),
@@ -36,156 +34,94 @@ where
 
     pub fn create(
         &mut self,
-        bvh: &mut Bvh,
-        instance_handle: P::InstanceHandle,
+        mesh_handle: P::MeshHandle,
         triangles: impl Iterator<Item = Triangle> + ExactSizeIterator,
-        material_id: gpu::MaterialId,
-    ) {
+    ) -> Range<usize> {
         assert!(
-            !self.index.contains_key(&instance_handle),
-            "instance {instance_handle:?} has been already added - now it can \
-             be only updated or removed"
+            !self.index.contains_key(&mesh_handle),
+            "mesh {mesh_handle:?} has been already added - now it can be only \
+             removed"
         );
 
         assert!(
             triangles.len() > 0,
-            "instance {instance_handle:?} contains no triangles"
+            "mesh {mesh_handle:?} contains no triangles"
         );
 
-        let triangle_ids = if let Some(triangle_ids) =
-            self.allocator.take(triangles.len())
-        {
-            self.create_reusing_space(bvh, triangles, material_id, triangle_ids)
-        } else {
-            self.create_allocating_space(bvh, triangles, material_id)
-        };
+        let triangle_ids =
+            if let Some(triangle_ids) = self.ids.take(triangles.len()) {
+                for (triangle, tri) in
+                    triangles.zip(&mut self.buffer[triangle_ids.clone()])
+                {
+                    *tri = triangle.serialize();
+                }
+
+                triangle_ids
+            } else {
+                let first_triangle_id = self.buffer.len();
+
+                for triangle in triangles {
+                    self.buffer.push(triangle.serialize());
+                }
+
+                first_triangle_id..self.buffer.len()
+            };
 
         self.index.insert(
-            instance_handle,
-            IndexedInstance {
-                triangle_ids,
+            mesh_handle,
+            IndexedMesh {
+                triangle_ids: triangle_ids.clone(),
                 dirty: true,
             },
         );
 
         self.dirty = true;
-    }
-
-    fn create_reusing_space(
-        &mut self,
-        bvh: &mut Bvh,
-        triangles: impl Iterator<Item = Triangle>,
-        material_id: gpu::MaterialId,
-        triangle_ids: Range<usize>,
-    ) -> Range<usize> {
-        let mut triangle_id = triangle_ids.start;
-
-        let iter = triangles
-            .into_iter()
-            .zip(&mut self.buffer[triangle_ids.clone()])
-            .zip(bvh.update(triangle_ids.clone()));
-
-        for ((triangle, tri), prim) in iter {
-            *tri = triangle.serialize();
-
-            *prim = BvhPrimitive {
-                triangle_id: gpu::TriangleId::new(triangle_id as u32),
-                material_id,
-                center: triangle.center(),
-                bounds: triangle.bounds(),
-            };
-
-            triangle_id += 1;
-        }
 
         triangle_ids
     }
 
-    fn create_allocating_space(
-        &mut self,
-        bvh: &mut Bvh,
-        triangles: impl Iterator<Item = Triangle>,
-        material_id: gpu::MaterialId,
-    ) -> Range<usize> {
-        let first_triangle_id = self.buffer.len();
-
-        for (triangle_idx, triangle) in triangles.enumerate() {
-            self.buffer.push(triangle.serialize());
-
-            bvh.add(BvhPrimitive {
-                triangle_id: gpu::TriangleId::new(
-                    (first_triangle_id + triangle_idx) as u32,
-                ),
-                material_id,
-                center: triangle.center(),
-                bounds: triangle.bounds(),
-            });
-        }
-
-        first_triangle_id..self.buffer.len()
-    }
-
-    pub fn update(
-        &mut self,
-        bvh: &mut Bvh,
-        instance_handle: P::InstanceHandle,
-        triangles: impl Iterator<Item = Triangle> + ExactSizeIterator,
-        material_id: gpu::MaterialId,
-    ) {
-        let instance =
-            self.index.get_mut(&instance_handle).unwrap_or_else(|| {
-                panic!("instance not known: {instance_handle:?}")
-            });
-
-        let iter = triangles
-            .into_iter()
-            .zip(&mut self.buffer[instance.triangle_ids.clone()])
-            .zip(bvh.update(instance.triangle_ids.clone()));
-
-        for ((triangle, tri), prim) in iter {
-            *tri = triangle.serialize();
-
-            prim.material_id = material_id;
-            prim.center = triangle.center();
-            prim.bounds = triangle.bounds();
-        }
-
-        instance.dirty = true;
-        self.dirty = true;
-    }
-
-    pub fn remove(
-        &mut self,
-        bvh: &mut Bvh,
-        instance_handle: P::InstanceHandle,
-    ) {
-        let Some(instance) = self.index.remove(&instance_handle) else {
+    /// Removes triangles of given mesh.
+    ///
+    /// Triangles stay in the buffer until [`Self::release()`] says that no
+    /// BVH refers to them anymore - since the top-level BVH might be getting
+    /// rebuilt on a worker thread, the current one can still point at them for
+    /// a few frames.
+    pub fn remove(&mut self, mesh_handle: P::MeshHandle) {
+        let Some(mesh) = self.index.remove(&mesh_handle) else {
             return;
         };
 
-        self.allocator.give(instance.triangle_ids.clone());
+        self.ids.remove(mesh.triangle_ids);
+    }
 
-        for prim in bvh.update(instance.triangle_ids) {
-            prim.kill();
-        }
+    /// Makes triangles of the removed meshes available for reuse, provided
+    /// they are not referred to by the currently used BVH.
+    ///
+    /// Should be called after refreshing the BVH, with its current and latest
+    /// generation (see: [`crate::Bvh::generation()`]).
+    pub fn release(&mut self, generation: u64, latest_generation: u64) {
+        self.ids.release(generation, latest_generation);
     }
 
     pub fn len(&self) -> usize {
         self.buffer.len()
     }
 
-    pub fn count(&self, instance_handle: P::InstanceHandle) -> Option<usize> {
-        self.index
-            .get(&instance_handle)
-            .map(|instance| instance.triangle_ids.len())
+    pub fn as_slice(&self) -> &[gpu::Triangle] {
+        &self.buffer
+    }
+
+    pub fn get(&self, mesh_handle: P::MeshHandle) -> Option<&[gpu::Triangle]> {
+        let IndexedMesh { triangle_ids, .. } = self.index.get(&mesh_handle)?;
+
+        Some(&self.buffer[triangle_ids.clone()])
     }
 
     pub fn as_vertex_buffer(
         &self,
-        instance_handle: P::InstanceHandle,
+        mesh_handle: P::MeshHandle,
     ) -> Option<(usize, wgpu::BufferSlice<'_>)> {
-        let IndexedInstance { triangle_ids, .. } =
-            self.index.get(&instance_handle)?;
+        let IndexedMesh { triangle_ids, .. } = self.index.get(&mesh_handle)?;
 
         let vertices = 3 * triangle_ids.len();
 
@@ -216,16 +152,16 @@ where
             // Reallocating already flushes the entire buffer, so there's no
             // need to flush it again
         } else {
-            for instance in self.index.values_mut() {
-                if !mem::take(&mut instance.dirty) {
+            for mesh in self.index.values_mut() {
+                if !mem::take(&mut mesh.dirty) {
                     continue;
                 }
 
-                let offset = instance.triangle_ids.start
-                    * mem::size_of::<gpu::Triangle>();
+                let offset =
+                    mesh.triangle_ids.start * mem::size_of::<gpu::Triangle>();
 
-                let size = instance.triangle_ids.len()
-                    * mem::size_of::<gpu::Triangle>();
+                let size =
+                    mesh.triangle_ids.len() * mem::size_of::<gpu::Triangle>();
 
                 self.buffer.flush_part(queue, offset, size);
             }
@@ -240,7 +176,80 @@ where
 }
 
 #[derive(Debug)]
-struct IndexedInstance {
+struct IndexedMesh {
     triangle_ids: Range<usize>,
     dirty: bool,
 }
+
+/// Allocator of triangle ids which defers reusing ids of removed meshes until
+/// no BVH generation refers to them.
+#[derive(Debug, Default)]
+struct TriangleIds {
+    allocator: Allocator,
+
+    /// Ids removed since the last release, not yet assigned a generation
+    removed: Vec<Range<usize>>,
+
+    /// Ids together with the first BVH generation that doesn't refer to them
+    retired: Vec<(u64, Range<usize>)>,
+}
+
+impl TriangleIds {
+    fn take(&mut self, len: usize) -> Option<Range<usize>> {
+        self.allocator.take(len)
+    }
+
+    fn remove(&mut self, ids: Range<usize>) {
+        self.removed.push(ids);
+    }
+
+    fn release(&mut self, generation: u64, latest_generation: u64) {
+        // Removed ids can be still referred to by the current tree or by the
+        // tree being built right now, but not by the latest one (since it's
+        // been, or will be, built from the instances as they are now)
+        for ids in self.removed.drain(..) {
+            self.retired.push((latest_generation, ids));
+        }
+
+        let mut idx = 0;
+
+        while idx < self.retired.len() {
+            if self.retired[idx].0 <= generation {
+                self.allocator.give(self.retired.swap_remove(idx).1);
+            } else {
+                idx += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_reused_once_bvh_catches_up() {
+        let mut target = TriangleIds::default();
+
+        target.allocator.give(0..4);
+
+        let mesh = target.take(4).unwrap();
+
+        // Mesh gets removed (and another one inserted) while generation #1
+        // is being built on a worker thread, so the changes go into the
+        // generation #2 - until then, the current tree (generation #0) and
+        // the pending one both refer to the removed triangles
+        target.remove(mesh.clone());
+        target.release(0, 2);
+
+        assert_eq!(None, target.take(4));
+
+        target.release(1, 2);
+
+        assert_eq!(None, target.take(4));
+
+        target.release(2, 2);
+
+        assert_eq!(Some(mesh), target.take(4));
+    }
+}
@@ -1,6 +1,6 @@
 use std::ops::{Add, AddAssign};
 
-use spirv_std::glam::Vec3;
+use spirv_std::glam::{vec3, Affine3A, Vec3};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct BoundingBox {
@@ -31,9 +31,46 @@ impl BoundingBox {
         extent.x * extent.y + extent.y * extent.z + extent.z * extent.x
     }
 
+    pub fn center(&self) -> Vec3 {
+        (self.min() + self.max()) / 2.0
+    }
+
+    /// Returns the common part of both bounding boxes; if they don't
+    /// intersect, returns an unset bounding box.
+    pub fn intersection(&self, other: Self) -> Self {
+        let min = self.min().max(other.min());
+        let max = self.max().min(other.max());
+
+        if min.cmple(max).all() {
+            Self::new(min, max)
+        } else {
+            Self::default()
+        }
+    }
+
     pub fn is_set(&self) -> bool {
         self.min.x != Self::default().min.x
     }
+
+    /// Returns a bounding box that encloses this bounding box after applying
+    /// given transformation to it.
+    pub fn transform(&self, xform: Affine3A) -> Self {
+        let (min, max) = (self.min(), self.max());
+
+        [
+            vec3(min.x, min.y, min.z),
+            vec3(min.x, min.y, max.z),
+            vec3(min.x, max.y, min.z),
+            vec3(min.x, max.y, max.z),
+            vec3(max.x, min.y, min.z),
+            vec3(max.x, min.y, max.z),
+            vec3(max.x, max.y, min.z),
+            vec3(max.x, max.y, max.z),
+        ]
+        .into_iter()
+        .map(|corner| xform.transform_point3(corner))
+        .collect()
+    }
 }
 
 impl Default for BoundingBox {